@@ -0,0 +1,57 @@
+//! Backoff on the interfaces that keep failing.
+//!
+//! An interface whose send thread can't be created, or whose sends fail, is removed and would be
+//! re-created on the next interface check, failing again and logging a warning every time. Instead,
+//! each consecutive failure doubles the delay before the interface is retried.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Delay before retrying an interface after its first failure
+const FIRST_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay before retrying an interface
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Time without failures after which an interface's past failures are forgotten
+const RESET_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    retry_at: Instant,
+}
+
+/// Consecutive failures of the interfaces, and when each may be retried
+#[derive(Debug, Default)]
+pub struct FailureBackoff {
+    interfaces: HashMap<String, Failures>,
+}
+
+impl FailureBackoff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that an interface failed, returning the delay before it's retried
+    pub fn record_failure(&mut self, ifname: &str) -> Duration {
+        let now = Instant::now();
+        self.interfaces.retain(|_, failures| now.saturating_duration_since(failures.retry_at) < RESET_AFTER);
+        let failures = self.interfaces.entry(ifname.to_owned()).or_insert(Failures { count: 0, retry_at: now });
+        let delay = FIRST_DELAY.saturating_mul(1 << failures.count.min(16)).min(MAX_DELAY);
+        failures.count += 1;
+        failures.retry_at = now + delay;
+        delay
+    }
+
+    /// Returns whether an interface must not be retried yet
+    pub fn is_waiting(&self, ifname: &str) -> bool {
+        self.interfaces.get(ifname).is_some_and(|failures| failures.retry_at > Instant::now())
+    }
+
+    /// Returns when the next waiting interface may be retried, if any is waiting
+    pub fn next_retry(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.interfaces.values().map(|failures| failures.retry_at).filter(|at| *at > now).min()
+    }
+}
@@ -0,0 +1,72 @@
+//! Dampening of unstable interfaces.
+//!
+//! An interface that keeps going down and coming back (e.g. on weak Wi-Fi) would otherwise be
+//! re-added as soon as it reappears, carrying traffic until its next drop. Once an interface went
+//! down twice within [`STABLE_PERIOD`], it's held out of service when it comes back, for a period
+//! doubling with every further drop.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+/// Hold period after the second drop
+const FIRST_HOLD: Duration = Duration::from_secs(5);
+
+/// Longest hold period
+const MAX_HOLD: Duration = Duration::from_secs(300);
+
+/// Time without drops after which an interface's past drops are forgotten
+const STABLE_PERIOD: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+struct Flaps {
+    count: u32,
+    last_at: Instant,
+    held_until: Instant,
+}
+
+/// Drops of the interfaces, and how long each is held out of service
+#[derive(Debug, Default)]
+pub struct FlapDampening {
+    interfaces: HashMap<String, Flaps>,
+}
+
+impl FlapDampening {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that an interface went down, holding it out of service if it keeps doing so
+    pub fn record_down(&mut self, ifname: &str) {
+        let now = Instant::now();
+        self.interfaces.retain(|_, flaps| now.duration_since(flaps.last_at) < STABLE_PERIOD);
+        let flaps = self.interfaces.entry(ifname.to_owned()).or_insert(Flaps {
+            count: 0,
+            last_at: now,
+            held_until: now,
+        });
+        flaps.count += 1;
+        flaps.last_at = now;
+        if flaps.count >= 2 {
+            let hold = FIRST_HOLD.saturating_mul(1 << (flaps.count - 2).min(16)).min(MAX_HOLD);
+            flaps.held_until = now + hold;
+            warn!(
+                monotonic_counter.rengarde_interface_flaps_total = 1_u64,
+                iface_name = ifname,
+                "Interface '{}' went down {} times recently; holding it out of service for {:?}", ifname, flaps.count, hold
+            );
+        }
+    }
+
+    /// Returns whether an interface is held out of service
+    pub fn is_held(&self, ifname: &str) -> bool {
+        self.interfaces.get(ifname).is_some_and(|flaps| flaps.held_until > Instant::now())
+    }
+
+    /// Returns when the next held interface is released, if any is held
+    pub fn next_release(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.interfaces.values().map(|flaps| flaps.held_until).filter(|until| *until > now).min()
+    }
+}
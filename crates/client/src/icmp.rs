@@ -0,0 +1,148 @@
+//! Fast path failure detection through the ICMP errors the kernel queues on a socket.
+//!
+//! With `IP_RECVERR` (`IPV6_RECVERR`) enabled, the ICMP errors received for datagrams sent on an
+//! unconnected UDP socket are reported to its next receive or send call, and queued with their
+//! details on its error queue, so a "port unreachable" or "network unreachable" marks a path as
+//! down right away instead of after a timeout.
+//!
+//! With Don't-Fragment set, the kernel discovers each path's MTU too: datagrams larger than it fail
+//! with `EMSGSIZE`, and the error queue reports the MTU.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::fd::AsRawFd;
+
+/// ICMP error received for a datagram sent on a socket
+#[derive(Debug, Clone, Copy)]
+pub struct IcmpError {
+    pub errno: i32,
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    /// MTU of the path, for "fragmentation needed" ("packet too big") errors
+    pub mtu: Option<u32>,
+}
+
+impl fmt::Display for IcmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (ICMP type {}, code {})",
+            io::Error::from_raw_os_error(self.errno),
+            self.icmp_type,
+            self.icmp_code
+        )
+    }
+}
+
+/// Asks the kernel to report the ICMP errors received for the socket's datagrams
+pub fn enable_recverr(socket: &impl AsRawFd, ipv6: bool) -> io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::SOL_IPV6, libc::IPV6_RECVERR)
+    } else {
+        (libc::SOL_IP, libc::IP_RECVERR)
+    };
+    let enabled: libc::c_int = 1;
+    // SAFETY: the option value points to a live c_int of the given length
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&enabled as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets Don't-Fragment on the socket's datagrams, so the kernel discovers the path MTU instead of
+/// fragmenting them
+pub fn set_dont_fragment(socket: &impl AsRawFd, ipv6: bool) -> io::Result<()> {
+    let (level, name, value) = if ipv6 {
+        (libc::SOL_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO)
+    } else {
+        (libc::SOL_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO)
+    };
+    // SAFETY: the option value points to a live c_int of the given length
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns whether a socket error means a datagram was larger than the path MTU
+pub fn is_too_big(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+/// Returns whether a socket error means the destination can't be reached from this path
+pub fn is_unreachable(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ECONNREFUSED | libc::ENETUNREACH | libc::EHOSTUNREACH)
+    )
+}
+
+/// Drains the socket's error queue without blocking, returning the most recent ICMP error
+pub fn take_errors(socket: &impl AsRawFd) -> Option<IcmpError> {
+    let mut latest = None;
+    loop {
+        let mut data = [0_u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        // Room for one control message carrying the extended error and the offender's address
+        let mut control = [0_u64; 16];
+        // SAFETY: msghdr is a plain C struct, for which all zeroes is a valid value
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        // SAFETY: msg points to buffers that outlive the call
+        let ret = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if ret < 0 {
+            return latest;
+        }
+
+        // SAFETY: the control messages were written by the kernel within msg_controllen
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while let Some(header) = unsafe { cmsg.as_ref() } {
+            let is_recverr = (header.cmsg_level == libc::SOL_IP && header.cmsg_type == libc::IP_RECVERR)
+                || (header.cmsg_level == libc::SOL_IPV6 && header.cmsg_type == libc::IPV6_RECVERR);
+            if is_recverr {
+                // SAFETY: IP_RECVERR control messages start with a sock_extended_err
+                let err: libc::sock_extended_err = unsafe { (libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err).read_unaligned() };
+                // Datagrams larger than the known path MTU fail locally, before any ICMP error
+                let too_big = err.ee_errno as i32 == libc::EMSGSIZE;
+                if matches!(err.ee_origin, libc::SO_EE_ORIGIN_ICMP | libc::SO_EE_ORIGIN_ICMP6)
+                    || (too_big && err.ee_origin == libc::SO_EE_ORIGIN_LOCAL)
+                {
+                    latest = Some(IcmpError {
+                        errno: err.ee_errno as i32,
+                        icmp_type: err.ee_type,
+                        icmp_code: err.ee_code,
+                        mtu: (too_big && err.ee_info > 0).then_some(err.ee_info),
+                    });
+                }
+            }
+            // SAFETY: cmsg is a control message of msg
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+    }
+}
@@ -1,9 +1,447 @@
-// The maximum transmission unit (MTU) of an Ethernet frame is 1518 bytes with the normal untagged
-// Ethernet frame overhead of 18 bytes and the 1500-byte payload.
-pub const BUFFER_SIZE: usize = 1500;
+use std::net::IpAddr;
 
+use anyhow::{anyhow, bail, Context, Result};
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use serde::Serialize;
+use serde_json::{json, Value};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use shared::cli::Args;
+use shared::config::Layers;
+use shared::datagram::PeerAddr;
+use shared::instance::InstanceLock;
+use shared::BuildInfo;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+pub mod backoff;
+pub mod flap;
+pub mod icmp;
+pub mod netlink;
+pub mod nm;
+pub mod pacing;
+pub mod scheduler;
 pub mod types;
+pub mod usage;
 pub mod service;
+pub mod wrapper;
+
+pub use types::{Settings, BondingMode, ClientSettings, DataCap, Failover, InterfacePattern, InterfaceSettings, WebManager, WrapperSettings};
+pub use scheduler::{PathInfo, Scheduler};
+pub use service::Service;
+
+/// Command line of the client
+#[derive(Debug, Parser)]
+#[command(
+    name = env!("CARGO_PKG_NAME"),
+    version,
+    about = "rengarde client: sends the WireGuard traffic over every available interface to the rengarde server",
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub args: Args,
+}
+
+/// Commands of the client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Subcommand)]
+pub enum Command {
+    /// Run the client (default)
+    #[default]
+    Run,
+    /// Validate the configuration and what it references, then exit
+    CheckConfig,
+    /// Print the JSON Schema of the configuration file
+    GenerateSchema,
+    /// Write a commented example configuration to CONFIG, or to the standard output if it's '-'
+    GenerateConfig,
+    /// List the network interfaces, their addresses and whether they would be used
+    ListInterfaces {
+        /// Print them as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the version and build information
+    Version {
+        /// Print them as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the shell completions
+    Completions {
+        shell: Shell,
+    },
+}
+
+/// Runs the client, or the other command of its command line
+pub async fn run(cli: Cli) -> Result<()> {
+    let Cli { command, args } = cli;
+    let command = command.unwrap_or_default();
+    match command {
+        Command::Version { json } => return shared::print_version(&build_info()?, json),
+        Command::Completions { shell } => {
+            shared::cli::print_completions(shell, Cli::command());
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let _guard = shared::telemetry()
+        .service_name("rengarde-client")
+        .log_level(args.log_level)
+        .log_format(args.log_format)
+        .init()?;
+    match command {
+        Command::GenerateSchema => {
+            let schema = shared::config::schema::generate::<Settings>("rengarde client configuration");
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        Command::GenerateConfig => return shared::config::write_example(args.config(), include_str!("../engarde.yml.sample")),
+        // Before the header, so the JSON output can be piped
+        Command::ListInterfaces { json } => return list_interfaces(&args, json),
+        _ => {}
+    }
+
+    shared::print_header(&build_info()?, args.log_format);
+
+    let settings = load_settings(&args)?;
+    if args.print_config {
+        return shared::config::print(&settings);
+    }
+
+    if command == Command::CheckConfig {
+        check_config(&settings.client).await?;
+        info!("Configuration '{}' is valid", args.config());
+        return Ok(());
+    }
+
+    // Fail fast if another instance already uses the same listen address
+    let _lock = InstanceLock::acquire(env!("CARGO_PKG_NAME"), &settings.client.listen_addr, env!("CARGO_PKG_VERSION"))?;
+
+    let service = Service::new(settings.client);
+
+    // Reload the configuration on signals
+    tokio::spawn({
+        let service = service.clone();
+        async move {
+            if let Err(err) = reload_on_hangup(args, service).await {
+                warn!("Reload signal handler failed: {:?}", err);
+            }
+        }
+    });
+
+    service.run().await?;
+    Ok(())
+}
+
+const INTERFACE_CHECK_INTERVAL: u64 = 1000;
+const ADDRESSES_PER_INTERFACE: usize = 1;
+const BILLING_DAY: u8 = 1;
+const LOSS_THRESHOLD: f64 = 2.0;
+const RECOVERY_TIME: u64 = 30;
+
+/// Metadata of the build, printed in the header of the logs and by `version`
+fn build_info() -> Result<BuildInfo> {
+    let rust_runtime = if cfg!(feature = "rt-rayon") {
+        "rayon"
+    } else if cfg!(feature = "rt-tokio") {
+        "tokio"
+    } else {
+        unimplemented!("No runtime feature enabled");
+    };
+
+    Ok(BuildInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        official_build: option_env!("RENGARDE_OFFICIAL_BUILD").unwrap_or("false").parse::<bool>()?,
+        git_describe: env!("VERGEN_GIT_DESCRIBE"),
+        git_dirty: env!("VERGEN_GIT_DIRTY") == "true",
+        build_timestamp: env!("VERGEN_BUILD_TIMESTAMP"),
+        target_triple: env!("VERGEN_CARGO_TARGET_TRIPLE"),
+        runtime: rust_runtime,
+    })
+}
+
+/// Defaults of the settings, the lowest layer of the configuration
+fn defaults() -> Value {
+    json!({
+        "client": {
+            "writeTimeout": 10,
+            "bufferSize": shared::DEFAULT_BUFFER_SIZE,
+            "interfaceCheckInterval": INTERFACE_CHECK_INTERVAL,
+            "packetTraceSampling": 1,
+            "addressesPerInterface": ADDRESSES_PER_INTERFACE,
+            "dedupWindow": 1000,
+            "failover": { "lossThreshold": LOSS_THRESHOLD, "recoveryTime": RECOVERY_TIME },
+            "dataCap": { "billingDay": BILLING_DAY },
+        }
+    })
+}
+
+/// Loads the configuration file given on the command line, between the defaults and the command line's overrides,
+/// and validates it
+fn load_settings(args: &Args) -> Result<Settings> {
+    let layers = Layers {
+        defaults: defaults(),
+        environment: shared::config::environment(),
+        command_line: args.listen_addr.iter().map(|listen_addr| ("client.listenAddr".to_owned(), json!(listen_addr))).collect(),
+        strict: args.strict,
+        engarde_compat: args.engarde_compat,
+        profile: args.profile.clone(),
+    };
+    let mut settings: Settings = shared::config::load(args.config(), layers)?;
+    if let Some(wrapper) = &mut settings.client.wrapper {
+        shared::config::load_secret("client.wrapper.psk", &mut wrapper.psk, wrapper.psk_file.as_deref())?;
+        shared::config::load_secret("client.wrapper.encryptionKey", &mut wrapper.encryption_key, wrapper.encryption_key_file.as_deref())?;
+    }
+    if let Some(web_manager) = &mut settings.client.web_manager {
+        shared::config::load_secret("client.webManager.password", &mut web_manager.password, web_manager.password_file.as_deref())?;
+    }
+    if let Some(description) = &settings.client.description {
+        info!("{}", description);
+    }
+
+    if settings.client.buffer_size == Some(0) {
+        info!("Buffer size set to 0; setting to {} bytes.", shared::DEFAULT_BUFFER_SIZE);
+        settings.client.buffer_size = Some(shared::DEFAULT_BUFFER_SIZE);
+    }
+    if settings.client.interface_check_interval == Some(0) {
+        info!("Interface check interval set to 0; setting to {}ms.", INTERFACE_CHECK_INTERVAL);
+        settings.client.interface_check_interval = Some(INTERFACE_CHECK_INTERVAL);
+    }
+    if settings.client.addresses_per_interface == Some(0) {
+        info!("Addresses per interface set to 0; setting to {}.", ADDRESSES_PER_INTERFACE);
+        settings.client.addresses_per_interface = Some(ADDRESSES_PER_INTERFACE);
+    }
+    if settings.client.dedup_window == Some(0) {
+        info!("Dedup window set to 0; disabling duplicate suppression.");
+        settings.client.dedup_window = None;
+    }
+    if settings.client.best_paths == Some(0) {
+        info!("Best paths set to 0; duplicating on every path.");
+        settings.client.best_paths = None;
+    }
+
+    if settings.client.mode == BondingMode::Failover {
+        if settings.client.failover.is_none() {
+            info!("Failover not set; setting the loss threshold to {}% and the recovery time to {}s.", LOSS_THRESHOLD, RECOVERY_TIME);
+            settings.client.failover = Some(Failover { loss_threshold: Some(LOSS_THRESHOLD), recovery_time: Some(RECOVERY_TIME) });
+        }
+        if settings.client.wrapper.as_ref().is_none_or(|wrapper| !wrapper.path_reports) {
+            warn!("Failover mode measures loss with path reports, which are disabled; never duplicating.");
+        }
+    }
+    if settings.client.dscp.is_some_and(|dscp| dscp > 63) {
+        warn!("DSCP must be between 0 and 63; not marking packets.");
+        settings.client.dscp = None;
+    }
+    for (ifname, iface) in &mut settings.client.interfaces {
+        if iface.dscp.is_some_and(|dscp| dscp > 63) {
+            warn!("DSCP of interface '{}' must be between 0 and 63; ignoring it.", ifname);
+            iface.dscp = None;
+        }
+    }
+    if let Some(data_cap) = &mut settings.client.data_cap {
+        match data_cap.billing_day {
+            Some(0) => {
+                info!("Billing day set to 0; setting to {}.", BILLING_DAY);
+                data_cap.billing_day = Some(BILLING_DAY);
+            }
+            Some(day @ 29..) => {
+                warn!("Billing day {} doesn't exist in every month; setting to 28.", day);
+                data_cap.billing_day = Some(28);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Reloads the configuration file on `SIGHUP`, applying it to the running service
+#[tracing::instrument(skip_all)]
+async fn reload_on_hangup(args: Args, service: Service) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        hangup.recv().await;
+        info!("Reloading the configuration");
+        match load_settings(&args) {
+            Ok(settings) => service.reload(settings.client),
+            Err(err) => warn!("Failed to reload the configuration; keeping the current one: {:?}", err),
+        }
+    }
+}
+
+/// Checks what the settings reference: the listen and server addresses resolve, the directory of a Unix listen
+/// socket exists, and the interfaces configured by name exist
+///
+/// Every error is logged with the setting it comes from, before failing with their count.
+async fn check_config(settings: &ClientSettings) -> Result<()> {
+    let mut errors = Vec::new();
+    let mut check = |setting: String, result: Result<()>| {
+        if let Err(err) = result {
+            error!("{}: {:#}", setting, err);
+            errors.push(setting);
+        }
+    };
+
+    match PeerAddr::unix_path(&settings.listen_addr) {
+        Some(path) => {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(std::path::Path::new("."));
+            check("client.listenAddr".to_owned(), std::fs::metadata(dir)
+                .map(|_| ())
+                .with_context(|| format!("Directory '{}' of the socket doesn't exist", dir.display())));
+        }
+        None => check("client.listenAddr".to_owned(), resolve(&settings.listen_addr).await),
+    }
+    check("client.dstAddr".to_owned(), resolve(&settings.dst_addr).await);
+    if let Some(listen_addr) = settings.web_manager.as_ref().and_then(|web_manager| web_manager.listen_addr.as_deref()) {
+        check("client.webManager.listenAddr".to_owned(), resolve(listen_addr).await);
+    }
+
+    let interfaces = NetworkInterface::show()?.into_iter().map(|iface| iface.name).collect::<Vec<_>>();
+    let exists = |ifname: &str| if interfaces.iter().any(|name| name == ifname) {
+        Ok(())
+    } else {
+        Err(anyhow!("Interface '{}' doesn't exist", ifname))
+    };
+    let mut configured = settings.interfaces.iter().collect::<Vec<_>>();
+    configured.sort_by_key(|(ifname, _)| *ifname);
+    for (ifname, iface) in configured {
+        check(format!("client.interfaces.{}", ifname), exists(ifname));
+        if let Some(dst_addr) = &iface.dst_addr {
+            check(format!("client.interfaces.{}.dstAddr", ifname), resolve(dst_addr).await);
+        }
+        if let Some(vrf) = &iface.vrf {
+            check(format!("client.interfaces.{}.vrf", ifname), exists(vrf));
+        }
+    }
+    let by_name = [
+        ("weights", settings.weights.keys().collect::<Vec<_>>()),
+        ("pacingKbps", settings.pacing_kbps.keys().collect()),
+        ("standby", settings.standby.iter().collect()),
+        ("dataCap.quotaMb", settings.data_cap.iter().flat_map(|data_cap| data_cap.quota_mb.keys()).collect()),
+    ];
+    for (setting, mut ifnames) in by_name {
+        ifnames.sort();
+        for ifname in ifnames {
+            check(format!("client.{}.{}", setting, ifname), exists(ifname));
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("{} invalid setting(s): {}", errors.len(), errors.join(", "));
+    }
+    Ok(())
+}
+
+async fn resolve(addr: &str) -> Result<()> {
+    tokio::net::lookup_host(addr)
+        .await
+        .with_context(|| format!("Address '{}' can't be resolved", addr))?
+        .next()
+        .ok_or_else(|| anyhow!("Address '{}' resolves to no address", addr))?;
+    Ok(())
+}
+
+/// An interface as `list-interfaces` describes it
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InterfaceInfo {
+    name: String,
+    operstate: Option<String>,
+    mtu: Option<usize>,
+    addresses: Vec<IpAddr>,
+    // Addresses rengarde would send from
+    selected: Vec<IpAddr>,
+    // Why rengarde would skip the interface, if it would
+    skipped: Option<String>,
+}
+
+/// Lists the network interfaces, and which of their addresses the configuration would send from, or why it would
+/// skip them
+///
+/// Without a readable configuration, the addresses are the ones the defaults would pick.
+fn list_interfaces(args: &Args, json: bool) -> Result<()> {
+    let layers = Layers {
+        defaults: defaults(),
+        environment: shared::config::environment(),
+        engarde_compat: args.engarde_compat,
+        profile: args.profile.clone(),
+        ..Default::default()
+    };
+    let settings = shared::config::load::<Settings>(args.config(), layers).map(|settings| settings.client).ok();
+    let interfaces = NetworkInterface::show()?
+        .into_iter()
+        .map(|iface| {
+            let mut addresses = iface.addr.iter().map(|addr| addr.ip()).collect::<Vec<_>>();
+            addresses.dedup();
+            let (selected, skipped) = match &settings {
+                Some(settings) => {
+                    let selected = service::source_addresses(settings, &iface);
+                    let skipped = if let Some(reason) = settings.interface_filter(&iface.name) {
+                        Some(reason.to_owned())
+                    } else if !service::is_link_up(&iface.name) {
+                        Some("has no link".to_owned())
+                    } else if selected.is_empty() {
+                        Some("has no address".to_owned())
+                    } else if !selected.iter().any(|addr| service::has_route_to_server(settings, &iface.name, *addr)) {
+                        Some("has no route to the server".to_owned())
+                    } else {
+                        None
+                    };
+                    (selected, skipped)
+                }
+                None => (service::get_address_by_interface(&iface, false).into_iter().collect(), None),
+            };
+            InterfaceInfo {
+                operstate: service::operstate(&iface.name),
+                mtu: service::interface_mtu(&iface.name),
+                name: iface.name,
+                addresses,
+                selected,
+                skipped,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&interfaces)?);
+        return Ok(());
+    }
+    if settings.is_none() {
+        println!("Configuration '{}' can't be read; showing the addresses the defaults would pick", args.config());
+    }
+    for iface in interfaces {
+        let join = |addrs: &[IpAddr]| addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>().join(", ");
+        println!();
+        println!("{}", iface.name);
+        println!("  State: {}", iface.operstate.as_deref().unwrap_or("unknown"));
+        println!("  MTU: {}", iface.mtu.map(|mtu| mtu.to_string()).unwrap_or_default());
+        println!("  Addresses: {}", join(&iface.addresses));
+        println!("  Address: {}", join(&iface.selected));
+        if let Some(reason) = iface.skipped {
+            println!("  Skipped: {}", reason);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defines_a_valid_command_line() {
+        Cli::command().debug_assert();
+    }
 
-pub use types::{Settings, ClientSettings, WebManager};
-pub use service::Service; 
\ No newline at end of file
+    #[test]
+    fn runs_by_default() {
+        let cli = Cli::try_parse_from(["client", "engarde.yml"]).unwrap();
+        assert_eq!(cli.command.unwrap_or_default(), Command::Run);
+        assert_eq!(cli.args.config(), "engarde.yml");
+    }
+}
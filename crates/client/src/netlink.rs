@@ -0,0 +1,79 @@
+//! Interface change notifications from the kernel, over a route netlink socket.
+//!
+//! The socket subscribes to the link and IPv4/IPv6 address multicast groups, so the client re-scans
+//! its interfaces within milliseconds of a change instead of polling them. Notifications are only
+//! used as a wake-up signal; their contents aren't parsed.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use tokio::io::unix::AsyncFd;
+
+/// Subscription to the kernel's link and address change notifications
+pub struct LinkMonitor {
+    socket: AsyncFd<OwnedFd>,
+}
+
+impl LinkMonitor {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { socket: AsyncFd::new(socket)? })
+    }
+
+    /// Waits for the next change notification, then drains the pending ones so a burst of changes
+    /// wakes the caller once
+    pub async fn changed(&self) -> io::Result<()> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let mut guard = self.socket.readable().await?;
+            let mut notified = false;
+            loop {
+                let received = unsafe {
+                    libc::recv(self.socket.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+                };
+                if received >= 0 {
+                    notified = true;
+                    continue;
+                }
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EAGAIN) => break,
+                    Some(libc::EINTR) => continue,
+                    // Notifications were lost because the socket buffer overflowed; something changed anyway
+                    Some(libc::ENOBUFS) => notified = true,
+                    _ => return Err(err),
+                }
+            }
+            guard.clear_ready();
+            if notified {
+                return Ok(());
+            }
+        }
+    }
+}
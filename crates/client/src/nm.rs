@@ -0,0 +1,163 @@
+//! NetworkManager integration, through `nmcli`.
+//!
+//! On desktops, an interface having an address doesn't mean it's usable: NetworkManager may still
+//! be activating it, or its connectivity check may have found a captive portal. The client follows
+//! NetworkManager's view of its devices instead, and re-scans them whenever `nmcli monitor` reports
+//! a change. Devices NetworkManager doesn't manage are left to the address-based detection.
+
+use std::collections::HashMap;
+use std::io;
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+
+/// Quiet period ending a burst of change notifications
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Device state of an activated connection
+const STATE_ACTIVATED: u32 = 100;
+
+/// Device state of a device NetworkManager doesn't manage
+const STATE_UNMANAGED: u32 = 10;
+
+/// Result of NetworkManager's connectivity check on a device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Connectivity {
+    #[default]
+    Unknown,
+    None,
+    Portal,
+    Limited,
+    Full,
+}
+
+impl Connectivity {
+    fn parse(value: &str) -> Self {
+        // Printed e.g. as `4 (full)`
+        match value.split_whitespace().next() {
+            Some("1") => Self::None,
+            Some("2") => Self::Portal,
+            Some("3") => Self::Limited,
+            Some("4") => Self::Full,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// State of a device as NetworkManager sees it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Device {
+    pub state: u32,
+    pub ip4_connectivity: Connectivity,
+    pub ip6_connectivity: Connectivity,
+}
+
+impl Device {
+    /// Returns why the device can't carry traffic from an address of the given family, if it can't
+    pub fn unusable_reason(&self, ipv6: bool) -> Option<&'static str> {
+        if self.state == STATE_UNMANAGED {
+            return None;
+        }
+        if self.state != STATE_ACTIVATED {
+            return Some("isn't activated by NetworkManager");
+        }
+        let connectivity = if ipv6 { self.ip6_connectivity } else { self.ip4_connectivity };
+        match connectivity {
+            Connectivity::Portal => Some("is behind a captive portal"),
+            Connectivity::None => Some("has no connectivity according to NetworkManager"),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the devices NetworkManager knows, by interface name
+pub async fn devices() -> io::Result<HashMap<String, Device>> {
+    let output = Command::new("nmcli")
+        .args([
+            "--terse",
+            "--fields",
+            "GENERAL.DEVICE,GENERAL.STATE,GENERAL.IP4-CONNECTIVITY,GENERAL.IP6-CONNECTIVITY",
+            "device",
+            "show",
+        ])
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_owned()));
+    }
+
+    // Prints `FIELD:value` lines, starting with the device name for each device
+    let mut devices = HashMap::new();
+    let mut current: Option<(String, Device)> = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        match field {
+            "GENERAL.DEVICE" => {
+                devices.extend(current.take());
+                current = Some((value.to_owned(), Device::default()));
+            }
+            "GENERAL.STATE" => {
+                if let Some((_, device)) = &mut current {
+                    device.state = value.split_whitespace().next().and_then(|state| state.parse().ok()).unwrap_or_default();
+                }
+            }
+            "GENERAL.IP4-CONNECTIVITY" => {
+                if let Some((_, device)) = &mut current {
+                    device.ip4_connectivity = Connectivity::parse(value);
+                }
+            }
+            "GENERAL.IP6-CONNECTIVITY" => {
+                if let Some((_, device)) = &mut current {
+                    device.ip6_connectivity = Connectivity::parse(value);
+                }
+            }
+            _ => {}
+        }
+    }
+    devices.extend(current);
+    Ok(devices)
+}
+
+/// Subscription to NetworkManager's change notifications
+pub struct Monitor {
+    // Kept to kill `nmcli monitor` when dropped
+    _child: Child,
+    lines: Lines<BufReader<ChildStdout>>,
+}
+
+impl Monitor {
+    pub fn new() -> io::Result<Self> {
+        let mut child = Command::new("nmcli")
+            .arg("monitor")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or_else(|| io::Error::other("nmcli monitor has no output"))?;
+        Ok(Self {
+            _child: child,
+            lines: BufReader::new(stdout).lines(),
+        })
+    }
+
+    /// Waits for the next change, then for the burst of changes it belongs to, so the caller wakes
+    /// once
+    pub async fn changed(&mut self) -> io::Result<()> {
+        self.next_line().await?;
+        while let Ok(line) = tokio::time::timeout(SETTLE_TIME, self.next_line()).await {
+            line?;
+        }
+        Ok(())
+    }
+
+    async fn next_line(&mut self) -> io::Result<String> {
+        self.lines
+            .next_line()
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "nmcli monitor exited"))
+    }
+}
@@ -0,0 +1,65 @@
+//! Pacing of the sends on an interface.
+//!
+//! Bursts sent on a paced interface are queued and released at the configured rate instead of all at
+//! once, so constrained uplinks (satellite, 4G) don't drop the tail of the burst. Timers being coarse,
+//! packets go out in small batches whenever the pacer falls behind by less than [`SLACK`].
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use shared::drops::{self, DropReason};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, Level};
+
+/// Packets queued on a paced interface at most; beyond it, packets aren't sent on that interface
+pub const QUEUE_LEN: usize = 64;
+
+/// How far ahead of schedule the pacer sends without sleeping
+const SLACK: Duration = Duration::from_millis(1);
+
+/// Spawns the task pacing the sends on an interface at `kbps`, returning the queue feeding it
+///
+/// The task stops once the queue is dropped.
+pub fn spawn(ifname: String, socket: Arc<UdpSocket>, dst_addr: SocketAddr, kbps: u32) -> mpsc::Sender<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+    tokio::spawn(pace(ifname, socket, dst_addr, kbps, receiver));
+    sender
+}
+
+async fn pace(ifname: String, socket: Arc<UdpSocket>, dst_addr: SocketAddr, kbps: u32, mut receiver: mpsc::Receiver<Vec<u8>>) {
+    let bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+    let mut next = Instant::now();
+    while let Some(buf) = receiver.recv().await {
+        // Idle time earns no credit, so the next burst is paced too
+        let now = Instant::now();
+        if next < now {
+            next = now;
+        } else if next > now + SLACK {
+            sleep_until(next).await;
+        }
+
+        match socket.send_to(&buf, dst_addr).await {
+            Ok(sent_bytes) => {
+                debug!(
+                    monotonic_counter.rengarde_path_sent_packets_total = 1_u64,
+                    monotonic_counter.rengarde_path_sent_bytes_total = sent_bytes as u64,
+                    iface_name = ifname
+                );
+                shared::packet_event!(Level::TRACE, "\tSent {} paced bytes on iface {}", sent_bytes, ifname);
+            }
+            Err(err) => {
+                debug!(
+                    monotonic_counter.rengarde_path_send_errors_total = 1_u64,
+                    iface_name = ifname,
+                    "Failed to send paced packet on interface '{}': {:?}", ifname, err
+                );
+                drops::record(DropReason::SendError);
+            }
+        }
+        next += Duration::from_secs_f64(buf.len() as f64 / bytes_per_sec);
+    }
+    debug!("Pacer of interface '{}' closed", ifname);
+}
@@ -0,0 +1,220 @@
+//! Path selection: which interfaces carry each packet sent to the server.
+//!
+//! For every packet, the service hands the usable paths (up primary interfaces, else standby ones)
+//! and their measurements to a [`Scheduler`]. The bonding modes are schedulers; embedders can plug
+//! their own policy in with [`Service::with_scheduler`](crate::Service::with_scheduler).
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use shared::control::PathReport;
+use tracing::{info, warn};
+
+use crate::types::{BondingMode, ClientSettings};
+
+/// Measurements of a usable path when a packet is scheduled
+#[derive(Debug, Clone)]
+pub struct PathInfo {
+    pub ifname: String,
+    /// Last time traffic was received from the server on this path
+    pub last_received_at: Instant,
+    /// Latest report from the server about what it received on this path
+    pub upstream: Option<PathReport>,
+    /// Smoothed round-trip time of this path, measured with echo requests
+    pub rtt: Option<Duration>,
+}
+
+impl PathInfo {
+    /// Returns how the path ranks when picking the best paths, lower being better, if its upstream
+    /// loss or round-trip time was measured
+    ///
+    /// Loss counts in whole percents, so that measurement noise doesn't override a lower RTT.
+    pub fn quality(&self) -> Option<(u16, Duration)> {
+        if self.upstream.is_none() && self.rtt.is_none() {
+            return None;
+        }
+        let loss_percent = self.upstream.map_or(0, |report| report.loss_permille / 10);
+        Some((loss_percent, self.rtt.unwrap_or(Duration::MAX)))
+    }
+}
+
+/// Policy picking the paths each packet is sent on
+pub trait Scheduler: Send {
+    /// Returns the names of the interfaces the next packet is sent on, or `None` to send it on every
+    /// usable path
+    ///
+    /// `paths` are the usable paths, sorted by name; `duplication_limit` is the number of paths the
+    /// server asked to duplicate on at most while its uplink is congested.
+    fn schedule(&mut self, paths: &[PathInfo], duplication_limit: Option<usize>) -> Option<Vec<String>>;
+}
+
+/// Builds the scheduler of the configured bonding mode
+pub fn from_settings(settings: &ClientSettings) -> Box<dyn Scheduler> {
+    match settings.mode {
+        BondingMode::Duplicate => Box::new(Duplicate::new(settings.best_paths)),
+        BondingMode::RoundRobin => Box::new(RoundRobin::new(settings.weights.clone())),
+        BondingMode::ActiveBackup => Box::new(ActiveBackup::default()),
+        BondingMode::Failover => {
+            let failover = settings.failover.clone().unwrap_or_default();
+            Box::new(Failover::new(
+                failover.loss_threshold.unwrap_or(2.0),
+                Duration::from_secs(failover.recovery_time.unwrap_or(30)),
+            ))
+        }
+    }
+}
+
+/// Returns the names of the `limit` paths that most recently received from the server
+pub fn most_recently_active(paths: &[PathInfo], limit: usize) -> Vec<String> {
+    let mut paths: Vec<_> = paths.iter().collect();
+    paths.sort_by_key(|path| std::cmp::Reverse(path.last_received_at));
+    paths.into_iter().take(limit).map(|path| path.ifname.clone()).collect()
+}
+
+/// Returns the names of the `limit` paths with the lowest upstream loss, then round-trip time, or
+/// `None` if any of them has neither measured
+pub fn best_paths(paths: &[PathInfo], limit: usize) -> Option<Vec<String>> {
+    let mut paths = paths
+        .iter()
+        .map(|path| Some((path.ifname.clone(), path.quality()?)))
+        .collect::<Option<Vec<_>>>()?;
+    paths.sort_by_key(|(_, quality)| *quality);
+    Some(paths.into_iter().take(limit).map(|(ifname, _)| ifname).collect())
+}
+
+/// Sends each packet on every path, or only on the best ones if configured
+///
+/// Duplicates on fewer paths while the server is congested; without quality data, congestion limits
+/// to the most recently active paths.
+#[derive(Debug)]
+pub struct Duplicate {
+    best_paths: Option<usize>,
+}
+
+impl Duplicate {
+    pub fn new(best_paths: Option<usize>) -> Self {
+        Self { best_paths }
+    }
+}
+
+impl Scheduler for Duplicate {
+    fn schedule(&mut self, paths: &[PathInfo], duplication_limit: Option<usize>) -> Option<Vec<String>> {
+        self.best_paths
+            .and_then(|best| best_paths(paths, duplication_limit.map_or(best, |limit| limit.min(best))))
+            .or_else(|| duplication_limit.map(|limit| most_recently_active(paths, limit)))
+    }
+}
+
+/// Sends each packet on the next path in turn, following the paths' weights smoothly (e.g. with
+/// weights 2 and 1: a, b, a, a, b, a...)
+#[derive(Debug)]
+pub struct RoundRobin {
+    /// Weight of each interface; those not listed weigh 1
+    weights: HashMap<String, u32>,
+    /// Running weight of each interface
+    current: HashMap<String, i64>,
+}
+
+impl RoundRobin {
+    pub fn new(weights: HashMap<String, u32>) -> Self {
+        Self {
+            weights,
+            current: HashMap::new(),
+        }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn schedule(&mut self, paths: &[PathInfo], _duplication_limit: Option<usize>) -> Option<Vec<String>> {
+        self.current.retain(|ifname, _| paths.iter().any(|path| path.ifname == *ifname));
+        let mut total = 0;
+        for path in paths {
+            let weight = self.weights.get(&path.ifname).copied().unwrap_or(1) as i64;
+            total += weight;
+            *self.current.entry(path.ifname.clone()).or_default() += weight;
+        }
+        let (next, weight) = self.current.iter_mut().max_by_key(|(ifname, weight)| (**weight, std::cmp::Reverse(*ifname)))?;
+        *weight -= total;
+        Some(vec![next.clone()])
+    }
+}
+
+/// Sends every packet on one path, switching to the best usable path once it isn't usable anymore
+#[derive(Debug, Default)]
+pub struct ActiveBackup {
+    active: Option<String>,
+}
+
+impl ActiveBackup {
+    /// Returns the active path, switching first if it isn't usable anymore
+    pub fn select(&mut self, paths: &[PathInfo]) -> Option<String> {
+        if self.active.as_ref().is_some_and(|ifname| paths.iter().any(|path| path.ifname == *ifname)) {
+            return self.active.clone();
+        }
+        let next = best_paths(paths, 1)
+            .and_then(|best| best.into_iter().next())
+            .or_else(|| paths.first().map(|path| path.ifname.clone()))?;
+        match self.active.replace(next.clone()) {
+            Some(previous) => warn!("Active path '{}' isn't usable anymore; switching to '{}'", previous, next),
+            None => info!("Active path: '{}'", next),
+        }
+        Some(next)
+    }
+}
+
+impl Scheduler for ActiveBackup {
+    fn schedule(&mut self, paths: &[PathInfo], _duplication_limit: Option<usize>) -> Option<Vec<String>> {
+        self.select(paths).map(|ifname| vec![ifname])
+    }
+}
+
+/// Sends every packet on the active path, but on every path while the active path's upstream loss
+/// is above the threshold and for the recovery time after it dropped back under it
+#[derive(Debug)]
+pub struct Failover {
+    active: ActiveBackup,
+    /// Upstream loss in percents
+    loss_threshold: f64,
+    recovery_time: Duration,
+    /// While failing over, since when the active path's loss is back under the threshold
+    healthy_since: Option<Option<Instant>>,
+}
+
+impl Failover {
+    pub fn new(loss_threshold: f64, recovery_time: Duration) -> Self {
+        Self {
+            active: ActiveBackup::default(),
+            loss_threshold,
+            recovery_time,
+            healthy_since: None,
+        }
+    }
+}
+
+impl Scheduler for Failover {
+    fn schedule(&mut self, paths: &[PathInfo], _duplication_limit: Option<usize>) -> Option<Vec<String>> {
+        let preferred = self.active.select(paths)?;
+        let loss_permille = paths
+            .iter()
+            .find(|path| path.ifname == preferred)
+            .and_then(|path| path.upstream)
+            .map(|report| report.loss_permille);
+        if loss_permille.is_some_and(|loss| loss as f64 / 10.0 > self.loss_threshold) {
+            if self.healthy_since.is_none() {
+                warn!("Loss on '{}' is above {}%; duplicating on every path", preferred, self.loss_threshold);
+            }
+            self.healthy_since = Some(None);
+        } else if let Some(since) = &mut self.healthy_since {
+            let since = since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= self.recovery_time {
+                info!("Loss on '{}' stayed under {}% for {:?}; stopping duplication", preferred, self.loss_threshold, self.recovery_time);
+                self.healthy_since = None;
+            }
+        }
+
+        match self.healthy_since {
+            Some(_) => None,
+            None => Some(vec![preferred]),
+        }
+    }
+}
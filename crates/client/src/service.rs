@@ -1,47 +1,165 @@
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 use anyhow::{anyhow, Result};
 use dashmap::DashMap;
 use futures::StreamExt;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use shared::control::{self, Message, PathReport};
+use shared::datagram::{DatagramSocket, PeerAddr};
+use shared::dedup::DedupWindow;
+use shared::drops::{self, DropReason};
+use shared::frame::{self, Kind};
+use shared::packet_trace;
+use shared::profile::MemoryProfile;
+use shared::summary;
 use tokio::net::UdpSocket;
 use tokio::select;
+use tokio::sync::Notify;
 use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info, info_span, Instrument, trace, warn};
+use tracing::{debug, info, info_span, Instrument, Level, Span, trace, warn};
 
-use crate::types::{ClientSettings, SendingRoutine};
+use crate::backoff::FailureBackoff;
+use crate::flap::FlapDampening;
+use crate::icmp;
+use crate::netlink::LinkMonitor;
+use crate::nm;
+use crate::pacing;
+use crate::scheduler::{self, PathInfo, Scheduler};
+use crate::types::{ClientSettings, InterfaceSettings, SendingRoutine, TokenBucket};
+use crate::usage::{self, DataUsage};
+use crate::wrapper::Wrapper;
 
-// The maximum transmission unit (MTU) of an Ethernet frame is 1518 bytes with the normal untagged
-// Ethernet frame overhead of 18 bytes and the 1500-byte payload.
-const BUFFER_SIZE: usize = 1500;
+/// Bytes WireGuard adds around each packet it tunnels
+const WIREGUARD_OVERHEAD: usize = 32;
+
+/// WireGuard's default MTU, for which interfaces with a standard 1500-byte MTU leave room
+const WIREGUARD_DEFAULT_MTU: usize = 1420;
+
+/// Interval between interface checks when netlink reports the changes, in case one is missed
+const RESCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
 type SendingRoutines = Arc<DashMap<String, SendingRoutine>>;
 
 #[derive(Clone)]
 pub struct Service {
     shutdown: CancellationToken,
-    settings: ClientSettings,
+    /// Current settings, replaced on reloads
+    settings: Arc<RwLock<Arc<ClientSettings>>>,
+    /// Notified on reloads, to check the interfaces against the new settings right away
+    reloaded: Arc<Notify>,
     routines: SendingRoutines,
-    source_addr: Arc<Mutex<SocketAddr>>,
+    source_addr: Arc<Mutex<PeerAddr>>,
+    wrapper: Option<Arc<Wrapper>>,
+    profile: MemoryProfile,
+    /// Whether only standby interfaces are up, and carry data
+    on_standby: Arc<AtomicBool>,
+    scheduler: Arc<Mutex<Box<dyn Scheduler>>>,
+    /// Whether the scheduler follows the configured bonding mode, and is replaced when it's reloaded
+    configured_scheduler: bool,
+    failures: Arc<Mutex<FailureBackoff>>,
+    /// Payloads recently sent to WireGuard, to drop the copies received on the other interfaces
+    dedup: Option<Arc<Mutex<DedupWindow>>>,
 }
 
 impl Service {
     pub fn new(settings: ClientSettings) -> Self {
+        info!("Bonding mode: {:?}", settings.mode);
+        let scheduler = scheduler::from_settings(&settings);
+        Self {
+            configured_scheduler: true,
+            ..Self::with_scheduler(settings, scheduler)
+        }
+    }
+
+    /// Creates a service picking the paths of each packet with a custom scheduler instead of the
+    /// configured bonding mode
+    pub fn with_scheduler(settings: ClientSettings, scheduler: Box<dyn Scheduler>) -> Self {
+        let profile = MemoryProfile::new(settings.low_memory);
+        info!("Memory profile: {}", profile);
+
         Self {
             shutdown: CancellationToken::new(),
-            settings,
-            routines: Arc::new(DashMap::new()),
+            wrapper: settings.wrapper.as_ref().map(|wrapper| Arc::new(Wrapper::new(wrapper))),
+            dedup: settings.dedup_window.map(|window| {
+                Arc::new(Mutex::new(DedupWindow::new(std::time::Duration::from_millis(window))))
+            }),
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+            reloaded: Arc::new(Notify::new()),
+            routines: Arc::new(profile.new_map()),
+            profile,
+            on_standby: Arc::new(AtomicBool::new(false)),
+            scheduler: Arc::new(Mutex::new(scheduler)),
+            configured_scheduler: false,
+            failures: Arc::new(Mutex::new(FailureBackoff::new())),
             source_addr: Arc::new(Mutex::new(
-                SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)), 0)
+                PeerAddr::Udp(SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)), 0))
             )),
         }
     }
 
+    /// Returns the current settings
+    fn settings(&self) -> Arc<ClientSettings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Applies reloaded settings to the running service
+    ///
+    /// The interfaces are checked against the new included and excluded ones right away, the paths
+    /// whose destination or interface settings changed are re-created, and the scheduler follows
+    /// the new scheduling options. The listen address, the web manager, the memory profile, the
+    /// NetworkManager integration, the dedup window, the data cap, the wrapper and the buffer size
+    /// need a restart.
+    pub fn reload(&self, settings: ClientSettings) {
+        let current = self.settings();
+        if settings == *current {
+            info!("Settings unchanged");
+            return;
+        }
+        if current.listen_addr != settings.listen_addr
+            || current.web_manager != settings.web_manager
+            || current.low_memory != settings.low_memory
+            || current.network_manager != settings.network_manager
+            || current.dedup_window != settings.dedup_window
+            || current.data_cap != settings.data_cap
+            || current.wrapper != settings.wrapper
+            || current.buffer_size != settings.buffer_size
+        {
+            warn!("The listen address, web manager, memory profile, NetworkManager, dedup window, data cap, wrapper and buffer size settings are only applied on restart");
+        }
+
+        let scheduling_changed = current.mode != settings.mode
+            || current.weights != settings.weights
+            || current.best_paths != settings.best_paths
+            || current.failover != settings.failover;
+        if scheduling_changed && self.configured_scheduler {
+            info!("Bonding mode: {:?}", settings.mode);
+            *self.scheduler.lock().unwrap() = scheduler::from_settings(&settings);
+        }
+
+        let changed: Vec<String> = self.routines
+            .iter()
+            .filter(|routine| PathSettings::new(&current, &routine.iface) != PathSettings::new(&settings, &routine.iface))
+            .map(|routine| routine.key().clone())
+            .collect();
+        packet_trace::set_sampling(settings.packet_trace_sampling.unwrap());
+        *self.settings.write().unwrap() = Arc::new(settings);
+        for ifname in changed {
+            info!("Interface '{}' settings changed; re-creating it", ifname);
+            self.routines.remove(&ifname);
+        }
+        self.reloaded.notify_one();
+    }
+
     pub async fn run(&self) -> Result<()> {
-        let settings = &self.settings;
-        let wireguard_socket = Arc::new(UdpSocket::bind(&settings.listen_addr).await?);
+        let settings = self.settings();
+        let wireguard_socket = Arc::new(DatagramSocket::bind(&settings.listen_addr).await?);
+        packet_trace::set_sampling(settings.packet_trace_sampling.unwrap());
+        // Report no interfaces rather than no data until the first one is added
+        debug!(counter.rengarde_interfaces_active = 0_i64, counter.rengarde_paths_up = 0_i64);
 
         info!("Listening on: {}", &settings.listen_addr);
 
@@ -59,6 +177,35 @@ impl Service {
             }
         });
 
+        let join_send_heartbeats = tokio::spawn({
+            let service = self.clone();
+            async move { service.send_heartbeats().await }
+        });
+
+        let join_send_echoes = tokio::spawn({
+            let service = self.clone();
+            async move { service.send_echoes().await }
+        });
+
+        let join_send_keepalives = tokio::spawn({
+            let service = self.clone();
+            async move { service.send_keepalives().await }
+        });
+
+        let join_account_data_usage = tokio::spawn({
+            let service = self.clone();
+            async move {
+                if let Err(err) = service.account_data_usage().await {
+                    warn!("account_data_usage thread failed: {:?}", err);
+                }
+            }
+        });
+
+        let join_log_summaries = tokio::spawn({
+            let service = self.clone();
+            async move { service.log_summaries().await }
+        });
+
         let join_receive_from_wireguard = tokio::spawn({
             let service = self.clone();
             async move {
@@ -75,6 +222,21 @@ impl Service {
             _ = join_update_available_interfaces => {
                 warn!("update_available_interfaces thread closed");
             }
+            _ = join_send_heartbeats => {
+                warn!("send_heartbeats thread closed");
+            }
+            _ = join_send_echoes => {
+                warn!("send_echoes thread closed");
+            }
+            _ = join_send_keepalives => {
+                warn!("send_keepalives thread closed");
+            }
+            _ = join_account_data_usage => {
+                warn!("account_data_usage thread closed");
+            }
+            _ = join_log_summaries => {
+                warn!("log_summaries thread closed");
+            }
             _ = join_receive_from_wireguard => {
                 warn!("receive_from_wireguard thread closed");
             }
@@ -86,111 +248,318 @@ impl Service {
         Ok(())
     }
 
-    async fn update_available_interfaces(&self, wireguard_socket: Arc<UdpSocket>) -> Result<()> {
+    async fn update_available_interfaces(&self, wireguard_socket: Arc<DatagramSocket>) -> Result<()> {
+        let mut monitor = match LinkMonitor::new() {
+            Ok(monitor) => {
+                debug!("Watching interface changes over netlink");
+                Some(monitor)
+            }
+            Err(err) => {
+                warn!("Failed to watch interface changes over netlink; polling them instead: {:?}", err);
+                None
+            }
+        };
+        let mut nm_monitor = self.settings().network_manager.then(|| match nm::Monitor::new() {
+            Ok(monitor) => {
+                debug!("Watching NetworkManager changes");
+                Some(monitor)
+            }
+            Err(err) => {
+                warn!("Failed to watch NetworkManager changes: {:?}", err);
+                None
+            }
+        }).flatten();
+        let mut flaps = FlapDampening::new();
         loop {
             debug!("Checking available interfaces...");
+            let settings = self.settings();
             let interfaces = NetworkInterface::show()?;
+            let nm_devices = if settings.network_manager {
+                nm::devices().await.unwrap_or_else(|err| {
+                    warn!("Failed to list NetworkManager devices: {:?}", err);
+                    HashMap::new()
+                })
+            } else {
+                HashMap::new()
+            };
+            let nm_reason = |ifname: &str, source_addr: IpAddr| {
+                nm_devices.get(ifname).and_then(|device| device.unusable_reason(source_addr.is_ipv6()))
+            };
 
             let drop_list: Vec<_> = self.routines.iter().filter_map(|routine| {
-                if self.settings.excluded_interfaces.contains(routine.key()) {
-                    warn!("Interface '{}' is excluded; removing it", routine.key());
-                    return Some(routine.key().clone());
+                // Also tell whether the interface went down, to dampen the unstable ones
+                if let Some(reason) = settings.interface_filter(&routine.iface) {
+                    warn!("Interface '{}' {}; removing it", routine.key(), reason);
+                    return Some((routine.key().clone(), false));
                 }
-                match interfaces.iter().find(|interface| &interface.name == routine.key()) {
+                match interfaces.iter().find(|interface| interface.name == routine.iface) {
+                    Some(_) if !is_link_up(&routine.iface) => {
+                        warn!("Interface '{}' has no link; removing it", routine.key());
+                        Some((routine.key().clone(), true))
+                    }
                     Some(iface) => {
-                        match get_address_by_interface(iface) {
-                            Some(addr) => {
-                                if addr != routine.value().src_addr.ip() {
-                                    info!("Interface '{}' address changed; re-creating it", routine.key());
-                                    Some(routine.key().clone())
-                                } else {
-                                    None
-                                }
-                            }
-                            None => {
-                                warn!("Interface '{}' has no address; removing it", routine.key());
-                                Some(routine.key().clone())
-                            }
+                        let addrs = source_addresses(&settings, iface);
+                        if addrs.is_empty() {
+                            warn!("Interface '{}' has no address; removing it", routine.key());
+                            Some((routine.key().clone(), true))
+                        } else if let Some(reason) = nm_reason(&routine.iface, routine.src_addr.ip()) {
+                            warn!("Interface '{}' {}; removing it", routine.key(), reason);
+                            Some((routine.key().clone(), true))
+                        } else if !has_route_to_server(&settings, &routine.iface, routine.src_addr.ip()) {
+                            warn!("Interface '{}' has no route to the server; removing it", routine.key());
+                            Some((routine.key().clone(), true))
+                        } else if !addrs.iter().enumerate().any(|(index, addr)| {
+                            *addr == routine.src_addr.ip() && path_name(&iface.name, index, addr) == *routine.key()
+                        }) {
+                            info!("Interface '{}' address changed; re-creating it", routine.key());
+                            Some((routine.key().clone(), false))
+                        } else {
+                            None
                         }
                     }
                     None => {
                         warn!("Interface '{}' no longer exists; removing it", routine.key());
-                        Some(routine.key().clone())
+                        Some((routine.key().clone(), true))
                     }
                 }
+            }).map(|(key, went_down)| {
+                let iface = self.routines.get(&key).map(|routine| routine.iface.clone());
+                (key, iface.filter(|_| went_down))
             }).collect();
 
             if !drop_list.is_empty() {
-                drop_list.into_iter().for_each(|key| {
+                drop_list.into_iter().for_each(|(key, went_down)| {
+                    if let Some(iface) = went_down {
+                        flaps.record_down(&iface);
+                    }
                     self.routines.remove(&key);
                 });
             }
 
             for iface in interfaces {
-                if self.settings.excluded_interfaces.contains(&iface.name) {
+                if settings.interface_filter(&iface.name).is_some() {
+                    continue;
+                }
+                if !is_link_up(&iface.name) {
+                    debug!("Interface '{}' has no link; skipping it", iface.name);
                     continue;
                 }
-                if self.routines.contains_key(&iface.name) {
+                if flaps.is_held(&iface.name) {
+                    debug!("Interface '{}' is unstable; skipping it", iface.name);
                     continue;
                 }
 
-                if let Some(source_addr) = get_address_by_interface(&iface) {
-                    if let Err(err) = self.create_send_thread(&iface, source_addr, wireguard_socket.clone()).await {
-                        warn!("Failed to create send thread for interface '{}': {:?}", iface.name, err);
+                for (index, source_addr) in source_addresses(&settings, &iface).into_iter().enumerate() {
+                    let name = path_name(&iface.name, index, &source_addr);
+                    if self.routines.contains_key(&name) {
+                        continue;
+                    }
+                    if self.profile.max_entries().is_some_and(|max| self.routines.len() >= max) {
+                        debug!("Sending routine limit reached; skipping interface '{}'", name);
+                        continue;
+                    }
+                    if let Some(reason) = nm_reason(&iface.name, source_addr) {
+                        debug!("Interface '{}' {}; skipping it", name, reason);
+                        continue;
+                    }
+                    if !has_route_to_server(&settings, &iface.name, source_addr) {
+                        debug!("Interface '{}' has no route to the server; skipping it", name);
+                        continue;
+                    }
+                    if self.failures.lock().unwrap().is_waiting(&name) {
+                        debug!("Interface '{}' failed recently; skipping it", name);
+                        continue;
+                    }
+
+                    match self.create_send_thread(&iface, &name, source_addr, wireguard_socket.clone()).await {
+                        Ok(()) => debug!("Created send thread for interface '{}'", name),
+                        Err(err) => {
+                            let delay = self.failures.lock().unwrap().record_failure(&name);
+                            warn!("Failed to create send thread for interface '{}'; retrying in {:?}: {:?}", name, delay, err);
+                        }
                     }
-                    debug!("Created send thread for interface '{}'", iface.name);
                 }
             }
 
+            // Keep asking for the wrapper capabilities until the server answers
+            if let Some(wrapper) = self.wrapper.as_ref().filter(|wrapper| !wrapper.is_negotiated()) {
+                self.send_hello(wrapper).await;
+            }
+
+            // Poll at the check interval without netlink, and until the server answers the hello
+            let negotiating = self.wrapper.as_ref().is_some_and(|wrapper| !wrapper.is_negotiated());
+            let check_interval = std::time::Duration::from_millis(settings.interface_check_interval.unwrap_or(1000));
+            let mut interval = if monitor.is_none() || negotiating {
+                check_interval
+            } else {
+                RESCAN_INTERVAL.max(check_interval)
+            };
+            // Check again as soon as a held or failed interface may be used again
+            let next_release = flaps.next_release().into_iter().chain(self.failures.lock().unwrap().next_retry()).min();
+            if let Some(release) = next_release {
+                interval = interval.min(release.saturating_duration_since(std::time::Instant::now()));
+            }
             debug!("Checking available interfaces finished; sleeping...");
             select! {
                 _ = self.shutdown.cancelled() => {
                     debug!("Shutdown signal received; closing update_available_interfaces thread");
                     return Ok(());
                 }
-                _ = sleep(std::time::Duration::from_secs(1)) => {}
+                result = async {
+                    match &monitor {
+                        Some(monitor) => monitor.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    match result {
+                        Ok(()) => debug!("Interfaces changed"),
+                        Err(err) => {
+                            warn!("Failed to watch interface changes over netlink; polling them instead: {:?}", err);
+                            monitor = None;
+                        }
+                    }
+                }
+                result = async {
+                    match &mut nm_monitor {
+                        Some(monitor) => monitor.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    match result {
+                        Ok(()) => debug!("NetworkManager reported a change"),
+                        Err(err) => {
+                            warn!("Failed to watch NetworkManager changes: {:?}", err);
+                            nm_monitor = None;
+                        }
+                    }
+                }
+                _ = self.reloaded.notified() => debug!("Settings reloaded"),
+                _ = sleep(interval) => {}
             }
         }
     }
 
-    async fn create_send_thread(&self, iface: &NetworkInterface, source_addr: std::net::IpAddr, wireguard_socket: Arc<UdpSocket>) -> Result<()> {
-        info!("New interface '{}' with IP '{}', adding it", iface.name, source_addr);
+    async fn create_send_thread(&self, iface: &NetworkInterface, name: &str, source_addr: IpAddr, wireguard_socket: Arc<DatagramSocket>) -> Result<()> {
+        info!("New interface '{}' with IP '{}', adding it", name, source_addr);
 
-        let dst_addr = tokio::net::lookup_host(&self.settings.dst_addr)
+        let settings = self.settings();
+        let iface_settings = settings.interfaces.get(&iface.name).cloned().unwrap_or_default();
+        let dst = iface_settings.dst_addr.as_ref().unwrap_or(&settings.dst_addr);
+        let dst_addr = tokio::net::lookup_host(dst)
             .await
-            .map_err(|err| anyhow!("Failed to resolve destination address '{}': {:?}", self.settings.dst_addr, err))
-            .and_then(|mut addrs| {
-                addrs.next().ok_or_else(|| anyhow!("No address found for destination address '{}'", self.settings.dst_addr))
+            .map_err(|err| anyhow!("Failed to resolve destination address '{}': {:?}", dst, err))
+            .and_then(|addrs| {
+                // Prefer an address of the source's family, which the socket can reach
+                let addrs: Vec<SocketAddr> = addrs.collect();
+                addrs.iter()
+                    .find(|addr| addr.is_ipv6() == source_addr.is_ipv6())
+                    .or(addrs.first())
+                    .copied()
+                    .ok_or_else(|| anyhow!("No address found for destination address '{}'", dst))
             })?;
         debug!("\tDestination address: '{:?}'", dst_addr);
 
-        let src_addr = SocketAddr::new(source_addr, 0);
+        let src_addr = SocketAddr::new(source_addr, iface_settings.src_port.unwrap_or(0));
         debug!("\tSource address: '{:?}'", src_addr);
 
         let src_socket = UdpSocket::bind(src_addr).await?;
         debug!("\tBound udp socket to '{}'", src_addr);
 
-        if !iface.name.is_empty() {
-            src_socket.bind_device(Some(iface.name.as_bytes()))?;
-            debug!("\tBound udp socket to interface '{}'", iface.name);
+        // With a firewall mark, policy routing can pick the uplink if binding to the interface isn't allowed
+        let fwmark = iface_settings.fwmark.or(settings.fwmark);
+        if let Some(fwmark) = fwmark {
+            socket2::SockRef::from(&src_socket)
+                .set_mark(fwmark)
+                .map_err(|err| anyhow!("Failed to set firewall mark {:#x}: {:?}", fwmark, err))?;
+            debug!("\tMarked udp socket with firewall mark {:#x}", fwmark);
+        }
+
+        // Binding to the interface's VRF device makes the VRF's routing table apply to the socket
+        let device = iface_settings.vrf.as_deref().unwrap_or(&iface.name);
+        if !device.is_empty() {
+            match src_socket.bind_device(Some(device.as_bytes())) {
+                Ok(()) => debug!("\tBound udp socket to device '{}'", device),
+                Err(err) if fwmark.is_some() => {
+                    warn!("\tFailed to bind udp socket to device '{}'; relying on its firewall mark: {:?}", device, err);
+                }
+                Err(err) => return Err(anyhow!("Failed to bind udp socket to device '{}': {:?}", device, err)),
+            }
+        }
+
+        if let Some(dscp) = iface_settings.dscp.or(settings.dscp) {
+            let tos = (dscp as u32) << 2;
+            let socket = socket2::SockRef::from(&src_socket);
+            let result = if src_addr.is_ipv6() { socket.set_tclass_v6(tos) } else { socket.set_tos(tos) };
+            match result {
+                Ok(()) => debug!("\tMarking packets on interface '{}' with DSCP {}", iface.name, dscp),
+                Err(err) => warn!("\tFailed to set DSCP {} on interface '{}': {:?}", dscp, iface.name, err),
+            }
+        }
+
+        match icmp::enable_recverr(&src_socket, src_addr.is_ipv6()) {
+            Ok(()) => debug!("\tEnabled ICMP error reporting on interface '{}'", iface.name),
+            Err(err) => warn!("\tFailed to enable ICMP error reporting on interface '{}': {:?}", iface.name, err),
+        }
+
+        // Fragmented datagrams ruin latency on some links (e.g. LTE), so warn about too small MTUs
+        let max_datagram = interface_mtu(&iface.name).map(|mtu| {
+            let ip_header = if src_addr.is_ipv6() { 40 } else { 20 };
+            let max_datagram = mtu.saturating_sub(ip_header + 8);
+            let overhead = self.wrapper.as_ref().map_or(0, |wrapper| wrapper.overhead());
+            let wireguard_mtu = max_datagram.saturating_sub(overhead + WIREGUARD_OVERHEAD);
+            if wireguard_mtu < WIREGUARD_DEFAULT_MTU {
+                warn!("\tInterface '{}' has MTU {}; set the WireGuard MTU to {} or less to avoid fragmentation", iface.name, mtu, wireguard_mtu);
+            } else {
+                debug!("\tInterface '{}' has MTU {}, fitting a WireGuard MTU of {}", iface.name, mtu, wireguard_mtu);
+            }
+            max_datagram
+        });
+
+        if settings.dont_fragment {
+            match icmp::set_dont_fragment(&src_socket, src_addr.is_ipv6()) {
+                Ok(()) => debug!("\tDiscovering the path MTU of interface '{}'", iface.name),
+                Err(err) => warn!("\tFailed to set Don't-Fragment on interface '{}': {:?}", iface.name, err),
+            }
         }
 
         let src_socket = Arc::new(src_socket);
 
-        let routine = SendingRoutine::new(
-            iface.name.to_owned(),
+        let mut routine = SendingRoutine::new(
+            name.to_owned(),
             src_socket,
             src_addr,
             dst_addr,
         );
+        routine.iface = iface.name.to_owned();
+        routine.max_datagram = max_datagram;
+        routine.write_timeout = settings.write_timeout.filter(|ms| *ms > 0).map(std::time::Duration::from_millis);
+        routine.standby = settings.standby.contains(&iface.name);
+        // Metered interfaces only carry data on standby, so tethered phones don't burn their data plan
+        let metered = match iface_settings.metered {
+            Some(metered) => metered,
+            None if settings.detect_metered => is_metered(&iface.name).await,
+            None => false,
+        };
+        if metered && !routine.standby {
+            info!("\tInterface '{}' is metered; putting it on standby", iface.name);
+            routine.standby = true;
+        }
+        if let Some(kbps) = iface_settings.max_rate_kbps.filter(|kbps| *kbps > 0) {
+            debug!("\tLimiting interface '{}' to {} kbps", iface.name, kbps);
+            routine.rate_limit = Some(TokenBucket::new(kbps));
+        }
+        if let Some(kbps) = settings.pacing_kbps.get(&iface.name).filter(|kbps| **kbps > 0) {
+            debug!("\tPacing interface '{}' at {} kbps", iface.name, kbps);
+            routine.pacer = Some(pacing::spawn(name.to_owned(), routine.src_socket.clone(), dst_addr, *kbps));
+        }
 
-        if let Some(routine) = self.routines.insert(iface.name.to_owned(), routine) {
+        if let Some(routine) = self.routines.insert(name.to_owned(), routine) {
             panic!("Interface '{}' already existed when we tried to add it", routine.ifname);
         };
 
         tokio::spawn({
             let this = self.clone();
-            let ifname = iface.name.to_owned();
+            let ifname = name.to_owned();
             let wireguard_socket = wireguard_socket.clone();
             async move {
                 if let Err(err) = this.wireguard_write_back(ifname.clone(), wireguard_socket).await {
@@ -199,37 +568,438 @@ impl Service {
                 debug!("wireguard_write_back thread closed: '{}'", ifname);
             }
         });
-        debug!("\tStarted wireguard_write_back thread for interface '{}'", iface.name);
+        debug!("\tStarted wireguard_write_back thread for interface '{}'", name);
 
         Ok(())
     }
 
-    async fn wireguard_write_back(&self, ifname: String, wireguard_socket: Arc<UdpSocket>) -> Result<()> {
-        let mut buf = [0; BUFFER_SIZE];
+    /// Removes the interfaces whose sends failed, backing off before re-creating them
+    fn remove_failed(&self, ifnames: Vec<String>) {
+        for ifname in ifnames {
+            let delay = self.failures.lock().unwrap().record_failure(&ifname);
+            debug!(
+                monotonic_counter.rengarde_path_restarts_total = 1_u64,
+                iface_name = ifname,
+                "Retrying interface '{}' in {:?}", ifname, delay
+            );
+            self.routines.remove(&ifname);
+        }
+    }
+
+    /// Returns the paths that may carry data, sorted by name: the up primary interfaces, else the up
+    /// standby ones, else all of them
+    fn usable_paths(&self) -> Vec<PathInfo> {
+        let up: Vec<(PathInfo, bool)> = self.routines
+            .iter()
+            .filter(|routine| routine.is_up())
+            .map(|routine| (routine.path_info(), routine.standby || routine.over_cap))
+            .collect();
+        let primary_up = up.iter().any(|(_, standby)| !standby);
+        let on_standby = !primary_up && !up.is_empty();
+        if self.on_standby.swap(on_standby, Ordering::Relaxed) != on_standby {
+            if on_standby {
+                warn!("All primary paths are down; sending on standby interfaces");
+            } else {
+                info!("Primary paths are back; standby interfaces stop carrying data");
+            }
+        }
+
+        let mut paths: Vec<PathInfo> = if up.is_empty() {
+            self.routines.iter().map(|routine| routine.path_info()).collect()
+        } else {
+            up.into_iter().filter(|(_, standby)| *standby != primary_up).map(|(path, _)| path).collect()
+        };
+        paths.sort_by(|a, b| a.ifname.cmp(&b.ifname));
+        paths
+    }
+
+    /// Picks the usable path carrying a parity frame: one not carrying data if there is any,
+    /// rotating through them with `rotation`
+    fn parity_routine(&self, rotation: u32, usable: &[PathInfo], data_routines: &[String]) -> Option<String> {
+        let mut ifnames: Vec<String> = usable.iter().map(|path| path.ifname.clone()).filter(|ifname| !data_routines.contains(ifname)).collect();
+        if ifnames.is_empty() {
+            ifnames = usable.iter().map(|path| path.ifname.clone()).collect();
+        }
+        if ifnames.is_empty() {
+            return None;
+        }
+        let index = rotation as usize % ifnames.len();
+        Some(ifnames.swap_remove(index))
+    }
+
+    /// Sends heartbeats on every path once negotiated, marking the paths that stop answering as dead, and reports the
+    /// state of every path
+    async fn send_heartbeats(&self) {
+        let mut id: u32 = 0;
+        let mut buf = Vec::new();
+        loop {
+            let interval = self.wrapper.as_ref().and_then(|wrapper| wrapper.heartbeat_interval());
+            select! {
+                _ = self.shutdown.cancelled() => {
+                    debug!("Shutdown signal received; closing send_heartbeats thread");
+                    return;
+                }
+                _ = sleep(interval.unwrap_or(std::time::Duration::from_secs(1))) => {}
+            }
+            for mut routine in self.routines.iter_mut() {
+                routine.report_state();
+            }
+            let (Some(wrapper), Some(interval)) = (self.wrapper.as_ref(), interval) else {
+                continue;
+            };
+
+            id = id.wrapping_add(1);
+            wrapper.heartbeat(id, &mut buf);
+            let mut targets = Vec::new();
+            for mut routine in self.routines.iter_mut() {
+                if routine.is_alive && routine.last_heartbeat_ack_at.elapsed() > interval * control::HEARTBEAT_MISSES {
+                    warn!("Interface '{}' missed its heartbeats; path is dead", routine.ifname);
+                    routine.is_alive = false;
+                }
+                targets.push((routine.ifname.clone(), routine.src_socket.clone(), routine.dst_addr));
+            }
+            for (ifname, socket, dst_addr) in targets {
+                if let Err(err) = socket.send_to(&buf, dst_addr).await {
+                    debug!("Failed to send heartbeat on interface '{}': {:?}", ifname, err);
+                }
+            }
+        }
+    }
+
+    /// Accounts the data used by the metered interfaces, demoting those over their quota to standby
+    /// until the next billing period
+    async fn account_data_usage(&self) -> Result<()> {
+        let settings = self.settings();
+        let Some(data_cap) = &settings.data_cap else {
+            self.shutdown.cancelled().await;
+            return Ok(());
+        };
+        let mut usage = DataUsage::load(data_cap)?;
+        usage.log();
+        loop {
+            let shutdown = select! {
+                _ = self.shutdown.cancelled() => true,
+                _ = sleep(usage::ACCOUNTING_INTERVAL) => false,
+            };
+
+            if usage.roll_over() {
+                usage.log();
+            }
+            for mut routine in self.routines.iter_mut() {
+                let bytes = std::mem::take(&mut routine.metered_bytes);
+                if usage.add(&routine.iface, bytes) {
+                    warn!("Interface '{}' used up its monthly quota; demoting it to standby", routine.iface);
+                }
+                routine.over_cap = usage.is_over_cap(&routine.iface);
+            }
+            if let Err(err) = usage.save() {
+                warn!("Failed to save data usage: {:?}", err);
+            }
+
+            if shutdown {
+                debug!("Shutdown signal received; closing account_data_usage thread");
+                return Ok(());
+            }
+        }
+    }
+
+    /// Logs the traffic sent and received on each path since the previous summary, every `summaryInterval` seconds
+    async fn log_summaries(&self) {
+        // Bytes and packets sent and received on each path at the previous summary
+        let mut previous: HashMap<String, [usize; 4]> = HashMap::new();
+        let mut since = std::time::Instant::now();
+        loop {
+            // Checked again every second while disabled, so a reload enabling it takes effect
+            let interval = self.settings().summary_interval.filter(|interval| *interval > 0);
+            select! {
+                _ = self.shutdown.cancelled() => {
+                    debug!("Shutdown signal received; closing log_summaries thread");
+                    return;
+                }
+                _ = sleep(std::time::Duration::from_secs(interval.unwrap_or(1))) => {}
+            }
+            if interval.is_none() {
+                continue;
+            }
+
+            let elapsed = since.elapsed();
+            since = std::time::Instant::now();
+            let mut current = HashMap::new();
+            let mut lines = Vec::new();
+            for routine in self.routines.iter() {
+                let totals = [routine.total_sent_bytes, routine.total_sent_packets, routine.total_received_bytes, routine.total_received_packets];
+                let [sent_bytes, sent_packets, received_bytes, received_packets] = previous.get(&routine.ifname).copied().unwrap_or_default();
+                lines.push(format!(
+                    "{} sent {}, received {}{}",
+                    routine.ifname,
+                    summary::rates(totals[0].saturating_sub(sent_bytes), totals[1].saturating_sub(sent_packets), elapsed),
+                    summary::rates(totals[2].saturating_sub(received_bytes), totals[3].saturating_sub(received_packets), elapsed),
+                    if routine.standby { " (standby)" } else if !routine.is_alive { " (dead)" } else { "" },
+                ));
+                current.insert(routine.ifname.clone(), totals);
+            }
+            previous = current;
+            lines.sort();
+            if lines.is_empty() {
+                info!("Summary over {}s: no interface", elapsed.as_secs());
+            } else {
+                info!("Summary over {}s: {}", elapsed.as_secs(), lines.join("; "));
+            }
+        }
+    }
+
+    fn heartbeat_acked(&self, ifname: &str, id: u32) {
+        trace!("Heartbeat {} acknowledged on interface '{}'", id, ifname);
+        if let Some(mut routine) = self.routines.get_mut(ifname) {
+            routine.last_heartbeat_ack_at = std::time::Instant::now();
+            if !routine.is_alive {
+                info!("Interface '{}' answers heartbeats again; path is alive", ifname);
+                routine.is_alive = true;
+            }
+        }
+    }
+
+    /// Sends echo requests on every path once negotiated, to measure their round-trip times
+    async fn send_echoes(&self) {
+        let mut id: u32 = 0;
+        let mut buf = Vec::new();
+        loop {
+            let interval = self.wrapper.as_ref().and_then(|wrapper| wrapper.echo_interval());
+            select! {
+                _ = self.shutdown.cancelled() => {
+                    debug!("Shutdown signal received; closing send_echoes thread");
+                    return;
+                }
+                _ = sleep(interval.unwrap_or(std::time::Duration::from_secs(1))) => {}
+            }
+            let (Some(wrapper), Some(_)) = (self.wrapper.as_ref(), interval) else {
+                continue;
+            };
+
+            // A request still unanswered is lost; the next one replaces it
+            id = id.wrapping_add(1);
+            let now = std::time::Instant::now();
+            let mut targets = Vec::new();
+            for mut routine in self.routines.iter_mut() {
+                routine.echo_sent = Some((id, now));
+                targets.push((routine.ifname.clone(), routine.src_socket.clone(), routine.dst_addr, routine.rtt));
+            }
+            for (ifname, socket, dst_addr, rtt) in targets {
+                wrapper.echo(id, rtt, &mut buf);
+                if let Err(err) = socket.send_to(&buf, dst_addr).await {
+                    debug!("Failed to send echo request on interface '{}': {:?}", ifname, err);
+                }
+            }
+        }
+    }
+
+    /// Sends keepalives on the paths idle for the negotiated interval, so their NAT mappings stay open
+    async fn send_keepalives(&self) {
+        let mut buf = Vec::new();
+        loop {
+            let interval = self.wrapper.as_ref().and_then(|wrapper| wrapper.keepalive_interval());
+            select! {
+                _ = self.shutdown.cancelled() => {
+                    debug!("Shutdown signal received; closing send_keepalives thread");
+                    return;
+                }
+                // Check twice per interval, so no path stays idle much longer than the interval
+                _ = sleep(interval.map_or(std::time::Duration::from_secs(1), |interval| interval / 2)) => {}
+            }
+            let (Some(wrapper), Some(interval)) = (self.wrapper.as_ref(), interval) else {
+                continue;
+            };
+
+            wrapper.keepalive(&mut buf);
+            let idle: Vec<_> = self.routines
+                .iter()
+                .filter(|routine| !routine.is_closing && routine.last_sent_at.elapsed() >= interval)
+                .map(|routine| routine.ifname.clone())
+                .collect();
+            let mut drop_list = Vec::new();
+            for ifname in idle {
+                if let Some(mut routine) = self.routines.get_mut(&ifname) {
+                    trace!("Sending keepalive on idle interface '{}'", ifname);
+                    drop_list.extend(routine.send_to(&buf).await);
+                }
+            }
+            self.remove_failed(drop_list);
+        }
+    }
+
+    fn echo_replied(&self, ifname: &str, id: u32) {
+        let Some(mut routine) = self.routines.get_mut(ifname) else {
+            return;
+        };
+        let Some((_, sent_at)) = routine.echo_sent.take_if(|(sent_id, _)| *sent_id == id) else {
+            trace!("Ignoring stale echo reply {} on interface '{}'", id, ifname);
+            return;
+        };
+        let sample = sent_at.elapsed();
+        routine.record_rtt(sample);
+        debug!(
+            histogram.rengarde_path_rtt_seconds = sample.as_secs_f64(),
+            iface_name = ifname,
+            "Round-trip time on interface '{}': {:?} (smoothed {:?})", ifname, sample, routine.rtt.unwrap_or_default()
+        );
+    }
+
+    fn path_reported(&self, ifname: &str, report: PathReport) {
+        debug!(
+            histogram.rengarde_path_upstream_loss_permille = report.loss_permille as u64,
+            iface_name = ifname,
+            "Server received {} packets ({} bytes, {}.{}% lost) on interface '{}'",
+            report.packets, report.bytes, report.loss_permille / 10, report.loss_permille % 10, ifname
+        );
+        if let Some(mut routine) = self.routines.get_mut(ifname) {
+            routine.upstream = Some(report);
+        }
+    }
+
+    async fn send_hello(&self, wrapper: &Wrapper) {
+        let mut buf = Vec::new();
+        wrapper.hello(&mut buf);
+
+        let targets: Vec<_> = self.routines
+            .iter()
+            .map(|routine| (routine.ifname.clone(), routine.src_socket.clone(), routine.dst_addr))
+            .collect();
+        for (ifname, socket, dst_addr) in targets {
+            match socket.send_to(&buf, dst_addr).await {
+                Ok(_) => debug!("Sent hello on interface '{}'", ifname),
+                Err(err) => debug!("Failed to send hello on interface '{}': {:?}", ifname, err),
+            }
+        }
+    }
+
+    /// Strips the framing of a datagram received from the server, consuming control messages
+    fn unwrap_received<'a>(&self, ifname: &str, datagram: &'a mut [u8]) -> Option<&'a [u8]> {
+        let Some(wrapper) = self.wrapper.as_ref().filter(|_| frame::is_frame(datagram)) else {
+            return Some(datagram);
+        };
+
+        let payload = match wrapper.decode(datagram) {
+            Ok((header, payload)) if header.kind == Kind::Control => {
+                match Message::decode(payload) {
+                    Ok(Message::HeartbeatAck { id }) => self.heartbeat_acked(ifname, id),
+                    Ok(Message::PathReport(report)) => self.path_reported(ifname, report),
+                    Ok(Message::EchoReply { id }) => self.echo_replied(ifname, id),
+                    Ok(message) => wrapper.handle_control(message),
+                    Err(err) => {
+                        debug!("Dropping invalid control message on interface '{}': {}", ifname, err);
+                        drops::record(DropReason::Invalid);
+                    }
+                }
+                return None;
+            }
+            Ok((header, payload)) => {
+                if header.sequence.is_some() || header.timestamp.is_some() {
+                    self.record_downstream(ifname, &header, payload.len());
+                }
+                payload
+            }
+            Err(err) => {
+                debug!("Dropping invalid frame on interface '{}': {}", ifname, err);
+                drops::record(DropReason::Invalid);
+                return None;
+            }
+        };
+        Some(payload)
+    }
+
+    /// Accounts a numbered or timestamped frame received from the server, counting the frames it
+    /// skipped as lost and following the queueing delay of the path
+    fn record_downstream(&self, ifname: &str, header: &frame::Header, bytes: usize) {
+        let Some(mut routine) = self.routines.get_mut(ifname) else {
+            return;
+        };
+        let lost = routine.downstream.record(header.sequence, bytes);
+        if lost > 0 {
+            debug!(
+                monotonic_counter.rengarde_path_lost_packets_total = lost as u64,
+                iface_name = ifname,
+                "{} packets from the server lost on interface '{}'", lost, ifname
+            );
+        }
+        match header.timestamp.and_then(|timestamp| routine.delay.record(timestamp)) {
+            Some(true) => {
+                warn!(
+                    "Queueing delay on interface '{}' is building up ({:?}); the link may be bufferbloated",
+                    ifname, routine.delay.queueing_delay().unwrap_or_default()
+                );
+                debug!(monotonic_counter.rengarde_path_queueing_delay_warnings_total = 1_u64, iface_name = ifname);
+            }
+            Some(false) => info!("Queueing delay on interface '{}' is back to normal", ifname),
+            None => {}
+        }
+    }
+
+    async fn wireguard_write_back(&self, ifname: String, wireguard_socket: Arc<DatagramSocket>) -> Result<()> {
+        let buffer_size = self.settings().buffer_size.unwrap_or(shared::DEFAULT_BUFFER_SIZE);
+        let mut buf = vec![0; buffer_size];
         loop {
             let routine = self.routines.get(&ifname).ok_or_else(|| anyhow!("Interface '{}' not found", ifname))?;
-            debug!("Got interface {} from routines", ifname);
+            shared::packet_event!(Level::DEBUG, "Got interface {} from routines", ifname);
             if routine.is_closing {
                 warn!("Interface '{}' is closing; closing thread", ifname);
                 return Ok(());
             }
             let socket = routine.src_socket.clone();
+            let closed = routine.closed.clone();
             drop(routine);
 
-            debug!("Waiting for data from interface '{}'", ifname);
+            shared::packet_event!(Level::DEBUG, "Waiting for data from interface '{}'", ifname);
             select! {
                 t = socket.recv_from(&mut buf) => {
                     match t {
                         Ok((received_bytes, _)) => {
-                            debug!("Received {} bytes from interface '{}'", received_bytes, ifname);
+                            let received_at = std::time::Instant::now();
+                            debug!(
+                                monotonic_counter.rengarde_path_received_packets_total = 1_u64,
+                                monotonic_counter.rengarde_path_received_bytes_total = received_bytes as u64,
+                                iface_name = ifname,
+                                "Received {} bytes from interface '{}'", received_bytes, ifname
+                            );
                             let mut routine = self.routines.get_mut(&ifname).ok_or_else(|| anyhow!("Interface '{}' not found", ifname))?;
                             routine.last_received_at = std::time::Instant::now();
                             routine.total_received_bytes += received_bytes;
+                            routine.total_received_packets += 1;
+                            routine.metered_bytes += received_bytes as u64;
+                            routine.mark_reachable();
                             drop(routine);
 
-                            let wg_addr = *self.source_addr.lock().unwrap();
-                            wireguard_socket.send_to(&buf[..received_bytes], wg_addr).await?;
-                            trace!("\tSent {} bytes to wireguard", received_bytes);
+                            if let Some(payload) = self.unwrap_received(&ifname, &mut buf[..received_bytes]) {
+                                if self.dedup.as_ref().is_some_and(|dedup| dedup.lock().unwrap().is_duplicate(payload)) {
+                                    debug!(
+                                        monotonic_counter.rengarde_downstream_duplicates_dropped_total = 1_u64,
+                                        iface_name = ifname,
+                                        "Dropping a copy of a packet already received from the server"
+                                    );
+                                    drops::record(DropReason::Duplicate);
+                                    continue;
+                                }
+                                let wg_addr = self.source_addr.lock().unwrap().clone();
+                                wireguard_socket.send_to(payload, &wg_addr).await?;
+                                debug!(
+                                    histogram.rengarde_forwarding_latency_seconds = received_at.elapsed().as_secs_f64(),
+                                    direction = "downstream",
+                                    iface_name = ifname
+                                );
+                                shared::packet_event!(Level::TRACE, "\tSent {} bytes to wireguard", payload.len());
+                            }
+                        }
+                        // A "fragmentation needed" error for a sent datagram
+                        Err(err) if icmp::is_too_big(&err) => {
+                            let mtu = icmp::take_errors(&*socket).and_then(|icmp| icmp.mtu);
+                            let mut routine = self.routines.get_mut(&ifname).ok_or_else(|| anyhow!("Interface '{}' not found", ifname))?;
+                            if let Some(mtu) = mtu {
+                                routine.record_path_mtu(mtu as usize);
+                            }
+                        }
+                        Err(err) if icmp::is_unreachable(&err) => {
+                            let reason = icmp::take_errors(&*socket).map_or_else(|| err.to_string(), |icmp| icmp.to_string());
+                            let mut routine = self.routines.get_mut(&ifname).ok_or_else(|| anyhow!("Interface '{}' not found", ifname))?;
+                            routine.mark_unreachable(&reason);
                         }
                         Err(err) => {
                             warn!("Error receiving from interface '{}': {:?}", ifname, err);
@@ -238,6 +1008,10 @@ impl Service {
                         }
                     }
                 }
+                _ = closed.cancelled() => {
+                    debug!("Interface '{}' was removed; closing thread", ifname);
+                    return Ok(());
+                }
                 _ = self.shutdown.cancelled() => {
                     debug!("Shutdown signal received; closing thread");
                     return Ok(());
@@ -246,10 +1020,14 @@ impl Service {
         }
     }
 
-    async fn receive_from_wireguard(&self, wireguard_socket: Arc<UdpSocket>) -> Result<()> {
-        let mut buf = [0; BUFFER_SIZE];
+    async fn receive_from_wireguard(&self, wireguard_socket: Arc<DatagramSocket>) -> Result<()> {
+        let buffer_size = self.settings().buffer_size.unwrap_or(shared::DEFAULT_BUFFER_SIZE);
+        let mut buf = vec![0; buffer_size];
+        let mut frame_buf = Vec::with_capacity(buffer_size);
+        let mut parity_bufs: Vec<(u32, Vec<u8>)> = Vec::new();
         loop {
-            let span = info_span!("receive_from_wireguard_loop");
+            static PACKETS: AtomicU32 = AtomicU32::new(0);
+            let span = if packet_trace::sample(&PACKETS) { info_span!("receive_from_wireguard_loop") } else { Span::none() };
             select! {
                 _ = self.shutdown.cancelled() => {
                     debug!("Shutdown signal received; closing thread");
@@ -258,28 +1036,71 @@ impl Service {
                 result = wireguard_socket.recv_from(&mut buf).instrument(span) => {
                     match result {
                         Ok((received_bytes, src_addr)) => {
-                            *self.source_addr.lock().unwrap() = src_addr;
-                            trace!(
+                            let received_at = std::time::Instant::now();
+                            // Replies go to the last sender; unbound Unix senders can't be answered
+                            if let Some(src_addr) = &src_addr {
+                                let mut source_addr = self.source_addr.lock().unwrap();
+                                if *source_addr != *src_addr {
+                                    *source_addr = src_addr.clone();
+                                }
+                            }
+                            shared::packet_event!(
+                                Level::TRACE,
                                 received_bytes = received_bytes,
-                                src_addr = src_addr.to_string(),
+                                src_addr = format!("{:?}", src_addr),
                                 "Received {} bytes from wireguard on '{:?}'", received_bytes, src_addr
                             );
-                            trace!("\tSending to {} clients", self.routines.len());
+                            shared::packet_event!(Level::TRACE, "\tSending to {} clients", self.routines.len());
 
-                            let drop_list = futures::stream::iter(self.routines.iter_mut())
+                            let framing = self.wrapper.as_ref().and_then(|wrapper| Some((wrapper, wrapper.header()?)));
+                            parity_bufs.clear();
+                            let datagram = match framing {
+                                Some((wrapper, mut header)) => {
+                                    for parity in wrapper.prepare(&mut header, &buf[..received_bytes]) {
+                                        let header = frame::Header { fec: Some(parity.tag), sequence: None, ..header };
+                                        let mut parity_buf = Vec::with_capacity(buffer_size);
+                                        wrapper.encode(&header, &parity.payload, &mut parity_buf);
+                                        parity_bufs.push((parity.tag.group.wrapping_add(parity.tag.index as u32), parity_buf));
+                                    }
+                                    wrapper.encode(&header, &buf[..received_bytes], &mut frame_buf);
+                                    &frame_buf[..]
+                                }
+                                None => &buf[..received_bytes],
+                            };
+
+                            // Skip dead, unreachable and standby paths, unless no better one is up
+                            let usable = self.usable_paths();
+                            let duplication_limit = self.wrapper.as_ref().and_then(|wrapper| wrapper.duplication_limit());
+                            let allowed = self.scheduler.lock().unwrap().schedule(&usable, duplication_limit);
+                            let allowed = allowed.unwrap_or_else(|| usable.iter().map(|path| path.ifname.clone()).collect());
+                            let routines = self.routines.iter_mut().filter(|routine| allowed.contains(routine.key()));
+
+                            let mut drop_list = futures::stream::iter(routines)
                                 .filter_map(|mut routine| async move {
-                                    routine.send_to(&buf[..received_bytes]).await
+                                    routine.send_to(datagram).await
                                 })
                                 .collect::<Vec<String>>()
                                 .await;
 
+                            // Send each parity frame of a completed FEC group on a single path, outside the data paths if possible
+                            for (rotation, parity_buf) in &parity_bufs {
+                                let Some(ifname) = self.parity_routine(*rotation, &usable, &allowed) else {
+                                    continue;
+                                };
+                                if let Some(mut routine) = self.routines.get_mut(&ifname) {
+                                    drop_list.extend(routine.send_to(parity_buf).await);
+                                }
+                            }
+
+                            debug!(
+                                histogram.rengarde_forwarding_latency_seconds = received_at.elapsed().as_secs_f64(),
+                                direction = "upstream"
+                            );
                             if !drop_list.is_empty() {
-                                drop_list.into_iter().for_each(|ifname| {
-                                    self.routines.remove(&ifname);
-                                });
+                                self.remove_failed(drop_list);
                             }
 
-                            trace!("Sent to {} clients", self.routines.len());
+                            shared::packet_event!(Level::TRACE, "Sent to {} clients", self.routines.len());
                         }
                         Err(err) => {
                             warn!("Error receiving from wireguard: {:?}", err);
@@ -291,26 +1112,218 @@ impl Service {
     }
 }
 
-fn get_address_by_interface(iface: &NetworkInterface) -> Option<std::net::IpAddr> {
-    iface.addr.iter().find_map(|addr| {
-        let ip = addr.ip();
-        match ip {
-            std::net::IpAddr::V4(v4) => {
-                if v4.is_private() {
-                    return Some(ip);
-                }
-                if v4.is_loopback() {
-                    return Some(ip);
-                }
-                if v4.is_link_local() {
-                    return Some(ip);
-                }
-                if v4.is_multicast() {
-                    return None;
-                }
-                Some(ip)
+/// Settings a path of an interface is created with, which re-create it when they change
+#[derive(PartialEq)]
+struct PathSettings<'a> {
+    iface: Option<&'a InterfaceSettings>,
+    dst_addr: &'a str,
+    write_timeout: Option<u64>,
+    fwmark: Option<u32>,
+    dscp: Option<u8>,
+    dont_fragment: bool,
+    standby: bool,
+    detect_metered: bool,
+    pacing_kbps: Option<u32>,
+}
+
+impl<'a> PathSettings<'a> {
+    fn new(settings: &'a ClientSettings, ifname: &str) -> Self {
+        let iface = settings.interfaces.get(ifname);
+        Self {
+            iface,
+            dst_addr: iface.and_then(|iface| iface.dst_addr.as_deref()).unwrap_or(&settings.dst_addr),
+            write_timeout: settings.write_timeout,
+            fwmark: settings.fwmark,
+            dscp: settings.dscp,
+            dont_fragment: settings.dont_fragment,
+            standby: settings.standby.iter().any(|standby| standby == ifname),
+            detect_metered: settings.detect_metered,
+            pacing_kbps: settings.pacing_kbps.get(ifname).copied(),
+        }
+    }
+}
+
+/// Returns the addresses to send from on an interface, one path each: the configured one if the
+/// interface has it, else its first suitable addresses, up to the configured number
+pub fn source_addresses(settings: &ClientSettings, iface: &NetworkInterface) -> Vec<IpAddr> {
+    match settings.interfaces.get(&iface.name).and_then(|iface_settings| iface_settings.src_addr) {
+        Some(src_addr) => iface.addr.iter().filter(|addr| addr.ip() == src_addr).take(1).map(|_| src_addr).collect(),
+        None => {
+            let limit = settings.addresses_per_interface.unwrap_or(1);
+            get_addresses_by_interface(iface, settings.prefer_ipv6).into_iter().take(limit).collect()
+        }
+    }
+}
+
+/// Returns whether an interface has a route to the server for the given source address, or
+/// doesn't need one
+pub fn has_route_to_server(settings: &ClientSettings, ifname: &str, source_addr: IpAddr) -> bool {
+    let iface_settings = settings.interfaces.get(ifname);
+    if !iface_settings.and_then(|iface_settings| iface_settings.require_route).unwrap_or(settings.require_route) {
+        return true;
+    }
+    // Only a literal destination can be matched against the routes; a hostname needs a default route
+    let dst = iface_settings.and_then(|iface_settings| iface_settings.dst_addr.as_ref()).unwrap_or(&settings.dst_addr);
+    let dst = dst.parse::<SocketAddr>().ok().map(|addr| addr.ip()).filter(|ip| ip.is_ipv6() == source_addr.is_ipv6());
+    has_route(ifname, source_addr.is_ipv6(), dst)
+}
+
+/// Returns the operational state of an interface in sysfs (e.g. `up`, `down` or `unknown`), if it has one
+pub fn operstate(ifname: &str) -> Option<String> {
+    let operstate = std::fs::read_to_string(std::path::Path::new("/sys/class/net").join(ifname).join("operstate")).ok()?;
+    Some(operstate.trim().to_owned())
+}
+
+/// Returns whether an interface has a link, from its operational state and carrier in sysfs
+///
+/// An address alone doesn't prove it: a stale DHCP lease outlives the link. Interfaces whose state
+/// is unknown (e.g. loopback, tunnels, or without sysfs) count as up.
+pub fn is_link_up(ifname: &str) -> bool {
+    let path = std::path::Path::new("/sys/class/net").join(ifname);
+    if matches!(operstate(ifname).as_deref(), Some("down" | "lowerlayerdown" | "dormant" | "notpresent")) {
+        return false;
+    }
+    // Reading the carrier fails while the interface is administratively down
+    std::fs::read_to_string(path.join("carrier")).map_or(true, |carrier| carrier.trim() != "0")
+}
+
+/// Returns whether the main routing table has a default route of the given family through an
+/// interface, or a route to `dst` if given
+fn has_route(ifname: &str, ipv6: bool, dst: Option<IpAddr>) -> bool {
+    // Routes that discard traffic, like the unreachable IPv6 default route on loopback
+    const RTF_REJECT: u32 = 0x0200;
+    let path = if ipv6 { "/proc/net/ipv6_route" } else { "/proc/net/route" };
+    let Ok(routes) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    routes.lines().any(|route| {
+        let fields: Vec<&str> = route.split_whitespace().collect();
+        // IPv4: `Iface Destination Gateway Flags RefCnt Use Metric Mask ...`, in host byte order; IPv6:
+        // `Destination PrefixLen Source PrefixLen NextHop Metric RefCnt Use Flags Iface`, in network byte order
+        let (device, destination, prefix_len, flags) = match (ipv6, fields.as_slice()) {
+            (false, [device, destination, _, flags, _, _, _, mask, ..]) => {
+                let (Ok(destination), Ok(mask)) = (u32::from_str_radix(destination, 16), u32::from_str_radix(mask, 16)) else {
+                    return false;
+                };
+                let destination = IpAddr::from(std::net::Ipv4Addr::from(destination.to_ne_bytes()));
+                (*device, destination, mask.count_ones(), *flags)
+            }
+            (true, [destination, prefix_len, _, _, _, _, _, _, flags, device]) => {
+                let (Ok(destination), Ok(prefix_len)) = (u128::from_str_radix(destination, 16), u32::from_str_radix(prefix_len, 16)) else {
+                    return false;
+                };
+                (*device, IpAddr::from(Ipv6Addr::from(destination)), prefix_len, *flags)
             }
-            std::net::IpAddr::V6(_) => None,
+            _ => return false,
+        };
+        let flags = u32::from_str_radix(flags, 16).unwrap_or(RTF_REJECT);
+        if device != ifname || flags & RTF_REJECT != 0 {
+            return false;
+        }
+        match dst {
+            Some(dst) => prefix_matches(destination, prefix_len, dst),
+            None => prefix_len == 0,
         }
     })
-} 
\ No newline at end of file
+}
+
+/// Returns whether an address is in the network of the given prefix
+fn prefix_matches(network: IpAddr, prefix_len: u32, addr: IpAddr) -> bool {
+    match (network, addr) {
+        (IpAddr::V4(network), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len.min(32)).unwrap_or(0);
+            u32::from(network) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len.min(128)).unwrap_or(0);
+            u128::from(network) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Returns the MTU of an interface, if sysfs reports it
+pub fn interface_mtu(ifname: &str) -> Option<usize> {
+    let mtu = std::fs::read_to_string(std::path::Path::new("/sys/class/net").join(ifname).join("mtu")).ok()?;
+    mtu.trim().parse().ok()
+}
+
+/// Returns whether NetworkManager considers an interface metered (e.g. a tethered phone), or false
+/// if it can't tell
+async fn is_metered(ifname: &str) -> bool {
+    let output = tokio::process::Command::new("nmcli")
+        .args(["--terse", "--fields", "GENERAL.METERED", "device", "show", ifname])
+        .output()
+        .await;
+    match output {
+        // Prints e.g. `GENERAL.METERED:yes (guessed)`
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .rsplit(':')
+            .next()
+            .is_some_and(|metered| metered.starts_with("yes")),
+        Ok(output) => {
+            debug!("NetworkManager doesn't know whether interface '{}' is metered: {}", ifname, String::from_utf8_lossy(&output.stderr).trim());
+            false
+        }
+        Err(err) => {
+            debug!("Failed to ask NetworkManager whether interface '{}' is metered: {:?}", ifname, err);
+            false
+        }
+    }
+}
+
+/// Returns the address to send from on an interface: its first IPv4 address, else its best IPv6
+/// address, or the other way around if `prefer_ipv6`
+pub fn get_address_by_interface(iface: &NetworkInterface, prefer_ipv6: bool) -> Option<IpAddr> {
+    get_addresses_by_interface(iface, prefer_ipv6).into_iter().next()
+}
+
+/// Returns the addresses an interface can send from, best first: its IPv4 addresses, then its IPv6
+/// addresses by rank, or the other way around if `prefer_ipv6`
+pub fn get_addresses_by_interface(iface: &NetworkInterface, prefer_ipv6: bool) -> Vec<IpAddr> {
+    let ipv4 = iface.addr.iter().filter_map(|addr| match addr.ip() {
+        IpAddr::V4(v4) if !v4.is_multicast() => Some(IpAddr::V4(v4)),
+        _ => None,
+    });
+    let mut ipv6: Vec<(u8, IpAddr)> = iface.addr
+        .iter()
+        .filter_map(|addr| match addr.ip() {
+            IpAddr::V6(v6) => Some((ipv6_rank(&v6)?, IpAddr::V6(v6))),
+            IpAddr::V4(_) => None,
+        })
+        .collect();
+    ipv6.sort_by_key(|(rank, _)| *rank);
+    let ipv6 = ipv6.into_iter().map(|(_, addr)| addr);
+
+    let mut addrs: Vec<IpAddr> = if prefer_ipv6 { ipv6.chain(ipv4).collect() } else { ipv4.chain(ipv6).collect() };
+    addrs.dedup();
+    addrs
+}
+
+/// Returns the name of the path sending from the `index`-th address of an interface: the interface
+/// name for the first one, and the interface name and address for the others
+fn path_name(ifname: &str, index: usize, addr: &IpAddr) -> String {
+    match index {
+        0 => ifname.to_owned(),
+        _ => format!("{}@{}", ifname, addr),
+    }
+}
+
+/// Ranks an IPv6 source address, lower being better: global unicast, then unique local, then the
+/// others, then loopback; returns `None` for the addresses that can't be used without a scope
+/// (link-local) or at all
+fn ipv6_rank(ip: &Ipv6Addr) -> Option<u8> {
+    let prefix = ip.segments()[0];
+    if ip.is_multicast() || ip.is_unspecified() || prefix & 0xffc0 == 0xfe80 {
+        None
+    } else if prefix & 0xe000 == 0x2000 {
+        Some(0)
+    } else if prefix & 0xfe00 == 0xfc00 {
+        Some(1)
+    } else if ip.is_loopback() {
+        Some(3)
+    } else {
+        Some(2)
+    }
+}
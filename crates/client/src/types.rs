@@ -1,41 +1,411 @@
-use std::net::SocketAddr;
-use std::time::Instant;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info, trace, warn};
+use shared::control::PathReport;
+use shared::drops::{self, DropReason};
+use shared::path::{DelayTrend, PathStats};
+use tracing::{debug, info, warn, Level};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::icmp;
+use crate::scheduler::PathInfo;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Settings {
     pub client: ClientSettings,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ClientSettings {
     pub description: Option<String>,
+    // Address WireGuard sends its traffic to, or `unix:<path>` to bind a Unix datagram socket for a userspace WireGuard
+    // implementation instead, whose own socket must be bound to a path to receive the replies.
     pub listen_addr: String,
     pub dst_addr: String,
+    // Write timeout in milliseconds for the writes to the server. A packet whose write on an interface times out is
+    // dropped on that interface, so a stalled path doesn't delay the others. Defaults to 10; 0 disables it.
     pub write_timeout: Option<u64>,
-    pub excluded_interfaces: Vec<String>,
+    // Size in bytes of the buffers receiving datagrams, from WireGuard and from the server, which must hold the
+    // largest WireGuard packet plus the headers of the wrapper. Raise it on LANs with jumbo frames. Defaults to 1500.
+    pub buffer_size: Option<usize>,
+    // Interfaces never bonded, by name or pattern: a glob (e.g. `docker*`) or a regular expression starting with `^`
+    // (e.g. `^veth`).
+    pub excluded_interfaces: Vec<InterfacePattern>,
+    // Interval in milliseconds between interface checks where netlink doesn't report interface changes, and between
+    // the hellos sent until the server answers. Defaults to 1000; embedded devices may want a longer one.
+    pub interface_check_interval: Option<u64>,
+    // Interval in seconds between the INFO summaries of the traffic sent and received on each interface, for setups
+    // without a metrics backend. Disabled if not set.
+    pub summary_interval: Option<u64>,
+    // Emit the per-packet spans and log events for one packet in N at each place they're emitted, e.g. 1000 to
+    // save CPU on gigabit links; 0 disables them. Lifecycle logs and metrics are unaffected. Defaults to 1, every
+    // packet.
+    pub packet_trace_sampling: Option<u32>,
+    // Bond only the interfaces listed here (minus the excluded ones) instead of every interface, which is safer on
+    // routers with dozens of virtual interfaces. Takes the same patterns. Every interface is bonded if not set.
+    #[serde(default)]
+    pub included_interfaces: Vec<InterfacePattern>,
     pub web_manager: Option<WebManager>,
+    // Shrink maps and buffers for embedded targets with little memory.
+    #[serde(default)]
+    pub low_memory: bool,
+    // How packets are spread over the paths: `duplicate` sends each one on every path (the default), `round-robin`
+    // sends each one on the next path in turn, `active-backup` sends them all on one path until it fails, and
+    // `failover` sends them all on one path but duplicates them on every path while its loss is high.
+    #[serde(default)]
+    pub mode: BondingMode,
+    // In `failover` mode, when to start and stop duplicating on the backup paths.
+    pub failover: Option<Failover>,
+    // In `round-robin` mode, relative share of the packets sent on each interface, by name, to match the capacity
+    // of the links (e.g. `{eth0: 10, wwan0: 1}`). Interfaces not listed weigh 1.
+    #[serde(default)]
+    pub weights: HashMap<String, u32>,
+    // Rate in kilobits per second each interface, by name, paces its sends at (e.g. `{wwan0: 5000}`), queueing bursts
+    // instead of sending them at once, so constrained uplinks like satellite or 4G don't drop them. Set it close to
+    // the uplink capacity; packets are not sent on the interface while its queue is full.
+    #[serde(default)]
+    pub pacing_kbps: HashMap<String, u32>,
+    // In `duplicate` mode, duplicate each packet only on the `bestPaths` paths with the lowest upstream loss (in whole percents), then
+    // round-trip time, as measured with `wrapper.pathReports` and `wrapper.echoInterval`. Every path is used while
+    // any lacks both measurements.
+    pub best_paths: Option<usize>,
+    // Interfaces, by name, kept connected (with keepalives and heartbeats) but carrying no data until every other
+    // path is down, e.g. to spare a metered LTE link. Detecting a path down needs `wrapper.heartbeatInterval`.
+    #[serde(default)]
+    pub standby: Vec<String>,
+    // Ask NetworkManager which interfaces are metered (e.g. tethered phones) and keep them on standby too.
+    #[serde(default)]
+    pub detect_metered: bool,
+    // On desktops, only bond the interfaces NetworkManager activated and whose connectivity check didn't find a
+    // captive portal or no connectivity, and re-scan them as soon as NetworkManager reports a change. Interfaces it
+    // doesn't manage are bonded as usual. Needs `nmcli`.
+    #[serde(default)]
+    pub network_manager: bool,
+    // Send from the IPv6 address of the interfaces that have both an IPv4 and an IPv6 one. Interfaces with only IPv6
+    // addresses (e.g. on many mobile carriers) use their global one either way; `dstAddr` must then resolve to an
+    // IPv6 address too.
+    #[serde(default)]
+    pub prefer_ipv6: bool,
+    // Number of addresses of each interface to send from, each as a separate path (named `<interface>@<address>`
+    // besides the first), e.g. to bond both the CGNAT IPv4 and the global IPv6 address of a mobile link. Defaults to 1.
+    pub addresses_per_interface: Option<usize>,
+    // DSCP value, from 0 to 63, marking the packets sent on every interface (e.g. 46 for expedited forwarding), so
+    // ISP and local QoS policies can prioritize the tunnel traffic; interfaces can override it.
+    pub dscp: Option<u8>,
+    // Firewall mark (SO_MARK) set on the sockets of every interface, so Linux policy routing rules can force the traffic
+    // out of the intended uplink, even where binding to the interface isn't allowed; interfaces can override it.
+    pub fwmark: Option<u32>,
+    // Set the Don't-Fragment bit on the packets sent to the server, letting the kernel discover the MTU of each path
+    // instead of fragmenting. The discovered path MTUs are logged and reported as the `rengarde_path_mtu_bytes`
+    // metric; packets too large for them are dropped, which shows a misconfigured WireGuard MTU right away.
+    #[serde(default)]
+    pub dont_fragment: bool,
+    // Only bond the interfaces with a default route, or a route to `dstAddr` if it's an address, in the main routing
+    // table, of their source address's family. Spares a path to interfaces that can't reach the server, e.g. with only
+    // a link-local address; interfaces can override it.
+    #[serde(default)]
+    pub require_route: bool,
+    // Milliseconds during which copies of a packet received from the server on several interfaces are dropped, by
+    // comparing payload hashes. Servers send their replies over every path of the session (and engarde servers over
+    // every path), so WireGuard only gets each of them once. Defaults to 1000ms; 0 disables it.
+    pub dedup_window: Option<u64>,
+    // Settings of specific interfaces, by name (e.g. to send the LTE traffic to another server port).
+    #[serde(default)]
+    pub interfaces: HashMap<String, InterfaceSettings>,
+    // Monthly data quotas of metered interfaces, which are demoted to standby once they used theirs.
+    pub data_cap: Option<DataCap>,
+    // Wraps every packet in a rengarde frame. Requires a rengarde server; leave unset to stay engarde-compatible.
+    pub wrapper: Option<WrapperSettings>,
+}
+
+/// Interface name, or pattern matching interface names: a glob with `*` and `?` wildcards, or a
+/// regular expression if it starts with `^`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(try_from = "String", into = "String")]
+pub struct InterfacePattern {
+    pattern: String,
+    regex: Option<Regex>,
+}
+
+impl PartialEq for InterfacePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl InterfacePattern {
+    pub fn matches(&self, ifname: &str) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(ifname),
+            None => self.pattern == ifname,
+        }
+    }
+}
+
+impl TryFrom<String> for InterfacePattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        let regex = if pattern.starts_with('^') {
+            Some(Regex::new(&pattern)?)
+        } else if pattern.contains(['*', '?']) {
+            let glob = regex::escape(&pattern).replace(r"\*", ".*").replace(r"\?", ".");
+            Some(Regex::new(&format!("^{}$", glob))?)
+        } else {
+            None
+        };
+        Ok(Self { pattern, regex })
+    }
+}
+
+impl From<InterfacePattern> for String {
+    fn from(pattern: InterfacePattern) -> Self {
+        pattern.pattern
+    }
+}
+
+impl ClientSettings {
+    /// Returns why an interface must not be bonded, if it must not
+    pub fn interface_filter(&self, ifname: &str) -> Option<&'static str> {
+        if self.excluded_interfaces.iter().any(|excluded| excluded.matches(ifname)) {
+            Some("is excluded")
+        } else if !self.included_interfaces.is_empty() && !self.included_interfaces.iter().any(|included| included.matches(ifname)) {
+            Some("isn't included")
+        } else {
+            None
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum BondingMode {
+    #[default]
+    Duplicate,
+    RoundRobin,
+    ActiveBackup,
+    Failover,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Failover {
+    // Upstream loss in percents on the preferred path, as measured with `wrapper.pathReports`, above which every
+    // packet is duplicated on the backup paths too.
+    pub loss_threshold: Option<f64>,
+    // Seconds the loss must stay under the threshold before duplication stops.
+    pub recovery_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WrapperSettings {
+    // Tag frames with a session ID, so the server groups the paths of this client into a single session.
+    #[serde(default)]
+    pub session: bool,
+    // Fixed session ID (implies `session`), e.g. to keep server-side labels across restarts. Random if not set.
+    pub session_id: Option<u64>,
+    // Append a CRC32C checksum to every frame, so datagrams corrupted in transit are dropped by the server.
+    #[serde(default)]
+    pub checksum: bool,
+    // Let the server reduce the number of paths each packet is duplicated on while its uplink is congested.
+    #[serde(default)]
+    pub congestion_feedback: bool,
+    // Pre-shared key authenticating every packet; must match the server's `psk`.
+    // Traffic is framed from the first packet, so the server must be a rengarde server.
+    pub psk: Option<String>,
+    // File holding the `psk` instead, e.g. a systemd credential or a mounted Kubernetes secret.
+    pub psk_file: Option<String>,
+    // Secret for the XChaCha20-Poly1305 encryption hiding the WireGuard traffic from DPI middleboxes; must match
+    // the server's `encryptionKey`. Traffic is framed from the first packet, so the server must be a rengarde server.
+    pub encryption_key: Option<String>,
+    // File holding the `encryptionKey` instead.
+    pub encryption_key_file: Option<String>,
+    // Pad every frame up to the smallest of these sizes (in bytes) that fits it, e.g. `[256, 512, 1024, 1500]`,
+    // trading bandwidth for traffic patterns that are harder to fingerprint. Frames larger than every size aren't padded.
+    #[serde(default)]
+    pub padding: Vec<usize>,
+    // Send parity frames after every `fec` data frames (2 to 48), on other paths when possible, so the
+    // server can rebuild lost frames without retransmission. Implies `session`, as the server rebuilds the frames
+    // of a session across its paths.
+    pub fec: Option<u8>,
+    // Number of parity frames per group (1 to 15), e.g. 3 with `fec: 10` for heavily lossy links. A single parity
+    // frame is a plain XOR of the group and rebuilds one lost frame; more use a Reed-Solomon code and rebuild as
+    // many lost frames as there are parity frames. Defaults to 1.
+    pub fec_parity: Option<u8>,
+    // Number the data frames, so the server can restore their order and drop duplicates before WireGuard. Implies
+    // `session`, as the frames are numbered across the paths of the session.
+    #[serde(default)]
+    pub sequence: bool,
+    // Send a heartbeat on each path every `heartbeatInterval` milliseconds, so both ends stop using a path after
+    // three missed heartbeats instead of waiting for the client timeout, even while the tunnel is idle.
+    pub heartbeat_interval: Option<u64>,
+    // Ask the server to report periodically, for each path, the packets, bytes and loss it received, so the
+    // upstream health of every interface shows in the logs and metrics.
+    #[serde(default)]
+    pub path_reports: bool,
+    // Send an echo request on each path every `echoInterval` milliseconds to measure its round-trip time, which
+    // is logged, exported as a metric and reported to the server for its web manager.
+    pub echo_interval: Option<u64>,
+    // Send a tiny keepalive frame on each path that sent nothing for `keepaliveInterval` seconds, so the NAT and
+    // firewall mappings of secondary links don't expire while WireGuard's PersistentKeepalive only refreshes one.
+    pub keepalive_interval: Option<u64>,
+    // Timestamp every frame, in both directions, so each end follows the queueing delay of every path and warns
+    // when it builds up (e.g. on a bufferbloated LTE link).
+    #[serde(default)]
+    pub timestamps: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceSettings {
+    // Source address to bind, among the interface's addresses, instead of the first suitable one.
+    pub src_addr: Option<IpAddr>,
+    // Source port to bind instead of a random one, so firewall and NAT rules can match the interface's traffic and
+    // the server sees the same source across restarts.
+    pub src_port: Option<u16>,
+    // Server address to send to on this interface instead of `dstAddr`.
+    pub dst_addr: Option<String>,
+    // DSCP value, from 0 to 63, marking the packets sent on this interface; overrides `dscp`.
+    pub dscp: Option<u8>,
+    // Whether the interface is metered, keeping it on standby; overrides the detection with `detectMetered`.
+    pub metered: Option<bool>,
+    // VRF device the interface is enslaved to, on routers using VRFs: the socket is bound to it instead of the
+    // interface, so it uses the VRF's routing table, and still sends from the interface's address.
+    pub vrf: Option<String>,
+    // Firewall mark set on the sockets of this interface; overrides `fwmark`.
+    pub fwmark: Option<u32>,
+    // Maximum rate in kilobits per second sent on this interface, so a slow or metered link isn't saturated by
+    // duplication; packets beyond it (after a 100ms burst) are not sent on it.
+    pub max_rate_kbps: Option<u32>,
+    // Whether the interface needs a route to the server to be bonded; overrides `requireRoute`.
+    pub require_route: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DataCap {
+    // Path of the JSON file persisting the data used by each interface in the current billing period.
+    pub usage_file: String,
+    // Day of the month, from 1 to 28, the billing period starts on (in UTC).
+    pub billing_day: Option<u8>,
+    // Megabytes each metered interface, by name, may send and receive per billing period (e.g. `{wwan0: 10000}`).
+    // Interfaces not listed are unlimited.
+    #[serde(default)]
+    pub quota_mb: HashMap<String, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct WebManager {
     pub listen_addr: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
+    // File holding the `password` instead, e.g. a systemd credential or a mounted Kubernetes secret.
+    pub password_file: Option<String>,
+}
+
+/// How long a path reported unreachable stays down before being tried again
+pub const UNREACHABLE_HOLD: Duration = Duration::from_secs(5);
+
+/// Smallest burst a rate-limited path lets through, so slow limits still pass full-sized packets
+const MIN_BURST: f64 = 16_384.0;
+
+/// Token bucket capping the rate sent on a path, refilled continuously and holding up to 100ms of traffic
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Refill rate in bytes per second
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(kbps: u32) -> Self {
+        let rate = kbps as f64 * 1000.0 / 8.0;
+        let burst = (rate / 10.0).max(MIN_BURST);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes the tokens for `bytes`, returning false without taking any if there aren't enough
+    pub fn take(&mut self, bytes: usize) -> bool {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * self.rate).min(self.burst);
+        self.refilled_at = now;
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
 }
 
 pub struct SendingRoutine {
+    /// Name of the path, which is the interface name unless the interface has several paths
     pub ifname: String,
+    /// Name of the interface the path sends on
+    pub iface: String,
+    /// Whether the path only carries data while every primary path is down
+    pub standby: bool,
     pub src_socket: std::sync::Arc<tokio::net::UdpSocket>,
     pub src_addr: SocketAddr,
     pub dst_addr: SocketAddr,
     pub last_received_at: Instant,
     pub total_received_bytes: usize,
+    pub total_received_packets: usize,
+    /// Bytes and packets sent (or queued for pacing) on this interface
+    pub total_sent_bytes: usize,
+    pub total_sent_packets: usize,
+    /// Last time traffic was sent on this path
+    pub last_sent_at: Instant,
     pub is_closing: bool,
+    /// Last time the server acknowledged a heartbeat sent on this path
+    pub last_heartbeat_ack_at: Instant,
+    /// Whether the path answers heartbeats; dead paths only carry heartbeats until they recover
+    pub is_alive: bool,
+    /// Last time the network reported the server unreachable through this path
+    pub unreachable_at: Option<Instant>,
+    /// Latest report from the server about what it received on this path
+    pub upstream: Option<PathReport>,
+    /// Traffic received from the server on this path, to measure its loss
+    pub downstream: PathStats,
+    /// Queueing delay of the traffic received from the server on this path
+    pub delay: DelayTrend,
+    /// Echo request awaiting its reply on this path, and when it was sent
+    pub echo_sent: Option<(u32, Instant)>,
+    /// Smoothed round-trip time of this path, measured with echo requests
+    pub rtt: Option<Duration>,
+    /// Rate cap of this path, if configured
+    pub rate_limit: Option<TokenBucket>,
+    /// Queue of the pacer sending on this path, if paced
+    pub pacer: Option<tokio::sync::mpsc::Sender<Vec<u8>>>,
+    /// Bytes sent and received on this path since its data usage was last accounted
+    pub metered_bytes: u64,
+    /// Whether this path used up its monthly quota, demoting it to standby
+    pub over_cap: bool,
+    /// Largest datagram the interface's MTU carries without fragmentation, if known
+    pub max_datagram: Option<usize>,
+    /// Whether a datagram too large for the interface's MTU was reported on this path
+    pub oversized: bool,
+    /// MTU of the path to the server, once the kernel discovered one
+    pub path_mtu: Option<usize>,
+    /// Cancelled once the path is removed, so its receiving thread releases the socket right away
+    /// (e.g. to re-bind a fixed source port)
+    pub closed: tokio_util::sync::CancellationToken,
+    /// Time after which a write on this path is given up, if limited
+    pub write_timeout: Option<Duration>,
+    /// Whether the path is up in the `rengarde_path_up` metric
+    reported_up: bool,
 }
 
 impl SendingRoutine {
@@ -47,21 +417,175 @@ impl SendingRoutine {
             dst_addr = dst_addr.to_string(),
             "\tAdded interface '{}' to sending routines", ifname
         );
+        debug!(counter.rengarde_interfaces_active = 1_i64);
+        // Start the path's series at 0, so alerts see the paths that never came up
+        debug!(counter.rengarde_path_up = 0_i64, iface_name = ifname);
         Self {
+            iface: ifname.clone(),
+            standby: false,
             ifname,
             src_socket,
             src_addr,
             dst_addr,
             last_received_at: Instant::now(),
             total_received_bytes: 0,
+            total_received_packets: 0,
+            total_sent_bytes: 0,
+            total_sent_packets: 0,
+            last_sent_at: Instant::now(),
             is_closing: false,
+            last_heartbeat_ack_at: Instant::now(),
+            is_alive: true,
+            unreachable_at: None,
+            upstream: None,
+            downstream: PathStats::new(),
+            delay: DelayTrend::new(),
+            echo_sent: None,
+            rtt: None,
+            rate_limit: None,
+            pacer: None,
+            metered_bytes: 0,
+            over_cap: false,
+            max_datagram: None,
+            oversized: false,
+            path_mtu: None,
+            closed: tokio_util::sync::CancellationToken::new(),
+            write_timeout: None,
+            reported_up: false,
+        }
+    }
+
+    /// Folds a round-trip time sample into the smoothed RTT, weighting it 1/8 like TCP does
+    pub fn record_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    /// Returns whether the path answers heartbeats and wasn't recently reported unreachable
+    pub fn is_up(&self) -> bool {
+        self.is_alive && self.unreachable_at.is_none_or(|at| at.elapsed() >= UNREACHABLE_HOLD)
+    }
+
+    /// Reports a change of the path's state in the `rengarde_path_up` metric, which is 1 while the path is up, and in
+    /// the `rengarde_paths_up` total
+    ///
+    /// Called periodically rather than on every change, as the path also comes back up once its unreachable hold
+    /// expires.
+    pub fn report_state(&mut self) {
+        let up = self.is_up();
+        if up != self.reported_up {
+            self.reported_up = up;
+            let change = if up { 1_i64 } else { -1_i64 };
+            debug!(counter.rengarde_path_up = change, iface_name = self.ifname);
+            debug!(counter.rengarde_paths_up = change);
+        }
+    }
+
+    /// Returns the measurements of the path, for schedulers
+    pub fn path_info(&self) -> PathInfo {
+        PathInfo {
+            ifname: self.ifname.clone(),
+            last_received_at: self.last_received_at,
+            upstream: self.upstream,
+            rtt: self.rtt,
+        }
+    }
+
+    /// Marks the path as down after the network reported the server unreachable through it
+    ///
+    /// The path is tried again after [`UNREACHABLE_HOLD`], or as soon as traffic is received on it.
+    pub fn mark_unreachable(&mut self, reason: &str) {
+        debug!(monotonic_counter.rengarde_path_unreachable_total = 1_u64, iface_name = self.ifname);
+        if self.is_up() {
+            warn!("Interface '{}' reported the server unreachable: {}; path is down", self.ifname, reason);
+        }
+        self.unreachable_at = Some(Instant::now());
+    }
+
+    /// Records that traffic was received on the path, proving the server reachable through it
+    pub fn mark_reachable(&mut self) {
+        if self.unreachable_at.take().is_some() {
+            info!("Interface '{}' receives traffic again; path is up", self.ifname);
+        }
+    }
+
+    /// Records the path MTU the kernel discovered, lowering the largest datagram the path carries
+    pub fn record_path_mtu(&mut self, mtu: usize) {
+        debug!(histogram.rengarde_path_mtu_bytes = mtu as u64, iface_name = self.ifname);
+        if self.path_mtu.replace(mtu) == Some(mtu) {
+            return;
+        }
+        let ip_header = if self.src_addr.is_ipv6() { 40 } else { 20 };
+        let max_datagram = mtu.saturating_sub(ip_header + 8);
+        warn!("Path MTU of '{}' is {} bytes; datagrams over {} bytes are dropped", self.ifname, mtu, max_datagram);
+        if self.max_datagram.is_none_or(|max| max_datagram < max) {
+            self.max_datagram = Some(max_datagram);
+            self.oversized = false;
         }
     }
 
     pub async fn send_to(&mut self, buf: &[u8]) -> Option<String> {
-        match self.src_socket.send_to(buf, self.dst_addr).await {
+        if let Some(max_datagram) = self.max_datagram.filter(|max| buf.len() > *max) {
+            debug!(monotonic_counter.rengarde_path_oversized_packets_total = 1_u64, iface_name = self.ifname);
+            if !self.oversized {
+                self.oversized = true;
+                warn!(
+                    "Sending {} bytes on '{}', whose MTU carries {} bytes without fragmentation; lower the WireGuard MTU by {} bytes",
+                    buf.len(), self.ifname, max_datagram, buf.len() - max_datagram
+                );
+            }
+        }
+        if self.rate_limit.as_mut().is_some_and(|bucket| !bucket.take(buf.len())) {
+            debug!(monotonic_counter.rengarde_path_rate_limited_packets_total = 1_u64, iface_name = self.ifname);
+            drops::record(DropReason::RateLimit);
+            return None;
+        }
+        if let Some(pacer) = &self.pacer {
+            if pacer.try_send(buf.to_vec()).is_err() {
+                debug!(monotonic_counter.rengarde_path_pacing_dropped_packets_total = 1_u64, iface_name = self.ifname);
+                drops::record(DropReason::RateLimit);
+                return None;
+            }
+            self.last_sent_at = Instant::now();
+            self.metered_bytes += buf.len() as u64;
+            self.total_sent_bytes += buf.len();
+            self.total_sent_packets += 1;
+            return None;
+        }
+        let send = self.src_socket.send_to(buf, self.dst_addr);
+        let result = match self.write_timeout {
+            Some(write_timeout) => match tokio::time::timeout(write_timeout, send).await {
+                Ok(result) => result,
+                Err(_) => {
+                    debug!(
+                        monotonic_counter.rengarde_path_write_timeouts_total = 1_u64,
+                        iface_name = self.ifname,
+                        "Write on '{}' timed out; dropping the packet", self.ifname
+                    );
+                    drops::record(DropReason::WriteTimeout);
+                    return None;
+                }
+            },
+            None => send.await,
+        };
+        if result.is_err() {
+            debug!(monotonic_counter.rengarde_path_send_errors_total = 1_u64, iface_name = self.ifname);
+        }
+        match result {
             Ok(sent_bytes) => {
-                trace!(
+                self.last_sent_at = Instant::now();
+                self.metered_bytes += sent_bytes as u64;
+                self.total_sent_bytes += sent_bytes;
+                self.total_sent_packets += 1;
+                debug!(
+                    monotonic_counter.rengarde_path_sent_packets_total = 1_u64,
+                    monotonic_counter.rengarde_path_sent_bytes_total = sent_bytes as u64,
+                    iface_name = self.ifname
+                );
+                shared::packet_event!(
+                    Level::TRACE,
                     sent_bytes = sent_bytes,
                     dst_ifname = self.ifname,
                     dst_addr = self.dst_addr.to_string(),
@@ -69,7 +593,22 @@ impl SendingRoutine {
                 );
                 None
             }
+            Err(err) if icmp::is_too_big(&err) => {
+                debug!(monotonic_counter.rengarde_path_too_big_packets_total = 1_u64, iface_name = self.ifname);
+                drops::record(DropReason::TooBig);
+                if let Some(mtu) = icmp::take_errors(&*self.src_socket).and_then(|icmp| icmp.mtu) {
+                    self.record_path_mtu(mtu as usize);
+                }
+                None
+            }
+            Err(err) if icmp::is_unreachable(&err) => {
+                let reason = icmp::take_errors(&*self.src_socket).map_or_else(|| err.to_string(), |icmp| icmp.to_string());
+                self.mark_unreachable(&reason);
+                drops::record(DropReason::Unreachable);
+                None
+            }
             Err(err) => {
+                drops::record(DropReason::SendError);
                 warn!(
                     event = "disconnect",
                     dst_addr = self.dst_addr.to_string(),
@@ -83,6 +622,12 @@ impl SendingRoutine {
 
 impl Drop for SendingRoutine {
     fn drop(&mut self) {
+        self.closed.cancel();
+        if self.reported_up {
+            debug!(counter.rengarde_path_up = -1_i64, iface_name = self.ifname);
+            debug!(counter.rengarde_paths_up = -1_i64);
+        }
+        debug!(counter.rengarde_interfaces_active = -1_i64);
         debug!(
             event = "removed",
             iface_name = self.ifname,
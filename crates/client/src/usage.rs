@@ -0,0 +1,148 @@
+//! Monthly data usage of metered interfaces, persisted across restarts.
+//!
+//! Every interface with a quota counts the bytes it sends and receives (UDP payloads) during the
+//! current billing period, which starts on the configured day of each month, in UTC. Interfaces over
+//! their quota are demoted to standby until the next period.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::types::DataCap;
+
+/// Interval between the accounting of the bytes counted by the paths, which also saves the usage
+pub const ACCOUNTING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Usage persisted in the usage file
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Usage {
+    /// Month the current billing period started in, as `YYYY-MM`
+    period: String,
+    /// Bytes sent and received by each metered interface during the period
+    #[serde(default)]
+    bytes: BTreeMap<String, u64>,
+}
+
+/// Data used by the metered interfaces during the current billing period
+#[derive(Debug)]
+pub struct DataUsage {
+    path: PathBuf,
+    billing_day: u8,
+    /// Quota of each metered interface, in bytes
+    quotas: HashMap<String, u64>,
+    usage: Usage,
+}
+
+impl DataUsage {
+    /// Loads the usage from the usage file, starting from zero if it doesn't exist yet
+    pub fn load(data_cap: &DataCap) -> Result<Self> {
+        let path = PathBuf::from(&data_cap.usage_file);
+        let usage = if path.exists() {
+            let usage = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read usage file '{}'", path.display()))?;
+            let usage = serde_json::from_str(&usage)
+                .with_context(|| format!("Failed to parse usage file '{}'", path.display()))?;
+            debug!("Loaded data usage from '{}'", path.display());
+            usage
+        } else {
+            info!("Usage file '{}' not found; starting from zero", path.display());
+            Usage::default()
+        };
+
+        let mut data_usage = Self {
+            path,
+            billing_day: data_cap.billing_day.unwrap_or(1),
+            quotas: data_cap.quota_mb
+                .iter()
+                .map(|(ifname, mb)| (ifname.clone(), mb.saturating_mul(1_000_000)))
+                .collect(),
+            usage,
+        };
+        data_usage.roll_over();
+        Ok(data_usage)
+    }
+
+    /// Adds the bytes sent and received by an interface, returning whether it just went over its quota
+    pub fn add(&mut self, ifname: &str, bytes: u64) -> bool {
+        let Some(quota) = self.quotas.get(ifname) else {
+            return false;
+        };
+        let used = self.usage.bytes.entry(ifname.to_owned()).or_default();
+        let was_over = *used >= *quota;
+        *used += bytes;
+        !was_over && *used >= *quota
+    }
+
+    /// Returns whether an interface used up its quota for the current billing period
+    pub fn is_over_cap(&self, ifname: &str) -> bool {
+        self.quotas.get(ifname).is_some_and(|quota| self.usage.bytes.get(ifname).is_some_and(|used| used >= quota))
+    }
+
+    /// Resets the usage once a new billing period started, returning whether it did
+    pub fn roll_over(&mut self) -> bool {
+        let period = billing_period(SystemTime::now(), self.billing_day);
+        if self.usage.period == period {
+            return false;
+        }
+        if !self.usage.period.is_empty() {
+            info!("Billing period {} started; resetting data usage", period);
+        }
+        self.usage = Usage {
+            period,
+            bytes: BTreeMap::new(),
+        };
+        true
+    }
+
+    /// Atomically replaces the usage file with the current usage
+    pub fn save(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let usage = serde_json::to_string_pretty(&self.usage)?;
+        std::fs::write(&tmp_path, usage)
+            .with_context(|| format!("Failed to write usage file '{}'", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace usage file '{}'", self.path.display()))?;
+        debug!("Saved data usage to '{}'", self.path.display());
+        Ok(())
+    }
+
+    /// Logs the usage of every metered interface
+    pub fn log(&self) {
+        for (ifname, quota) in &self.quotas {
+            let used = self.usage.bytes.get(ifname).copied().unwrap_or_default();
+            if used >= *quota {
+                warn!("Interface '{}' used {} of its {} MB quota; kept on standby", ifname, used / 1_000_000, quota / 1_000_000);
+            } else {
+                debug!("Interface '{}' used {} of its {} MB quota", ifname, used / 1_000_000, quota / 1_000_000);
+            }
+        }
+    }
+}
+
+/// Returns the month the billing period containing `now` started in, as `YYYY-MM`
+fn billing_period(now: SystemTime, billing_day: u8) -> String {
+    let days = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86_400;
+    let (mut year, mut month, day) = civil_from_days(days as i64);
+    if day < billing_day as u32 {
+        (year, month) = if month == 1 { (year - 1, 12) } else { (year, month - 1) };
+    }
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date, after Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
@@ -0,0 +1,296 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use shared::control::{Capabilities, Message};
+use shared::fec::{self, FecEncoder, Parity};
+use shared::frame::{self, Codec, FrameError, Kind, SessionId};
+use shared::path;
+use tracing::{debug, info, warn};
+
+use crate::types::WrapperSettings;
+
+/// How long a duplication limit holds without being refreshed by the server
+const DUPLICATION_LIMIT_TTL: Duration = Duration::from_secs(3);
+
+/// Frames outgoing traffic once the server has agreed on the requested capabilities
+#[derive(Debug)]
+pub struct Wrapper {
+    session_id: Option<SessionId>,
+    requested: Capabilities,
+    codec: Codec,
+    /// Header of outgoing data frames once negotiated
+    negotiated: RwLock<Option<frame::Header>>,
+    /// Maximum number of paths to duplicate on, as last requested by the server
+    duplication_limit: RwLock<Option<(usize, Instant)>>,
+    /// Parity encoder, once forward error correction is negotiated
+    fec: Option<Mutex<FecEncoder>>,
+    fec_negotiated: AtomicBool,
+    /// Sequence number of the next data frame
+    next_sequence: AtomicU32,
+    heartbeat_interval: Option<Duration>,
+    heartbeat_negotiated: AtomicBool,
+    echo_interval: Option<Duration>,
+    echo_negotiated: AtomicBool,
+    keepalive_interval: Option<Duration>,
+    keepalive_negotiated: AtomicBool,
+}
+
+impl Wrapper {
+    pub fn new(settings: &WrapperSettings) -> Self {
+        let mut requested = Capabilities::empty();
+        // Parity frames go over other paths than their group, and the frames are numbered across the paths, so the
+        // server must decode and reorder the paths together
+        let session = settings.session || settings.session_id.is_some() || settings.fec.is_some() || settings.sequence;
+        let session_id = session.then(|| {
+            requested |= Capabilities::SESSION_ID;
+            settings.session_id.unwrap_or_else(|| RandomState::new().build_hasher().finish())
+        });
+        if settings.checksum {
+            requested |= Capabilities::CHECKSUM;
+        }
+        if settings.congestion_feedback {
+            requested |= Capabilities::CONGESTION_FEEDBACK;
+        }
+        let heartbeat_interval = settings.heartbeat_interval.filter(|interval| *interval > 0).map(|interval| {
+            requested |= Capabilities::HEARTBEAT;
+            Duration::from_millis(interval.min(u16::MAX as u64))
+        });
+        if settings.sequence {
+            requested |= Capabilities::SEQUENCE;
+        }
+        let echo_interval = settings.echo_interval.filter(|interval| *interval > 0).map(|interval| {
+            requested |= Capabilities::ECHO;
+            Duration::from_millis(interval)
+        });
+        let keepalive_interval = settings.keepalive_interval.filter(|interval| *interval > 0).map(|interval| {
+            requested |= Capabilities::KEEPALIVE;
+            Duration::from_secs(interval)
+        });
+        if settings.timestamps {
+            requested |= Capabilities::TIMESTAMP;
+        }
+        if settings.path_reports {
+            requested |= Capabilities::PATH_REPORT;
+        }
+        if !settings.padding.is_empty() {
+            requested |= Capabilities::PADDING;
+        }
+        let fec = settings.fec.map(|size| {
+            requested |= Capabilities::FEC;
+            if !(2..=fec::MAX_GROUP_SIZE).contains(&size) {
+                warn!("FEC group size {} out of range; clamping to 2..={}", size, fec::MAX_GROUP_SIZE);
+            }
+            let parity = settings.fec_parity.unwrap_or(1);
+            if !(1..=fec::MAX_PARITY).contains(&parity) {
+                warn!("FEC parity {} out of range; clamping to 1..={}", parity, fec::MAX_PARITY);
+            }
+            info!("FEC enabled: {} parity frames every {} data frames", parity, size);
+            Mutex::new(FecEncoder::new(size, parity))
+        });
+
+        info!("Wrapper enabled; requesting capabilities: {}", requested);
+        if let Some(session_id) = session_id {
+            info!("Session ID: {}", session_id);
+        }
+
+        let codec = Codec::new(settings.psk.as_deref(), settings.encryption_key.as_deref())
+            .with_padding(settings.padding.clone());
+        if codec.requires_auth() {
+            info!("Pre-shared key set; authenticating every packet");
+        }
+        if codec.can_encrypt() {
+            info!("Encryption key set; encrypting every packet");
+        }
+        if codec.can_pad() {
+            info!("Padding frames up to {:?} bytes", settings.padding);
+        }
+
+        Self {
+            session_id,
+            requested,
+            codec,
+            negotiated: RwLock::new(None),
+            duplication_limit: RwLock::new(None),
+            fec,
+            fec_negotiated: AtomicBool::new(false),
+            next_sequence: AtomicU32::new(0),
+            heartbeat_interval,
+            heartbeat_negotiated: AtomicBool::new(false),
+            echo_interval,
+            echo_negotiated: AtomicBool::new(false),
+            keepalive_interval,
+            keepalive_negotiated: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the header of outgoing data frames, or `None` to send raw traffic
+    ///
+    /// Until negotiated, traffic is raw unless it must be authenticated or encrypted, in which case
+    /// it is framed without any optional capability.
+    pub fn header(&self) -> Option<frame::Header> {
+        self.negotiated.read().unwrap().or_else(|| {
+            (self.codec.requires_auth() || self.codec.can_encrypt()).then(|| frame::Header {
+                encrypted: self.codec.can_encrypt(),
+                ..frame::Header::default()
+            })
+        })
+    }
+
+    /// Returns the number of bytes framing adds around each data packet
+    pub fn overhead(&self) -> usize {
+        self.header().map_or(0, |header| self.codec.overhead(&header))
+    }
+
+    pub fn is_negotiated(&self) -> bool {
+        self.negotiated.read().unwrap().is_some()
+    }
+
+    /// Frames a payload with the given header
+    pub fn encode(&self, header: &frame::Header, payload: &[u8], out: &mut Vec<u8>) {
+        self.codec.encode(header, payload, out);
+    }
+
+    /// Fills the per-frame fields of an outgoing data frame's header (sequence number, timestamp, FEC
+    /// tag) as negotiated, returning the parity frames to send too if the frame completes its FEC group
+    pub fn prepare(&self, header: &mut frame::Header, payload: &[u8]) -> Vec<Parity> {
+        if header.sequence.is_some() {
+            header.sequence = Some(self.next_sequence.fetch_add(1, Ordering::Relaxed));
+        }
+        if header.timestamp.is_some() {
+            header.timestamp = Some(path::timestamp());
+        }
+        let Some(fec) = self.fec.as_ref().filter(|_| self.fec_negotiated.load(Ordering::Relaxed)) else {
+            return Vec::new();
+        };
+        let (tag, parity) = fec.lock().unwrap().push(payload);
+        header.fec = Some(tag);
+        parity
+    }
+
+    /// Splits a frame received from the server into its header and payload
+    pub fn decode<'a>(&self, datagram: &'a mut [u8]) -> Result<(frame::Header, &'a [u8]), FrameError> {
+        self.codec.decode(datagram)
+    }
+
+    /// Returns the maximum number of paths to duplicate each packet on, if the server asked for one
+    pub fn duplication_limit(&self) -> Option<usize> {
+        self.duplication_limit
+            .read()
+            .unwrap()
+            .filter(|(_, received_at)| received_at.elapsed() < DUPLICATION_LIMIT_TTL)
+            .map(|(max_paths, _)| max_paths)
+    }
+
+    /// Encodes the `Hello` frame requesting this wrapper's capabilities
+    pub fn hello(&self, out: &mut Vec<u8>) {
+        Message::Hello { capabilities: self.requested }.encode_frame(&self.codec, self.session_id, self.codec.can_encrypt(), out);
+    }
+
+    /// Returns the interval to send heartbeats at on each path, once negotiated
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        self.heartbeat_interval.filter(|_| self.heartbeat_negotiated.load(Ordering::Relaxed))
+    }
+
+    /// Encodes a `Heartbeat` frame
+    pub fn heartbeat(&self, id: u32, out: &mut Vec<u8>) {
+        let interval_ms = self.heartbeat_interval.unwrap_or_default().as_millis() as u16;
+        Message::Heartbeat { id, interval_ms }.encode_frame(&self.codec, self.session_id, self.codec.can_encrypt(), out);
+    }
+
+    /// Returns the interval to send echo requests at on each path, once negotiated
+    pub fn echo_interval(&self) -> Option<Duration> {
+        self.echo_interval.filter(|_| self.echo_negotiated.load(Ordering::Relaxed))
+    }
+
+    /// Encodes an `EchoRequest` frame carrying the path's last measured round-trip time
+    pub fn echo(&self, id: u32, rtt: Option<Duration>, out: &mut Vec<u8>) {
+        let rtt_us = rtt.map_or(0, |rtt| rtt.as_micros().clamp(1, u32::MAX as u128) as u32);
+        Message::EchoRequest { id, rtt_us }.encode_frame(&self.codec, self.session_id, self.codec.can_encrypt(), out);
+    }
+
+    /// Returns how long a path may stay idle before sending it a keepalive, once negotiated
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval.filter(|_| self.keepalive_negotiated.load(Ordering::Relaxed))
+    }
+
+    /// Encodes a `Keepalive` frame
+    pub fn keepalive(&self, out: &mut Vec<u8>) {
+        Message::Keepalive.encode_frame(&self.codec, self.session_id, self.codec.can_encrypt(), out);
+    }
+
+    /// Applies a control message received from the server
+    pub fn handle_control(&self, message: Message) {
+        match message {
+            Message::HelloAck { capabilities } => {
+                let granted = capabilities & self.requested;
+                let header = frame::Header {
+                    kind: Kind::Data,
+                    session_id: self.session_id.filter(|_| granted.contains(Capabilities::SESSION_ID)),
+                    checksum: granted.contains(Capabilities::CHECKSUM),
+                    encrypted: self.codec.can_encrypt(),
+                    padded: granted.contains(Capabilities::PADDING),
+                    fec: None,
+                    sequence: granted.contains(Capabilities::SEQUENCE).then_some(0),
+                    timestamp: granted.contains(Capabilities::TIMESTAMP).then_some(0),
+                };
+                self.fec_negotiated.store(granted.contains(Capabilities::FEC), Ordering::Relaxed);
+                self.heartbeat_negotiated.store(granted.contains(Capabilities::HEARTBEAT), Ordering::Relaxed);
+                self.echo_negotiated.store(granted.contains(Capabilities::ECHO), Ordering::Relaxed);
+                self.keepalive_negotiated.store(granted.contains(Capabilities::KEEPALIVE), Ordering::Relaxed);
+                if self.negotiated.write().unwrap().replace(header) != Some(header) {
+                    info!("Negotiated capabilities with server: {}", granted);
+                    if granted != self.requested {
+                        warn!("Server doesn't support all requested capabilities; requested: {}", self.requested);
+                    }
+                }
+            }
+            Message::DuplicationLimit { max_paths } => {
+                let limit = (max_paths > 0).then(|| (max_paths as usize, Instant::now()));
+                let previous = std::mem::replace(&mut *self.duplication_limit.write().unwrap(), limit)
+                    .filter(|(_, received_at)| received_at.elapsed() < DUPLICATION_LIMIT_TTL);
+                match (previous, limit) {
+                    (None, Some(_)) => warn!("Server uplink congested; duplicating on at most {} paths", max_paths),
+                    (Some(_), None) => info!("Server uplink recovered; duplicating on all paths"),
+                    _ => debug!("Duplication limit refreshed: {}", max_paths),
+                }
+            }
+            message => {
+                warn!("Ignoring unexpected control message from server: {:?}", message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the session ID and the capabilities the wrapper requests in its hello
+    fn hello(settings: &WrapperSettings) -> (Option<SessionId>, Capabilities) {
+        let mut out = Vec::new();
+        Wrapper::new(settings).hello(&mut out);
+        let (header, body) = Codec::default().decode(&mut out).unwrap();
+        let Ok(Message::Hello { capabilities }) = Message::decode(body) else {
+            panic!("not a hello");
+        };
+        (header.session_id, capabilities)
+    }
+
+    #[test]
+    fn requests_a_session_for_fec_and_sequence() {
+        let (session_id, capabilities) = hello(&WrapperSettings::default());
+        assert_eq!(session_id, None);
+        assert!(!capabilities.contains(Capabilities::SESSION_ID));
+
+        let (session_id, capabilities) = hello(&WrapperSettings { fec: Some(4), ..WrapperSettings::default() });
+        assert!(session_id.is_some());
+        assert!(capabilities.contains(Capabilities::SESSION_ID | Capabilities::FEC));
+
+        let (session_id, capabilities) = hello(&WrapperSettings { sequence: true, ..WrapperSettings::default() });
+        assert!(session_id.is_some());
+        assert!(capabilities.contains(Capabilities::SESSION_ID | Capabilities::SEQUENCE));
+    }
+}
@@ -0,0 +1,78 @@
+//! Single binary bundling the client and the server, e.g. for OpenWrt where storage is tight: `rengarde client` and
+//! `rengarde server` take the same arguments as the `client` and `server` binaries.
+
+use anyhow::Result;
+use clap::{CommandFactory, Parser};
+
+/// rengarde: bonds WireGuard traffic over every available interface
+#[derive(Debug, Parser)]
+#[command(name = "rengarde", version)]
+enum Cli {
+    /// Run the client, or another client command (see 'rengarde client --help')
+    Client(client::Cli),
+    /// Run the server, or another server command (see 'rengarde server --help')
+    Server(server::Cli),
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Cli::parse() {
+        // The shells complete the whole binary, so either subcommand prints the completions of both
+        Cli::Client(client::Cli { command: Some(client::Command::Completions { shell }), .. })
+        | Cli::Server(server::Cli { command: Some(server::Command::Completions { shell }), .. }) => {
+            shared::cli::print_completions(shell, Cli::command());
+            Ok(())
+        }
+        Cli::Client(cli) => client::run(cli).await,
+        Cli::Server(cli) => server::run(cli).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+    use clap_complete::Shell;
+
+    use super::*;
+
+    #[test]
+    fn defines_a_valid_command_line() {
+        Cli::command().debug_assert();
+    }
+
+    /// Returns the subcommands and long options of a command and of its subcommands
+    fn names(command: &clap::Command) -> Vec<String> {
+        let options = command.get_arguments().filter_map(|arg| arg.get_long()).map(|long| format!("--{}", long));
+        let subcommands = command.get_subcommands().flat_map(|subcommand| [subcommand.get_name().to_owned()].into_iter().chain(names(subcommand)));
+        options.chain(subcommands).collect()
+    }
+
+    #[test]
+    fn completes_every_command_and_option() {
+        let expected = names(&Cli::command());
+        for name in ["client", "server", "check-config", "list-interfaces", "completions", "--config", "--log-level", "--json"] {
+            assert!(expected.iter().any(|expected| expected == name), "{} isn't part of the command line", name);
+        }
+        for shell in Shell::value_variants() {
+            let script = shared::cli::completions(*shell, Cli::command(), "rengarde");
+            for name in &expected {
+                // fish names the long options without their dashes
+                let name = match (shell, name.strip_prefix("--")) {
+                    (Shell::Fish, Some(long)) => format!("-l {}", long),
+                    _ => name.clone(),
+                };
+                assert!(script.contains(&name), "{} completions lack {}", shell, name);
+            }
+        }
+    }
+
+    #[test]
+    fn completes_the_values_of_the_options() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = shared::cli::completions(shell, client::Cli::command(), "rengarde-client");
+            for value in ["debug", "json"] {
+                assert!(script.contains(value), "{} completions lack {}", shell, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info, warn};
+
+use crate::config::AutoBan;
+
+/// Most sources tracked at once; beyond it, new sources aren't tracked until old ones expire
+const MAX_TRACKED: usize = 4096;
+
+/// Invalid packets counted from a source since the start of its counting interval
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// Temporarily bans the sources that keep sending invalid packets (malformed, unauthenticated or
+/// raw where frames are required), so their traffic is dropped before being decoded
+#[derive(Debug)]
+pub struct BanList {
+    threshold: u32,
+    interval: Duration,
+    duration: Duration,
+    failures: HashMap<IpAddr, Failures>,
+    banned: HashMap<IpAddr, Instant>,
+}
+
+impl BanList {
+    pub fn new(auto_ban: &AutoBan) -> Self {
+        Self {
+            threshold: auto_ban.threshold.unwrap(),
+            interval: Duration::from_secs(auto_ban.interval.unwrap()),
+            duration: Duration::from_secs(auto_ban.ban_time.unwrap()),
+            failures: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    /// Returns whether a source is banned, lifting its ban once expired
+    pub fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.banned.get(&ip) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                info!("Ban of '{}' expired", ip);
+                self.banned.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Counts an invalid packet from a source, banning it once it sent too many within the interval
+    pub fn record_invalid(&mut self, ip: IpAddr, now: Instant) {
+        if self.failures.len() + self.banned.len() >= MAX_TRACKED {
+            self.failures.retain(|_, failures| now.duration_since(failures.since) < self.interval);
+            self.banned.retain(|_, until| *until > now);
+            if self.failures.len() + self.banned.len() >= MAX_TRACKED && !self.failures.contains_key(&ip) {
+                debug!("Too many sources tracked; not tracking '{}'", ip);
+                return;
+            }
+        }
+
+        let failures = self.failures.entry(ip).or_insert(Failures { count: 0, since: now });
+        if now.duration_since(failures.since) >= self.interval {
+            *failures = Failures { count: 0, since: now };
+        }
+        failures.count += 1;
+        if failures.count >= self.threshold {
+            warn!(
+                monotonic_counter.rengarde_bans_total = 1_u64,
+                "Banning '{}' for {:?} after {} invalid packets", ip, self.duration, failures.count
+            );
+            self.failures.remove(&ip);
+            self.banned.insert(ip, now + self.duration);
+        }
+    }
+}
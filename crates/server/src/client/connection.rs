@@ -1,42 +1,269 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use futures::FutureExt;
+use shared::control::{Capabilities, Message};
+use shared::datagram::DatagramSocket;
+use shared::dedup::DedupWindow;
+use shared::drops::{self, DropReason};
+use shared::fec::FecDecoder;
+use shared::frame::{self, Codec, Kind, SessionId};
 use tokio::net::UdpSocket;
-use tracing::{info, trace};
+use tokio::select;
+use tracing::{debug, info, trace, warn, Level};
 
-use crate::BUFFER_SIZE;
-use crate::client::types::{Client, Clients};
+use crate::client::{BanList, ClientKey, ClientManager};
+use crate::client::reorder::ReorderBuffer;
+use crate::congestion::CongestionMonitor;
+use crate::wireguard::{Destination, Upstreams};
 
 /// Handles receiving data from clients and forwarding it to the WireGuard interface
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip_all)]
 pub async fn receive_from_client(
-    clients: Clients,
+    client_manager: ClientManager,
     client_socket: Arc<UdpSocket>,
-    wireguard_socket: Arc<UdpSocket>,
-    wireguard_addr: &str,
+    upstreams: Upstreams,
+    destination: Destination,
+    codec: Codec,
+    congestion: Arc<CongestionMonitor>,
+    reorder_timeout: Option<Duration>,
+    dedup_window: Option<Duration>,
+    mut ban_list: Option<BanList>,
+    wrapper_only: bool,
+    buffer_size: usize,
 ) -> Result<()> {
-    let mut buf = [0; BUFFER_SIZE];
+    let mut buf = vec![0; buffer_size];
+    let mut dedup = dedup_window.map(DedupWindow::new);
+    let mut fec_decoders: HashMap<ClientKey, FecDecoder> = HashMap::new();
+    let mut reorder_buffers: HashMap<ClientKey, ReorderBuffer> = HashMap::new();
     loop {
-        let (received_bytes, src_addr) = client_socket.recv_from(&mut buf).await?;
+        // Wait for a datagram, or for a packet held for reordering to time out
+        let deadline = reorder_buffers.values().filter_map(ReorderBuffer::deadline).min();
+        let (received_bytes, src_addr) = select! {
+            result = client_socket.recv_from(&mut buf) => result?,
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                let now = Instant::now();
+                // The packets of clients gone since are dropped, rather than reopening their sockets
+                reorder_buffers.retain(|key, _| client_manager.is_connected(key));
+                for (key, reorder_buffer) in reorder_buffers.iter_mut() {
+                    reorder_buffer.expire(now);
+                    let session_id = match key {
+                        ClientKey::Session(session_id) => Some(*session_id),
+                        ClientKey::Addr(_) => None,
+                    };
+                    let wireguard_socket = match upstreams.socket(session_id) {
+                        Ok(wireguard_socket) => wireguard_socket,
+                        Err(err) => {
+                            warn!("Failed to open a WireGuard socket for session {:?}; dropping its reordered packets: {:?}", session_id, err);
+                            while reorder_buffer.pop_ready().is_some() {
+                                drops::record(DropReason::SendError);
+                            }
+                            continue;
+                        }
+                    };
+                    while let Some(payload) = reorder_buffer.pop_ready() {
+                        forward(&wireguard_socket, &destination, &congestion, &mut dedup, *key, &payload).await?;
+                    }
+                }
+                continue;
+            }
+        };
 
-        trace!(
+        shared::packet_event!(
+            Level::TRACE,
             received_bytes = received_bytes,
             src_addr = src_addr.to_string(),
             "Received {} bytes from client '{:?}'", received_bytes, src_addr
         );
 
+        // Drop the traffic of banned sources before spending any time on it
+        let now = Instant::now();
+        if ban_list.as_mut().is_some_and(|ban_list| ban_list.is_banned(src_addr.ip(), now)) {
+            trace!(monotonic_counter.rengarde_banned_packets_total = 1_u64, "Dropping datagram from banned '{:?}'", src_addr);
+            drops::record(DropReason::Banned);
+            continue;
+        }
+
+        // Unwrap framed traffic; anything else is raw engarde traffic
+        let datagram = &mut buf[..received_bytes];
+        let (header, payload) = if frame::is_frame(datagram) {
+            match codec.decode(datagram) {
+                Ok((header, payload)) => (Some(header), payload),
+                Err(err) => {
+                    debug!(
+                        monotonic_counter.rengarde_invalid_frames_total = 1_u64,
+                        reason = err.reason(),
+                        "Dropping invalid frame from '{:?}': {}", src_addr, err
+                    );
+                    drops::record(DropReason::Invalid);
+                    if let Some(ban_list) = &mut ban_list {
+                        ban_list.record_invalid(src_addr.ip(), now);
+                    }
+                    continue;
+                }
+            }
+        } else if codec.requires_auth() || wrapper_only {
+            debug!(
+                monotonic_counter.rengarde_invalid_frames_total = 1_u64,
+                reason = if codec.requires_auth() { "unauthenticated" } else { "raw" },
+                "Dropping raw datagram from '{:?}'", src_addr
+            );
+            drops::record(DropReason::Invalid);
+            if let Some(ban_list) = &mut ban_list {
+                ban_list.record_invalid(src_addr.ip(), now);
+            }
+            continue;
+        } else {
+            (None, &*datagram)
+        };
+        let session_id = header.and_then(|header| header.session_id);
+        let encrypted = header.is_some_and(|header| header.encrypted);
+        let key = ClientKey::new(src_addr, session_id);
+
         // Update client state
-        clients.entry(src_addr).and_modify(|client| {
-            client.update(received_bytes);
-        }).or_insert_with(|| {
-            info!("New client connected: '{:?}'", src_addr);
-            Client::new(src_addr)
+        if !client_manager.add_or_update_client(src_addr, header.as_ref(), received_bytes) {
+            continue;
+        }
+
+        // Control messages are consumed here and never reach WireGuard
+        if header.is_some_and(|header| header.kind == Kind::Control) {
+            if let Err(err) = handle_control(&client_manager, &client_socket, &codec, src_addr, session_id, encrypted, payload).await {
+                warn!("Failed to handle control message from '{:?}': {:?}", src_addr, err);
+            }
+            continue;
+        }
+
+        let wireguard_socket = match upstreams.socket(session_id) {
+            Ok(wireguard_socket) => wireguard_socket,
+            Err(err) => {
+                warn!("Failed to open a WireGuard socket for session {:?}; dropping traffic from '{:?}': {:?}", session_id, src_addr, err);
+                drops::record(DropReason::SendError);
+                continue;
+            }
+        };
+
+        // Rebuild lost frames from their FEC group; parity frames never reach WireGuard themselves
+        let fec = header.and_then(|header| header.fec);
+        let recovered = fec.map(|tag| {
+            if !fec_decoders.contains_key(&key) {
+                fec_decoders.retain(|key, _| client_manager.is_connected(key));
+            }
+            fec_decoders.entry(key).or_default().push(tag, payload)
         });
+        for recovered in recovered.iter().flatten() {
+            debug!(monotonic_counter.rengarde_fec_recovered_total = 1_u64, "Recovered a lost frame from '{:?}'", src_addr);
+            forward(&wireguard_socket, &destination, &congestion, &mut dedup, key, recovered).await?;
+        }
+        if fec.is_some_and(|tag| tag.is_parity()) {
+            continue;
+        }
+
+        // Restore the order of sequenced frames if configured; a client numbers its frames across all its paths, so
+        // without a session to group them, each path would hold its frames for the gaps the others fill
+        let sequence = header.and_then(|header| header.sequence).filter(|_| session_id.is_some());
+        let (Some(reorder_timeout), Some(sequence)) = (reorder_timeout, sequence) else {
+            forward(&wireguard_socket, &destination, &congestion, &mut dedup, key, payload).await?;
+            debug!(histogram.rengarde_forwarding_latency_seconds = now.elapsed().as_secs_f64(), direction = "upstream");
+            continue;
+        };
+        if !reorder_buffers.contains_key(&key) {
+            reorder_buffers.retain(|key, _| client_manager.is_connected(key));
+        }
+        let reorder_buffer = reorder_buffers.entry(key).or_insert_with(|| ReorderBuffer::new(reorder_timeout));
+        // Packets held for reordering wait on purpose, so only the ones forwarded right away are timed
+        if reorder_buffer.admit(sequence, payload) {
+            forward(&wireguard_socket, &destination, &congestion, &mut dedup, key, payload).await?;
+            debug!(histogram.rengarde_forwarding_latency_seconds = now.elapsed().as_secs_f64(), direction = "upstream");
+        }
+        while let Some(payload) = reorder_buffer.pop_ready() {
+            forward(&wireguard_socket, &destination, &congestion, &mut dedup, key, &payload).await?;
+        }
+    }
+}
 
-        // Forward to WireGuard
-        wireguard_socket.send_to(&buf[..received_bytes], wireguard_addr).await?;
-        trace!(
-            "\tSent {} bytes to wireguard on '{:?}'", received_bytes, wireguard_addr
+/// Forwards a payload of a client to WireGuard unless it's a duplicate; a socket that isn't immediately
+/// writable has a full send buffer
+///
+/// Send errors (e.g. WireGuard's address became unreachable) only drop the payload, leaving WireGuard's
+/// liveness detection to recover.
+async fn forward(
+    wireguard_socket: &DatagramSocket,
+    destination: &Destination,
+    congestion: &CongestionMonitor,
+    dedup: &mut Option<DedupWindow>,
+    key: ClientKey,
+    payload: &[u8],
+) -> Result<()> {
+    if dedup.as_mut().is_some_and(|dedup| dedup.is_duplicate(payload)) {
+        debug!(monotonic_counter.rengarde_duplicates_dropped_total = 1_u64, client = key.to_string());
+        drops::record(DropReason::Duplicate);
+        return Ok(());
+    }
+    congestion.record_send(wireguard_socket.writable().now_or_never().is_none());
+    let wireguard_addr = destination.addr();
+    destination.record_sent();
+    if let Err(err) = wireguard_socket.send_to(payload, &wireguard_addr).await {
+        debug!(
+            monotonic_counter.rengarde_wireguard_send_errors_total = 1_u64,
+            client = key.to_string(),
+            "Error writing to wireguard on '{}': {}", wireguard_addr, err
         );
+        drops::record(DropReason::SendError);
+        return Ok(());
+    }
+    debug!(monotonic_counter.rengarde_client_forwarded_bytes = payload.len() as u64, client = key.to_string());
+    shared::packet_event!(Level::TRACE, "\tSent {} bytes to wireguard on '{}'", payload.len(), wireguard_addr);
+    Ok(())
+}
+
+/// Answers a control message received from a client
+async fn handle_control(
+    client_manager: &ClientManager,
+    client_socket: &UdpSocket,
+    codec: &Codec,
+    src_addr: SocketAddr,
+    session_id: Option<SessionId>,
+    encrypted: bool,
+    body: &[u8],
+) -> Result<()> {
+    match Message::decode(body)? {
+        Message::Hello { capabilities } => {
+            let granted = capabilities & Capabilities::supported();
+            info!("Client '{:?}' negotiated capabilities: {}", src_addr, granted);
+            client_manager.set_capabilities(src_addr, granted);
+
+            let mut buf = Vec::new();
+            Message::HelloAck { capabilities: granted }.encode_frame(codec, session_id, encrypted, &mut buf);
+            client_socket.send_to(&buf, src_addr).await?;
+        }
+        Message::Heartbeat { id, interval_ms } => {
+            trace!("Heartbeat {} from '{:?}'", id, src_addr);
+            client_manager.record_heartbeat(src_addr, Duration::from_millis(interval_ms as u64));
+
+            let mut buf = Vec::new();
+            Message::HeartbeatAck { id }.encode_frame(codec, session_id, encrypted, &mut buf);
+            client_socket.send_to(&buf, src_addr).await?;
+        }
+        Message::EchoRequest { id, rtt_us } => {
+            trace!("Echo request {} from '{:?}'", id, src_addr);
+            if rtt_us > 0 {
+                client_manager.record_rtt(src_addr, Duration::from_micros(rtt_us as u64));
+            }
+
+            let mut buf = Vec::new();
+            Message::EchoReply { id }.encode_frame(codec, session_id, encrypted, &mut buf);
+            client_socket.send_to(&buf, src_addr).await?;
+        }
+        Message::Keepalive => {
+            trace!("Keepalive from '{:?}'", src_addr);
+        }
+        message => {
+            debug!("Ignoring unexpected control message from '{:?}': {:?}", src_addr, message);
+        }
     }
-} 
\ No newline at end of file
+    Ok(())
+}
@@ -1,26 +1,102 @@
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
+use anyhow::Result;
 use dashmap::DashMap;
-use tracing::{info, warn};
+use shared::control::{Capabilities, PathReport};
+use shared::drops::{self, DropReason};
+use shared::frame::Header;
+use shared::profile::MemoryProfile;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
 
-use crate::client::types::{Client, Clients};
+use crate::client::types::{Client, ClientEvent, ClientKey, Clients, Session, Sessions};
+use crate::drain::Drain;
+use crate::state::{Annotation, State, StateFile};
+
+/// Minimum interval between the warnings about refused clients
+const REFUSED_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Client timeout and limit, which a configuration reload may change
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    timeout: Duration,
+    max_clients: Option<usize>,
+}
 
 /// Manages client connections and their lifecycle
 #[derive(Clone)]
 pub struct ClientManager {
     clients: Clients,
-    timeout: Duration,
+    sessions: Sessions,
+    annotations: Arc<DashMap<ClientKey, Annotation>>,
+    state_file: Option<StateFile>,
+    limits: Arc<RwLock<Limits>>,
+    profile: MemoryProfile,
+    /// Packets refused from new clients since the last warning, and when it was logged
+    refused: Arc<Mutex<(u64, Option<Instant>)>>,
+    drain: Drain,
+    events: mpsc::Sender<ClientEvent>,
 }
 
 impl ClientManager {
-    /// Creates a new client manager with the specified timeout
-    pub fn new(timeout_seconds: u64) -> Self {
-        Self {
-            clients: Arc::new(DashMap::new()),
-            timeout: Duration::from_secs(timeout_seconds),
-        }
+    /// Creates a new client manager with the specified timeout and client limit (the memory profile's
+    /// if not set), restoring annotations from the state file; new clients are refused while `drain`
+    /// is on
+    ///
+    /// The returned receiver must be handed to [`Self::process_events`].
+    pub fn new(
+        timeout_seconds: u64,
+        max_clients: Option<usize>,
+        state_file: Option<StateFile>,
+        profile: MemoryProfile,
+        drain: Drain,
+    ) -> Result<(Self, mpsc::Receiver<ClientEvent>)> {
+        let state = match &state_file {
+            Some(state_file) => state_file.load()?,
+            None => State::default(),
+        };
+        let (events, receiver) = mpsc::channel(profile.channel_capacity());
+        // Report no clients rather than no data until the first one connects
+        debug!(counter.rengarde_clients_active = 0_i64, counter.rengarde_sessions_active = 0_i64);
+
+        let client_manager = Self {
+            clients: Arc::new(profile.new_map()),
+            sessions: Arc::new(profile.new_map()),
+            annotations: Arc::new(state.annotations.into_iter().collect()),
+            state_file,
+            limits: Arc::new(RwLock::new(Limits {
+                timeout: Duration::from_secs(timeout_seconds),
+                max_clients: max_clients.or(profile.max_entries()),
+            })),
+            profile,
+            refused: Arc::new(Mutex::new((0, None))),
+            drain,
+            events,
+        };
+        Ok((client_manager, receiver))
+    }
+
+    /// Creates the client manager of another tunnel, with its own clients and limits but the annotations, state file
+    /// and drain switch of this one, so the clients of every tunnel are labelled and drained alike
+    pub fn for_tunnel(&self, timeout_seconds: u64, max_clients: Option<usize>) -> (Self, mpsc::Receiver<ClientEvent>) {
+        let (events, receiver) = mpsc::channel(self.profile.channel_capacity());
+        let client_manager = Self {
+            clients: Arc::new(self.profile.new_map()),
+            sessions: Arc::new(self.profile.new_map()),
+            annotations: self.annotations.clone(),
+            state_file: self.state_file.clone(),
+            limits: Arc::new(RwLock::new(Limits {
+                timeout: Duration::from_secs(timeout_seconds),
+                max_clients: max_clients.or(self.profile.max_entries()),
+            })),
+            profile: self.profile,
+            refused: Arc::new(Mutex::new((0, None))),
+            drain: self.drain.clone(),
+            events,
+        };
+        (client_manager, receiver)
     }
 
     /// Returns a reference to the clients collection
@@ -28,39 +104,357 @@ impl ClientManager {
         self.clients.clone()
     }
 
-    /// Adds or updates a client with the given address
-    pub fn add_or_update_client(&self, addr: SocketAddr, bytes_received: usize) {
-        self.clients.entry(addr).and_modify(|client| {
-            client.update(bytes_received);
-        }).or_insert_with(|| {
-            info!("New client connected: '{:?}'", addr);
-            Client::new(addr)
+    /// Returns a reference to the sessions collection
+    pub fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+
+    /// Replaces the client timeout and limit (the memory profile's if not set), e.g. after a
+    /// configuration reload; clients beyond a lowered limit stay until they time out
+    pub fn set_limits(&self, timeout_seconds: u64, max_clients: Option<usize>) {
+        *self.limits.write().unwrap() = Limits {
+            timeout: Duration::from_secs(timeout_seconds),
+            max_clients: max_clients.or(self.profile.max_entries()),
+        };
+    }
+
+    /// Returns the drain mode switch
+    pub fn drain(&self) -> &Drain {
+        &self.drain
+    }
+
+    /// Adds or updates a client with the given address, grouping it into its session if tagged
+    ///
+    /// This is on the hot path: anything but the map updates is deferred to [`Self::process_events`].
+    /// Returns `false` if the client is new but the client limit has been reached, or the server is
+    /// draining; new addresses of a current session are still accepted while draining.
+    pub fn add_or_update_client(&self, addr: SocketAddr, header: Option<&Header>, bytes_received: usize) -> bool {
+        let session_id = header.and_then(|header| header.session_id);
+        let encrypted = header.is_some_and(|header| header.encrypted);
+        if self.drain.is_draining()
+            && !self.clients.contains_key(&addr)
+            && session_id.is_none_or(|session_id| !self.sessions.contains_key(&session_id))
+        {
+            debug!(monotonic_counter.rengarde_clients_refused_total = 1_u64, "Draining; refusing client '{:?}'", addr);
+            drops::record(DropReason::Refused);
+            return false;
+        }
+        let max_clients = self.limits.read().unwrap().max_clients;
+        if let Some(max_clients) = max_clients.filter(|max| !self.clients.contains_key(&addr) && self.clients.len() >= *max) {
+            debug!(monotonic_counter.rengarde_clients_refused_total = 1_u64, "Client limit reached; refusing client '{:?}'", addr);
+            drops::record(DropReason::Refused);
+            self.warn_refused(max_clients);
+            return false;
+        }
+
+        if let Some(session_id) = session_id {
+            self.sessions.entry(session_id).and_modify(|session| {
+                session.update(addr, bytes_received);
+            }).or_insert_with(|| {
+                self.notify(ClientEvent::SessionStarted(session_id));
+                Session::new(session_id, addr)
+            });
+        }
+
+        let mut client = self.clients.entry(addr).or_insert_with(|| {
+            self.notify(ClientEvent::Connected { addr, session_id });
+            Client::new(addr, session_id, None)
         });
+        client.update(bytes_received);
+        let lost = client.path.record(header.and_then(|header| header.sequence), bytes_received);
+        if let Some(building_up) = header.and_then(|header| header.timestamp).and_then(|timestamp| client.delay.record(timestamp)) {
+            self.notify(ClientEvent::QueueingDelayChanged { addr, building_up });
+        }
+        if client.session_id != session_id {
+            client.session_id = session_id;
+            self.notify(ClientEvent::SessionChanged { addr, session_id });
+        }
+        if client.encrypted != encrypted {
+            client.encrypted = encrypted;
+            self.notify(ClientEvent::EncryptionChanged { addr, encrypted });
+        }
+
+        debug!(
+            monotonic_counter.rengarde_client_received_bytes = bytes_received as u64,
+            client = client.client_key().to_string(),
+            label = client.label.as_deref().unwrap_or_default(),
+        );
+        if lost > 0 {
+            debug!(
+                monotonic_counter.rengarde_client_lost_packets_total = lost as u64,
+                client = client.client_key().to_string(),
+                label = client.label.as_deref().unwrap_or_default(),
+            );
+        }
+        true
+    }
+
+    /// Counts a packet refused from a new client, warning about the refused packets at most every
+    /// [`REFUSED_WARNING_INTERVAL`]
+    fn warn_refused(&self, max_clients: usize) {
+        let mut refused = self.refused.lock().unwrap();
+        let (count, warned_at) = &mut *refused;
+        *count += 1;
+        if warned_at.is_some_and(|warned_at| warned_at.elapsed() < REFUSED_WARNING_INTERVAL) {
+            return;
+        }
+        warn!("Client limit of {} reached; refused {} packets from new clients", max_clients, count);
+        *refused = (0, Some(Instant::now()));
+    }
+
+    /// Queues an event for [`Self::process_events`] without waiting
+    fn notify(&self, event: ClientEvent) {
+        if let Err(err) = self.events.try_send(event) {
+            warn!("Dropping client event: {}", err);
+        }
     }
 
-    /// Removes a client by address
-    pub fn remove_client(&self, addr: SocketAddr) {
-        self.clients.remove(&addr);
+    /// Handles the bookkeeping deferred by [`Self::add_or_update_client`]: logging, labels and metrics
+    pub async fn process_events(&self, mut events: mpsc::Receiver<ClientEvent>) {
+        while let Some(event) = events.recv().await {
+            match event {
+                ClientEvent::Connected { addr, session_id } => {
+                    let label = self.label(&ClientKey::new(addr, session_id));
+                    info!("New client connected: '{:?}' ({})", addr, label.as_deref().unwrap_or("unlabeled"));
+                    debug!(monotonic_counter.rengarde_clients_connected_total = 1_u64, counter.rengarde_clients_active = 1_i64);
+                    if let Some(mut client) = self.clients.get_mut(&addr) {
+                        client.label = label;
+                    }
+                }
+                ClientEvent::SessionStarted(session_id) => {
+                    info!("New session started: '{}'", session_id);
+                    debug!(monotonic_counter.rengarde_sessions_started_total = 1_u64, counter.rengarde_sessions_active = 1_i64);
+                }
+                ClientEvent::SessionChanged { addr, session_id } => {
+                    info!("Client '{:?}' moved to session {:?}", addr, session_id);
+                    if let Some(mut client) = self.clients.get_mut(&addr) {
+                        client.label = self.label(&client.client_key());
+                    }
+                }
+                ClientEvent::EncryptionChanged { addr, encrypted } => {
+                    info!("Client '{:?}' {} encryption", addr, if encrypted { "enabled" } else { "disabled" });
+                }
+                ClientEvent::QueueingDelayChanged { addr, building_up: true } => {
+                    warn!("Queueing delay from client '{:?}' is building up; its link may be bufferbloated", addr);
+                    debug!(monotonic_counter.rengarde_client_queueing_delay_warnings_total = 1_u64);
+                }
+                ClientEvent::QueueingDelayChanged { addr, building_up: false } => {
+                    info!("Queueing delay from client '{:?}' is back to normal", addr);
+                }
+            }
+        }
+    }
+
+    /// Records the wrapper capabilities negotiated by a client
+    pub fn set_capabilities(&self, addr: SocketAddr, capabilities: Capabilities) {
+        if let Some(mut client) = self.clients.get_mut(&addr) {
+            client.capabilities = capabilities;
+        }
+    }
+
+    /// Records a heartbeat received from a client, sent every `interval`
+    pub fn record_heartbeat(&self, addr: SocketAddr, interval: Duration) {
+        if let Some(mut client) = self.clients.get_mut(&addr) {
+            client.heartbeat = Some((Instant::now(), interval));
+        }
+    }
+
+    /// Records the round-trip time a client measured on this address
+    pub fn record_rtt(&self, addr: SocketAddr, rtt: Duration) {
+        if let Some(mut client) = self.clients.get_mut(&addr) {
+            debug!(
+                histogram.rengarde_client_rtt_seconds = rtt.as_secs_f64(),
+                client = client.client_key().to_string(),
+                label = client.label.as_deref().unwrap_or_default(),
+            );
+            client.rtt = Some(rtt);
+        }
+    }
+
+    /// Returns the addresses of the clients that negotiated the given capability, and whether they
+    /// encrypt their traffic
+    pub fn clients_with(&self, capability: Capabilities) -> Vec<(SocketAddr, bool)> {
+        self.clients
+            .iter()
+            .filter(|client| client.capabilities.contains(capability))
+            .map(|client| (client.addr, client.encrypted))
+            .collect()
+    }
+
+    /// Closes the report interval of every client, returning the traffic received since the previous
+    /// call from each client that asked for path reports, with its address and whether it encrypts
+    /// its traffic
+    pub fn take_path_reports(&self) -> Vec<(SocketAddr, bool, PathReport)> {
+        let mut reports = Vec::new();
+        for mut client in self.clients.iter_mut() {
+            let sequenced = client.path.is_sequenced();
+            let report = client.path.take_report();
+            if sequenced {
+                debug!(
+                    histogram.rengarde_client_loss_permille = report.loss_permille as u64,
+                    client = client.client_key().to_string(),
+                    label = client.label.as_deref().unwrap_or_default(),
+                );
+            }
+            debug!(
+                histogram.rengarde_client_received_bytes_per_second = client.bytes_per_second(),
+                client = client.client_key().to_string(),
+                label = client.label.as_deref().unwrap_or_default(),
+            );
+            if let Some(queueing_delay) = client.delay.queueing_delay() {
+                debug!(
+                    histogram.rengarde_client_queueing_delay_seconds = queueing_delay.as_secs_f64(),
+                    client = client.client_key().to_string(),
+                    label = client.label.as_deref().unwrap_or_default(),
+                );
+            }
+            client.last_report = Some(report);
+            if client.capabilities.contains(Capabilities::PATH_REPORT) {
+                reports.push((client.addr, client.encrypted, report));
+            }
+        }
+        reports
+    }
+
+    /// Removes a client by address, returning it unless it was gone already
+    pub fn remove_client(&self, addr: SocketAddr) -> Option<Client> {
+        let (_, client) = self.clients.remove(&addr)?;
+        debug!(counter.rengarde_clients_active = -1_i64);
         info!("Client removed: '{:?}'", addr);
+        Some(client)
+    }
+
+    /// Removes a client that stopped sending for longer than the timeout
+    pub fn time_out_client(&self, addr: SocketAddr) {
+        if let Some(client) = self.remove_client(addr) {
+            warn!(
+                monotonic_counter.rengarde_client_timeouts_total = 1_u64,
+                client = client.client_key().to_string(),
+                label = client.label.as_deref().unwrap_or_default(),
+                "Client '{:?}' timed out", addr
+            );
+        }
     }
 
-    /// Checks for and removes timed-out clients
+    /// Checks for and removes timed-out clients and sessions
     pub fn cleanup_timeout_clients(&self) {
         let now = Instant::now();
+        let timeout = self.limits.read().unwrap().timeout;
         let timeout_clients: Vec<SocketAddr> = self.clients
             .iter()
-            .filter(|client| now.duration_since(client.last_received_at) > self.timeout)
+            .filter(|client| now.duration_since(client.last_received_at) > timeout)
             .map(|client| client.addr)
             .collect();
 
         for addr in timeout_clients {
-            warn!("Client '{:?}' timed out", addr);
+            self.time_out_client(addr);
+        }
+
+        // Heartbeats tell dead paths apart from idle ones well before the timeout
+        let dead_paths: Vec<SocketAddr> = self.clients
+            .iter()
+            .filter(|client| client.missed_heartbeats(now))
+            .map(|client| client.addr)
+            .collect();
+
+        for addr in dead_paths {
+            warn!("Client '{:?}' missed its heartbeats; path is dead", addr);
             self.remove_client(addr);
         }
+
+        self.sessions.retain(|id, session| {
+            let alive = now.duration_since(session.last_received_at) <= timeout;
+            if !alive {
+                warn!(counter.rengarde_sessions_active = -1_i64, "Session '{}' timed out", id);
+            }
+            alive
+        });
+        debug!("{} clients in {} sessions connected", self.client_count(), self.sessions.len());
+    }
+
+    /// Returns whether the client (address or session) is still connected
+    pub fn is_connected(&self, key: &ClientKey) -> bool {
+        match key {
+            ClientKey::Session(session_id) => self.sessions.contains_key(session_id),
+            ClientKey::Addr(addr) => self.clients.contains_key(addr),
+        }
     }
 
     /// Gets the number of connected clients
     pub fn client_count(&self) -> usize {
         self.clients.len()
     }
-} 
\ No newline at end of file
+
+    /// Returns the annotation attached to the given client, if any
+    pub fn annotation(&self, key: &ClientKey) -> Option<Annotation> {
+        self.annotations.get(key).map(|annotation| annotation.clone())
+    }
+
+    /// Attaches (or with `None`, removes) an annotation and persists it to the state file
+    pub fn annotate(&self, key: ClientKey, annotation: Option<Annotation>) -> Result<()> {
+        let label = annotation.as_ref().and_then(|annotation| annotation.label.clone());
+        match annotation {
+            Some(annotation) => self.annotations.insert(key, annotation),
+            None => self.annotations.remove(&key).map(|(_, annotation)| annotation),
+        };
+        self.clients
+            .iter_mut()
+            .filter(|client| client.client_key() == key)
+            .for_each(|mut client| client.label = label.clone());
+        info!("Annotation of client '{}' updated", key);
+
+        self.save_state()
+    }
+
+    fn label(&self, key: &ClientKey) -> Option<String> {
+        self.annotations.get(key).and_then(|annotation| annotation.label.clone())
+    }
+
+    fn save_state(&self) -> Result<()> {
+        let Some(state_file) = &self.state_file else {
+            return Ok(());
+        };
+        let state = State {
+            annotations: self.annotations
+                .iter()
+                .map(|annotation| (*annotation.key(), annotation.value().clone()))
+                .collect(),
+        };
+        state_file.save(&state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_the_annotations_and_drain_with_the_other_tunnels() {
+        let (main, _events) = ClientManager::new(30, None, None, MemoryProfile::new(false), Drain::new()).unwrap();
+        let (tunnel, _tunnel_events) = main.for_tunnel(30, None);
+        let addr = "192.0.2.1:40000".parse().unwrap();
+
+        assert!(tunnel.add_or_update_client(addr, None, 100));
+        assert_eq!(tunnel.client_count(), 1);
+        assert_eq!(main.client_count(), 0);
+
+        let annotation = Annotation { label: Some("site-a".to_owned()), notes: None };
+        tunnel.annotate(ClientKey::Addr(addr), Some(annotation.clone())).unwrap();
+        assert_eq!(main.annotation(&ClientKey::Addr(addr)), Some(annotation));
+        assert_eq!(tunnel.clients().get(&addr).unwrap().label.as_deref(), Some("site-a"));
+
+        main.drain().set(true);
+        assert!(!tunnel.add_or_update_client("192.0.2.2:40000".parse().unwrap(), None, 100));
+    }
+
+    #[test]
+    fn removes_a_client_once() {
+        let (client_manager, _events) = ClientManager::new(30, None, None, MemoryProfile::new(false), Drain::new()).unwrap();
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        assert!(client_manager.add_or_update_client(addr, None, 100));
+
+        assert_eq!(client_manager.remove_client(addr).map(|client| client.addr), Some(addr));
+        assert!(client_manager.remove_client(addr).is_none());
+        assert_eq!(client_manager.client_count(), 0);
+    }
+}
@@ -1,7 +1,10 @@
+mod ban;
 mod connection;
 mod manager;
+mod reorder;
 mod types;
 
+pub use ban::BanList;
 pub use connection::receive_from_client;
 pub use manager::ClientManager;
-pub use types::Clients; 
\ No newline at end of file
+pub use types::{Client, ClientEvent, ClientKey, Sessions}; 
\ No newline at end of file
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use shared::drops::{self, DropReason};
+use tracing::debug;
+
+/// Most packets held at once; beyond it, the oldest gap is given up on
+const MAX_HELD: usize = 128;
+
+/// Number of sequence numbers before the next expected one remembered to drop duplicates
+const HISTORY: u32 = 64;
+
+/// Restores the order of a client's sequenced packets, holding early ones until the gap before them
+/// fills or times out, and dropping duplicates received on several paths
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    timeout: Duration,
+    /// Next sequence number to deliver, unknown until the first packet
+    next: Option<u32>,
+    /// Bitmap of the delivered sequence numbers preceding `next`, most recent in the lowest bit
+    delivered: u64,
+    held: HashMap<u32, (Instant, Vec<u8>)>,
+}
+
+impl ReorderBuffer {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next: None,
+            delivered: 0,
+            held: HashMap::new(),
+        }
+    }
+
+    /// Admits a received packet, returning whether it must be delivered right away
+    ///
+    /// Early packets are copied and held; call [`Self::pop_ready`] afterwards to deliver the held
+    /// packets the admitted one made consecutive.
+    pub fn admit(&mut self, sequence: u32, payload: &[u8]) -> bool {
+        let Some(next) = self.next else {
+            self.next = Some(sequence.wrapping_add(1));
+            self.delivered = 1;
+            return true;
+        };
+
+        let ahead = sequence.wrapping_sub(next) as i32;
+        if ahead == 0 {
+            self.advance();
+            return true;
+        }
+
+        if ahead < 0 {
+            let behind = ahead.unsigned_abs();
+            if behind > MAX_HELD as u32 + HISTORY {
+                // Most likely the client restarted its sequence
+                debug!("Sequence jumped back from {} to {}; resetting", next, sequence);
+                self.held.clear();
+                self.next = Some(sequence.wrapping_add(1));
+                self.delivered = 1;
+                return true;
+            }
+            if behind <= HISTORY {
+                let bit = 1 << (behind - 1);
+                if self.delivered & bit != 0 {
+                    drops::record(DropReason::Duplicate);
+                    return false;
+                }
+                self.delivered |= bit;
+            }
+            // Too late to be reordered, but WireGuard may still accept it
+            return true;
+        }
+
+        if self.held.contains_key(&sequence) {
+            drops::record(DropReason::Duplicate);
+            return false;
+        }
+        self.held.insert(sequence, (Instant::now(), payload.to_vec()));
+        debug!(monotonic_counter.rengarde_reordered_packets_total = 1_u64);
+        if ahead as usize >= MAX_HELD || self.held.len() >= MAX_HELD {
+            self.skip_gap();
+        }
+        false
+    }
+
+    /// Returns the next held packet if it is now consecutive
+    pub fn pop_ready(&mut self) -> Option<Vec<u8>> {
+        let (_, payload) = self.held.remove(&self.next?)?;
+        self.advance();
+        Some(payload)
+    }
+
+    /// Gives up on the gap before the held packets once the oldest has been held for too long
+    ///
+    /// Call [`Self::pop_ready`] afterwards to deliver the packets after the gap.
+    pub fn expire(&mut self, now: Instant) {
+        if self.deadline().is_some_and(|deadline| deadline <= now) {
+            self.skip_gap();
+        }
+    }
+
+    /// Returns when the oldest held packet times out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held.values().map(|(held_at, _)| *held_at + self.timeout).min()
+    }
+
+    /// Moves past the missing packets up to the earliest held one
+    fn skip_gap(&mut self) {
+        let Some(next) = self.next else {
+            return;
+        };
+        let Some(earliest) = self.held.keys().min_by_key(|sequence| sequence.wrapping_sub(next)).copied() else {
+            return;
+        };
+        let gap = earliest.wrapping_sub(next);
+        debug!(monotonic_counter.rengarde_reorder_lost_packets_total = gap as u64, "Skipping {} missing packets", gap);
+        self.delivered = self.delivered.checked_shl(gap).unwrap_or(0);
+        self.next = Some(earliest);
+    }
+
+    fn advance(&mut self) {
+        self.delivered = (self.delivered << 1) | 1;
+        self.next = self.next.map(|next| next.wrapping_add(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    /// Admits the packet numbered `sequence`, returning the packets delivered in order
+    fn receive(buffer: &mut ReorderBuffer, sequence: u32) -> Vec<u32> {
+        let payload = sequence.to_be_bytes();
+        let mut delivered = Vec::new();
+        if buffer.admit(sequence, &payload) {
+            delivered.push(sequence);
+        }
+        delivered.extend(std::iter::from_fn(|| buffer.pop_ready()).map(|payload| u32::from_be_bytes(payload.try_into().unwrap())));
+        delivered
+    }
+
+    #[test]
+    fn delivers_packets_in_order_right_away() {
+        let mut buffer = ReorderBuffer::new(TIMEOUT);
+        for sequence in 10..20 {
+            assert_eq!(receive(&mut buffer, sequence), [sequence]);
+        }
+        assert_eq!(buffer.deadline(), None);
+    }
+
+    #[test]
+    fn holds_early_packets_until_the_gap_fills() {
+        let mut buffer = ReorderBuffer::new(TIMEOUT);
+        assert_eq!(receive(&mut buffer, 1), [1]);
+        assert!(receive(&mut buffer, 4).is_empty());
+        assert!(receive(&mut buffer, 3).is_empty());
+        assert!(buffer.deadline().is_some());
+        assert_eq!(receive(&mut buffer, 2), [2, 3, 4]);
+        assert_eq!(buffer.deadline(), None);
+        assert_eq!(receive(&mut buffer, 5), [5]);
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let mut buffer = ReorderBuffer::new(TIMEOUT);
+        assert_eq!(receive(&mut buffer, 1), [1]);
+        assert!(receive(&mut buffer, 1).is_empty());
+        // Held and delivered copies alike
+        assert!(receive(&mut buffer, 3).is_empty());
+        assert!(receive(&mut buffer, 3).is_empty());
+        assert_eq!(receive(&mut buffer, 2), [2, 3]);
+        assert!(receive(&mut buffer, 2).is_empty());
+        assert!(receive(&mut buffer, 3).is_empty());
+    }
+
+    #[test]
+    fn skips_the_gap_once_it_times_out() {
+        let mut buffer = ReorderBuffer::new(TIMEOUT);
+        assert_eq!(receive(&mut buffer, 1), [1]);
+        assert!(receive(&mut buffer, 3).is_empty());
+        assert!(receive(&mut buffer, 4).is_empty());
+
+        let deadline = buffer.deadline().unwrap();
+        buffer.expire(deadline - Duration::from_millis(1));
+        assert_eq!(buffer.pop_ready(), None);
+        buffer.expire(deadline);
+        assert_eq!(std::iter::from_fn(|| buffer.pop_ready()).count(), 2);
+        assert_eq!(receive(&mut buffer, 5), [5]);
+        // The skipped packet is still forwarded if it arrives late, once
+        assert_eq!(receive(&mut buffer, 2), [2]);
+        assert!(receive(&mut buffer, 2).is_empty());
+    }
+
+    #[test]
+    fn skips_the_gap_when_holding_too_many_packets() {
+        let mut buffer = ReorderBuffer::new(TIMEOUT);
+        assert_eq!(receive(&mut buffer, 0), [0]);
+        let ahead = MAX_HELD as u32 + 1;
+        assert_eq!(receive(&mut buffer, ahead), [ahead]);
+        assert_eq!(receive(&mut buffer, ahead + 1), [ahead + 1]);
+    }
+
+    #[test]
+    fn follows_the_sequence_across_wrapping_and_restarts() {
+        let mut buffer = ReorderBuffer::new(TIMEOUT);
+        assert_eq!(receive(&mut buffer, u32::MAX - 1), [u32::MAX - 1]);
+        assert!(receive(&mut buffer, 0).is_empty());
+        assert_eq!(receive(&mut buffer, u32::MAX), [u32::MAX, 0]);
+
+        // A client restarting numbers its packets from a sequence far behind
+        let mut buffer = ReorderBuffer::new(TIMEOUT);
+        assert_eq!(receive(&mut buffer, 100_000), [100_000]);
+        assert_eq!(receive(&mut buffer, 0), [0]);
+        assert_eq!(receive(&mut buffer, 1), [1]);
+    }
+}
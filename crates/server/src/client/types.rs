@@ -1,36 +1,241 @@
+use std::fmt;
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use anyhow::anyhow;
 use dashmap::DashMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use shared::control::{self, Capabilities, PathReport};
+use shared::frame::SessionId;
+use shared::path::{DelayTrend, PathStats};
 
 /// Represents a connected client with its state and statistics
 #[derive(Debug)]
 pub struct Client {
     /// The client's socket address
     pub addr: SocketAddr,
+    /// The session this address belongs to, if the client tags its traffic
+    pub session_id: Option<SessionId>,
+    /// Timestamp of the first received packet
+    pub first_seen_at: Instant,
     /// Timestamp of the last received packet
     pub last_received_at: Instant,
     /// Total number of bytes received from this client
     pub total_received_bytes: usize,
+    /// Total number of packets received from this client
+    pub total_received_packets: usize,
+    /// Traffic received from this client over the last seconds
+    pub rate: RateWindow,
+    /// Operator-provided label, attached to the client's metrics
+    pub label: Option<String>,
+    /// Wrapper capabilities negotiated on this address
+    pub capabilities: Capabilities,
+    /// Whether the client encrypts its traffic, and expects encrypted traffic back
+    pub encrypted: bool,
+    /// Last heartbeat received on this address, and the interval the client sends them at
+    pub heartbeat: Option<(Instant, Duration)>,
+    /// Traffic received on this address since the last path report
+    pub path: PathStats,
+    /// Traffic received on this address during the last complete report interval
+    pub last_report: Option<PathReport>,
+    /// Queueing delay of this path, followed from the timestamps of the client's frames
+    pub delay: DelayTrend,
+    /// Round-trip time of this path, as last measured by the client
+    pub rtt: Option<Duration>,
 }
 
 impl Client {
     /// Creates a new client with the given address and current timestamp
-    pub fn new(addr: SocketAddr) -> Self {
+    pub fn new(addr: SocketAddr, session_id: Option<SessionId>, label: Option<String>) -> Self {
+        let now = Instant::now();
         Self {
             addr,
-            last_received_at: Instant::now(),
+            session_id,
+            first_seen_at: now,
+            last_received_at: now,
             total_received_bytes: 0,
+            total_received_packets: 0,
+            rate: RateWindow::new(now),
+            label,
+            capabilities: Capabilities::empty(),
+            encrypted: false,
+            heartbeat: None,
+            path: PathStats::new(),
+            last_report: None,
+            delay: DelayTrend::new(),
+            rtt: None,
         }
     }
 
-    /// Updates the client's last received timestamp and adds to total bytes
+    /// Updates the client's last received timestamp and adds to total bytes and packets
     pub fn update(&mut self, bytes_received: usize) {
         self.last_received_at = Instant::now();
         self.total_received_bytes += bytes_received;
+        self.total_received_packets += 1;
+        self.rate.record(self.last_received_at, bytes_received);
+    }
+
+    /// Returns the packets received per second over the last [`RATE_WINDOW`] seconds
+    pub fn packets_per_second(&self) -> f64 {
+        self.rate.rates(Instant::now()).0
+    }
+
+    /// Returns the bytes received per second over the last [`RATE_WINDOW`] seconds
+    pub fn bytes_per_second(&self) -> f64 {
+        self.rate.rates(Instant::now()).1
+    }
+
+    /// Returns whether the client sends heartbeats on this address but missed the last ones,
+    /// meaning the path is dead even if the client is still connected through others
+    pub fn missed_heartbeats(&self, now: Instant) -> bool {
+        self.heartbeat.is_some_and(|(received_at, interval)| {
+            now.saturating_duration_since(received_at) > interval * control::HEARTBEAT_MISSES
+        })
+    }
+
+    /// Returns the key identifying this client across addresses
+    pub fn client_key(&self) -> ClientKey {
+        ClientKey::new(self.addr, self.session_id)
+    }
+}
+
+/// Number of seconds the receive rates of clients are averaged over
+pub const RATE_WINDOW: usize = 10;
+
+/// Packets and bytes received over the last [`RATE_WINDOW`] seconds, in one-second buckets
+#[derive(Debug)]
+pub struct RateWindow {
+    started_at: Instant,
+    /// Second since `started_at` each bucket counts, with its packets and bytes
+    buckets: [(u64, u64, u64); RATE_WINDOW],
+}
+
+impl RateWindow {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started_at: now,
+            buckets: [(0, 0, 0); RATE_WINDOW],
+        }
+    }
+
+    pub fn record(&mut self, now: Instant, bytes: usize) {
+        let second = now.saturating_duration_since(self.started_at).as_secs();
+        let bucket = &mut self.buckets[second as usize % RATE_WINDOW];
+        if bucket.0 != second {
+            *bucket = (second, 0, 0);
+        }
+        bucket.1 += 1;
+        bucket.2 += bytes as u64;
+    }
+
+    /// Returns the packets and bytes received per second over the window, or since the start if
+    /// it's shorter
+    pub fn rates(&self, now: Instant) -> (f64, f64) {
+        let elapsed = now.saturating_duration_since(self.started_at);
+        let second = elapsed.as_secs();
+        let (packets, bytes) = self.buckets
+            .iter()
+            .filter(|(bucket, _, _)| second - bucket < RATE_WINDOW as u64)
+            .fold((0, 0), |(packets, bytes), (_, bucket_packets, bucket_bytes)| (packets + bucket_packets, bytes + bucket_bytes));
+        let span = elapsed.as_secs_f64().clamp(1.0, RATE_WINDOW as f64);
+        (packets as f64 / span, bytes as f64 / span)
+    }
+}
+
+/// Bookkeeping about a client, handled off the receive path
+#[derive(Debug)]
+pub enum ClientEvent {
+    Connected { addr: SocketAddr, session_id: Option<SessionId> },
+    SessionStarted(SessionId),
+    SessionChanged { addr: SocketAddr, session_id: Option<SessionId> },
+    EncryptionChanged { addr: SocketAddr, encrypted: bool },
+    QueueingDelayChanged { addr: SocketAddr, building_up: bool },
+}
+
+/// Groups the addresses (one per client interface) of a session-tagged client
+#[derive(Debug)]
+pub struct Session {
+    pub id: SessionId,
+    /// Timestamp of the first packet received on any address of the session
+    pub first_seen_at: Instant,
+    /// Timestamp of the last packet received on any address of the session
+    pub last_received_at: Instant,
+    /// Total number of bytes received on all addresses of the session
+    pub total_received_bytes: usize,
+    /// Address (client interface) the last packet of the session was received from
+    pub last_addr: SocketAddr,
+}
+
+impl Session {
+    pub fn new(id: SessionId, addr: SocketAddr) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            first_seen_at: now,
+            last_received_at: now,
+            total_received_bytes: 0,
+            last_addr: addr,
+        }
+    }
+
+    pub fn update(&mut self, addr: SocketAddr, bytes_received: usize) {
+        self.last_received_at = Instant::now();
+        self.total_received_bytes += bytes_received;
+        self.last_addr = addr;
+    }
+}
+
+/// Identifies a logical client: its session if it has one, its address otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ClientKey {
+    Session(SessionId),
+    Addr(SocketAddr),
+}
+
+impl ClientKey {
+    pub fn new(addr: SocketAddr, session_id: Option<SessionId>) -> Self {
+        session_id.map_or(Self::Addr(addr), Self::Session)
+    }
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Session(id) => write!(f, "session:{}", id),
+            Self::Addr(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl FromStr for ClientKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("session:") {
+            Some(id) => Ok(Self::Session(id.parse()?)),
+            None => s.parse()
+                .map(Self::Addr)
+                .map_err(|_| anyhow!("Invalid client key '{}': expected an address or 'session:<id>'", s)),
+        }
+    }
+}
+
+impl Serialize for ClientKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
     }
 }
 
 /// Thread-safe collection of connected clients
-pub type Clients = Arc<DashMap<SocketAddr, Client>>; 
\ No newline at end of file
+pub type Clients = Arc<DashMap<SocketAddr, Client>>;
+
+/// Thread-safe collection of active sessions
+pub type Sessions = Arc<DashMap<SessionId, Session>>;
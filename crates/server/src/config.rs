@@ -1,46 +1,217 @@
-use anyhow::Result;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tracing::{info, warn};
+use serde_json::{json, Value};
+use shared::cli::Args;
+use shared::config::Layers;
+use tracing::{error, info};
 
+use crate::health;
+use crate::web;
+use crate::state::StateFile;
 use crate::wireguard::types::WireGuardConfig;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Settings {
     pub server: Server,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Server {
     pub description: Option<String>,
     pub listen_addr: String,
+    // WireGuard address the clients' traffic is forwarded to, or `unix:<path>` for the Unix datagram socket of a
+    // userspace WireGuard implementation running next to the server (e.g. in the same container).
     pub dst_addr: String,
+    // Local address the traffic is forwarded to WireGuard from, e.g. `10.0.0.1:51000`, so WireGuard sees a known source
+    // (when it only accepts some peers, or runs in another network namespace). Sessions (`wrapper.session`) get their
+    // own socket on the same IP address with a random port. Defaults to `0.0.0.0:0`, a random port on every address.
+    pub wireguard_bind_addr: Option<SocketAddr>,
+    // Additional tunnels served by this process, each forwarding the clients of its listen address to its own
+    // WireGuard address (e.g. another WireGuard interface), with the settings below. Only the tunnel above has
+    // its clients listed and annotated by the web manager.
+    #[serde(default)]
+    pub tunnels: Vec<Tunnel>,
     // Client timeout in seconds. If a client doesn't send any packet for n seconds, engarde stops sending it packets.
     // You will need to set it to a slightly higher value than the PersistentKeepalive option in WireGuard clients.
     pub client_timeout: Option<u64>,
+    // Interval in seconds between the removals of the clients that timed out. Defaults to 5.
+    pub cleanup_interval: Option<u64>,
+    // Interval in seconds between the INFO summaries of the traffic received from each client, for setups without a
+    // metrics backend. Disabled if not set.
+    pub summary_interval: Option<u64>,
+    // Emit the per-packet spans and log events for one packet in N at each place they're emitted, e.g. 1000 to
+    // save CPU on gigabit links; 0 disables them. Lifecycle logs and metrics are unaffected. Defaults to 1, every
+    // packet.
+    pub packet_trace_sampling: Option<u32>,
     // Write timeout in milliseconds for socket writes. You can try to lower it if you're experiencing latency peaks, or raising it if the connection is unstable.
+    // A packet whose write to a client address times out is dropped for that address, so a stalled path doesn't delay the others.
     // You can disable write timeout by setting to 0; but it's easy to have issues if you need low latency.
     pub write_timeout: Option<u64>,
+    // Size in bytes of the buffers receiving datagrams, from the clients and from WireGuard, which must hold the
+    // largest WireGuard packet plus the headers of the wrapper. Raise it on LANs with jumbo frames. Defaults to 1500.
+    pub buffer_size: Option<usize>,
     pub web_manager: Option<WebManager>,
+    // Path of the JSON file used to persist server state (e.g. client labels and notes) across restarts.
+    pub state_file: Option<String>,
+    // Path of the JSON file the status served by the web manager (health, drain, clients and sessions of the main
+    // tunnel) is atomically written to every `statusInterval` seconds, for scripts and LuCI pages. Not written if
+    // not set.
+    pub status_file: Option<String>,
+    // Interval in seconds between the writes of the status file. Defaults to 5.
+    pub status_interval: Option<u64>,
+    // Maximum number of client addresses (one per client interface) tracked at once. Traffic from new addresses
+    // beyond it is dropped, so scanners spraying the listen port can't exhaust the memory. Unlimited if not set, or
+    // 64 with `lowMemory`.
+    pub max_clients: Option<usize>,
+    // Shrink maps and buffers for embedded targets with little memory.
+    #[serde(default)]
+    pub low_memory: bool,
+    // Pre-shared key authenticating every packet from clients, which must set the same `wrapper.psk`.
+    // Raw and unauthenticated traffic is silently dropped.
+    pub psk: Option<String>,
+    // File holding the `psk` instead, e.g. a systemd credential or a mounted Kubernetes secret.
+    pub psk_file: Option<String>,
+    // Drop datagrams that aren't wrapper frames instead of forwarding them as raw engarde traffic, so random
+    // traffic (e.g. from scanners) is never registered as a client. Every client must enable its wrapper, and its
+    // traffic is dropped until it negotiated with the server, unless it sets a `psk` or an `encryptionKey`.
+    #[serde(default)]
+    pub wrapper_only: bool,
+    // Secret for the XChaCha20-Poly1305 encryption hiding the WireGuard traffic from DPI middleboxes;
+    // clients opt in by setting the same `wrapper.encryptionKey`.
+    pub encryption_key: Option<String>,
+    // File holding the `encryptionKey` instead.
+    pub encryption_key_file: Option<String>,
+    // Temporarily ban the source addresses that keep sending invalid packets: malformed frames, and unauthenticated
+    // or raw traffic with a `psk` or `wrapperOnly`.
+    pub auto_ban: Option<AutoBan>,
+    // Ask wrapper clients to duplicate on fewer paths while the uplink towards WireGuard is congested.
+    pub congestion_control: Option<CongestionControl>,
+    // Interval in seconds between checks of the dependencies referenced above (destination address resolvable,
+    // state file writable); failures are reported by the web manager's health endpoint.
+    pub health_check_interval: Option<u64>,
+    // Interval in seconds between the resolutions of the destination addresses, so a WireGuard endpoint behind
+    // dynamic DNS keeps receiving the traffic after its IP changes. Packets are sent to the last resolved address
+    // in between. Defaults to 60.
+    pub resolve_interval: Option<u64>,
+    // Seconds WireGuard may leave the traffic forwarded to it unanswered before it's considered dead (e.g. after it
+    // restarted on another port): the destination addresses are resolved again, the sessions' sockets are re-bound,
+    // and the web manager's health endpoint reports it until WireGuard answers again. WireGuard answers within 10s
+    // of receiving traffic even when it has nothing to send. Defaults to 30; 0 disables it.
+    pub upstream_timeout: Option<u64>,
+    // Milliseconds to hold packets that arrive ahead of a missing one from clients numbering their frames
+    // (`wrapper.sequence`), so WireGuard's replay window doesn't drop badly reordered multi-path traffic.
+    // Duplicates are dropped too. Only the frames tagged with a session are reordered, as a session numbers its frames
+    // across all its paths; the others are forwarded as they come. Disabled if not set.
+    pub reorder_timeout: Option<u64>,
+    // Milliseconds during which copies of a packet received on several client paths are dropped, by comparing
+    // payload hashes, so WireGuard only gets each packet once even from clients that don't number their frames
+    // (e.g. engarde clients). Disabled if not set.
+    pub dedup_window: Option<u64>,
+    // Interval in seconds between the reports sent to clients that ask for them (`wrapper.pathReports`) with the
+    // packets, bytes and loss received from each of their addresses. The loss shown by the web manager is measured
+    // over the same interval.
+    pub path_report_interval: Option<u64>,
     pub wireguard: Option<WireGuardConfig>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Tunnel {
+    pub listen_addr: String,
+    pub dst_addr: String,
+    // Local address the traffic of this tunnel is forwarded to WireGuard from. Defaults to the IP address of the
+    // `wireguardBindAddr` above, with a random port.
+    pub wireguard_bind_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoBan {
+    // Number of invalid packets within the interval that bans their source.
+    pub threshold: Option<u32>,
+    // Interval in seconds over which the invalid packets are counted.
+    pub interval: Option<u64>,
+    // Seconds a source stays banned.
+    pub ban_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CongestionControl {
+    // Maximum number of paths clients duplicate each packet on while congested.
+    pub reduced_paths: Option<u8>,
+    // Fraction of the packets per second that must find the WireGuard socket buffer full to consider the uplink congested.
+    pub threshold: Option<f64>,
+    // Seconds without congestion before clients are told to restore full duplication.
+    pub recovery_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct WebManager {
     pub listen_addr: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
+    // File holding the `password` instead, e.g. a systemd credential or a mounted Kubernetes secret.
+    pub password_file: Option<String>,
+}
+
+const CLIENT_TIMEOUT: u64 = 30;
+const CLEANUP_INTERVAL: u64 = 5;
+const HEALTH_CHECK_INTERVAL: u64 = 60;
+const RESOLVE_INTERVAL: u64 = 60;
+const PATH_REPORT_INTERVAL: u64 = 5;
+const STATUS_INTERVAL: u64 = 5;
+const REDUCED_PATHS: u8 = 1;
+const BAN_THRESHOLD: u32 = 20;
+const BAN_INTERVAL: u64 = 10;
+const BAN_TIME: u64 = 300;
+
+/// Defaults of the settings, the lowest layer of the configuration
+fn defaults() -> Value {
+    json!({
+        "server": {
+            "clientTimeout": CLIENT_TIMEOUT,
+            "wireguardBindAddr": "0.0.0.0:0",
+            "cleanupInterval": CLEANUP_INTERVAL,
+            "writeTimeout": 10,
+            "bufferSize": shared::DEFAULT_BUFFER_SIZE,
+            "healthCheckInterval": HEALTH_CHECK_INTERVAL,
+            "resolveInterval": RESOLVE_INTERVAL,
+            "upstreamTimeout": 30,
+            "pathReportInterval": PATH_REPORT_INTERVAL,
+            "statusInterval": STATUS_INTERVAL,
+            "packetTraceSampling": 1,
+            "congestionControl": { "reducedPaths": REDUCED_PATHS, "threshold": 0.01, "recoveryTime": 5 },
+            "autoBan": { "threshold": BAN_THRESHOLD, "interval": BAN_INTERVAL, "banTime": BAN_TIME },
+        }
+    })
 }
 
-pub fn load_config() -> Result<Settings> {
-    let config_path = std::env::args().nth(1).unwrap_or_else(|| {
-        String::from("engarde.yml")
-    });
+/// Loads the configuration file given on the command line, between the defaults and the command line's overrides
+pub fn load_config(args: &Args) -> Result<Settings> {
+    let layers = Layers {
+        defaults: defaults(),
+        environment: shared::config::environment(),
+        command_line: args.listen_addr.iter().map(|listen_addr| ("server.listenAddr".to_owned(), json!(listen_addr))).collect(),
+        strict: args.strict,
+        engarde_compat: args.engarde_compat,
+        profile: args.profile.clone(),
+    };
+    let mut settings: Settings = shared::config::load(args.config(), layers)?;
+
+    let server = &mut settings.server;
+    shared::config::load_secret("server.psk", &mut server.psk, server.psk_file.as_deref())?;
+    shared::config::load_secret("server.encryptionKey", &mut server.encryption_key, server.encryption_key_file.as_deref())?;
+    if let Some(web_manager) = &mut server.web_manager {
+        shared::config::load_secret("server.webManager.password", &mut web_manager.password, web_manager.password_file.as_deref())?;
+    }
 
-    let settings = std::fs::read_to_string(&config_path)?;
-    let settings: Settings = serde_yaml::from_str(&settings)?;
-    
     if let Some(description) = &settings.server.description {
         info!("{}", description);
     }
@@ -48,24 +219,132 @@ pub fn load_config() -> Result<Settings> {
     Ok(settings)
 }
 
+/// Validates the loaded settings, replacing the values that would stall a task with their defaults and deriving the
+/// settings that depend on others
 pub fn validate_settings(mut settings: Settings) -> Result<Settings> {
-    // Validate and set default client timeout
-    if matches!(settings.server.client_timeout, None | Some(0)) {
-        info!("Client timeout not set; setting to 30s.");
-        settings.server.client_timeout = Some(30);
+    let server = &mut settings.server;
+    if server.client_timeout == Some(0) {
+        info!("Client timeout set to 0; setting to {}s.", CLIENT_TIMEOUT);
+        server.client_timeout = Some(CLIENT_TIMEOUT);
+    }
+    if server.cleanup_interval == Some(0) {
+        info!("Cleanup interval set to 0; setting to {}s.", CLEANUP_INTERVAL);
+        server.cleanup_interval = Some(CLEANUP_INTERVAL);
+    }
+    if server.buffer_size == Some(0) {
+        info!("Buffer size set to 0; setting to {} bytes.", shared::DEFAULT_BUFFER_SIZE);
+        server.buffer_size = Some(shared::DEFAULT_BUFFER_SIZE);
+    }
+    if server.health_check_interval == Some(0) {
+        info!("Health check interval set to 0; setting to {}s.", HEALTH_CHECK_INTERVAL);
+        server.health_check_interval = Some(HEALTH_CHECK_INTERVAL);
+    }
+    if server.resolve_interval == Some(0) {
+        info!("Resolve interval set to 0; setting to {}s.", RESOLVE_INTERVAL);
+        server.resolve_interval = Some(RESOLVE_INTERVAL);
+    }
+    if server.path_report_interval == Some(0) {
+        info!("Path report interval set to 0; setting to {}s.", PATH_REPORT_INTERVAL);
+        server.path_report_interval = Some(PATH_REPORT_INTERVAL);
+    }
+    if server.status_interval == Some(0) {
+        info!("Status interval set to 0; setting to {}s.", STATUS_INTERVAL);
+        server.status_interval = Some(STATUS_INTERVAL);
+    }
+
+    // Tunnels forward from the IP address of the main tunnel by default
+    let wireguard_bind_ip = server.wireguard_bind_addr.unwrap().ip();
+    for tunnel in &mut server.tunnels {
+        tunnel.wireguard_bind_addr.get_or_insert(SocketAddr::new(wireguard_bind_ip, 0));
     }
 
-    // Validate and set default write timeout
-    if settings.server.write_timeout.is_none() {
-        info!("Write timeout not set; setting to 10ms.");
-        settings.server.write_timeout = Some(10);
+    // Disable WireGuard liveness detection with a zero timeout
+    if server.upstream_timeout == Some(0) {
+        info!("Upstream timeout set to 0; disabling WireGuard liveness detection.");
+        server.upstream_timeout = None;
     }
 
-    // Warn if write timeout is enabled (not implemented yet)
-    if !matches!(settings.server.write_timeout, Some(0)) {
-        warn!("Write timeout is not implemented yet: setting to 0 to disable!");
-        settings.server.write_timeout = Some(0);
+    // Disable reordering with a zero timeout
+    if server.reorder_timeout == Some(0) {
+        info!("Reorder timeout set to 0; disabling reordering.");
+        server.reorder_timeout = None;
+    }
+
+    // Disable duplicate suppression with a zero window
+    if server.dedup_window == Some(0) {
+        info!("Dedup window set to 0; disabling duplicate suppression.");
+        server.dedup_window = None;
+    }
+
+    if let Some(congestion_control) = &mut server.congestion_control {
+        if congestion_control.reduced_paths == Some(0) {
+            info!("Congestion control reduced paths set to 0; setting to {}.", REDUCED_PATHS);
+            congestion_control.reduced_paths = Some(REDUCED_PATHS);
+        }
+    }
+
+    if let Some(auto_ban) = &mut server.auto_ban {
+        if auto_ban.threshold == Some(0) {
+            info!("Auto ban threshold set to 0; setting to {}.", BAN_THRESHOLD);
+            auto_ban.threshold = Some(BAN_THRESHOLD);
+        }
+        if auto_ban.interval == Some(0) {
+            info!("Auto ban interval set to 0; setting to {}s.", BAN_INTERVAL);
+            auto_ban.interval = Some(BAN_INTERVAL);
+        }
+        if auto_ban.ban_time == Some(0) {
+            info!("Auto ban time set to 0; setting to {}s.", BAN_TIME);
+            auto_ban.ban_time = Some(BAN_TIME);
+        }
     }
 
     Ok(settings)
-} 
\ No newline at end of file
+}
+
+/// Checks what the validated settings reference, as the server would when starting: the listen and destination
+/// addresses resolve, the Unix sockets exist, and the state file is writable
+///
+/// Every error is logged with the setting it comes from, before failing with their count.
+pub async fn check(server: &Server) -> Result<()> {
+    let mut errors = Vec::new();
+    let mut check = |setting: &str, result: Result<()>| {
+        if let Err(err) = result {
+            error!("{}: {:#}", setting, err);
+            errors.push(setting.to_owned());
+        }
+    };
+
+    check("server.listenAddr", resolve(&server.listen_addr).await);
+    check("server.dstAddr", health::check(&server.dst_addr, None).await);
+    for (index, tunnel) in server.tunnels.iter().enumerate() {
+        check(&format!("server.tunnels[{}].listenAddr", index), resolve(&tunnel.listen_addr).await);
+        check(&format!("server.tunnels[{}].dstAddr", index), health::check(&tunnel.dst_addr, None).await);
+    }
+    if let Some(web_manager) = &server.web_manager {
+        let listen_addr = web_manager.listen_addr.as_deref().ok_or_else(|| anyhow!("Web manager listen address not set"));
+        check("server.webManager.listenAddr", match listen_addr {
+            Ok(listen_addr) => resolve(listen_addr).await,
+            Err(err) => Err(err),
+        });
+    }
+    if let Some(state_file) = &server.state_file {
+        check("server.stateFile", StateFile::new(state_file).check_writable());
+    }
+    if let Some(status_file) = &server.status_file {
+        check("server.statusFile", web::check_status_file(Path::new(status_file)));
+    }
+
+    if !errors.is_empty() {
+        bail!("{} invalid setting(s): {}", errors.len(), errors.join(", "));
+    }
+    Ok(())
+}
+
+async fn resolve(addr: &str) -> Result<()> {
+    tokio::net::lookup_host(addr)
+        .await
+        .with_context(|| format!("Address '{}' can't be resolved", addr))?
+        .next()
+        .ok_or_else(|| anyhow!("Address '{}' resolves to no address", addr))?;
+    Ok(())
+}
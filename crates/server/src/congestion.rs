@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use shared::control::{Capabilities, Message};
+use shared::frame::Codec;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::client::ClientManager;
+use crate::config::CongestionControl;
+
+/// Counts sends towards WireGuard that found the socket buffer full
+#[derive(Debug, Default)]
+pub struct CongestionMonitor {
+    sends: AtomicU64,
+    blocked: AtomicU64,
+}
+
+impl CongestionMonitor {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Records a send; `blocked` tells whether the socket wasn't immediately writable
+    pub fn record_send(&self, blocked: bool) {
+        self.sends.fetch_add(1, Ordering::Relaxed);
+        if blocked {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the fraction of blocked sends since the previous call
+    fn take_pressure(&self) -> f64 {
+        let sends = self.sends.swap(0, Ordering::Relaxed);
+        let blocked = self.blocked.swap(0, Ordering::Relaxed);
+        if sends == 0 {
+            0.0
+        } else {
+            blocked as f64 / sends as f64
+        }
+    }
+}
+
+/// Signals clients to reduce duplication while the uplink is congested, and to restore it afterwards
+#[tracing::instrument(skip_all)]
+pub async fn control_duplication(
+    monitor: Arc<CongestionMonitor>,
+    client_manager: ClientManager,
+    client_socket: Arc<UdpSocket>,
+    codec: Codec,
+    settings: CongestionControl,
+) -> Result<()> {
+    let reduced_paths = settings.reduced_paths.unwrap();
+    let threshold = settings.threshold.unwrap();
+    let recovery_time = Duration::from_secs(settings.recovery_time.unwrap());
+
+    let mut congested_since: Option<Instant> = None;
+    let mut last_pressure_at = Instant::now();
+    let mut buf = Vec::new();
+    let mut encrypted_buf = Vec::new();
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let pressure = monitor.take_pressure();
+        if pressure >= threshold {
+            last_pressure_at = Instant::now();
+            if congested_since.is_none() {
+                warn!("Uplink to WireGuard congested ({:.1}% of sends blocked); limiting duplication to {} paths", pressure * 100.0, reduced_paths);
+                congested_since = Some(Instant::now());
+            }
+        }
+
+        let message = match congested_since {
+            Some(since) if last_pressure_at.elapsed() >= recovery_time => {
+                info!("Uplink to WireGuard recovered after {:?}; restoring full duplication", since.elapsed());
+                congested_since = None;
+                Message::DuplicationLimit { max_paths: 0 }
+            }
+            // Clients drop the limit unless it is refreshed, so keep repeating it
+            Some(_) => Message::DuplicationLimit { max_paths: reduced_paths },
+            None => continue,
+        };
+
+        message.encode_frame(&codec, None, false, &mut buf);
+        if codec.can_encrypt() {
+            message.encode_frame(&codec, None, true, &mut encrypted_buf);
+        }
+        for (addr, encrypted) in client_manager.clients_with(Capabilities::CONGESTION_FEEDBACK) {
+            let datagram = if encrypted { &encrypted_buf } else { &buf };
+            if let Err(err) = client_socket.send_to(datagram, addr).await {
+                debug!("Failed to send duplication limit to client '{:?}': {:?}", addr, err);
+            }
+        }
+    }
+}
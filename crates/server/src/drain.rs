@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+/// Drain mode, in which the server stops accepting new clients while it keeps serving the current
+/// ones until they time out, so it can be taken out of an HA setup without cutting anyone off
+///
+/// Shared by every tunnel; toggled with `SIGUSR1` (start) and `SIGUSR2` (stop), or through the web
+/// manager.
+#[derive(Debug, Clone, Default)]
+pub struct Drain {
+    draining: Arc<AtomicBool>,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Starts or stops draining
+    pub fn set(&self, draining: bool) {
+        if self.draining.swap(draining, Ordering::Relaxed) == draining {
+            return;
+        }
+        if draining {
+            warn!("Draining: refusing new clients, serving the current ones until they time out");
+        } else {
+            info!("Drain stopped: accepting new clients again");
+        }
+    }
+}
+
+/// Starts draining on `SIGUSR1`, and stops on `SIGUSR2`
+#[tracing::instrument(skip_all)]
+pub async fn handle_signals(drain: Drain) -> Result<()> {
+    let mut start = signal(SignalKind::user_defined1())?;
+    let mut stop = signal(SignalKind::user_defined2())?;
+    loop {
+        tokio::select! {
+            _ = start.recv() => drain.set(true),
+            _ = stop.recv() => drain.set(false),
+        }
+    }
+}
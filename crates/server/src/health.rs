@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use shared::datagram::PeerAddr;
+use tracing::{debug, info, warn};
+
+use crate::state::StateFile;
+
+/// Health of the external dependencies referenced by the configuration, as last checked
+///
+/// Starts healthy; [`check_periodically`] flips it with the reason of the first failing check, and
+/// the tunnels with the reason their WireGuard endpoint is considered dead.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    reason: Arc<RwLock<Option<String>>>,
+    /// Why WireGuard is considered dead, by destination address
+    upstreams: Arc<RwLock<BTreeMap<String, String>>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns why the server is unhealthy, or `None` if it is healthy
+    pub fn reason(&self) -> Option<String> {
+        let reason = self.reason.read().unwrap().clone();
+        reason.or_else(|| self.upstreams.read().unwrap().values().next().cloned())
+    }
+
+    /// Flags the WireGuard endpoint at `dst_addr` as dead with a reason, or as alive with `None`
+    pub fn set_upstream(&self, dst_addr: &str, reason: Option<String>) {
+        let mut upstreams = self.upstreams.write().unwrap();
+        match reason {
+            Some(reason) => upstreams.insert(dst_addr.to_owned(), reason),
+            None => upstreams.remove(dst_addr),
+        };
+    }
+
+    fn set(&self, reason: Option<String>) {
+        let mut current = self.reason.write().unwrap();
+        match (&*current, &reason) {
+            (None, Some(reason)) => warn!("Server unhealthy: {}", reason),
+            (Some(_), None) => info!("Server healthy again"),
+            (Some(previous), Some(reason)) if previous != reason => warn!("Server still unhealthy: {}", reason),
+            _ => {}
+        }
+        *current = reason;
+    }
+}
+
+/// Revalidates the configured dependencies every `interval`, updating `health`
+#[tracing::instrument(skip_all)]
+pub async fn check_periodically(health: Health, dst_addr: String, state_file: Option<StateFile>, interval: Duration) {
+    loop {
+        let result = check(&dst_addr, state_file.as_ref()).await;
+        debug!("Health check done: {:?}", result);
+        health.set(result.err().map(|err| format!("{:#}", err)));
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Checks that the destination address resolves, or its Unix socket exists, and that the state file is writable
+pub async fn check(dst_addr: &str, state_file: Option<&StateFile>) -> Result<()> {
+    if let Some(path) = PeerAddr::unix_path(dst_addr) {
+        std::fs::metadata(&path).with_context(|| format!("Destination socket '{}' doesn't exist", path.display()))?;
+    } else {
+        tokio::net::lookup_host(dst_addr)
+            .await
+            .with_context(|| format!("Destination address '{}' can't be resolved", dst_addr))?
+            .next()
+            .ok_or_else(|| anyhow!("Destination address '{}' resolves to no address", dst_addr))?;
+    }
+
+    if let Some(state_file) = state_file {
+        state_file.check_writable()?;
+    }
+    Ok(())
+}
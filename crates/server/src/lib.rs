@@ -0,0 +1,500 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use shared::cli::Args;
+use shared::datagram::DatagramSocket;
+use shared::frame::Codec;
+use shared::instance::InstanceLock;
+use shared::profile::MemoryProfile;
+use shared::BuildInfo;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+mod config;
+mod client;
+mod congestion;
+mod drain;
+mod health;
+mod path_report;
+mod reload;
+mod state;
+mod summary;
+mod web;
+mod wireguard;
+
+use client::{BanList, ClientEvent, ClientManager};
+use congestion::CongestionMonitor;
+use drain::Drain;
+use health::Health;
+use state::StateFile;
+use wireguard::{Destination, Upstreams};
+use wireguard::types::WireGuardConfig;
+
+/// Command line of the server
+#[derive(Debug, Parser)]
+#[command(
+    name = env!("CARGO_PKG_NAME"),
+    version,
+    about = "rengarde server: forwards the traffic of rengarde clients, received over several paths, to WireGuard",
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub args: Args,
+}
+
+/// Commands of the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Subcommand)]
+pub enum Command {
+    /// Run the server (default)
+    #[default]
+    Run,
+    /// Validate the configuration and what it references, then exit
+    CheckConfig,
+    /// Print the JSON Schema of the configuration file
+    GenerateSchema,
+    /// Write a commented example configuration to CONFIG, or to the standard output if it's '-'
+    GenerateConfig,
+    /// Print the version and build information
+    Version {
+        /// Print them as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the shell completions
+    Completions {
+        shell: Shell,
+    },
+}
+
+/// Runs the server, or the other command of its command line
+pub async fn run(cli: Cli) -> Result<()> {
+    let Cli { command, args } = cli;
+    let command = command.unwrap_or_default();
+    match command {
+        Command::Version { json } => return shared::print_version(&build_info()?, json),
+        Command::Completions { shell } => {
+            shared::cli::print_completions(shell, Cli::command());
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Initialize logging and print header
+    let _guard = shared::telemetry()
+        .service_name("rengarde-server")
+        .log_level(args.log_level)
+        .log_format(args.log_format)
+        .init()?;
+    match command {
+        Command::GenerateSchema => {
+            let schema = shared::config::schema::generate::<config::Settings>("rengarde server configuration");
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        Command::GenerateConfig => return shared::config::write_example(args.config(), include_str!("../engarde.yml.sample")),
+        _ => {}
+    }
+    shared::print_header(&build_info()?, args.log_format);
+
+    // Load and validate configuration
+    let settings = config::load_config(&args)?;
+    let settings = config::validate_settings(settings)?;
+    if args.print_config {
+        return shared::config::print(&settings);
+    }
+    let server = Arc::new(settings.server);
+    if command == Command::CheckConfig {
+        config::check(&server).await?;
+        info!("Configuration '{}' is valid", args.config());
+        return Ok(());
+    }
+
+    // Fail fast if another instance already uses one of the listen addresses
+    let _locks = std::iter::once(&server.listen_addr)
+        .chain(server.tunnels.iter().map(|tunnel| &tunnel.listen_addr))
+        .map(|listen_addr| InstanceLock::acquire(env!("CARGO_PKG_NAME"), listen_addr, env!("CARGO_PKG_VERSION")))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Initialize client manager
+    let profile = MemoryProfile::new(server.low_memory);
+    info!("Memory profile: {}", profile);
+    let state_file = server.state_file.as_ref().map(StateFile::new);
+    let drain = Drain::new();
+    let (client_manager, client_events) = ClientManager::new(server.client_timeout.unwrap(), server.max_clients, state_file, profile, drain.clone())?;
+
+    // Start and stop draining on signals
+    tokio::spawn({
+        let drain = drain.clone();
+        async move {
+            if let Err(err) = drain::handle_signals(drain).await {
+                warn!("Drain signal handler failed: {:?}", err);
+            }
+        }
+    });
+
+    // Periodically revalidate the dependencies referenced by the configuration
+    let health = Health::new();
+    tokio::spawn(health::check_periodically(
+        health.clone(),
+        server.dst_addr.clone(),
+        server.state_file.as_ref().map(StateFile::new),
+        Duration::from_secs(server.health_check_interval.unwrap()),
+    ));
+
+    // Reload the configuration on signals
+    let (settings, _) = watch::channel(server.clone());
+    let settings_receiver = settings.subscribe();
+    tokio::spawn(async move {
+        if let Err(err) = reload::handle_signals(args, settings).await {
+            warn!("Reload signal handler failed: {:?}", err);
+        }
+    });
+
+    // Sample the per-packet tracing as configured
+    tokio::spawn({
+        let mut settings = settings_receiver.clone();
+        async move {
+            loop {
+                shared::packet_trace::set_sampling(settings.borrow_and_update().packet_trace_sampling.unwrap());
+                if settings.changed().await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    // Give each additional tunnel its own clients, annotated and drained along with the main tunnel's
+    let tunnel_managers = server
+        .tunnels
+        .iter()
+        .map(|tunnel| (tunnel.clone(), client_manager.for_tunnel(server.client_timeout.unwrap(), server.max_clients)))
+        .collect::<Vec<_>>();
+
+    // Start the web manager if configured
+    let web_tunnels = std::iter::once((server.listen_addr.clone(), client_manager.clone()))
+        .chain(tunnel_managers.iter().map(|(tunnel, (client_manager, _))| (tunnel.listen_addr.clone(), client_manager.clone())))
+        .collect();
+    tokio::spawn(web::serve_with_reloads(settings_receiver.clone(), web::Tunnels::new(web_tunnels), health.clone()));
+
+    // Write the status file if configured
+    tokio::spawn(web::write_status_periodically(settings_receiver.clone(), client_manager.clone(), health.clone()));
+
+    // Authenticate frames if a pre-shared key is configured, decrypt them if an encryption key is
+    let codec = Codec::new(server.psk.as_deref(), server.encryption_key.as_deref());
+    if codec.requires_auth() {
+        info!("Pre-shared key set; dropping unauthenticated traffic");
+    }
+    if codec.can_encrypt() {
+        info!("Encryption key set; encrypting traffic to clients that encrypt theirs");
+    }
+    if server.wrapper_only && !codec.requires_auth() {
+        info!("Wrapper only; dropping raw traffic");
+    }
+
+    // Serve the additional tunnels, each with its own clients; the first of them failing stops the server
+    let mut tunnels = JoinSet::new();
+    for (tunnel, (client_manager, client_events)) in tunnel_managers {
+        let destination = Destination::resolve(&tunnel.dst_addr).await?;
+        tunnels.spawn({
+            let settings = settings_receiver.clone();
+            let codec = codec.clone();
+            let health = health.clone();
+            async move {
+                run_tunnel(settings, &tunnel.listen_addr, tunnel.wireguard_bind_addr.unwrap(), destination, client_manager, client_events, codec, health)
+                    .await
+                    .with_context(|| format!("Tunnel '{}' failed", tunnel.listen_addr))
+            }
+        });
+    }
+
+    let destination = Destination::resolve(&server.dst_addr).await?;
+    let main_tunnel = run_tunnel(settings_receiver, &server.listen_addr, server.wireguard_bind_addr.unwrap(), destination, client_manager, client_events, codec, health);
+    tokio::select! {
+        result = main_tunnel => result?,
+        result = first_failure(&mut tunnels) => result?,
+    }
+    warn!("All threads joined; exiting...");
+
+    Ok(())
+}
+
+/// Waits for the first of the tunnels to fail, or forever if none does
+async fn first_failure(tunnels: &mut JoinSet<Result<()>>) -> Result<()> {
+    while let Some(result) = tunnels.join_next().await {
+        result??;
+    }
+    std::future::pending().await
+}
+
+/// Forwards the traffic of the clients connecting to `listen_addr` to WireGuard at `destination`
+/// from `bind_addr`, and WireGuard's replies back to them, until the processing tasks end
+#[allow(clippy::too_many_arguments)]
+async fn run_tunnel(
+    mut settings: watch::Receiver<Arc<config::Server>>,
+    listen_addr: &str,
+    bind_addr: SocketAddr,
+    destination: Destination,
+    client_manager: ClientManager,
+    client_events: mpsc::Receiver<ClientEvent>,
+    codec: Codec,
+    health: Health,
+) -> Result<()> {
+    let server = settings.borrow_and_update().clone();
+    let wireguard_socket = if destination.is_unix() {
+        DatagramSocket::bind_unix_temporary().context("Failed to bind the WireGuard socket")?
+    } else {
+        DatagramSocket::bind_udp(bind_addr).with_context(|| format!("Failed to bind the WireGuard socket to '{}'", bind_addr))?
+    };
+    let wireguard_socket = Arc::new(wireguard_socket);
+    let client_socket = Arc::new(UdpSocket::bind(listen_addr).await?);
+
+    info!("Listening on: {}", listen_addr);
+
+    // Handle new client bookkeeping off the receive path
+    tokio::spawn({
+        let client_manager = client_manager.clone();
+        async move { client_manager.process_events(client_events).await }
+    });
+
+    // Start signaling duplication limits to clients if configured
+    let congestion = CongestionMonitor::new();
+    if let Some(congestion_control) = server.congestion_control.clone() {
+        tokio::spawn({
+            let congestion = congestion.clone();
+            let client_manager = client_manager.clone();
+            let client_socket = client_socket.clone();
+            let codec = codec.clone();
+            async move {
+                if let Err(err) = congestion::control_duplication(congestion, client_manager, client_socket, codec, congestion_control).await {
+                    warn!("Congestion control failed: {:?}", err);
+                }
+            }
+        });
+    }
+
+    // Follow the changes of WireGuard's address
+    tokio::spawn(destination.clone().refresh_periodically(Duration::from_secs(server.resolve_interval.unwrap())));
+
+    // Account per-path loss, and report per-path reception to the clients that ask for it
+    tokio::spawn(path_report::report_periodically(
+        client_manager.clone(),
+        client_socket.clone(),
+        codec.clone(),
+        Duration::from_secs(server.path_report_interval.unwrap()),
+    ));
+
+    // Log the traffic of the clients if asked to
+    tokio::spawn(summary::log_periodically(settings.clone(), client_manager.clone(), listen_addr.to_owned()));
+
+    // Apply the reloaded timeouts and client limit
+    let (wireguard_config, wireguard_config_receiver) = watch::channel(
+        WireGuardConfig::new(server.client_timeout.unwrap(), server.write_timeout.unwrap(), server.buffer_size.unwrap())
+    );
+    tokio::spawn({
+        let client_manager = client_manager.clone();
+        let mut settings = settings.clone();
+        async move {
+            while settings.changed().await.is_ok() {
+                let server = settings.borrow_and_update().clone();
+                client_manager.set_limits(server.client_timeout.unwrap(), server.max_clients);
+                wireguard_config.send_replace(WireGuardConfig::new(
+                    server.client_timeout.unwrap(),
+                    server.write_timeout.unwrap(),
+                    server.buffer_size.unwrap(),
+                ));
+            }
+        }
+    });
+
+    // Forward each session through its own socket, so WireGuard tells the remote sites apart
+    let upstreams = Upstreams::new(
+        wireguard_socket,
+        bind_addr.ip(),
+        destination.clone(),
+        client_manager.clone(),
+        client_socket.clone(),
+        codec.clone(),
+        wireguard_config_receiver.clone(),
+    );
+
+    // Recover from WireGuard restarting, or moving to another address
+    if let Some(upstream_timeout) = server.upstream_timeout {
+        tokio::spawn(destination.clone().watch_liveness(Duration::from_secs(upstream_timeout), upstreams.clone(), health));
+    }
+
+    // Spawn the main processing tasks, the first of them failing or panicking ending the tunnel rather than leaving
+    // it half-dead
+    let mut tasks = JoinSet::new();
+    tasks.spawn({
+        let client_manager = client_manager.clone();
+        let client_socket = client_socket.clone();
+        let upstreams = upstreams.clone();
+        let destination = destination.clone();
+        let codec = codec.clone();
+        let reorder_timeout = server.reorder_timeout.map(Duration::from_millis);
+        let dedup_window = server.dedup_window.map(Duration::from_millis);
+        let ban_list = server.auto_ban.as_ref().map(BanList::new);
+        let wrapper_only = server.wrapper_only;
+        let buffer_size = server.buffer_size.unwrap();
+        async move {
+            client::receive_from_client(
+                client_manager,
+                client_socket,
+                upstreams,
+                destination,
+                codec,
+                congestion,
+                reorder_timeout,
+                dedup_window,
+                ban_list,
+                wrapper_only,
+                buffer_size,
+            )
+            .await
+            .context("Receiving from the clients failed")
+        }
+    });
+
+    tasks.spawn({
+        let client_manager = client_manager.clone();
+        let wireguard_socket = upstreams.shared();
+        let client_socket = client_socket.clone();
+        let config = wireguard_config_receiver;
+        async move {
+            wireguard::receive_from_wireguard(
+                client_manager,
+                wireguard_socket,
+                client_socket,
+                destination,
+                codec,
+                config,
+                None,
+            )
+            .await
+            .context("Receiving from WireGuard failed")
+        }
+    });
+
+    // Spawn client cleanup task
+    tasks.spawn({
+        let client_manager = client_manager.clone();
+        async move {
+            loop {
+                let cleanup_interval = Duration::from_secs(settings.borrow().cleanup_interval.unwrap());
+                tokio::time::sleep(cleanup_interval).await;
+                client_manager.cleanup_timeout_clients();
+                upstreams.cleanup(&client_manager.sessions());
+            }
+        }
+    });
+
+    while let Some(result) = tasks.join_next().await {
+        result.context("A task of the tunnel panicked")??;
+    }
+    Ok(())
+}
+
+/// Metadata of the build, printed in the header of the logs and by `version`
+fn build_info() -> Result<BuildInfo> {
+    let rust_runtime = if cfg!(feature = "rt-rayon") {
+        "rayon"
+    } else if cfg!(feature = "rt-tokio") {
+        "tokio"
+    } else {
+        unimplemented!("No runtime feature enabled");
+    };
+
+    Ok(BuildInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        official_build: option_env!("RENGARDE_OFFICIAL_BUILD").unwrap_or("false").parse::<bool>()?,
+        git_describe: env!("VERGEN_GIT_DESCRIBE"),
+        git_dirty: env!("VERGEN_GIT_DIRTY") == "true",
+        build_timestamp: env!("VERGEN_BUILD_TIMESTAMP"),
+        target_triple: env!("VERGEN_CARGO_TARGET_TRIPLE"),
+        runtime: rust_runtime,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defines_a_valid_command_line() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn runs_by_default() {
+        let cli = Cli::try_parse_from(["server", "engarde.yml"]).unwrap();
+        assert_eq!(cli.command.unwrap_or_default(), Command::Run);
+        assert_eq!(cli.args.config(), "engarde.yml");
+    }
+
+    /// Runs a tunnel listening on `listen_addr` with `settings`, until it ends
+    async fn run_test_tunnel(listen_addr: &str, settings: serde_json::Value) -> Result<()> {
+        let settings: config::Server = serde_json::from_value(settings).unwrap();
+        let (_, settings) = watch::channel(Arc::new(settings));
+        let (client_manager, client_events) = ClientManager::new(30, None, None, MemoryProfile::new(false), Drain::new()).unwrap();
+        let destination = Destination::resolve("127.0.0.1:9").await.unwrap();
+        let bind_addr = "127.0.0.1:0".parse().unwrap();
+        let tunnel = run_tunnel(settings, listen_addr, bind_addr, destination, client_manager, client_events, Codec::default(), Health::new());
+        tokio::time::timeout(Duration::from_secs(5), tunnel).await.expect("the tunnel kept running")
+    }
+
+    fn tunnel_settings() -> serde_json::Value {
+        serde_json::json!({
+            "listenAddr": "127.0.0.1:0",
+            "dstAddr": "127.0.0.1:9",
+            "clientTimeout": 30,
+            "cleanupInterval": 5,
+            "writeTimeout": 10,
+            "bufferSize": 1500,
+            "resolveInterval": 60,
+            "pathReportInterval": 5,
+        })
+    }
+
+    #[tokio::test]
+    async fn stops_the_tunnel_when_a_task_panics() {
+        // The cleanup task panics on the missing interval, while the receiving tasks keep running
+        let mut settings = tunnel_settings();
+        settings.as_object_mut().unwrap().remove("cleanupInterval");
+        let err = run_test_tunnel("127.0.0.1:0", settings).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("panicked"), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn fails_to_listen_on_an_address_in_use() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listen_addr = socket.local_addr().unwrap().to_string();
+        assert!(run_test_tunnel(&listen_addr, tunnel_settings()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_failing_tunnel() {
+        let mut tunnels = JoinSet::new();
+        tunnels.spawn(std::future::pending());
+        tunnels.spawn(async { Ok(()) });
+        tunnels.spawn(async { Err(anyhow::anyhow!("Address in use")) });
+        let err = first_failure(&mut tunnels).await.unwrap_err();
+        assert_eq!(err.to_string(), "Address in use");
+
+        let mut tunnels = JoinSet::<Result<()>>::new();
+        tunnels.spawn(async { panic!("tunnel panicked") });
+        assert!(first_failure(&mut tunnels).await.is_err());
+
+        let mut tunnels = JoinSet::new();
+        tunnels.spawn(async { Ok(()) });
+        assert!(tokio::time::timeout(Duration::from_millis(10), first_failure(&mut tunnels)).await.is_err());
+    }
+}
@@ -0,0 +1,32 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use shared::control::Message;
+use shared::frame::Codec;
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use crate::client::ClientManager;
+
+/// Periodically closes the loss accounting interval of every client address, and reports what the
+/// server received on it to the clients that asked, so they can tell the upstream health of each of
+/// their interfaces
+#[tracing::instrument(skip_all)]
+pub async fn report_periodically(
+    client_manager: ClientManager,
+    client_socket: Arc<UdpSocket>,
+    codec: Codec,
+    interval: Duration,
+) {
+    let mut buf = Vec::new();
+    loop {
+        tokio::time::sleep(interval).await;
+
+        for (addr, encrypted, report) in client_manager.take_path_reports() {
+            Message::PathReport(report).encode_frame(&codec, None, encrypted, &mut buf);
+            if let Err(err) = client_socket.send_to(&buf, addr).await {
+                debug!("Failed to send path report to client '{:?}': {:?}", addr, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use shared::cli::Args;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::config::{self, Server};
+
+/// Reloads the configuration file on `SIGHUP`, publishing the new settings to the tasks following
+/// them
+///
+/// Only the timeouts, the client limit, the web manager, the summaries, the status file and the
+/// per-packet tracing apply without a restart; the active clients and sessions are kept.
+#[tracing::instrument(skip_all)]
+pub async fn handle_signals(args: Args, settings: watch::Sender<Arc<Server>>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        hangup.recv().await;
+        info!("Reloading the configuration");
+        let server = match config::load_config(&args).and_then(config::validate_settings) {
+            Ok(settings) => settings.server,
+            Err(err) => {
+                warn!("Failed to reload the configuration; keeping the current one: {:?}", err);
+                continue;
+            }
+        };
+        if !only_reloadable_changed(&settings.borrow(), &server) {
+            warn!("Only the timeouts, client limit, web manager, summaries, status file and packet tracing are reloaded; restart the server to apply the other changes");
+        }
+        settings.send_replace(Arc::new(server));
+    }
+}
+
+/// Returns whether the settings only differ in what applies without a restart
+fn only_reloadable_changed(current: &Server, new: &Server) -> bool {
+    let mut applied = current.clone();
+    applied.description.clone_from(&new.description);
+    applied.client_timeout = new.client_timeout;
+    applied.cleanup_interval = new.cleanup_interval;
+    applied.write_timeout = new.write_timeout;
+    applied.max_clients = new.max_clients;
+    applied.web_manager.clone_from(&new.web_manager);
+    applied.summary_interval = new.summary_interval;
+    applied.status_file.clone_from(&new.status_file);
+    applied.status_interval = new.status_interval;
+    applied.packet_trace_sampling = new.packet_trace_sampling;
+    applied == *new
+}
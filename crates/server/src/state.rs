@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::client::ClientKey;
+
+/// Operator-provided metadata attached to a client
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Annotation {
+    /// Short label, also attached to the client's metrics
+    pub label: Option<String>,
+    /// Free-form notes, only shown in the web manager
+    pub notes: Option<String>,
+}
+
+/// Server state persisted across restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    #[serde(default)]
+    pub annotations: BTreeMap<ClientKey, Annotation>,
+}
+
+/// Location of the persisted server state
+///
+/// The tunnels share the file: their saves are serialized so they don't replace each other's temporary file.
+#[derive(Debug, Clone)]
+pub struct StateFile {
+    path: PathBuf,
+    saving: Arc<Mutex<()>>,
+}
+
+impl StateFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), saving: Arc::default() }
+    }
+
+    /// Loads the state, returning an empty state if the file doesn't exist yet
+    pub fn load(&self) -> Result<State> {
+        if !self.path.exists() {
+            info!("State file '{}' not found; starting with an empty state", self.path.display());
+            return Ok(State::default());
+        }
+
+        let state = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read state file '{}'", self.path.display()))?;
+        let state = serde_json::from_str(&state)
+            .with_context(|| format!("Failed to parse state file '{}'", self.path.display()))?;
+        debug!("Loaded state from '{}'", self.path.display());
+        Ok(state)
+    }
+
+    /// Checks that the state file can be written, without touching its contents
+    pub fn check_writable(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, "")
+            .and_then(|()| std::fs::remove_file(&tmp_path))
+            .with_context(|| format!("State file '{}' isn't writable", self.path.display()))
+    }
+
+    /// Atomically replaces the state file with the given state
+    pub fn save(&self, state: &State) -> Result<()> {
+        let _saving = self.saving.lock().unwrap();
+        let tmp_path = self.path.with_extension("tmp");
+        let state = serde_json::to_string_pretty(state)?;
+        std::fs::write(&tmp_path, state)
+            .with_context(|| format!("Failed to write state file '{}'", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace state file '{}'", self.path.display()))?;
+        debug!("Saved state to '{}'", self.path.display());
+        Ok(())
+    }
+}
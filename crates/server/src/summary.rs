@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use shared::summary;
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::client::ClientManager;
+use crate::config;
+
+/// Logs the traffic received from each client of `listen_addr` since the previous summary, every `summaryInterval`
+/// seconds of the current settings
+#[tracing::instrument(skip_all)]
+pub async fn log_periodically(mut settings: watch::Receiver<Arc<config::Server>>, client_manager: ClientManager, listen_addr: String) {
+    // Bytes and packets received from each address at the previous summary
+    let mut previous: HashMap<SocketAddr, (usize, usize)> = HashMap::new();
+    let mut since = Instant::now();
+    loop {
+        let interval = settings.borrow_and_update().summary_interval.filter(|interval| *interval > 0);
+        let Some(interval) = interval else {
+            if settings.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+
+        let elapsed = since.elapsed();
+        since = Instant::now();
+        let clients = client_manager.clients();
+        let mut current = HashMap::with_capacity(clients.len());
+        let mut lines = Vec::with_capacity(clients.len());
+        for client in clients.iter() {
+            let (bytes, packets) = previous.get(&client.addr).copied().unwrap_or_default();
+            lines.push(format!(
+                "{} {}",
+                client.addr,
+                summary::rates(
+                    client.total_received_bytes.saturating_sub(bytes),
+                    client.total_received_packets.saturating_sub(packets),
+                    elapsed,
+                ),
+            ));
+            current.insert(client.addr, (client.total_received_bytes, client.total_received_packets));
+        }
+        previous = current;
+        lines.sort();
+
+        info!(
+            "Summary of '{}' over {}s: {} clients, {} sessions{}{}",
+            listen_addr,
+            elapsed.as_secs(),
+            clients.len(),
+            client_manager.sessions().len(),
+            if lines.is_empty() { "" } else { "; received from " },
+            lines.join(", "),
+        );
+    }
+}
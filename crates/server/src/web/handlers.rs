@@ -0,0 +1,148 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use tracing::warn;
+
+use crate::client::{ClientKey, ClientManager};
+use crate::drain::Drain;
+use crate::health::Health;
+use crate::state::Annotation;
+use crate::web::types::{ClientInfo, DrainInfo, HealthInfo, SessionInfo, TunnelInfo};
+use crate::web::{Tunnel, Tunnels};
+
+/// Reports whether the configured dependencies passed their last check, and the server isn't
+/// draining, so load balancers move new clients elsewhere
+pub async fn health(State((health, drain)): State<(Health, Drain)>) -> (StatusCode, Json<HealthInfo>) {
+    let health = health_info(&health, &drain);
+    let status = if health.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health))
+}
+
+pub(super) fn health_info(health: &Health, drain: &Drain) -> HealthInfo {
+    let reason = health.reason().or_else(|| drain.is_draining().then(|| "Server is draining".to_owned()));
+    HealthInfo { healthy: reason.is_none(), reason }
+}
+
+/// Lists the tunnels with their number of clients and sessions, the main tunnel first
+pub async fn list_tunnels(State(tunnels): State<Tunnels>) -> Json<Vec<TunnelInfo>> {
+    Json(
+        tunnels
+            .iter()
+            .map(|(listen_addr, client_manager)| TunnelInfo {
+                listen_addr: listen_addr.clone(),
+                clients: client_manager.client_count(),
+                sessions: client_manager.sessions().len(),
+            })
+            .collect(),
+    )
+}
+
+/// Reports whether the server is draining
+pub async fn get_drain(Tunnel(client_manager): Tunnel) -> Json<DrainInfo> {
+    Json(DrainInfo { draining: client_manager.drain().is_draining() })
+}
+
+/// Starts draining: new clients are refused, the current ones are served until they time out
+pub async fn start_drain(Tunnel(client_manager): Tunnel) -> Json<DrainInfo> {
+    client_manager.drain().set(true);
+    Json(DrainInfo { draining: true })
+}
+
+/// Stops draining, accepting new clients again
+pub async fn stop_drain(Tunnel(client_manager): Tunnel) -> Json<DrainInfo> {
+    client_manager.drain().set(false);
+    Json(DrainInfo { draining: false })
+}
+
+/// Lists the connected clients along with their annotations
+pub async fn list_clients(Tunnel(client_manager): Tunnel) -> Json<Vec<ClientInfo>> {
+    Json(clients_info(&client_manager))
+}
+
+pub(super) fn clients_info(client_manager: &ClientManager) -> Vec<ClientInfo> {
+    client_manager.clients()
+        .iter()
+        .map(|client| ClientInfo {
+            address: client.addr,
+            session_id: client.session_id,
+            first_seen_secs_ago: client.first_seen_at.elapsed().as_secs(),
+            last_received_ms_ago: client.last_received_at.elapsed().as_millis(),
+            total_received_bytes: client.total_received_bytes,
+            total_received_packets: client.total_received_packets,
+            packets_per_second: client.packets_per_second(),
+            bytes_per_second: client.bytes_per_second(),
+            rtt_ms: client.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            loss_permille: client.last_report
+                .filter(|_| client.path.is_sequenced())
+                .map(|report| report.loss_permille),
+            queueing_delay_ms: client.delay.queueing_delay().map(|delay| delay.as_secs_f64() * 1000.0),
+            annotation: client_manager.annotation(&client.client_key()).unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Lists the active sessions along with their addresses and annotations
+pub async fn list_sessions(Tunnel(client_manager): Tunnel) -> Json<Vec<SessionInfo>> {
+    Json(sessions_info(&client_manager))
+}
+
+pub(super) fn sessions_info(client_manager: &ClientManager) -> Vec<SessionInfo> {
+    let clients = client_manager.clients();
+    client_manager.sessions()
+        .iter()
+        .map(|session| SessionInfo {
+            session_id: session.id,
+            addresses: clients
+                .iter()
+                .filter(|client| client.session_id == Some(session.id))
+                .map(|client| client.addr)
+                .collect(),
+            last_address: session.last_addr,
+            first_seen_secs_ago: session.first_seen_at.elapsed().as_secs(),
+            last_received_ms_ago: session.last_received_at.elapsed().as_millis(),
+            total_received_bytes: session.total_received_bytes,
+            annotation: client_manager.annotation(&ClientKey::Session(session.id)).unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Returns the annotation of a client
+pub async fn get_annotation(
+    Tunnel(client_manager): Tunnel,
+    Path(key): Path<String>,
+) -> Result<Json<Annotation>, StatusCode> {
+    let key = parse_key(&key)?;
+    client_manager.annotation(&key).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Attaches an annotation to a client, replacing any previous one
+pub async fn put_annotation(
+    Tunnel(client_manager): Tunnel,
+    Path(key): Path<String>,
+    Json(annotation): Json<Annotation>,
+) -> Result<Json<Annotation>, StatusCode> {
+    let key = parse_key(&key)?;
+    client_manager.annotate(key, Some(annotation.clone())).map_err(|err| {
+        warn!("Failed to persist annotation of client '{}': {:?}", key, err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(annotation))
+}
+
+/// Removes the annotation of a client
+pub async fn delete_annotation(
+    Tunnel(client_manager): Tunnel,
+    Path(key): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let key = parse_key(&key)?;
+    client_manager.annotate(key, None).map_err(|err| {
+        warn!("Failed to persist annotation of client '{}': {:?}", key, err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Parses a client key path segment: either an address or `session:<id>`
+fn parse_key(key: &str) -> Result<ClientKey, StatusCode> {
+    key.parse().map_err(|_| StatusCode::BAD_REQUEST)
+}
@@ -0,0 +1,191 @@
+mod handlers;
+mod status;
+mod types;
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Query, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::client::ClientManager;
+use crate::config::{Server, WebManager};
+use crate::health::Health;
+
+pub use status::{check_writable as check_status_file, write_periodically as write_status_periodically};
+
+/// Client managers of the tunnels, by listen address, the main tunnel's first
+#[derive(Clone)]
+pub struct Tunnels(Arc<Vec<(String, ClientManager)>>);
+
+impl Tunnels {
+    pub fn new(tunnels: Vec<(String, ClientManager)>) -> Self {
+        assert!(!tunnels.is_empty(), "the main tunnel is always served");
+        Self(Arc::new(tunnels))
+    }
+
+    /// Returns the client manager of the main tunnel
+    pub fn main(&self) -> &ClientManager {
+        &self.0[0].1
+    }
+
+    /// Returns the client manager of the tunnel listening on `listen_addr`
+    pub fn get(&self, listen_addr: &str) -> Option<&ClientManager> {
+        self.0.iter().find(|(tunnel, _)| tunnel == listen_addr).map(|(_, client_manager)| client_manager)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, ClientManager)> {
+        self.0.iter()
+    }
+}
+
+#[derive(Deserialize)]
+struct TunnelQuery {
+    tunnel: Option<String>,
+}
+
+/// Client manager of the tunnel a request names with `?tunnel=<listen address>`, the main tunnel's by default
+pub struct Tunnel(pub ClientManager);
+
+#[async_trait]
+impl FromRequestParts<Tunnels> for Tunnel {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, tunnels: &Tunnels) -> Result<Self, StatusCode> {
+        let Query(query) = Query::<TunnelQuery>::try_from_uri(&parts.uri).map_err(|_| StatusCode::BAD_REQUEST)?;
+        match query.tunnel {
+            Some(listen_addr) => tunnels.get(&listen_addr).cloned().map(Tunnel).ok_or(StatusCode::NOT_FOUND),
+            None => Ok(Tunnel(tunnels.main().clone())),
+        }
+    }
+}
+
+/// Serves the web manager API until the listener fails
+///
+/// The client endpoints serve the main tunnel, or the one named with `?tunnel=<listen address>`.
+#[tracing::instrument(skip_all)]
+pub async fn serve(web_manager: &WebManager, tunnels: Tunnels, health: Health) -> Result<()> {
+    let listen_addr = web_manager.listen_addr
+        .as_deref()
+        .ok_or_else(|| anyhow!("Web manager listen address not set"))?;
+
+    let credentials = match (&web_manager.username, &web_manager.password) {
+        (Some(username), Some(password)) => Some(BASE64.encode(format!("{}:{}", username, password))),
+        _ => None,
+    };
+
+    let drain = tunnels.main().drain().clone();
+    let app = Router::new()
+        .route("/api/v1/tunnels", get(handlers::list_tunnels))
+        .route("/api/v1/clients", get(handlers::list_clients))
+        .route("/api/v1/sessions", get(handlers::list_sessions))
+        .route(
+            "/api/v1/drain",
+            get(handlers::get_drain).put(handlers::start_drain).delete(handlers::stop_drain),
+        )
+        .route(
+            "/api/v1/clients/:key/annotation",
+            get(handlers::get_annotation)
+                .put(handlers::put_annotation)
+                .delete(handlers::delete_annotation),
+        )
+        .with_state(tunnels)
+        .layer(middleware::from_fn_with_state(Arc::new(credentials), basic_auth))
+        // Left unauthenticated for health probes
+        .merge(Router::new().route("/api/v1/health", get(handlers::health)).with_state((health, drain)));
+
+    let listener = TcpListener::bind(listen_addr).await?;
+    info!("Web manager listening on: {}", listen_addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Serves the web manager with the current settings, restarting it whenever a configuration reload
+/// changes them
+#[tracing::instrument(skip_all)]
+pub async fn serve_with_reloads(mut settings: watch::Receiver<Arc<Server>>, tunnels: Tunnels, health: Health) {
+    loop {
+        let web_manager = settings.borrow_and_update().web_manager.clone();
+        let serving = async {
+            if let Some(web_manager) = &web_manager {
+                if let Err(err) = serve(web_manager, tunnels.clone(), health.clone()).await {
+                    warn!("Web manager failed: {:?}", err);
+                }
+            }
+            std::future::pending::<()>().await
+        };
+        tokio::pin!(serving);
+        loop {
+            tokio::select! {
+                _ = &mut serving => {}
+                changed = settings.changed() => {
+                    // Reloads are over; keep serving with the current settings
+                    if changed.is_err() {
+                        return serving.await;
+                    }
+                    if settings.borrow().web_manager != web_manager {
+                        break;
+                    }
+                }
+            }
+        }
+        info!("Web manager settings changed; restarting it");
+    }
+}
+
+/// Rejects requests without the configured basic auth credentials
+async fn basic_auth(State(credentials): State<Arc<Option<String>>>, request: Request, next: Next) -> Response {
+    let Some(credentials) = credentials.as_deref() else {
+        return next.run(request).await;
+    };
+
+    let authorized = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .is_some_and(|value| value == credentials);
+
+    if authorized {
+        next.run(request).await
+    } else {
+        (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Basic realm=\"rengarde\"")]).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::profile::MemoryProfile;
+
+    use super::*;
+    use crate::drain::Drain;
+
+    async fn tunnel(tunnels: &Tunnels, uri: &str) -> Result<ClientManager, StatusCode> {
+        let (mut parts, ()) = Request::builder().uri(uri).body(()).unwrap().into_parts();
+        Tunnel::from_request_parts(&mut parts, tunnels).await.map(|Tunnel(client_manager)| client_manager)
+    }
+
+    #[tokio::test]
+    async fn selects_the_tunnel_by_listen_address() {
+        let (main, _events) = ClientManager::new(30, None, None, MemoryProfile::new(false), Drain::new()).unwrap();
+        let (other, _other_events) = main.for_tunnel(30, None);
+        other.add_or_update_client("192.0.2.1:40000".parse().unwrap(), None, 100);
+        let tunnels = Tunnels::new(vec![("0.0.0.0:59402".to_owned(), main), ("0.0.0.0:59403".to_owned(), other)]);
+
+        assert_eq!(tunnel(&tunnels, "/api/v1/clients").await.unwrap().client_count(), 0);
+        assert_eq!(tunnel(&tunnels, "/api/v1/clients?tunnel=0.0.0.0:59402").await.unwrap().client_count(), 0);
+        assert_eq!(tunnel(&tunnels, "/api/v1/clients?tunnel=0.0.0.0%3A59403").await.unwrap().client_count(), 1);
+        assert_eq!(tunnel(&tunnels, "/api/v1/clients?tunnel=0.0.0.0:1").await.err(), Some(StatusCode::NOT_FOUND));
+    }
+}
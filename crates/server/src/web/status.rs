@@ -0,0 +1,75 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::client::ClientManager;
+use crate::config::Server;
+use crate::health::Health;
+use crate::web::handlers;
+use crate::web::types::StatusInfo;
+
+/// Writes the status of the main tunnel to the `statusFile` of the current settings every `statusInterval` seconds,
+/// so scripts can read it without the web manager
+#[tracing::instrument(skip_all)]
+pub async fn write_periodically(mut settings: watch::Receiver<Arc<Server>>, client_manager: ClientManager, health: Health) {
+    let mut failing = false;
+    loop {
+        let (status_file, interval) = {
+            let settings = settings.borrow_and_update();
+            (settings.status_file.clone(), settings.status_interval.unwrap())
+        };
+        let Some(status_file) = status_file else {
+            if settings.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+
+        match write(Path::new(&status_file), &client_manager, &health).await {
+            Ok(()) if failing => {
+                info!("Status file '{}' written again", status_file);
+                failing = false;
+            }
+            Ok(()) => debug!("Wrote status file '{}'", status_file),
+            Err(err) if !failing => {
+                warn!("{:#}", err);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Checks that the status file can be written
+pub fn check_writable(path: &Path) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, "")
+        .and_then(|()| std::fs::remove_file(&tmp_path))
+        .with_context(|| format!("Status file '{}' isn't writable", path.display()))
+}
+
+/// Atomically replaces the status file with the current status
+async fn write(path: &Path, client_manager: &ClientManager, health: &Health) -> Result<()> {
+    let status = StatusInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        updated_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        health: handlers::health_info(health, client_manager.drain()),
+        draining: client_manager.drain().is_draining(),
+        clients: handlers::clients_info(client_manager),
+        sessions: handlers::sessions_info(client_manager),
+    };
+    let status = serde_json::to_string_pretty(&status)?;
+
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, status)
+        .await
+        .with_context(|| format!("Failed to write status file '{}'", tmp_path.display()))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Failed to replace status file '{}'", path.display()))
+}
@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+
+use serde::Serialize;
+use shared::frame::SessionId;
+
+use crate::state::Annotation;
+
+/// A connected client as reported by the web manager
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientInfo {
+    pub address: SocketAddr,
+    pub session_id: Option<SessionId>,
+    /// Seconds since the first packet was received from the client
+    pub first_seen_secs_ago: u64,
+    /// Milliseconds since the last packet was received from the client
+    pub last_received_ms_ago: u128,
+    pub total_received_bytes: usize,
+    pub total_received_packets: usize,
+    /// Packets and bytes received per second, averaged over the last 10 seconds
+    pub packets_per_second: f64,
+    pub bytes_per_second: f64,
+    /// Round-trip time of the path, as last measured by the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<f64>,
+    /// Fraction of the client's numbered frames lost on the path during the last report interval, in thousandths
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loss_permille: Option<u16>,
+    /// Smoothed queueing delay of the path, if the client timestamps its frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queueing_delay_ms: Option<f64>,
+    #[serde(flatten)]
+    pub annotation: Annotation,
+}
+
+/// A tunnel as reported by the web manager
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelInfo {
+    /// Address the tunnel listens on, naming it in `?tunnel=` of the other endpoints
+    pub listen_addr: String,
+    pub clients: usize,
+    pub sessions: usize,
+}
+
+/// An active session as reported by the web manager
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub session_id: SessionId,
+    /// Addresses (one per client interface) currently belonging to the session
+    pub addresses: Vec<SocketAddr>,
+    /// Address the last packet of the session was received from
+    pub last_address: SocketAddr,
+    /// Seconds since the first packet of the session was received
+    pub first_seen_secs_ago: u64,
+    /// Milliseconds since the last packet was received on any address of the session
+    pub last_received_ms_ago: u128,
+    pub total_received_bytes: usize,
+    #[serde(flatten)]
+    pub annotation: Annotation,
+}
+
+/// Result of the last health check
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthInfo {
+    pub healthy: bool,
+    /// Why the server is unhealthy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Whether the server is draining
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DrainInfo {
+    pub draining: bool,
+}
+
+/// Everything the web manager reports, as written to the status file
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusInfo {
+    pub version: &'static str,
+    /// Unix time the status was taken at, in seconds
+    pub updated_at: u64,
+    pub health: HealthInfo,
+    pub draining: bool,
+    pub clients: Vec<ClientInfo>,
+    pub sessions: Vec<SessionInfo>,
+}
@@ -2,50 +2,148 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use futures::StreamExt;
+use shared::control::Capabilities;
+use shared::datagram::DatagramSocket;
+use shared::drops::{self, DropReason};
+use shared::frame::{self, Codec, SessionId};
+use shared::path;
 use tokio::net::UdpSocket;
-use tracing::{debug, trace, warn};
+use tokio::sync::watch;
+use tracing::{debug, warn, Level};
 
-use crate::BUFFER_SIZE;
-use crate::client::Clients;
+use crate::client::{Client, ClientManager};
+use crate::wireguard::Destination;
 use crate::wireguard::types::WireGuardConfig;
 
-/// Handles receiving data from WireGuard interface and forwarding it to clients
+const ENCRYPTED: usize = 1 << 0;
+const SEQUENCED: usize = 1 << 1;
+const TIMESTAMPED: usize = 1 << 2;
+
+/// Returns how the traffic sent back to a client must be framed, as a combination of the flags above;
+/// `0` stands for raw traffic
+fn framing(client: &Client) -> usize {
+    let mut framing = 0;
+    if client.encrypted {
+        framing |= ENCRYPTED;
+    }
+    if client.capabilities.contains(Capabilities::SEQUENCE) {
+        framing |= SEQUENCED;
+    }
+    if client.capabilities.contains(Capabilities::TIMESTAMP) {
+        framing |= TIMESTAMPED;
+    }
+    framing
+}
+
+/// Handles receiving data from WireGuard interface and forwarding it to the clients of the session
+/// the socket forwards, or to the clients without a session
 #[tracing::instrument(skip_all)]
 pub async fn receive_from_wireguard(
-    clients: Clients,
-    wireguard_socket: Arc<UdpSocket>,
+    client_manager: ClientManager,
+    wireguard_socket: Arc<DatagramSocket>,
     client_socket: Arc<UdpSocket>,
-    client_timeout: u64,
-    _write_timeout: u64,
+    destination: Destination,
+    codec: Codec,
+    config: watch::Receiver<WireGuardConfig>,
+    session_id: Option<SessionId>,
 ) -> Result<()> {
-    let config = WireGuardConfig::new(client_timeout, _write_timeout);
-    let mut buf = [0; BUFFER_SIZE];
+    let mut buf = vec![0; config.borrow().buffer_size];
+    let mut framed_bufs: [Vec<u8>; 8] = Default::default();
+    let mut next_sequence: u32 = 0;
+    let clients = client_manager.clients();
 
     loop {
         let received_bytes = wireguard_socket.recv(&mut buf).await?;
         let received_at = std::time::Instant::now();
+        destination.record_received();
+        let config = *config.borrow();
 
-        debug!("Received {} bytes from wireguard", received_bytes);
+        shared::packet_event!(Level::DEBUG, "Received {} bytes from wireguard", received_bytes);
+
+        // Frame the packet once for each framing clients expect: encrypted if they encrypt their
+        // traffic, numbered and timestamped so they can measure per-path loss and queueing delay too
+        let needed = clients
+            .iter()
+            .filter(|client| client.session_id == session_id)
+            .fold(0_u8, |needed, client| needed | 1 << framing(&client));
+        let timestamp = path::timestamp();
+        let mut sequenced = false;
+        for (framing, framed_buf) in framed_bufs.iter_mut().enumerate().skip(1) {
+            if needed & 1 << framing == 0 {
+                continue;
+            }
+            let header = frame::Header {
+                encrypted: framing & ENCRYPTED != 0,
+                sequence: (framing & SEQUENCED != 0).then_some(next_sequence),
+                timestamp: (framing & TIMESTAMPED != 0).then_some(timestamp),
+                ..frame::Header::default()
+            };
+            codec.encode(&header, &buf[..received_bytes], framed_buf);
+            sequenced |= header.sequence.is_some();
+        }
+        if sequenced {
+            next_sequence = next_sequence.wrapping_add(1);
+        }
 
         // Send to clients
-        let drop_list: Vec<_> = futures::stream::iter(clients.iter())
+        let drop_list: Vec<_> = futures::stream::iter(clients.iter().filter(|client| client.session_id == session_id))
             .filter_map(|client| {
                 let client_socket = client_socket.clone();
+                let datagram = match framing(&client) {
+                    0 => &buf[..received_bytes],
+                    framing => &framed_bufs[framing][..],
+                };
                 async move {
                     // Check if the client has timed out
                     if received_at.duration_since(client.last_received_at) > config.client_timeout {
-                        warn!("Client '{:?}' timed out", client.addr);
-                        return Some(client.addr);
+                        drops::record(DropReason::ClientTimeout);
+                        return Some((client.addr, true));
                     }
 
-                    // Send to client
-                    if client_socket.send_to(&buf[..received_bytes], &client.addr).await.is_err() {
+                    // Skip paths the client stopped sending heartbeats on
+                    if client.missed_heartbeats(received_at) {
+                        shared::packet_event!(Level::TRACE, "Skipping dead path '{:?}'", client.addr);
+                        return None;
+                    }
+
+                    // Send to client, giving up on this packet if the write stalls
+                    let send = client_socket.send_to(datagram, &client.addr);
+                    let result = if config.write_timeout.is_zero() {
+                        send.await
+                    } else {
+                        match tokio::time::timeout(config.write_timeout, send).await {
+                            Ok(result) => result,
+                            Err(_) => {
+                                debug!(
+                                    monotonic_counter.rengarde_client_write_timeouts_total = 1_u64,
+                                    client = client.client_key().to_string(),
+                                    label = client.label.as_deref().unwrap_or_default(),
+                                    "Write to client '{:?}' timed out; dropping the packet", client.addr
+                                );
+                                drops::record(DropReason::WriteTimeout);
+                                return None;
+                            }
+                        }
+                    };
+                    if result.is_err() {
+                        debug!(
+                            monotonic_counter.rengarde_client_send_errors_total = 1_u64,
+                            client = client.client_key().to_string(),
+                            label = client.label.as_deref().unwrap_or_default(),
+                        );
                         warn!("Error writing to client '{:?}', terminating it", client.addr);
-                        return Some(client.addr);
+                        drops::record(DropReason::SendError);
+                        return Some((client.addr, false));
                     }
+                    debug!(
+                        monotonic_counter.rengarde_client_sent_bytes = datagram.len() as u64,
+                        client = client.client_key().to_string(),
+                        label = client.label.as_deref().unwrap_or_default(),
+                    );
 
-                    trace!(
-                        sent_bytes = received_bytes,
+                    shared::packet_event!(
+                        Level::TRACE,
+                        sent_bytes = datagram.len(),
                         dst_addr = client.addr.to_string(),
                         "\tSent {} bytes to client '{:?}'", received_bytes, client.addr
                     );
@@ -54,12 +152,15 @@ pub async fn receive_from_wireguard(
             })
             .collect::<Vec<_>>()
             .await;
+        debug!(histogram.rengarde_forwarding_latency_seconds = received_at.elapsed().as_secs_f64(), direction = "downstream");
 
-        // Drop the clients that have timed out
-        if !drop_list.is_empty() {
-            drop_list.into_iter().for_each(|addr| {
-                clients.remove(&addr);
-            });
+        // Drop the clients that have timed out, or can't be written to
+        for (addr, timed_out) in drop_list {
+            if timed_out {
+                client_manager.time_out_client(addr);
+            } else {
+                client_manager.remove_client(addr);
+            }
         }
     }
 } 
\ No newline at end of file
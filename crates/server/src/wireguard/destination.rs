@@ -0,0 +1,150 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use shared::datagram::PeerAddr;
+use tracing::{debug, info, warn};
+
+use crate::health::Health;
+use crate::wireguard::Upstreams;
+
+/// Interval between the checks of WireGuard's liveness
+const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Address of the WireGuard endpoint the clients' traffic is forwarded to
+///
+/// The configured host name is resolved once at startup and then every refresh interval, so the
+/// packets are sent to a cached address while a WireGuard endpoint behind dynamic DNS can still
+/// change its IP; `unix:<path>` addresses reference the Unix datagram socket of a userspace
+/// WireGuard instead. It also follows whether WireGuard answers the traffic forwarded to it.
+#[derive(Debug, Clone)]
+pub struct Destination {
+    name: Arc<str>,
+    addr: Arc<RwLock<PeerAddr>>,
+    /// When the oldest packet sent since WireGuard last answered was sent, in milliseconds since
+    /// [`epoch`] plus one; zero once WireGuard answered
+    unanswered_since: Arc<AtomicU64>,
+}
+
+impl Destination {
+    pub async fn resolve(name: &str) -> Result<Self> {
+        let addr = match PeerAddr::unix_path(name) {
+            Some(path) => PeerAddr::Unix(path),
+            None => PeerAddr::Udp(lookup(name).await?),
+        };
+        info!("Forwarding to WireGuard at '{}' ({})", name, addr);
+        Ok(Self {
+            name: name.into(),
+            addr: Arc::new(RwLock::new(addr)),
+            unanswered_since: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Returns the address the packets are currently sent to
+    pub fn addr(&self) -> PeerAddr {
+        self.addr.read().unwrap().clone()
+    }
+
+    /// Returns whether WireGuard is reached through a Unix datagram socket
+    pub fn is_unix(&self) -> bool {
+        matches!(*self.addr.read().unwrap(), PeerAddr::Unix(_))
+    }
+
+    /// Records a packet sent to WireGuard
+    pub fn record_sent(&self) {
+        let now = epoch().elapsed().as_millis() as u64 + 1;
+        let _ = self.unanswered_since.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Records a packet received from WireGuard
+    pub fn record_received(&self) {
+        self.unanswered_since.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns for how long WireGuard didn't answer the packets sent to it, if it didn't
+    fn silent_for(&self) -> Option<Duration> {
+        match self.unanswered_since.load(Ordering::Relaxed) {
+            0 => None,
+            since => Some(epoch().elapsed().saturating_sub(Duration::from_millis(since - 1))),
+        }
+    }
+
+    /// Resolves the host name again every `interval`, keeping the last address if that fails
+    ///
+    /// Returns right away if the configured address is an IP address or a Unix socket, which never
+    /// change.
+    #[tracing::instrument(skip_all)]
+    pub async fn refresh_periodically(self, interval: Duration) {
+        if self.name.parse::<SocketAddr>().is_ok() || self.is_unix() {
+            return;
+        }
+        loop {
+            tokio::time::sleep(interval).await;
+            self.refresh().await;
+        }
+    }
+
+    async fn refresh(&self) {
+        if self.is_unix() {
+            return;
+        }
+        match lookup(&self.name).await.map(PeerAddr::Udp) {
+            Ok(addr) => {
+                let mut current = self.addr.write().unwrap();
+                if *current != addr {
+                    info!("WireGuard address '{}' now resolves to {} (was {})", self.name, addr, *current);
+                    *current = addr;
+                } else {
+                    debug!("WireGuard address '{}' still resolves to {}", self.name, addr);
+                }
+            }
+            Err(err) => warn!("{:#}; still sending to {}", err, self.addr()),
+        }
+    }
+
+    /// Flags WireGuard as dead in `health` once it didn't answer for `timeout` (e.g. after it
+    /// restarted on another port), and recovers by resolving its address again and re-binding the
+    /// sessions' sockets; the flag is cleared as soon as WireGuard answers again
+    #[tracing::instrument(skip_all)]
+    pub async fn watch_liveness(self, timeout: Duration, upstreams: Upstreams, health: Health) {
+        let mut dead = false;
+        loop {
+            tokio::time::sleep(LIVENESS_CHECK_INTERVAL).await;
+            match self.silent_for() {
+                Some(silence) if silence >= timeout && !dead => {
+                    dead = true;
+                    warn!(
+                        monotonic_counter.rengarde_wireguard_dead_total = 1_u64,
+                        "WireGuard at '{}' didn't answer for {:?}; resolving it again and re-binding the sessions' sockets",
+                        self.name, silence
+                    );
+                    health.set_upstream(&self.name, Some(format!("WireGuard at '{}' doesn't answer", self.name)));
+                    self.refresh().await;
+                    upstreams.rebind();
+                }
+                None if dead => {
+                    dead = false;
+                    info!("WireGuard at '{}' answers again", self.name);
+                    health.set_upstream(&self.name, None);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Returns the instant the liveness timestamps are measured from
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+async fn lookup(name: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(name)
+        .await
+        .with_context(|| format!("Destination address '{}' can't be resolved", name))?
+        .next()
+        .ok_or_else(|| anyhow!("Destination address '{}' resolves to no address", name))
+}
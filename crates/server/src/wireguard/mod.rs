@@ -1,4 +1,8 @@
 mod connection;
+mod destination;
 pub mod types;
+mod upstreams;
 
-pub use connection::receive_from_wireguard; 
\ No newline at end of file
+pub use connection::receive_from_wireguard;
+pub use destination::Destination;
+pub use upstreams::Upstreams;
@@ -1,21 +1,25 @@
 use std::time::Duration;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 /// Configuration for WireGuard interface handling
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct WireGuardConfig {
     /// Client timeout in seconds
     pub client_timeout: Duration,
-    /// Write timeout in milliseconds (currently unused)
+    /// Write timeout in milliseconds; zero disables it
     pub write_timeout: Duration,
+    /// Size of the buffer receiving from WireGuard, in bytes
+    pub buffer_size: usize,
 }
 
 impl WireGuardConfig {
     /// Creates a new WireGuard configuration
-    pub fn new(client_timeout_seconds: u64, write_timeout_ms: u64) -> Self {
+    pub fn new(client_timeout_seconds: u64, write_timeout_ms: u64, buffer_size: usize) -> Self {
         Self {
             client_timeout: Duration::from_secs(client_timeout_seconds),
             write_timeout: Duration::from_millis(write_timeout_ms),
+            buffer_size,
         }
     }
 } 
\ No newline at end of file
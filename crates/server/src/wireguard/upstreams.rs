@@ -0,0 +1,129 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::Result;
+use dashmap::DashMap;
+use shared::frame::{Codec, SessionId};
+use shared::datagram::DatagramSocket;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+
+use crate::client::{ClientManager, Sessions};
+use crate::wireguard::{receive_from_wireguard, Destination};
+use crate::wireguard::types::WireGuardConfig;
+
+/// Socket forwarding a session's traffic to WireGuard, and the task sending WireGuard's replies back
+struct Upstream {
+    socket: Arc<DatagramSocket>,
+    task: AbortHandle,
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Sockets forwarding the clients' traffic to WireGuard
+///
+/// Each session gets its own socket, so WireGuard sees every remote site as a distinct endpoint and
+/// its replies only go back to that site; clients without a session share one socket, as engarde
+/// clients expect.
+#[derive(Clone)]
+pub struct Upstreams {
+    shared: Arc<DatagramSocket>,
+    /// Address the sessions' sockets are bound to, on random ports
+    bind_ip: IpAddr,
+    sessions: Arc<DashMap<SessionId, Upstream>>,
+    destination: Destination,
+    client_manager: ClientManager,
+    client_socket: Arc<UdpSocket>,
+    codec: Codec,
+    config: watch::Receiver<WireGuardConfig>,
+}
+
+impl Upstreams {
+    pub fn new(
+        shared: Arc<DatagramSocket>,
+        bind_ip: IpAddr,
+        destination: Destination,
+        client_manager: ClientManager,
+        client_socket: Arc<UdpSocket>,
+        codec: Codec,
+        config: watch::Receiver<WireGuardConfig>,
+    ) -> Self {
+        Self {
+            shared,
+            bind_ip,
+            sessions: Arc::new(DashMap::new()),
+            destination,
+            client_manager,
+            client_socket,
+            codec,
+            config,
+        }
+    }
+
+    /// Returns the socket shared by the clients without a session
+    pub fn shared(&self) -> Arc<DatagramSocket> {
+        self.shared.clone()
+    }
+
+    /// Returns the socket forwarding a session's traffic to WireGuard, opening it (and starting to
+    /// send WireGuard's replies back to the session) on the session's first packet
+    pub fn socket(&self, session_id: Option<SessionId>) -> Result<Arc<DatagramSocket>> {
+        let Some(session_id) = session_id else {
+            return Ok(self.shared.clone());
+        };
+        if let Some(upstream) = self.sessions.get(&session_id) {
+            return Ok(upstream.socket.clone());
+        }
+
+        let socket = if self.destination.is_unix() {
+            DatagramSocket::bind_unix_temporary()?
+        } else {
+            DatagramSocket::bind_udp(SocketAddr::new(self.bind_ip, 0))?
+        };
+        let socket = Arc::new(socket);
+        info!("Forwarding session '{}' to WireGuard from '{}'", session_id, socket.local_addr()?);
+        let task = tokio::spawn({
+            let client_manager = self.client_manager.clone();
+            let socket = socket.clone();
+            let client_socket = self.client_socket.clone();
+            let destination = self.destination.clone();
+            let codec = self.codec.clone();
+            let config = self.config.clone();
+            async move {
+                if let Err(err) = receive_from_wireguard(client_manager, socket, client_socket, destination, codec, config, Some(session_id)).await {
+                    warn!("receive_from_wireguard thread of session '{}' failed: {:?}", session_id, err);
+                }
+            }
+        });
+        self.sessions.insert(session_id, Upstream {
+            socket: socket.clone(),
+            task: task.abort_handle(),
+        });
+        Ok(socket)
+    }
+
+    /// Closes the sockets of every session, so each opens a new one on its next packet
+    pub fn rebind(&self) {
+        if !self.sessions.is_empty() {
+            info!("Closing the WireGuard sockets of {} sessions", self.sessions.len());
+            self.sessions.clear();
+        }
+    }
+
+    /// Closes the sockets of the sessions that ended
+    pub fn cleanup(&self, sessions: &Sessions) {
+        self.sessions.retain(|session_id, _| {
+            let active = sessions.contains_key(session_id);
+            if !active {
+                info!("Closing the WireGuard socket of session '{}'", session_id);
+            }
+            active
+        });
+    }
+}
@@ -0,0 +1,186 @@
+//! Command line of both binaries: `<binary> [OPTIONS] [COMMAND] [CONFIG]`.
+//!
+//! Each binary declares its commands with clap and flattens the [`Args`] shared by all of them, which are accepted
+//! before or after the command. A bare configuration path still works as the only argument, as it did for engarde.
+
+use clap::builder::PossibleValuesParser;
+use clap::builder::TypedValueParser;
+use clap_complete::Shell;
+use tracing_core::LevelFilter;
+
+use crate::LogFormat;
+
+/// Configuration file read when none is given
+pub const DEFAULT_CONFIG: &str = "engarde.yml";
+
+/// Options and configuration file of every command
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct Args {
+    /// Configuration file [default: engarde.yml]
+    #[arg(global = true, value_name = "CONFIG")]
+    config_path: Option<String>,
+    /// Configuration file, instead of CONFIG
+    #[arg(short = 'c', long = "config", global = true, value_name = "PATH", conflicts_with = "config_path")]
+    config_option: Option<String>,
+    /// Log level [default: info, or RUST_LOG]
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "LEVEL",
+        value_parser = PossibleValuesParser::new(["off", "error", "warn", "info", "debug", "trace"])
+            .map(|level| level.parse::<LevelFilter>().expect("the possible values are levels")),
+    )]
+    pub log_level: Option<LevelFilter>,
+    /// Log format
+    #[arg(long, global = true, value_name = "FORMAT", value_enum, default_value_t)]
+    pub log_format: LogFormat,
+    /// Listen address, overriding the configured one
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub listen_addr: Option<String>,
+    /// Reject unknown settings instead of warning about them
+    #[arg(long, global = true)]
+    pub strict: bool,
+    /// Profile of the configuration to use
+    #[arg(short, long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Read the settings engarde interpreted differently the way it did, and report them
+    #[arg(long, global = true)]
+    pub engarde_compat: bool,
+    /// Print the effective configuration, after the defaults and overrides, then exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+}
+
+impl Args {
+    /// Configuration file given with `--config` or as CONFIG, or the default one
+    pub fn config(&self) -> &str {
+        self.config_option.as_deref().or(self.config_path.as_deref()).unwrap_or(DEFAULT_CONFIG)
+    }
+}
+
+/// Prints the completion script of a shell for a binary's command line
+///
+/// The script completes the name the binary was run as, as packages rename it (e.g. `rengarde-client`).
+pub fn print_completions(shell: Shell, command: clap::Command) {
+    let program = std::env::args_os()
+        .next()
+        .and_then(|arg0| Some(std::path::Path::new(&arg0).file_name()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| command.get_name().to_owned());
+    print!("{}", completions(shell, command, &program));
+}
+
+/// Returns the completion script of a shell for a command line, completing `program`
+pub fn completions(shell: Shell, mut command: clap::Command, program: &str) -> String {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, program, &mut script);
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Parser, Subcommand};
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct Cli {
+        #[command(subcommand)]
+        command: Option<Command>,
+        #[command(flatten)]
+        args: Args,
+    }
+
+    #[derive(Debug, PartialEq, Subcommand)]
+    enum Command {
+        CheckConfig,
+        Version {
+            #[arg(long)]
+            json: bool,
+        },
+    }
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("rengarde").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn defines_a_valid_command_line() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn reads_the_default_configuration_without_arguments() {
+        let cli = parse(&[]).unwrap();
+        assert_eq!(cli.command, None);
+        assert_eq!(cli.args.config(), DEFAULT_CONFIG);
+        assert_eq!(cli.args.log_level, None);
+        assert_eq!(cli.args.log_format, LogFormat::Text);
+        assert!(!cli.args.strict && !cli.args.engarde_compat && !cli.args.print_config);
+    }
+
+    #[test]
+    fn takes_the_configuration_as_argument_or_option() {
+        assert_eq!(parse(&["client.yml"]).unwrap().args.config(), "client.yml");
+        assert_eq!(parse(&["--config", "client.yml"]).unwrap().args.config(), "client.yml");
+        assert_eq!(parse(&["-c", "client.yml"]).unwrap().args.config(), "client.yml");
+        assert_eq!(parse(&["--config=client.yml"]).unwrap().args.config(), "client.yml");
+        assert_eq!(parse(&["a.yml", "-c", "b.yml"]).unwrap_err().kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn accepts_the_options_before_or_after_the_command() {
+        for args in [
+            &["--strict", "-l", "debug", "check-config", "client.yml"][..],
+            &["check-config", "client.yml", "--strict", "--log-level", "debug"],
+            &["--log-level=debug", "check-config", "--strict", "-c", "client.yml"],
+        ] {
+            let cli = parse(args).unwrap();
+            assert_eq!(cli.command, Some(Command::CheckConfig), "{:?}", args);
+            assert_eq!(cli.args.config(), "client.yml", "{:?}", args);
+            assert_eq!(cli.args.log_level, Some(LevelFilter::DEBUG), "{:?}", args);
+            assert!(cli.args.strict, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn parses_the_options() {
+        let cli = parse(&[
+            "--log-format", "json", "--listen-addr", "127.0.0.1:59401", "-p", "lte", "--engarde-compat", "--print-config",
+            "version", "--json",
+        ])
+        .unwrap();
+        assert_eq!(cli.command, Some(Command::Version { json: true }));
+        assert_eq!(cli.args.log_format, LogFormat::Json);
+        assert_eq!(cli.args.listen_addr.as_deref(), Some("127.0.0.1:59401"));
+        assert_eq!(cli.args.profile.as_deref(), Some("lte"));
+        assert!(cli.args.engarde_compat && cli.args.print_config);
+        assert_eq!(parse(&["-l", "off"]).unwrap().args.log_level, Some(LevelFilter::OFF));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        use clap::error::ErrorKind;
+
+        assert_eq!(parse(&["--unknown"]).unwrap_err().kind(), ErrorKind::UnknownArgument);
+        assert_eq!(parse(&["a.yml", "b.yml"]).unwrap_err().kind(), ErrorKind::UnknownArgument);
+        assert_eq!(parse(&["--log-level", "loud"]).unwrap_err().kind(), ErrorKind::InvalidValue);
+        assert_eq!(parse(&["--log-format", "xml"]).unwrap_err().kind(), ErrorKind::InvalidValue);
+        assert_eq!(parse(&["--config"]).unwrap_err().kind(), ErrorKind::InvalidValue);
+        // Options of another command
+        assert_eq!(parse(&["check-config", "--json"]).unwrap_err().kind(), ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn prints_the_help() {
+        use clap::error::ErrorKind;
+
+        assert_eq!(parse(&["--help"]).unwrap_err().kind(), ErrorKind::DisplayHelp);
+        assert_eq!(parse(&["check-config", "-h"]).unwrap_err().kind(), ErrorKind::DisplayHelp);
+        let help = parse(&["--help"]).unwrap_err().to_string();
+        for option in ["--config", "--log-level", "--log-format", "--listen-addr", "--strict", "--profile", "--print-config"] {
+            assert!(help.contains(option), "{} missing from the help", option);
+        }
+    }
+}
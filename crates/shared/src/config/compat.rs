@@ -0,0 +1,111 @@
+//! Compatibility with the configuration files of the Go engarde (`--engarde-compat`), so its binaries can be replaced
+//! without touching their configuration first.
+//!
+//! rengarde reads engarde files as they are, except for the few settings engarde interpreted differently. In
+//! compatibility mode these are rewritten the way engarde read them, and every rewrite is reported with its rengarde
+//! equivalent, so the file can be migrated once and the mode dropped:
+//!
+//! - `writeTimeout: -1` disabled the write timeout, which rengarde does with `0`, and `0` meant the default.
+//! - `client.dstOverrides`, a list of `ifName` and `dstAddr`, set the server address of specific interfaces, which
+//!   rengarde does with `client.interfaces.<name>.dstAddr`.
+//! - `client.excludedInterfaces` only held interface names, which rengarde reads as globs when they contain `*` or `?`
+//!   and as regular expressions when they start with `^`: such names are matched exactly again.
+
+use serde_json::{Map, Value};
+
+/// Setting of an engarde configuration file that rengarde reads differently, and how it was rewritten
+#[derive(Debug)]
+pub struct Migration {
+    /// Path of the setting, e.g. `client.writeTimeout`
+    pub path: String,
+    /// What engarde meant, and how to write it for rengarde
+    pub note: String,
+}
+
+impl std::fmt::Display for Migration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}': {}", self.path, self.note)
+    }
+}
+
+/// Rewrites the settings of `sections` engarde interpreted differently the way it did, returning the report of the
+/// rewrites
+pub fn migrate(settings: &mut Value, sections: &[String]) -> Vec<Migration> {
+    let mut migrations = Vec::new();
+    for section in sections {
+        let Some(settings) = settings.get_mut(section).and_then(Value::as_object_mut) else {
+            continue;
+        };
+        write_timeout(section, settings, &mut migrations);
+        if section == "client" {
+            dst_overrides(settings, &mut migrations);
+            excluded_interfaces(settings, &mut migrations);
+        }
+    }
+    migrations
+}
+
+fn write_timeout(section: &str, settings: &mut Map<String, Value>, migrations: &mut Vec<Migration>) {
+    let path = format!("{}.writeTimeout", section);
+    match settings.get("writeTimeout").and_then(Value::as_i64) {
+        Some(-1) => {
+            settings.insert("writeTimeout".to_owned(), Value::from(0));
+            migrations.push(Migration { path, note: "-1 disabled the write timeout; write 0 instead".to_owned() });
+        }
+        Some(0) => {
+            // Removed, so the defaults fill it
+            settings.remove("writeTimeout");
+            migrations.push(Migration { path, note: "0 meant the default; remove it, as 0 disables the write timeout".to_owned() });
+        }
+        _ => {}
+    }
+}
+
+fn dst_overrides(settings: &mut Map<String, Value>, migrations: &mut Vec<Migration>) {
+    let Some(overrides) = settings.remove("dstOverrides") else {
+        return;
+    };
+    let interfaces = settings.entry("interfaces").or_insert_with(|| Value::Object(Map::new()));
+    for (index, dst_override) in overrides.as_array().into_iter().flatten().enumerate() {
+        let path = format!("client.dstOverrides.{}", index);
+        let (Some(ifname), Some(dst_addr)) = (dst_override.get("ifName").and_then(Value::as_str), dst_override.get("dstAddr")) else {
+            migrations.push(Migration { path, note: "needs both 'ifName' and 'dstAddr'; ignoring it".to_owned() });
+            continue;
+        };
+        if let Some(interfaces) = interfaces.as_object_mut() {
+            let interface = interfaces.entry(ifname).or_insert_with(|| Value::Object(Map::new()));
+            if let Some(interface) = interface.as_object_mut() {
+                interface.insert("dstAddr".to_owned(), dst_addr.clone());
+            }
+        }
+        migrations.push(Migration { path, note: format!("write 'client.interfaces.{}.dstAddr' instead", ifname) });
+    }
+}
+
+fn excluded_interfaces(settings: &mut Map<String, Value>, migrations: &mut Vec<Migration>) {
+    let Some(names) = settings.get_mut("excludedInterfaces").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for (index, name) in names.iter_mut().enumerate() {
+        let Some(ifname) = name.as_str().filter(|name| name.starts_with('^') || name.contains(['*', '?'])) else {
+            continue;
+        };
+        let pattern = format!("^{}$", escape(ifname));
+        migrations.push(Migration {
+            path: format!("client.excludedInterfaces.{}", index),
+            note: format!("'{}' was an interface name, not a pattern; write '{}' instead", ifname, pattern),
+        });
+        *name = Value::String(pattern);
+    }
+}
+
+/// Escapes the characters regular expressions give a meaning to
+fn escape(name: &str) -> String {
+    name.chars().fold(String::new(), |mut escaped, c| {
+        if r"\.+*?()|[]{}^$-#&~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
@@ -0,0 +1,711 @@
+//! Configuration files, in YAML (the default), JSON (`.json`) or TOML (`.toml`) depending on their extension, so
+//! they can be templated by tools that emit JSON or TOML natively.
+//!
+//! `RENGARDE_*` environment variables override the settings of the file, so containers don't need to bake it into
+//! their image: `RENGARDE_<SECTION>_<SETTING>` sets `<section>.<setting>`, with the setting in upper snake case and
+//! double underscores between nested settings, e.g. `RENGARDE_SERVER_LISTEN_ADDR` sets `server.listenAddr` and
+//! `RENGARDE_SERVER_WEB_MANAGER__LISTEN_ADDR` sets `server.webManager.listenAddr`. Values are parsed as YAML (e.g.
+//! `30`, `true`, `[eth0, wwan0]` or `{eth0: 10}`), except for settings the file sets to a string.
+//!
+//! A file can include others with `include:`, a path or a list of paths relative to it, which may be globs (e.g.
+//! `conf.d/*.yml`). The included files are merged in order, then the including file over them: tables are merged
+//! setting by setting, and other settings, lists included, are replaced. Site-common settings can so live in a file
+//! shared by every host, and each host's file only overrides what differs.
+//!
+//! The configuration is layered, each layer overriding the previous ones: the defaults, the file, the environment,
+//! then the command line.
+//!
+//! A section can hold named profiles under `profiles`, e.g. `home`, `hotel` and `tether` for a travelling client,
+//! each a table of the section's settings. `--profile <NAME>` merges one over its section, between the file and the
+//! environment, so the variants of a configuration live in one file.
+//!
+//! With `--engarde-compat`, the settings the Go engarde interpreted differently are read the way it did (see
+//! [`compat`]).
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::{error, info, warn};
+
+pub mod compat;
+pub mod schema;
+mod strict;
+
+/// Prefix of the environment variables overriding settings
+pub const ENV_PREFIX: &str = "RENGARDE_";
+
+/// Top-level setting listing the files a configuration file includes
+const INCLUDE: &str = "include";
+
+/// Setting of a section holding its named profiles
+const PROFILES: &str = "profiles";
+
+/// Layers of the configuration around its file
+#[derive(Debug, Default)]
+pub struct Layers {
+    /// Defaults of the settings, under the file. Their tables only fill the tables the configuration has, so optional
+    /// sections (e.g. `autoBan`) get their defaults without being enabled.
+    pub defaults: Value,
+    /// `RENGARDE_*` environment variables overriding the settings, see [`environment`]
+    pub environment: Vec<(String, String)>,
+    /// Settings given on the command line, by path (e.g. `server.listenAddr`), over the environment
+    pub command_line: Vec<(String, Value)>,
+    /// Whether unknown settings fail the load
+    pub strict: bool,
+    /// Whether the file is read the way engarde read it
+    pub engarde_compat: bool,
+    /// Profile merged over its section
+    pub profile: Option<String>,
+}
+
+/// Reads and parses the configuration file at `path`, in the format its extension selects, and layers it over the
+/// defaults and under the environment and the command line
+///
+/// Unknown settings, usually typos, are logged with the closest known one; if `layers.strict`, they fail the load too.
+/// If `layers.engarde_compat`, the settings engarde interpreted differently are rewritten and reported.
+/// If `layers.profile` is set, the profile is merged over the sections defining it.
+pub fn load<T: DeserializeOwned + JsonSchema>(path: &str, layers: Layers) -> Result<T> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read configuration file '{}'", path))?;
+    let settings = parse(path, &contents).with_context(|| format!("Invalid configuration file '{}'", path))?;
+    let including = Path::new(path).canonicalize().unwrap_or_else(|_| PathBuf::from(path));
+    let mut sources = Vec::new();
+    let mut settings = include(Path::new(path), contents, settings, &mut vec![including], &mut sources)?;
+    if layers.engarde_compat {
+        let migrations = compat::migrate(&mut settings, &sections::<T>());
+        if !migrations.is_empty() {
+            warn!("{} setting(s) of '{}' read the way engarde did; migrate them to drop --engarde-compat:", migrations.len(), path);
+        }
+        for migration in &migrations {
+            warn!("  {}", migration);
+        }
+    }
+    if let Some(profile) = &layers.profile {
+        select_profile(&mut settings, &sections::<T>(), profile, path)?;
+    }
+    fill_defaults(&mut settings, &layers.defaults, &mut Vec::new());
+
+    let mut overrides = layers.environment;
+    overrides.sort();
+    for (name, value) in overrides {
+        if let Some(path) = apply_override(&mut settings, &name, value)? {
+            sources.push(strict::Source::Override { name: format!("environment variable {}", name), path });
+        }
+    }
+    for (setting, value) in layers.command_line {
+        let path = setting.split('.').map(str::to_owned).collect::<Vec<_>>();
+        set(&mut settings, &path, value, "the command line")
+            .with_context(|| format!("Command line setting '{}' doesn't apply", setting))?;
+        sources.push(strict::Source::Override { name: "the command line".to_owned(), path });
+    }
+    // Default the settings the environment or the command line emptied too
+    fill_defaults(&mut settings, &layers.defaults, &mut Vec::new());
+    let strict = layers.strict;
+
+    let unknown = strict::unknown_settings::<T>(&settings, &sources);
+    for setting in &unknown {
+        if strict {
+            error!("{}", setting);
+        } else {
+            warn!("{}; ignoring it", setting);
+        }
+    }
+    if strict && !unknown.is_empty() {
+        let paths = unknown.iter().map(strict::UnknownSetting::located_path).collect::<Vec<_>>();
+        bail!("Invalid configuration: {} unknown setting(s): {}", paths.len(), paths.join(", "));
+    }
+
+    // Name the setting a type error comes from, as the parsed value no longer knows its line
+    serde_path_to_error::deserialize(settings).with_context(|| format!("Invalid configuration file '{}'", path))
+}
+
+/// Returns the `RENGARDE_*` variables of the process environment, overriding the settings
+pub fn environment() -> Vec<(String, String)> {
+    std::env::vars().filter(|(name, _)| name.starts_with(ENV_PREFIX)).collect()
+}
+
+/// Sets a secret setting from the file its `<setting>File` variant names (e.g. a systemd credential or a mounted
+/// Kubernetes secret), without the trailing newline
+pub fn load_secret(setting: &str, secret: &mut Option<String>, file: Option<&str>) -> Result<()> {
+    let Some(file) = file else {
+        return Ok(());
+    };
+    if secret.is_some() {
+        bail!("Both '{0}' and '{0}File' are set; keep only one", setting);
+    }
+    let contents = std::fs::read_to_string(file).with_context(|| format!("Failed to read '{}File' '{}'", setting, file))?;
+    *secret = Some(contents.trim_end_matches(['\r', '\n']).to_owned());
+    Ok(())
+}
+
+/// Writes a commented example configuration to `path`, or to the standard output if it's `-`, without overwriting an
+/// existing file
+pub fn write_example(path: &str, example: &str) -> Result<()> {
+    if path == "-" {
+        print!("{}", example);
+        return Ok(());
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, example.as_bytes()))
+        .with_context(|| format!("Failed to write example configuration '{}'", path))?;
+    info!("Example configuration written to '{}'", path);
+    Ok(())
+}
+
+fn parse(path: &str, contents: &str) -> Result<Value> {
+    let settings = match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(contents)?,
+        Some("toml") => toml::from_str(contents)?,
+        _ => serde_yaml::from_str(contents)?,
+    };
+    // An empty YAML file is null
+    Ok(match settings {
+        Value::Null => Value::Object(Map::new()),
+        settings => settings,
+    })
+}
+
+/// Returns the settings of the file at `path`, parsed from `contents`, merged over the files it includes, which include
+/// none of the files in `including`
+///
+/// The files are added to `sources` in the order they're merged in.
+fn include(
+    path: &Path,
+    contents: String,
+    mut settings: Value,
+    including: &mut Vec<PathBuf>,
+    sources: &mut Vec<strict::Source>,
+) -> Result<Value> {
+    let source = |settings: &Value| strict::Source::File {
+        path: path.display().to_string(),
+        contents,
+        settings: settings.clone(),
+    };
+    let Some(includes) = settings.as_object_mut().and_then(|settings| settings.remove(INCLUDE)) else {
+        sources.push(source(&settings));
+        return Ok(settings);
+    };
+    let patterns = match includes {
+        Value::String(pattern) => vec![pattern],
+        Value::Array(patterns) => patterns
+            .into_iter()
+            .map(|pattern| match pattern {
+                Value::String(pattern) => Ok(pattern),
+                _ => bail!("'{}' of '{}' must list paths", INCLUDE, path.display()),
+            })
+            .collect::<Result<_>>()?,
+        _ => bail!("'{}' of '{}' must be a path or a list of paths", INCLUDE, path.display()),
+    };
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut merged = Value::Object(Map::new());
+    for pattern in patterns {
+        let pattern = dir.join(pattern).to_string_lossy().into_owned();
+        let mut paths = glob::glob(&pattern)
+            .with_context(|| format!("Invalid include '{}' in '{}'", pattern, path.display()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        // A glob may match nothing, e.g. an empty `conf.d`, but a path must exist
+        if paths.is_empty() && !pattern.contains(['*', '?', '[']) {
+            bail!("File '{}' included by '{}' doesn't exist", pattern, path.display());
+        }
+        paths.sort();
+        for included in paths {
+            let canonical = included.canonicalize().unwrap_or_else(|_| included.clone());
+            if including.contains(&canonical) {
+                bail!("Including '{}' from '{}' loops", included.display(), path.display());
+            }
+            let contents = std::fs::read_to_string(&included)
+                .with_context(|| format!("Failed to read configuration file '{}'", included.display()))?;
+            let settings = parse(&included.to_string_lossy(), &contents)
+                .with_context(|| format!("Invalid configuration file '{}'", included.display()))?;
+            including.push(canonical);
+            let settings = include(&included, contents, settings, including, sources)?;
+            including.pop();
+            info!("Including configuration file '{}'", included.display());
+            merge(&mut merged, settings);
+        }
+    }
+    sources.push(source(&settings));
+    merge(&mut merged, settings);
+    Ok(merged)
+}
+
+/// Returns the sections of `T`, as a file may also hold the other binary's
+fn sections<T: JsonSchema>() -> Vec<String> {
+    let schema = schema::generate::<T>("");
+    schema.get("properties").and_then(Value::as_object).map(|sections| sections.keys().cloned().collect()).unwrap_or_default()
+}
+
+/// Merges the profile `name` over the sections of `sections` defining it
+fn select_profile(settings: &mut Value, sections: &[String], name: &str, path: &str) -> Result<()> {
+    let mut names = Vec::new();
+    let mut selected = false;
+    for section in sections {
+        let Some(settings) = settings.get_mut(section) else {
+            continue;
+        };
+        let Some(profiles) = settings.get(PROFILES).and_then(Value::as_object) else {
+            continue;
+        };
+        names.extend(profiles.keys().map(|name| format!("'{}'", name)));
+        if let Some(profile) = profiles.get(name).cloned() {
+            info!("Using profile '{}' of '{}'", name, section);
+            merge(settings, profile);
+            selected = true;
+        }
+    }
+    if !selected {
+        if names.is_empty() {
+            bail!("Profile '{}' not found: '{}' defines no profiles", name, path);
+        }
+        bail!("Profile '{}' not found in '{}' (expected {})", name, path, names.join(", "));
+    }
+    Ok(())
+}
+
+/// Merges `settings` over `base`, setting by setting in tables
+fn merge(base: &mut Value, settings: Value) {
+    match (base, settings) {
+        (Value::Object(base), Value::Object(settings)) => {
+            for (key, setting) in settings {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, setting),
+                    None => {
+                        base.insert(key, setting);
+                    }
+                }
+            }
+        }
+        (base, settings) => *base = settings,
+    }
+}
+
+/// Fills the settings missing from `settings`, or set to null, with their defaults, and the tables it has with theirs
+fn fill_defaults(settings: &mut Value, defaults: &Value, path: &mut Vec<String>) {
+    let (Value::Object(settings), Value::Object(defaults)) = (settings, defaults) else {
+        return;
+    };
+    for (key, default) in defaults {
+        path.push(key.clone());
+        match settings.get_mut(key) {
+            // An empty setting (`~` or `null`, or an empty environment variable) is unset
+            Some(Value::Null) | None if !default.is_object() => {
+                info!("Setting '{}' not set; defaulting to {}", path.join("."), default);
+                settings.insert(key.clone(), default.clone());
+            }
+            Some(setting) => fill_defaults(setting, default, path),
+            None => {}
+        }
+        path.pop();
+    }
+}
+
+/// Sets the setting the environment variable `name` references to `value`, returning its path unless it references
+/// none
+fn apply_override(settings: &mut Value, name: &str, value: String) -> Result<Option<Vec<String>>> {
+    let Some((section, setting)) = name[ENV_PREFIX.len()..].split_once('_') else {
+        return Ok(None);
+    };
+    let mut path = vec![section.to_lowercase()];
+    path.extend(setting.split("__").map(camel_case));
+    if path.iter().any(String::is_empty) {
+        bail!("Environment variable {} doesn't reference a setting", name);
+    }
+
+    let is_string = path.iter().try_fold(&*settings, |table, key| table.get(key)).is_some_and(Value::is_string);
+    let value = if is_string {
+        Value::String(value)
+    } else {
+        serde_yaml::from_str(&value).unwrap_or(Value::String(value))
+    };
+    set(settings, &path, value, name).with_context(|| format!("Environment variable {} doesn't apply", name))?;
+    Ok(Some(path))
+}
+
+/// Sets the setting at `path` to `value`, creating the tables it's in, on behalf of `source`
+fn set(settings: &mut Value, path: &[String], value: Value, source: &str) -> Result<()> {
+    let (key, tables) = path.split_last().unwrap();
+    let mut table = settings;
+    for (depth, key) in tables.iter().enumerate() {
+        let Value::Object(map) = table else {
+            bail!("'{}' isn't a table", path[..depth].join("."));
+        };
+        table = map.entry(key.clone()).or_insert_with(|| Value::Object(Map::new()));
+    }
+    let Value::Object(table) = table else {
+        bail!("'{}' isn't a table", tables.join("."));
+    };
+
+    info!("Setting '{}' overridden by {}", path.join("."), source);
+    table.insert(key.clone(), value);
+    Ok(())
+}
+
+/// Prints the effective settings as YAML, without the unset ones and with the secrets masked
+pub fn print<T: Serialize>(settings: &T) -> Result<()> {
+    let mut settings = serde_json::to_value(settings)?;
+    clean(&mut settings);
+    print!("{}", serde_yaml::to_string(&settings)?);
+    Ok(())
+}
+
+/// Settings holding secrets, masked when printed
+const SECRETS: &[&str] = &["psk", "encryptionKey", "password"];
+
+fn clean(settings: &mut Value) {
+    if let Value::Object(map) = settings {
+        map.retain(|_, value| !value.is_null());
+        for (key, value) in map {
+            if SECRETS.contains(&key.as_str()) && value.is_string() {
+                *value = Value::String("********".to_owned());
+            }
+            clean(value);
+        }
+    } else if let Value::Array(items) = settings {
+        items.iter_mut().for_each(clean);
+    }
+}
+
+/// Converts an upper snake case name (`LISTEN_ADDR`) to the camel case of the settings (`listenAddr`)
+fn camel_case(name: &str) -> String {
+    let mut words = name.split('_').filter(|word| !word.is_empty()).map(str::to_lowercase);
+    let mut camel_case = words.next().unwrap_or_default();
+    for word in words {
+        let mut chars = word.chars();
+        camel_case.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+        camel_case.push_str(chars.as_str());
+    }
+    camel_case
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct Settings {
+        layered: Option<Section>,
+    }
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct Section {
+        listen_addr: Option<String>,
+        write_timeout: Option<u64>,
+        retries: Option<u64>,
+        buffer_size: Option<u64>,
+        mtu: Option<u64>,
+        interfaces: Option<Vec<String>>,
+        web_manager: Option<WebManager>,
+        auto_ban: Option<AutoBan>,
+    }
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct WebManager {
+        listen_addr: Option<String>,
+        username: Option<String>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct AutoBan {
+        ban_time: Option<u64>,
+    }
+
+    /// Files written to a directory of their own, removed when dropped
+    struct TestFiles {
+        dir: PathBuf,
+        /// The path of the first file
+        path: String,
+    }
+
+    impl Drop for TestFiles {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn write_files(test: &str, files: &[(&str, &str)]) -> TestFiles {
+        let dir = std::env::temp_dir().join(format!("rengarde-config-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        let path = dir.join(files[0].0).to_string_lossy().into_owned();
+        TestFiles { dir, path }
+    }
+
+    fn defaults() -> Value {
+        json!({
+            "layered": {
+                "listenAddr": "0.0.0.0:1",
+                "writeTimeout": 10,
+                "retries": 3,
+                "bufferSize": 1500,
+                "mtu": 1420,
+                "webManager": { "username": "admin" },
+                "autoBan": { "banTime": 60 },
+            },
+        })
+    }
+
+    #[test]
+    fn layers_the_defaults_file_profile_environment_and_command_line() {
+        let files = write_files(
+            "layers",
+            &[
+                (
+                    "engarde.yml",
+                    "include: common.yml\n\
+                     layered:\n  \
+                       listenAddr: 0.0.0.0:2\n  \
+                       retries: 6\n  \
+                       bufferSize: ~\n  \
+                       interfaces: [eth0]\n  \
+                       webManager: { listenAddr: 127.0.0.1:9001 }\n  \
+                       profiles:\n    \
+                         lte: { retries: 7 }\n",
+                ),
+                ("common.yml", "layered:\n  writeTimeout: 20\n  retries: 5\n  interfaces: [eth0, wwan0]\n"),
+            ],
+        );
+        let layers = Layers {
+            defaults: defaults(),
+            environment: vec![
+                ("RENGARDE_LAYERED_LISTEN_ADDR".to_owned(), "0.0.0.0:3".to_owned()),
+                ("RENGARDE_LAYERED_WRITE_TIMEOUT".to_owned(), "40".to_owned()),
+                ("RENGARDE_LAYERED_MTU".to_owned(), String::new()),
+            ],
+            command_line: vec![("layered.listenAddr".to_owned(), json!("0.0.0.0:4"))],
+            strict: true,
+            profile: Some("lte".to_owned()),
+            ..Layers::default()
+        };
+        let settings = load::<Settings>(&files.path, layers);
+
+        let section = settings.unwrap().layered.unwrap();
+        // The command line over the environment, over the file, over the defaults
+        assert_eq!(section.listen_addr.as_deref(), Some("0.0.0.0:4"));
+        // The environment over the included file
+        assert_eq!(section.write_timeout, Some(40));
+        // The profile over the file, over the included file
+        assert_eq!(section.retries, Some(7));
+        // Lists are replaced rather than merged
+        assert_eq!(section.interfaces, Some(vec!["eth0".to_owned()]));
+        // Settings emptied by the file or the environment are defaulted
+        assert_eq!(section.buffer_size, Some(1500));
+        assert_eq!(section.mtu, Some(1420));
+        // Tables get their defaults, but only the ones the file has
+        let web_manager = section.web_manager.unwrap();
+        assert_eq!(web_manager.listen_addr.as_deref(), Some("127.0.0.1:9001"));
+        assert_eq!(web_manager.username.as_deref(), Some("admin"));
+        assert!(section.auto_ban.is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_settings_if_strict() {
+        let files = write_files("strict", &[("engarde.yml", "layered:\n  listenAddr: 0.0.0.0:2\n  writeTimout: 20\n")]);
+        let layers = || Layers { defaults: defaults(), ..Layers::default() };
+        assert_eq!(load::<Settings>(&files.path, layers()).unwrap().layered.unwrap().retries, Some(3));
+
+        let err = load::<Settings>(&files.path, Layers { strict: true, ..layers() }).unwrap_err().to_string();
+        assert!(err.contains("'layered.writeTimout' ("), "{}", err);
+        assert!(err.contains("engarde.yml:3)"), "{}", err);
+    }
+
+    #[test]
+    fn locates_the_unknown_settings_in_their_layer() {
+        let files = write_files(
+            "locate",
+            &[
+                ("engarde.yml", "include: common.yml
+layered:
+  retries: 6
+"),
+                ("common.yml", "layered:
+  retries: 5
+  bufferSise: 1500
+"),
+            ],
+        );
+        let layers = Layers {
+            defaults: defaults(),
+            environment: vec![("RENGARDE_LAYERED_RETRYS".to_owned(), "4".to_owned())],
+            strict: true,
+            ..Layers::default()
+        };
+        let err = load::<Settings>(&files.path, layers).unwrap_err().to_string();
+        assert!(err.contains("'layered.bufferSise' ("), "{}", err);
+        assert!(err.contains("common.yml:3)"), "{}", err);
+        assert!(err.contains("'layered.retrys' (environment variable RENGARDE_LAYERED_RETRYS)"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unknown_profiles() {
+        let files = write_files("profiles", &[("engarde.yml", "layered:\n  profiles:\n    lte: { retries: 7 }\n")]);
+        let err = load::<Settings>(&files.path, Layers { profile: Some("wifi".to_owned()), ..Layers::default() }).unwrap_err();
+        assert!(err.to_string().contains("(expected 'lte')"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_include_loops() {
+        let files = write_files("loop", &[("a.yml", "include: b.yml\n"), ("b.yml", "include: [a.yml]\n")]);
+        let err = load::<Settings>(&files.path, Layers::default()).unwrap_err();
+        assert!(err.to_string().contains("loops"), "{}", err);
+    }
+
+    #[test]
+    fn fills_the_unset_and_null_settings_with_their_defaults() {
+        let mut settings = json!({ "layered": { "retries": 6, "bufferSize": null, "autoBan": null } });
+        fill_defaults(&mut settings, &defaults(), &mut Vec::new());
+        assert_eq!(settings["layered"]["retries"], 6);
+        assert_eq!(settings["layered"]["bufferSize"], 1500);
+        assert_eq!(settings["layered"]["writeTimeout"], 10);
+        // A null table keeps its optional section disabled
+        assert_eq!(settings["layered"]["autoBan"], Value::Null);
+        assert!(settings["layered"].get("webManager").is_none());
+    }
+
+    #[test]
+    fn applies_environment_overrides() {
+        let mut settings = json!({ "layered": { "listenAddr": "0.0.0.0:1", "webManager": {} } });
+        let mut apply = |name: &str, value: &str| apply_override(&mut settings, name, value.to_owned());
+        apply("RENGARDE_LAYERED_WRITE_TIMEOUT", "30").unwrap();
+        apply("RENGARDE_LAYERED_INTERFACES", "[eth0, wwan0]").unwrap();
+        apply("RENGARDE_LAYERED_QUOTAS", "{eth0: 10}").unwrap();
+        apply("RENGARDE_LAYERED_WEB_MANAGER__LISTEN_ADDR", "127.0.0.1:9001").unwrap();
+        apply("RENGARDE_LAYERED_AUTO_BAN__BAN_TIME", "60").unwrap();
+        apply("RENGARDE_LAYERED", "ignored").unwrap();
+        assert_eq!(
+            settings,
+            json!({
+                "layered": {
+                    "listenAddr": "0.0.0.0:1",
+                    "writeTimeout": 30,
+                    "interfaces": ["eth0", "wwan0"],
+                    "quotas": { "eth0": 10 },
+                    "webManager": { "listenAddr": "127.0.0.1:9001" },
+                    "autoBan": { "banTime": 60 },
+                },
+            })
+        );
+
+        // Settings the file sets to a string stay strings
+        let mut settings = json!({ "layered": { "psk": "secret" } });
+        apply_override(&mut settings, "RENGARDE_LAYERED_PSK", "1234".to_owned()).unwrap();
+        assert_eq!(settings["layered"]["psk"], "1234");
+        apply_override(&mut settings, "RENGARDE_LAYERED_WEB_MANAGER", "true".to_owned()).unwrap();
+        assert_eq!(settings["layered"]["webManager"], true);
+
+        assert!(apply_override(&mut settings, "RENGARDE_LAYERED_WEB_MANAGER__LISTEN_ADDR", "a".to_owned()).is_err());
+        assert!(apply_override(&mut settings, "RENGARDE_LAYERED_WEB__", "a".to_owned()).is_err());
+        assert!(apply_override(&mut settings, "RENGARDE__PSK", "a".to_owned()).is_err());
+    }
+
+    #[test]
+    fn converts_environment_names_to_camel_case() {
+        assert_eq!(camel_case("LISTEN_ADDR"), "listenAddr");
+        assert_eq!(camel_case("PSK"), "psk");
+        assert_eq!(camel_case("DST_ADDR_"), "dstAddr");
+        assert_eq!(camel_case("UPSTREAM_TIMEOUT_MS"), "upstreamTimeoutMs");
+        assert_eq!(camel_case(""), "");
+    }
+
+    #[test]
+    fn prints_the_set_settings_with_the_secrets_masked() {
+        let mut settings = json!({
+            "server": {
+                "listenAddr": "0.0.0.0:59401",
+                "psk": "pre-shared key",
+                "encryptionKey": "encryption key",
+                "pskFile": null,
+                "webManager": { "username": "admin", "password": "hunter2" },
+                "tunnels": [{ "listenAddr": "0.0.0.0:59402", "psk": "tunnel key", "dstAddr": null }],
+            },
+        });
+        clean(&mut settings);
+        assert_eq!(
+            settings,
+            json!({
+                "server": {
+                    "listenAddr": "0.0.0.0:59401",
+                    "psk": "********",
+                    "encryptionKey": "********",
+                    "webManager": { "username": "admin", "password": "********" },
+                    "tunnels": [{ "listenAddr": "0.0.0.0:59402", "psk": "********" }],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn parses_toml_like_yaml_and_json() {
+        let toml = r#"
+            include = ["common.toml"]
+
+            [server]
+            listenAddr = "0.0.0.0:59401"
+            clientTimeout = 30
+            webManager.listenAddr = "127.0.0.1:9001"
+            "quoted.key" = 'C:\literal'
+            description = "tab\tand \u00e9"
+
+            [[server.tunnels]]
+            listenAddr = "0.0.0.0:59402"
+
+            [[server.tunnels]]
+            listenAddr = "0.0.0.0:59403"
+            lowMemory = true
+        "#;
+        let expected = json!({
+            "include": ["common.toml"],
+            "server": {
+                "listenAddr": "0.0.0.0:59401",
+                "clientTimeout": 30,
+                "webManager": { "listenAddr": "127.0.0.1:9001" },
+                "quoted.key": "C:\\literal",
+                "description": "tab\tand é",
+                "tunnels": [
+                    { "listenAddr": "0.0.0.0:59402" },
+                    { "listenAddr": "0.0.0.0:59403", "lowMemory": true },
+                ],
+            },
+        });
+        assert_eq!(parse("engarde.toml", toml).unwrap(), expected);
+        assert_eq!(parse("engarde.json", &expected.to_string()).unwrap(), expected);
+        assert_eq!(parse("engarde.yml", &serde_yaml::to_string(&expected).unwrap()).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_invalid_toml_with_its_line() {
+        for (toml, line) in [
+            ("[server]\nlistenAddr = \"a\"\n[server]\n", 3),
+            ("[server]\nwebManager.listenAddr = \"a\"\n[server.webManager]\n", 3),
+            ("[server]\nlistenAddr = \"a\"\nlistenAddr = \"b\"\n", 3),
+            ("[server]\ndescription = \"\\u+0e9\"\n", 2),
+            ("[server]\nlistenAddr = \n", 2),
+        ] {
+            let err = parse("engarde.toml", toml).unwrap_err().to_string();
+            assert!(err.contains(&format!("line {}", line)), "{:?} failed with: {}", toml, err);
+        }
+    }
+
+    #[test]
+    fn reads_empty_files_as_empty_tables() {
+        assert_eq!(parse("engarde.yml", "").unwrap(), json!({}));
+        assert_eq!(parse("engarde.toml", "").unwrap(), json!({}));
+    }
+}
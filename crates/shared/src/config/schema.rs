@@ -0,0 +1,84 @@
+//! JSON Schema of the configuration files, derived with schemars from the settings so it can't drift from them.
+//!
+//! Nested settings are inlined rather than referenced, so the schema of each setting is found by following
+//! `properties`, `additionalProperties` and `items` from the root. The settings are documented with plain comments,
+//! so the schema only describes the types that have doc comments.
+
+use schemars::generate::SchemaSettings;
+use schemars::JsonSchema;
+use serde_json::Value;
+
+/// Returns the JSON Schema of the configuration files deserialized into `T`
+pub fn generate<T: JsonSchema>(title: &str) -> Value {
+    let generator = SchemaSettings::draft2020_12()
+        .with(|settings| {
+            settings.inline_subschemas = true;
+        })
+        .into_generator();
+    let mut schema = generator.into_root_schema_for::<T>().to_value();
+    if let Value::Object(schema) = &mut schema {
+        schema.insert("title".to_owned(), Value::from(title));
+    }
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct Section {
+        listen_addr: String,
+        write_timeout: Option<u64>,
+        #[serde(default)]
+        interfaces: Vec<String>,
+        quotas: HashMap<String, u64>,
+        nested: Option<Nested>,
+        mode: Option<Mode>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct Nested {
+        usage_file: String,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(rename_all = "kebab-case")]
+    enum Mode {
+        RoundRobin,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    struct Settings {
+        section: Section,
+    }
+
+    #[test]
+    fn inlines_every_setting_under_its_serde_name() {
+        let schema = generate::<Settings>("test configuration");
+        assert_eq!(schema["title"], "test configuration");
+        assert!(schema.pointer("/$defs").is_none());
+
+        let section = &schema["properties"]["section"];
+        let mut settings = section["properties"].as_object().unwrap().keys().collect::<Vec<_>>();
+        settings.sort();
+        assert_eq!(settings, ["interfaces", "listenAddr", "mode", "nested", "quotas", "writeTimeout"]);
+        assert_eq!(section["required"], json!(["listenAddr", "quotas"]));
+        assert_eq!(section["properties"]["nested"]["properties"]["usageFile"]["type"], "string");
+        assert_eq!(section["properties"]["nested"]["required"], json!(["usageFile"]));
+        assert_eq!(section["properties"]["quotas"]["additionalProperties"]["type"], "integer");
+        assert_eq!(section["properties"]["interfaces"]["items"]["type"], "string");
+        assert!(section["properties"]["mode"].to_string().contains("round-robin"));
+    }
+}
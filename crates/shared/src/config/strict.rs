@@ -0,0 +1,190 @@
+//! Detection of the settings the binaries don't know, which serde otherwise ignores silently: a typo like
+//! `writeTimout` would leave `writeTimeout` to its default without a word.
+//!
+//! The configuration is matched against its JSON Schema. Only the sections' settings are checked, as the top level
+//! holds the sections of both binaries, and the profiles of a section are checked like the section itself.
+
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use super::{schema, PROFILES};
+
+/// Layer of the configuration setting some of its settings
+pub enum Source {
+    /// Configuration file, with the settings it sets itself
+    File { path: String, contents: String, settings: Value },
+    /// Environment variable or command line, named for the reader, setting the setting at `path`
+    Override { name: String, path: Vec<String> },
+}
+
+/// Where a setting was set
+#[derive(Debug)]
+pub enum Location {
+    /// Configuration file, and the line of the setting if it could be found
+    File { path: String, line: Option<usize> },
+    /// Environment variable or command line
+    Override(String),
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::File { path, line: Some(line) } => write!(f, "{}:{}", path, line),
+            Location::File { path, line: None } => write!(f, "{}", path),
+            Location::Override(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Setting of the configuration that no setting of `T` matches
+#[derive(Debug)]
+pub struct UnknownSetting {
+    /// Path of the setting, e.g. `client.writeTimout`
+    pub path: String,
+    /// Where the setting was set, if it could be found
+    pub location: Option<Location>,
+    /// Known setting with the closest name, if one is close enough to be a typo
+    pub suggestion: Option<String>,
+}
+
+impl UnknownSetting {
+    /// Returns the path of the setting, followed by where it was set
+    pub fn located_path(&self) -> String {
+        match &self.location {
+            Some(location) => format!("'{}' ({})", self.path, location),
+            None => format!("'{}'", self.path),
+        }
+    }
+}
+
+impl std::fmt::Display for UnknownSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown setting {}", self.located_path())?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, " (did you mean '{}'?)", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the settings of `settings`, merged from `sources` in order, that `T` doesn't know
+pub fn unknown_settings<T: JsonSchema>(settings: &Value, sources: &[Source]) -> Vec<UnknownSetting> {
+    let schema = schema::generate::<T>("");
+    let mut unknown = Vec::new();
+    if let (Value::Object(sections), Some(properties)) = (settings, schema.get("properties")) {
+        for (section, value) in sections {
+            if let Some(schema) = properties.get(section) {
+                let mut value = value.clone();
+                let profiles = value.as_object_mut().and_then(|settings| settings.remove(PROFILES));
+                let mut in_profiles = Vec::new();
+                for (name, profile) in profiles.iter().filter_map(Value::as_object).flatten() {
+                    walk(profile, schema, &mut vec![section.clone(), PROFILES.to_owned(), name.clone()], &mut in_profiles);
+                }
+                // The settings of the selected profile were merged into the section: report them in the profile only
+                let mut in_section = Vec::new();
+                walk(&value, schema, &mut vec![section.clone()], &mut in_section);
+                in_section.retain(|(path, _)| !in_profiles.iter().any(|(in_profile, _)| in_profile[3..] == path[1..]));
+                unknown.extend(in_section.into_iter().chain(in_profiles));
+            }
+        }
+    }
+    unknown
+        .into_iter()
+        .map(|(path, suggestion)| UnknownSetting { location: origin(sources, &path), path: path.join("."), suggestion })
+        .collect()
+}
+
+/// Returns where the setting at `path` was set: the last of `sources` setting it
+///
+/// Settings `--engarde-compat` renamed are in none of them.
+fn origin(sources: &[Source], path: &[String]) -> Option<Location> {
+    sources.iter().rev().find_map(|source| match source {
+        Source::Override { name, path: overridden } if path.starts_with(overridden) => Some(Location::Override(name.clone())),
+        Source::File { path: file, contents, settings } if get(settings, path).is_some() => {
+            Some(Location::File { path: file.clone(), line: locate(contents, path) })
+        }
+        _ => None,
+    })
+}
+
+/// Returns the setting at `path`, indexing arrays with its numeric keys
+fn get<'a>(settings: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(settings, |value, key| match value {
+        Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+        value => value.get(key),
+    })
+}
+
+fn walk(value: &Value, schema: &Value, path: &mut Vec<String>, unknown: &mut Vec<(Vec<String>, Option<String>)>) {
+    match value {
+        Value::Object(map) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (key, value) in map {
+                path.push(key.clone());
+                match (properties.and_then(|properties| properties.get(key)), schema.get("additionalProperties")) {
+                    (Some(schema), _) | (None, Some(schema)) => walk(value, schema, path, unknown),
+                    (None, None) => {
+                        let suggestion = properties.and_then(|properties| closest(key, properties.keys()));
+                        unknown.push((path.clone(), suggestion));
+                    }
+                }
+                path.pop();
+            }
+        }
+        Value::Array(items) => {
+            if let Some(schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    path.push(index.to_string());
+                    walk(item, schema, path, unknown);
+                    path.pop();
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns the name closest to `name`, if it's within a few edits of it
+fn closest<'a>(name: &str, names: impl Iterator<Item = &'a String>) -> Option<String> {
+    let max_distance = (name.len() / 3).max(2);
+    names
+        .map(|candidate| (distance(&name.to_lowercase(), &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.clone())
+}
+
+/// Levenshtein distance between two names
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            current.push((previous[j] + usize::from(a != *b)).min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Returns the line of the setting at `path`, finding each of its keys after the line of the previous one
+///
+/// Array indices are skipped, so a setting in an array is found in its first element having it.
+fn locate(contents: &str, path: &[String]) -> Option<usize> {
+    let lines = contents.lines().collect::<Vec<_>>();
+    let mut line = 0;
+    for key in path.iter().filter(|key| key.parse::<usize>().is_err()) {
+        line += lines[line..].iter().position(|text| defines(text, key))?;
+    }
+    Some(line + 1)
+}
+
+/// Returns whether a line of YAML, JSON or TOML sets `key`, or opens its table
+fn defines(text: &str, key: &str) -> bool {
+    text.match_indices(key).any(|(index, _)| {
+        let before = text[..index].trim_end_matches(['"', '\'']);
+        let after = text[index + key.len()..].trim_start_matches(['"', '\'']).trim_start();
+        (before.trim().is_empty() || before.ends_with([' ', '-', '{', '[', ',', '.'])) && after.starts_with([':', '=', ']', '.'])
+    })
+}
@@ -0,0 +1,378 @@
+//! Control messages exchanged between client and server inside [`Kind::Control`] frames.
+//!
+//! Every message starts with a one-byte type followed by its body:
+//!
+//! ```text
+//! Hello / HelloAck: | type | capabilities (4 bytes, big endian) |
+//! DuplicationLimit: | type | max paths (1 byte, 0 for no limit) |
+//! Heartbeat:        | type | id (4 bytes, big endian) | interval in ms (2 bytes, big endian) |
+//! HeartbeatAck:     | type | id (4 bytes, big endian) |
+//! PathReport:       | type | packets (4 bytes) | bytes (8 bytes) | loss in ‰ (2 bytes), all big endian |
+//! EchoRequest:      | type | id (4 bytes, big endian) | RTT in µs (4 bytes, big endian, 0 if unknown) |
+//! EchoReply:        | type | id (4 bytes, big endian) |
+//! Keepalive:        | type |
+//! ```
+//!
+//! The client sends `Hello` with the capabilities it wants to use; the server answers with
+//! `HelloAck` carrying the subset it supports. A client that never receives an answer (e.g. from a
+//! Go engarde server) keeps sending raw, engarde-compatible traffic.
+
+use std::fmt;
+
+use crate::frame::{Codec, FrameError, Header, Kind, SessionId};
+
+const TYPE_HELLO: u8 = 1;
+const TYPE_HELLO_ACK: u8 = 2;
+const TYPE_DUPLICATION_LIMIT: u8 = 3;
+const TYPE_HEARTBEAT: u8 = 4;
+const TYPE_HEARTBEAT_ACK: u8 = 5;
+const TYPE_PATH_REPORT: u8 = 6;
+const TYPE_ECHO_REQUEST: u8 = 7;
+const TYPE_ECHO_REPLY: u8 = 8;
+const TYPE_KEEPALIVE: u8 = 9;
+
+/// Number of consecutive heartbeats a path may miss before it is considered dead
+pub const HEARTBEAT_MISSES: u32 = 3;
+
+/// Optional wrapper features a peer can use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const SESSION_ID: Self = Self(1 << 0);
+    pub const CHECKSUM: Self = Self(1 << 1);
+    pub const CONGESTION_FEEDBACK: Self = Self(1 << 2);
+    pub const PADDING: Self = Self(1 << 3);
+    pub const FEC: Self = Self(1 << 4);
+    pub const SEQUENCE: Self = Self(1 << 5);
+    pub const HEARTBEAT: Self = Self(1 << 6);
+    pub const PATH_REPORT: Self = Self(1 << 7);
+    pub const ECHO: Self = Self(1 << 8);
+    pub const KEEPALIVE: Self = Self(1 << 9);
+    pub const TIMESTAMP: Self = Self(1 << 10);
+
+    const NAMES: &'static [(Self, &'static str)] = &[
+        (Self::SESSION_ID, "session_id"),
+        (Self::CHECKSUM, "checksum"),
+        (Self::CONGESTION_FEEDBACK, "congestion_feedback"),
+        (Self::PADDING, "padding"),
+        (Self::FEC, "fec"),
+        (Self::SEQUENCE, "sequence"),
+        (Self::HEARTBEAT, "heartbeat"),
+        (Self::PATH_REPORT, "path_report"),
+        (Self::ECHO, "echo"),
+        (Self::KEEPALIVE, "keepalive"),
+        (Self::TIMESTAMP, "timestamp"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Every capability this build understands
+    pub fn supported() -> Self {
+        Self::NAMES.iter().fold(Self::empty(), |all, (capability, _)| all | *capability)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl std::ops::BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// What the server received on one path since its previous report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathReport {
+    pub packets: u32,
+    pub bytes: u64,
+    /// Fraction of the sequenced frames lost, in thousandths
+    pub loss_permille: u16,
+}
+
+/// A control message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    /// Sent by the client on each path to request the given capabilities
+    Hello { capabilities: Capabilities },
+    /// Sent by the server in response to `Hello`, with the capabilities granted
+    HelloAck { capabilities: Capabilities },
+    /// Sent by the server while its uplink is congested, asking the client to duplicate each packet
+    /// on at most `max_paths` paths; `0` lifts the limit
+    DuplicationLimit { max_paths: u8 },
+    /// Sent by the client on each path every `interval_ms`, so the server can tell a dead path from
+    /// an idle tunnel
+    Heartbeat { id: u32, interval_ms: u16 },
+    /// Sent by the server on the path a `Heartbeat` came from
+    HeartbeatAck { id: u32 },
+    /// Sent by the server periodically on each path, describing what it received on it
+    PathReport(PathReport),
+    /// Sent by the client on each path to measure its round-trip time, carrying the last measurement
+    /// on that path so the server can expose it
+    EchoRequest { id: u32, rtt_us: u32 },
+    /// Sent by the server on the path an `EchoRequest` came from
+    EchoReply { id: u32 },
+    /// Sent by the client on idle paths to keep their NAT and firewall mappings open; ignored by
+    /// the server
+    Keepalive,
+}
+
+impl Message {
+    /// Writes the message body into `out`, replacing its contents
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.clear();
+        match self {
+            Self::Hello { capabilities } => {
+                out.push(TYPE_HELLO);
+                out.extend_from_slice(&capabilities.bits().to_be_bytes());
+            }
+            Self::HelloAck { capabilities } => {
+                out.push(TYPE_HELLO_ACK);
+                out.extend_from_slice(&capabilities.bits().to_be_bytes());
+            }
+            Self::DuplicationLimit { max_paths } => {
+                out.extend_from_slice(&[TYPE_DUPLICATION_LIMIT, *max_paths]);
+            }
+            Self::Heartbeat { id, interval_ms } => {
+                out.push(TYPE_HEARTBEAT);
+                out.extend_from_slice(&id.to_be_bytes());
+                out.extend_from_slice(&interval_ms.to_be_bytes());
+            }
+            Self::HeartbeatAck { id } => {
+                out.push(TYPE_HEARTBEAT_ACK);
+                out.extend_from_slice(&id.to_be_bytes());
+            }
+            Self::PathReport(report) => {
+                out.push(TYPE_PATH_REPORT);
+                out.extend_from_slice(&report.packets.to_be_bytes());
+                out.extend_from_slice(&report.bytes.to_be_bytes());
+                out.extend_from_slice(&report.loss_permille.to_be_bytes());
+            }
+            Self::EchoRequest { id, rtt_us } => {
+                out.push(TYPE_ECHO_REQUEST);
+                out.extend_from_slice(&id.to_be_bytes());
+                out.extend_from_slice(&rtt_us.to_be_bytes());
+            }
+            Self::EchoReply { id } => {
+                out.push(TYPE_ECHO_REPLY);
+                out.extend_from_slice(&id.to_be_bytes());
+            }
+            Self::Keepalive => {
+                out.push(TYPE_KEEPALIVE);
+            }
+        }
+    }
+
+    /// Writes the message as a complete control frame into `out`, replacing its contents
+    pub fn encode_frame(&self, codec: &Codec, session_id: Option<SessionId>, encrypted: bool, out: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        self.encode(&mut body);
+        let header = Header {
+            kind: Kind::Control,
+            session_id,
+            checksum: false,
+            encrypted,
+            padded: false,
+            fec: None,
+            sequence: None,
+            timestamp: None,
+        };
+        codec.encode(&header, &body, out);
+    }
+
+    /// Parses a message body
+    pub fn decode(body: &[u8]) -> Result<Self, FrameError> {
+        let [message_type, body @ ..] = body else {
+            return Err(FrameError::Truncated);
+        };
+        match *message_type {
+            TYPE_HELLO => Ok(Self::Hello { capabilities: decode_capabilities(body)? }),
+            TYPE_HELLO_ACK => Ok(Self::HelloAck { capabilities: decode_capabilities(body)? }),
+            TYPE_DUPLICATION_LIMIT => {
+                let max_paths = body.first().ok_or(FrameError::Truncated)?;
+                Ok(Self::DuplicationLimit { max_paths: *max_paths })
+            }
+            TYPE_HEARTBEAT => {
+                let Some(([id @ .., interval_high, interval_low], _)) = body.split_first_chunk::<6>() else {
+                    return Err(FrameError::Truncated);
+                };
+                Ok(Self::Heartbeat {
+                    id: u32::from_be_bytes(*id),
+                    interval_ms: u16::from_be_bytes([*interval_high, *interval_low]),
+                })
+            }
+            TYPE_HEARTBEAT_ACK => {
+                let (id, _) = body.split_first_chunk::<4>().ok_or(FrameError::Truncated)?;
+                Ok(Self::HeartbeatAck { id: u32::from_be_bytes(*id) })
+            }
+            TYPE_PATH_REPORT => {
+                let (packets, body) = body.split_first_chunk::<4>().ok_or(FrameError::Truncated)?;
+                let (bytes, body) = body.split_first_chunk::<8>().ok_or(FrameError::Truncated)?;
+                let (loss_permille, _) = body.split_first_chunk::<2>().ok_or(FrameError::Truncated)?;
+                Ok(Self::PathReport(PathReport {
+                    packets: u32::from_be_bytes(*packets),
+                    bytes: u64::from_be_bytes(*bytes),
+                    loss_permille: u16::from_be_bytes(*loss_permille),
+                }))
+            }
+            TYPE_ECHO_REQUEST => {
+                let (id, body) = body.split_first_chunk::<4>().ok_or(FrameError::Truncated)?;
+                let (rtt_us, _) = body.split_first_chunk::<4>().ok_or(FrameError::Truncated)?;
+                Ok(Self::EchoRequest {
+                    id: u32::from_be_bytes(*id),
+                    rtt_us: u32::from_be_bytes(*rtt_us),
+                })
+            }
+            TYPE_ECHO_REPLY => {
+                let (id, _) = body.split_first_chunk::<4>().ok_or(FrameError::Truncated)?;
+                Ok(Self::EchoReply { id: u32::from_be_bytes(*id) })
+            }
+            TYPE_KEEPALIVE => Ok(Self::Keepalive),
+            message_type => Err(FrameError::UnknownControlMessage(message_type)),
+        }
+    }
+}
+
+fn decode_capabilities(body: &[u8]) -> Result<Capabilities, FrameError> {
+    let (bits, _) = body.split_first_chunk::<4>().ok_or(FrameError::Truncated)?;
+    Ok(Capabilities::from_bits(u32::from_be_bytes(*bits)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Hello { capabilities: Capabilities::supported() },
+            Message::HelloAck { capabilities: Capabilities::SESSION_ID | Capabilities::FEC },
+            Message::DuplicationLimit { max_paths: 2 },
+            Message::DuplicationLimit { max_paths: 0 },
+            Message::Heartbeat { id: 0xdead_beef, interval_ms: 1000 },
+            Message::HeartbeatAck { id: 0xdead_beef },
+            Message::PathReport(PathReport { packets: 1234, bytes: 5_000_000_000, loss_permille: 15 }),
+            Message::EchoRequest { id: 7, rtt_us: 0 },
+            Message::EchoReply { id: 7 },
+            Message::Keepalive,
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let mut body = Vec::new();
+        for message in messages() {
+            message.encode(&mut body);
+            assert_eq!(Message::decode(&body), Ok(message));
+        }
+    }
+
+    #[test]
+    fn encodes_the_documented_layout() {
+        let mut body = vec![0xff; 4];
+        Message::Hello { capabilities: Capabilities::SESSION_ID | Capabilities::CHECKSUM }.encode(&mut body);
+        assert_eq!(body, [TYPE_HELLO, 0, 0, 0, 0b11]);
+        Message::Heartbeat { id: 1, interval_ms: 0x0203 }.encode(&mut body);
+        assert_eq!(body, [TYPE_HEARTBEAT, 0, 0, 0, 1, 2, 3]);
+        Message::PathReport(PathReport { packets: 1, bytes: 2, loss_permille: 3 }).encode(&mut body);
+        assert_eq!(body, [TYPE_PATH_REPORT, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 3]);
+        Message::EchoRequest { id: 1, rtt_us: 2 }.encode(&mut body);
+        assert_eq!(body, [TYPE_ECHO_REQUEST, 0, 0, 0, 1, 0, 0, 0, 2]);
+        Message::Keepalive.encode(&mut body);
+        assert_eq!(body, [TYPE_KEEPALIVE]);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        assert_eq!(Message::decode(&[]), Err(FrameError::Truncated));
+        let mut body = Vec::new();
+        for message in messages() {
+            message.encode(&mut body);
+            for len in 1..body.len() {
+                assert_eq!(Message::decode(&body[..len]), Err(FrameError::Truncated), "{:?} cut at {}", message, len);
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        let mut body = Vec::new();
+        Message::EchoReply { id: 3 }.encode(&mut body);
+        body.extend_from_slice(b"from a newer version");
+        assert_eq!(Message::decode(&body), Ok(Message::EchoReply { id: 3 }));
+    }
+
+    #[test]
+    fn rejects_unknown_messages() {
+        assert_eq!(Message::decode(&[0]), Err(FrameError::UnknownControlMessage(0)));
+        assert_eq!(Message::decode(&[200, 1, 2]), Err(FrameError::UnknownControlMessage(200)));
+    }
+
+    #[test]
+    fn round_trips_messages_in_frames() {
+        let codec = Codec::new(Some("psk"), Some("encryption key"));
+        let mut frame = Vec::new();
+        for message in messages() {
+            message.encode_frame(&codec, Some(42), true, &mut frame);
+            let (header, body) = codec.decode(&mut frame).unwrap();
+            assert_eq!(header.kind, Kind::Control);
+            assert_eq!(header.session_id, Some(42));
+            assert!(header.encrypted);
+            assert_eq!(Message::decode(body), Ok(message));
+        }
+    }
+
+    #[test]
+    fn names_the_capabilities() {
+        assert_eq!(Capabilities::empty().to_string(), "none");
+        assert_eq!((Capabilities::ECHO | Capabilities::SESSION_ID).to_string(), "session_id, echo");
+        // Capabilities of newer versions are carried, but not named
+        assert_eq!(Capabilities::from_bits(1 << 31).to_string(), "none");
+
+        let supported = Capabilities::supported();
+        assert!(Capabilities::NAMES.iter().all(|(capability, _)| supported.contains(*capability)));
+        assert_eq!(supported & Capabilities::from_bits(Capabilities::FEC.bits() | 1 << 31), Capabilities::FEC);
+    }
+}
@@ -0,0 +1,135 @@
+//! Datagram sockets towards WireGuard: UDP, or Unix datagram sockets for userspace WireGuard
+//! implementations running next to rengarde, configured as `unix:<path>`.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::net::{UdpSocket, UnixDatagram};
+
+/// Prefix of the addresses referencing a Unix datagram socket
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Address of a datagram socket peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddr {
+    Udp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl PeerAddr {
+    /// Returns the path of a `unix:<path>` address, or `None` for a UDP address
+    pub fn unix_path(addr: &str) -> Option<PathBuf> {
+        addr.strip_prefix(UNIX_PREFIX).map(PathBuf::from)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// UDP or Unix datagram socket
+#[derive(Debug)]
+pub enum DatagramSocket {
+    Udp(UdpSocket),
+    /// Unix datagram socket, and the path it's bound to, which is removed once it's dropped
+    Unix(UnixDatagram, PathBuf),
+}
+
+impl DatagramSocket {
+    /// Binds a socket to a UDP address, or to the path of a `unix:<path>` address, replacing the
+    /// socket file a previous run left behind
+    pub async fn bind(addr: &str) -> io::Result<Self> {
+        match PeerAddr::unix_path(addr) {
+            Some(path) => Self::bind_unix(path),
+            None => Ok(Self::Udp(UdpSocket::bind(addr).await?)),
+        }
+    }
+
+    /// Binds a UDP socket without waiting, from within a Tokio runtime
+    pub fn bind_udp(addr: SocketAddr) -> io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self::Udp(UdpSocket::from_std(socket)?))
+    }
+
+    /// Binds a Unix datagram socket to a fresh path in `$XDG_RUNTIME_DIR` (or the temporary
+    /// directory), so its peer can answer it
+    pub fn bind_unix_temporary() -> io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+            .join(format!("rengarde-{}-{}.sock", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        Self::bind_unix(path)
+    }
+
+    fn bind_unix(path: PathBuf) -> io::Result<Self> {
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        Ok(Self::Unix(UnixDatagram::bind(&path)?, path))
+    }
+
+    /// Returns the address the socket is bound to
+    pub fn local_addr(&self) -> io::Result<PeerAddr> {
+        match self {
+            Self::Udp(socket) => Ok(PeerAddr::Udp(socket.local_addr()?)),
+            Self::Unix(_, path) => Ok(PeerAddr::Unix(path.clone())),
+        }
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Udp(socket) => socket.recv(buf).await,
+            Self::Unix(socket, _) => socket.recv(buf).await,
+        }
+    }
+
+    /// Receives a datagram, along with the address of its sender; `None` for a Unix peer that
+    /// isn't bound to a path, and can't be answered
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PeerAddr>)> {
+        match self {
+            Self::Udp(socket) => {
+                let (received_bytes, addr) = socket.recv_from(buf).await?;
+                Ok((received_bytes, Some(PeerAddr::Udp(addr))))
+            }
+            Self::Unix(socket, _) => {
+                let (received_bytes, addr) = socket.recv_from(buf).await?;
+                Ok((received_bytes, addr.as_pathname().map(|path| PeerAddr::Unix(path.to_owned()))))
+            }
+        }
+    }
+
+    pub async fn send_to(&self, buf: &[u8], addr: &PeerAddr) -> io::Result<usize> {
+        match (self, addr) {
+            (Self::Udp(socket), PeerAddr::Udp(addr)) => socket.send_to(buf, addr).await,
+            (Self::Unix(socket, _), PeerAddr::Unix(path)) => socket.send_to(buf, path).await,
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't send to '{}' from this socket", addr))),
+        }
+    }
+
+    /// Waits for the socket to be writable
+    pub async fn writable(&self) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.writable().await,
+            Self::Unix(socket, _) => socket.writable().await,
+        }
+    }
+}
+
+impl Drop for DatagramSocket {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
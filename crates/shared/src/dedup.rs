@@ -0,0 +1,87 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
+
+/// Most payload hashes remembered at once; beyond it, the oldest are forgotten early
+const MAX_ENTRIES: usize = 16384;
+
+/// Remembers the hashes of the payloads recently forwarded to WireGuard, to drop the copies the
+/// other end sent on its other paths
+///
+/// WireGuard payloads are encrypted with a fresh counter each, so identical payloads can only be
+/// copies of the same packet, whichever path they came over. This lets the server drop duplicates
+/// from clients that don't number their frames, including engarde clients, and the client drop the
+/// copies the server sends to every address of its session.
+#[derive(Debug)]
+pub struct DedupWindow {
+    window: Duration,
+    hasher: RandomState,
+    seen: HashSet<u64>,
+    /// Remembered hashes, oldest first, with when they were first seen
+    order: VecDeque<(Instant, u64)>,
+}
+
+impl DedupWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            hasher: RandomState::new(),
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records a payload, returning whether a copy of it was already seen within the window
+    pub fn is_duplicate(&mut self, payload: &[u8]) -> bool {
+        let now = Instant::now();
+        while let Some((seen_at, hash)) = self.order.front() {
+            if now.duration_since(*seen_at) < self.window && self.order.len() < MAX_ENTRIES {
+                break;
+            }
+            self.seen.remove(hash);
+            self.order.pop_front();
+        }
+
+        let hash = self.hasher.hash_one(payload);
+        if !self.seen.insert(hash) {
+            return true;
+        }
+        self.order.push_back((now, hash));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_copies_within_the_window() {
+        let mut dedup = DedupWindow::new(Duration::from_secs(60));
+        assert!(!dedup.is_duplicate(b"first"));
+        assert!(!dedup.is_duplicate(b"second"));
+        assert!(dedup.is_duplicate(b"first"));
+        assert!(dedup.is_duplicate(b"second"));
+        assert!(dedup.is_duplicate(b"first"));
+    }
+
+    #[test]
+    fn forgets_payloads_after_the_window() {
+        let mut dedup = DedupWindow::new(Duration::from_millis(20));
+        assert!(!dedup.is_duplicate(b"payload"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!dedup.is_duplicate(b"payload"));
+        assert!(dedup.is_duplicate(b"payload"));
+    }
+
+    #[test]
+    fn forgets_the_oldest_payloads_beyond_its_capacity() {
+        let mut dedup = DedupWindow::new(Duration::from_secs(60));
+        for index in 0..=MAX_ENTRIES as u32 {
+            assert!(!dedup.is_duplicate(&index.to_be_bytes()));
+        }
+        assert_eq!(dedup.order.len(), MAX_ENTRIES);
+        assert!(!dedup.is_duplicate(&0_u32.to_be_bytes()));
+        assert!(dedup.is_duplicate(&(MAX_ENTRIES as u32).to_be_bytes()));
+    }
+}
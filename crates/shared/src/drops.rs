@@ -0,0 +1,52 @@
+//! Accounting of the dropped packets in a single `rengarde_drops_total` metric, labelled with the `reason` they were
+//! dropped for, so the loss seen by WireGuard can be attributed rather than guessed. The more detailed metrics of each
+//! drop (e.g. per interface or client) are kept alongside.
+
+use tracing::debug;
+
+/// Why a packet was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Sent by a source banned for sending invalid packets
+    Banned,
+    /// Malformed, unauthenticated or unexpected raw traffic
+    Invalid,
+    /// Sent by a new client while the server drains or has reached its client limit
+    Refused,
+    /// Copy of a packet already forwarded
+    Duplicate,
+    /// Meant for a client that timed out
+    ClientTimeout,
+    /// Its write didn't complete within the write timeout
+    WriteTimeout,
+    /// Its write failed
+    SendError,
+    /// Larger than the path MTU
+    TooBig,
+    /// Its path is unreachable
+    Unreachable,
+    /// Over the rate limit of its path, or its pacing queue is full
+    RateLimit,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::Banned => "banned",
+            DropReason::Invalid => "invalid",
+            DropReason::Refused => "refused",
+            DropReason::Duplicate => "duplicate",
+            DropReason::ClientTimeout => "client_timeout",
+            DropReason::WriteTimeout => "write_timeout",
+            DropReason::SendError => "send_error",
+            DropReason::TooBig => "too_big",
+            DropReason::Unreachable => "unreachable",
+            DropReason::RateLimit => "rate_limit",
+        }
+    }
+}
+
+/// Counts a packet dropped for `reason`
+pub fn record(reason: DropReason) {
+    debug!(monotonic_counter.rengarde_drops_total = 1_u64, reason = reason.as_str());
+}
@@ -0,0 +1,414 @@
+//! Forward error correction across the frames sent on all paths.
+//!
+//! Data frames are tagged with their position in a group of `size` frames; once a group is complete
+//! the sender emits `parity` parity frames (indices `size..size + parity`). Every frame of a group is
+//! treated as a shard: its payload prefixed with its length (2 bytes, big endian) and zero-extended
+//! to the longest shard of the group. With a single parity frame the parity is the XOR of the data
+//! shards; with more, it is computed with a Reed-Solomon code over GF(2^8). A receiver missing up to
+//! `parity` data frames of a group rebuilds them from the frames it did receive.
+
+use std::collections::HashMap;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// Largest number of data frames in a group
+pub const MAX_GROUP_SIZE: u8 = 48;
+
+/// Largest number of parity frames in a group, so that a group's frames fit in a bitmap
+pub const MAX_PARITY: u8 = 15;
+
+/// Number of most recent groups a decoder keeps, to tolerate reordering across paths
+const WINDOW: u32 = 32;
+
+const LEN_PREFIX: usize = 2;
+
+/// Position of a frame in its FEC group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FecTag {
+    pub group: u32,
+    /// Index of the frame in the group; data frames come first, then parity frames
+    pub index: u8,
+    /// Number of data frames in the group
+    pub size: u8,
+    /// Number of parity frames in the group
+    pub parity: u8,
+}
+
+impl FecTag {
+    pub fn is_parity(&self) -> bool {
+        self.index >= self.size
+    }
+
+    fn is_valid(&self) -> bool {
+        (2..=MAX_GROUP_SIZE).contains(&self.size)
+            && (1..=MAX_PARITY).contains(&self.parity)
+            && self.index < self.size + self.parity
+    }
+}
+
+/// Parity frame completing a group
+#[derive(Debug)]
+pub struct Parity {
+    pub tag: FecTag,
+    pub payload: Vec<u8>,
+}
+
+/// Tags outgoing data frames and computes the parity frames of each group
+#[derive(Debug)]
+pub struct FecEncoder {
+    size: u8,
+    parity: u8,
+    group: u32,
+    shards: Vec<Vec<u8>>,
+    reed_solomon: Option<ReedSolomon>,
+}
+
+impl FecEncoder {
+    /// Creates an encoder emitting `parity` parity frames every `size` data frames
+    ///
+    /// Both are clamped to their valid range: `2..=MAX_GROUP_SIZE` and `1..=MAX_PARITY`.
+    pub fn new(size: u8, parity: u8) -> Self {
+        let size = size.clamp(2, MAX_GROUP_SIZE);
+        let parity = parity.clamp(1, MAX_PARITY);
+        Self {
+            size,
+            parity,
+            group: 0,
+            shards: Vec::with_capacity(size as usize + parity as usize),
+            reed_solomon: (parity > 1).then(|| {
+                ReedSolomon::new(size as usize, parity as usize).expect("shard counts are in range")
+            }),
+        }
+    }
+
+    /// Tags the next data payload, returning the parity frames too if the payload completes its group
+    pub fn push(&mut self, payload: &[u8]) -> (FecTag, Vec<Parity>) {
+        let tag = FecTag {
+            group: self.group,
+            index: self.shards.len() as u8,
+            size: self.size,
+            parity: self.parity,
+        };
+        self.shards.push(shard(payload));
+        if self.shards.len() < self.size as usize {
+            return (tag, Vec::new());
+        }
+
+        let parity = compute_parity(&mut self.shards, self.parity, self.reed_solomon.as_ref())
+            .into_iter()
+            .enumerate()
+            .map(|(i, payload)| Parity {
+                tag: FecTag { index: self.size + i as u8, ..tag },
+                payload,
+            })
+            .collect();
+        self.shards.clear();
+        self.group = self.group.wrapping_add(1);
+        (tag, parity)
+    }
+}
+
+#[derive(Debug)]
+struct Group {
+    /// Number of data and parity frames, as tagged on the first frame received; frames tagged otherwise are ignored
+    size: u8,
+    parity: u8,
+    /// Bitmap of the received frame indices, parity included
+    received: u64,
+    /// Shards received so far, indexed like the frames
+    shards: Vec<Option<Vec<u8>>>,
+    done: bool,
+}
+
+impl Group {
+    fn new(tag: &FecTag) -> Self {
+        Self {
+            size: tag.size,
+            parity: tag.parity,
+            received: 0,
+            shards: vec![None; (tag.size + tag.parity) as usize],
+            done: false,
+        }
+    }
+}
+
+/// Rebuilds lost data frames from the frames and parity of their group
+#[derive(Debug, Default)]
+pub struct FecDecoder {
+    groups: HashMap<u32, Group>,
+    newest: u32,
+    /// Reed-Solomon code of the last multi-parity group, keyed by its shard counts
+    reed_solomon: Option<((u8, u8), ReedSolomon)>,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a received frame, returning the payloads of the group's lost data frames if they can
+    /// now be rebuilt
+    ///
+    /// Duplicates (e.g. the same frame received on several paths) are ignored, and so are frames whose group size or
+    /// parity count differ from the first frame received of their group.
+    pub fn push(&mut self, tag: FecTag, payload: &[u8]) -> Vec<Vec<u8>> {
+        if !tag.is_valid() {
+            return Vec::new();
+        }
+        let ahead = tag.group.wrapping_sub(self.newest);
+        if ahead > 0 && ahead < u32::MAX / 2 {
+            self.newest = tag.group;
+            self.groups.retain(|group, _| tag.group.wrapping_sub(*group) < WINDOW);
+        } else if self.newest.wrapping_sub(tag.group) >= WINDOW {
+            // Too old to be of any use
+            return Vec::new();
+        }
+
+        let group = self.groups.entry(tag.group).or_insert_with(|| Group::new(&tag));
+        let bit = 1_u64 << tag.index;
+        if group.done || group.received & bit != 0 || (group.size, group.parity) != (tag.size, tag.parity) {
+            return Vec::new();
+        }
+        group.received |= bit;
+        group.shards[tag.index as usize] = Some(if tag.is_parity() { payload.to_vec() } else { shard(payload) });
+
+        let data_mask = (1_u64 << tag.size) - 1;
+        let data_received = (group.received & data_mask).count_ones();
+        let received = group.received.count_ones();
+        if data_received == tag.size as u32 || received < tag.size as u32 {
+            group.done = data_received == tag.size as u32;
+            if group.done {
+                group.shards = Vec::new();
+            }
+            return Vec::new();
+        }
+
+        group.done = true;
+        let mut shards = std::mem::take(&mut group.shards);
+        let missing: Vec<usize> = (0..tag.size as usize).filter(|i| shards[*i].is_none()).collect();
+        let reed_solomon = (tag.parity > 1).then(|| {
+            let counts = (tag.size, tag.parity);
+            if self.reed_solomon.as_ref().is_none_or(|(cached, _)| *cached != counts) {
+                let reed_solomon = ReedSolomon::new(tag.size as usize, tag.parity as usize).expect("shard counts are in range");
+                self.reed_solomon = Some((counts, reed_solomon));
+            }
+            &self.reed_solomon.as_ref().unwrap().1
+        });
+        if !reconstruct(&mut shards, tag.size as usize, reed_solomon) {
+            return Vec::new();
+        }
+
+        missing
+            .into_iter()
+            .filter_map(|i| {
+                let shard = shards[i].as_ref()?;
+                let (len, rest) = shard.split_first_chunk::<LEN_PREFIX>()?;
+                rest.get(..u16::from_be_bytes(*len) as usize).map(<[u8]>::to_vec)
+            })
+            .collect()
+    }
+}
+
+/// Prefixes a data payload with its length
+fn shard(payload: &[u8]) -> Vec<u8> {
+    let mut shard = Vec::with_capacity(LEN_PREFIX + payload.len());
+    shard.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    shard.extend_from_slice(payload);
+    shard
+}
+
+/// Computes the parity shards of a complete group of data shards, zero-extending them in place
+fn compute_parity(data: &mut [Vec<u8>], parity: u8, reed_solomon: Option<&ReedSolomon>) -> Vec<Vec<u8>> {
+    let len = data.iter().map(Vec::len).max().unwrap_or_default();
+    data.iter_mut().for_each(|shard| shard.resize(len, 0));
+
+    let Some(reed_solomon) = reed_solomon else {
+        let mut xor = vec![0; len];
+        data.iter().for_each(|shard| xor_into(&mut xor, shard));
+        return vec![xor];
+    };
+    let mut parity = vec![vec![0; len]; parity as usize];
+    reed_solomon.encode_sep(data, &mut parity).expect("shards have the same length");
+    parity
+}
+
+/// Rebuilds the missing data shards of a group in place, returning whether it succeeded
+///
+/// Every shard is zero-extended to the length of the parity shards first.
+fn reconstruct(shards: &mut [Option<Vec<u8>>], size: usize, reed_solomon: Option<&ReedSolomon>) -> bool {
+    let Some(len) = shards[size..].iter().flatten().map(Vec::len).next() else {
+        return false;
+    };
+    if shards.iter().flatten().any(|shard| shard.len() > len) {
+        return false;
+    }
+    shards.iter_mut().flatten().for_each(|shard| shard.resize(len, 0));
+
+    let Some(reed_solomon) = reed_solomon else {
+        // A single missing data shard is the XOR of all the others and the parity
+        let mut xor = vec![0; len];
+        shards.iter().flatten().for_each(|shard| xor_into(&mut xor, shard));
+        if let Some(missing) = shards[..size].iter_mut().find(|shard| shard.is_none()) {
+            *missing = Some(xor);
+        }
+        return true;
+    };
+    reed_solomon.reconstruct_data(shards).is_ok()
+}
+
+fn xor_into(accumulator: &mut [u8], bytes: &[u8]) {
+    accumulator.iter_mut().zip(bytes).for_each(|(acc, byte)| *acc ^= byte);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Payloads of distinct lengths, so that rebuilt shards must be trimmed to the right one
+    fn payloads(count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|i| (0..=i * 7).map(|byte| (byte * 31 + i) as u8).collect()).collect()
+    }
+
+    /// Encodes the payloads as one group, returning its data and parity frames
+    fn encode(size: u8, parity: u8, payloads: &[Vec<u8>]) -> Vec<(FecTag, Vec<u8>)> {
+        let mut encoder = FecEncoder::new(size, parity);
+        let mut frames = Vec::new();
+        for payload in payloads {
+            let (tag, parity) = encoder.push(payload);
+            frames.push((tag, payload.clone()));
+            frames.extend(parity.into_iter().map(|parity| (parity.tag, parity.payload)));
+        }
+        frames
+    }
+
+    /// Feeds the frames not `lost` to a decoder, returning the payloads it rebuilt
+    fn decode(frames: &[(FecTag, Vec<u8>)], lost: &[usize]) -> Vec<Vec<u8>> {
+        let mut decoder = FecDecoder::new();
+        frames
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !lost.contains(i))
+            .flat_map(|(_, (tag, payload))| decoder.push(*tag, payload))
+            .collect()
+    }
+
+    #[test]
+    fn tags_data_frames_then_parity() {
+        let frames = encode(3, 1, &payloads(6));
+        let tags: Vec<_> = frames.iter().map(|(tag, _)| (tag.group, tag.index, tag.is_parity())).collect();
+        assert_eq!(
+            tags,
+            [(0, 0, false), (0, 1, false), (0, 2, false), (0, 3, true), (1, 0, false), (1, 1, false), (1, 2, false), (1, 3, true)]
+        );
+    }
+
+    #[test]
+    fn rebuilds_any_lost_data_frame_from_the_xor_parity() {
+        let payloads = payloads(4);
+        let frames = encode(4, 1, &payloads);
+        for (lost, payload) in payloads.iter().enumerate() {
+            assert_eq!(decode(&frames, &[lost]), std::slice::from_ref(payload), "lost frame {}", lost);
+        }
+    }
+
+    #[test]
+    fn rebuilds_nothing_without_loss_or_beyond_the_parity() {
+        let frames = encode(4, 1, &payloads(4));
+        assert!(decode(&frames, &[]).is_empty());
+        // Only the parity frame lost
+        assert!(decode(&frames, &[4]).is_empty());
+        assert!(decode(&frames, &[0, 2]).is_empty());
+    }
+
+    #[test]
+    fn rebuilds_each_group_separately() {
+        let payloads = payloads(9);
+        let frames = encode(3, 1, &payloads);
+        // One data frame lost in each of the three groups of four frames
+        assert_eq!(decode(&frames, &[1, 4, 10]), [payloads[1].clone(), payloads[3].clone(), payloads[8].clone()]);
+    }
+
+    #[test]
+    fn ignores_duplicates() {
+        let payloads = payloads(3);
+        let frames = encode(3, 1, &payloads);
+        let mut decoder = FecDecoder::new();
+        assert!(decoder.push(frames[0].0, &frames[0].1).is_empty());
+        assert!(decoder.push(frames[0].0, &frames[0].1).is_empty());
+        assert!(decoder.push(frames[3].0, &frames[3].1).is_empty());
+        assert_eq!(decoder.push(frames[2].0, &frames[2].1), [payloads[1].clone()]);
+        // Once rebuilt, the late frame and further copies are ignored
+        assert!(decoder.push(frames[1].0, &frames[1].1).is_empty());
+        assert!(decoder.push(frames[3].0, &frames[3].1).is_empty());
+    }
+
+    #[test]
+    fn ignores_frames_tagged_with_another_layout_than_their_group() {
+        let payloads = payloads(3);
+        let frames = encode(3, 1, &payloads);
+        let mut decoder = FecDecoder::new();
+        assert!(decoder.push(frames[0].0, &frames[0].1).is_empty());
+        // Claims the group has two data frames, which would complete it with the wrong parity
+        let forged = FecTag { index: 1, size: 2, ..frames[1].0 };
+        assert!(decoder.push(forged, b"forged").is_empty());
+        assert!(decoder.push(FecTag { index: 2, size: 2, ..frames[3].0 }, &frames[3].1).is_empty());
+        assert!(decoder.push(frames[2].0, &frames[2].1).is_empty());
+        assert_eq!(decoder.push(frames[3].0, &frames[3].1), [payloads[1].clone()]);
+    }
+
+    #[test]
+    fn rebuilds_up_to_parity_lost_data_frames_with_reed_solomon() {
+        let payloads = payloads(5);
+        let frames = encode(5, 3, &payloads);
+        assert_eq!(frames.len(), 8);
+        for lost in [vec![2], vec![0, 4], vec![1, 2, 3], vec![0, 3, 6], vec![5, 6, 7]] {
+            let expected: Vec<_> = lost.iter().filter(|i| **i < 5).map(|i| payloads[*i].clone()).collect();
+            assert_eq!(decode(&frames, &lost), expected, "lost frames {:?}", lost);
+        }
+    }
+
+    #[test]
+    fn rebuilds_nothing_beyond_the_reed_solomon_parity() {
+        let frames = encode(5, 3, &payloads(5));
+        assert!(decode(&frames, &[0, 1, 2, 3]).is_empty());
+        assert!(decode(&frames, &[0, 1, 5, 7]).is_empty());
+    }
+
+    #[test]
+    fn rebuilds_the_largest_groups() {
+        let payloads = payloads(MAX_GROUP_SIZE as usize);
+        let frames = encode(MAX_GROUP_SIZE, MAX_PARITY, &payloads);
+        let lost: Vec<_> = (0..MAX_PARITY as usize).map(|i| i * 3).collect();
+        let expected: Vec<_> = lost.iter().map(|i| payloads[*i].clone()).collect();
+        assert_eq!(decode(&frames, &lost), expected);
+    }
+
+    #[test]
+    fn ignores_frames_tagged_with_another_reed_solomon_layout() {
+        let payloads = payloads(4);
+        let frames = encode(4, 2, &payloads);
+        let mut decoder = FecDecoder::new();
+        assert!(decoder.push(frames[0].0, &frames[0].1).is_empty());
+        assert!(decoder.push(frames[1].0, &frames[1].1).is_empty());
+        // Claims more parity frames than the group has, at the index of the first one
+        assert!(decoder.push(FecTag { parity: 3, ..frames[4].0 }, &frames[4].1).is_empty());
+        assert!(decoder.push(FecTag { size: 3, index: 2, ..frames[2].0 }, &frames[2].1).is_empty());
+        assert!(decoder.push(frames[4].0, &frames[4].1).is_empty());
+        assert_eq!(decoder.push(frames[5].0, &frames[5].1), [payloads[2].clone(), payloads[3].clone()]);
+    }
+
+    #[test]
+    fn ignores_invalid_and_stale_tags() {
+        let mut decoder = FecDecoder::new();
+        assert!(decoder.push(FecTag { group: 0, index: 0, size: 1, parity: 1 }, b"").is_empty());
+        assert!(decoder.push(FecTag { group: 0, index: 3, size: 2, parity: 1 }, b"").is_empty());
+        assert!(decoder.push(FecTag { group: 0, index: 0, size: 2, parity: MAX_PARITY + 1 }, b"").is_empty());
+
+        let payloads = payloads(2);
+        let frames = encode(2, 1, &payloads);
+        assert!(decoder.push(FecTag { group: WINDOW, ..frames[0].0 }, &frames[0].1).is_empty());
+        // Group 0 is now out of the window
+        assert!(decoder.push(frames[0].0, &frames[0].1).is_empty());
+        assert!(decoder.push(frames[2].0, &frames[2].1).is_empty());
+    }
+}
@@ -0,0 +1,638 @@
+//! Optional framing wrapped around the tunneled WireGuard datagrams.
+//!
+//! A frame starts with [`MAGIC`]. Its first byte can never be the first byte of a raw WireGuard
+//! message (those start with a message type in `1..=4`), so framed and raw engarde traffic can share
+//! a port; the others make it unlikely for random datagrams (e.g. from scanners) to pass for frames.
+//!
+//! ```text
+//! +---------+---------+--------+--------+-----------------------+---------+---------------------+-----------------------+
+//! | magic   | version | kind   | flags  | session id (optional) | payload | checksum (optional) | auth tag (optional)   |
+//! | 4 bytes | 1 byte  | 1 byte | 1 byte | 8 bytes, big endian   |         | 4 bytes, CRC32C     | 16 bytes, HMAC-SHA256 |
+//! +---------+---------+--------+--------+-----------------------+---------+---------------------+-----------------------+
+//! ```
+//!
+//! The payload of a [`Kind::Data`] frame is a WireGuard datagram, the payload of a [`Kind::Control`]
+//! frame is a [`control::Message`](crate::control::Message). Each trailer covers every byte of the
+//! frame preceding it; the auth tag is keyed with the pre-shared key given to the [`Codec`].
+//!
+//! An encrypted frame replaces the payload with a random 24-byte nonce, the XChaCha20-Poly1305
+//! ciphertext and its 16-byte tag, so that middleboxes can't recognize the tunneled WireGuard
+//! traffic. Every client and the server share the key, so the nonces are drawn at random: at 192
+//! bits they don't collide, however many processes encrypt with it. The magic, flags and session id
+//! stay in the clear and are authenticated as associated data.
+//!
+//! A frame protected by forward error correction carries its [`FecTag`] (group, 4 bytes big endian;
+//! index, group size and parity count, 1 byte each) right after the session id.
+//!
+//! A sequenced data frame carries its sequence number (4 bytes, big endian) next, the same on
+//! every path it is duplicated on.
+//!
+//! A timestamped frame carries the time it was sent at next (microseconds on the sender's monotonic
+//! clock, 4 bytes big endian, wrapping), from which the receiver follows the path's queueing delay.
+//!
+//! A padded frame appends zeros and their count (2 bytes, big endian) to the payload, before
+//! encryption, to bring the frame up to one of the sizes configured on the [`Codec`].
+
+use std::fmt;
+
+use chacha20poly1305::aead::{AeadCore, AeadInPlace, OsRng};
+use chacha20poly1305::{KeyInit, Tag, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::fec::FecTag;
+
+pub const MAGIC: [u8; 4] = [0xE9, b'r', b'g', b'd'];
+pub const VERSION: u8 = 1;
+
+const FLAG_SESSION_ID: u8 = 0x01;
+const FLAG_CHECKSUM: u8 = 0x02;
+const FLAG_AUTH: u8 = 0x04;
+const FLAG_ENCRYPTED: u8 = 0x08;
+const FLAG_PADDED: u8 = 0x10;
+const FLAG_FEC: u8 = 0x20;
+const FLAG_SEQUENCE: u8 = 0x40;
+const FLAG_TIMESTAMP: u8 = 0x80;
+
+const HEADER_LEN: usize = MAGIC.len() + 3;
+const CHECKSUM_LEN: usize = 4;
+const AUTH_TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const AEAD_TAG_LEN: usize = 16;
+const PADDING_LEN_LEN: usize = 2;
+const FEC_TAG_LEN: usize = 7;
+const SEQUENCE_LEN: usize = 4;
+const TIMESTAMP_LEN: usize = 4;
+
+/// Identifies all the paths of one client
+pub type SessionId = u64;
+
+/// What the payload of a frame carries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Kind {
+    /// A tunneled WireGuard datagram
+    #[default]
+    Data = 0,
+    /// A control message exchanged between client and server
+    Control = 1,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = FrameError;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            0 => Ok(Self::Data),
+            1 => Ok(Self::Control),
+            kind => Err(FrameError::UnknownKind(kind)),
+        }
+    }
+}
+
+/// Frame header preceding the payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Header {
+    pub kind: Kind,
+    pub session_id: Option<SessionId>,
+    /// Whether a CRC32C checksum trails the payload
+    pub checksum: bool,
+    /// Whether the payload is encrypted; ignored when encoding with a codec without encryption key
+    pub encrypted: bool,
+    /// Whether the frame is padded; ignored when encoding with a codec without padding sizes
+    pub padded: bool,
+    /// Position of the frame in its forward error correction group
+    pub fec: Option<FecTag>,
+    /// Sequence number of a data frame, shared by all its duplicates
+    pub sequence: Option<u32>,
+    /// Time the frame was sent at, see [`crate::path::timestamp`]
+    pub timestamp: Option<u32>,
+}
+
+/// Reasons a datagram is rejected as a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    Truncated,
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    UnknownControlMessage(u8),
+    ChecksumMismatch,
+    Unauthenticated,
+    DecryptionFailed,
+}
+
+impl FrameError {
+    /// Short, stable name of the error, suitable as a metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Truncated => "truncated",
+            Self::InvalidMagic => "invalid_magic",
+            Self::UnsupportedVersion(_) => "unsupported_version",
+            Self::UnknownKind(_) => "unknown_kind",
+            Self::UnknownControlMessage(_) => "unknown_control_message",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::Unauthenticated => "unauthenticated",
+            Self::DecryptionFailed => "decryption_failed",
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "frame truncated"),
+            Self::InvalidMagic => write!(f, "invalid frame magic"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported frame version: {}", version),
+            Self::UnknownKind(kind) => write!(f, "unknown frame kind: {}", kind),
+            Self::UnknownControlMessage(message) => write!(f, "unknown control message: {}", message),
+            Self::ChecksumMismatch => write!(f, "frame checksum mismatch"),
+            Self::Unauthenticated => write!(f, "frame not authenticated"),
+            Self::DecryptionFailed => write!(f, "frame could not be decrypted"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl Header {
+    /// Number of bytes the header, encryption and checksum add around the payload
+    pub fn overhead(&self) -> usize {
+        HEADER_LEN + if self.session_id.is_some() { 8 } else { 0 }
+            + if self.fec.is_some() { FEC_TAG_LEN } else { 0 }
+            + if self.sequence.is_some() { SEQUENCE_LEN } else { 0 }
+            + if self.timestamp.is_some() { TIMESTAMP_LEN } else { 0 }
+            + if self.encrypted { NONCE_LEN + AEAD_TAG_LEN } else { 0 }
+            + if self.checksum { CHECKSUM_LEN } else { 0 }
+    }
+}
+
+/// Returns whether the datagram is a frame rather than raw WireGuard traffic
+pub fn is_frame(datagram: &[u8]) -> bool {
+    datagram.first() == Some(&MAGIC[0])
+}
+
+/// XChaCha20-Poly1305 key
+#[derive(Clone)]
+struct Encryption {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Encryption {
+    fn new(secret: &str) -> Self {
+        let key = Sha256::new()
+            .chain_update(b"rengarde outer encryption")
+            .chain_update(secret.as_bytes())
+            .finalize();
+        Self {
+            cipher: XChaCha20Poly1305::new(&key),
+        }
+    }
+
+    fn next_nonce(&self) -> XNonce {
+        XChaCha20Poly1305::generate_nonce(&mut OsRng)
+    }
+}
+
+/// Encodes and decodes frames, authenticating them if a pre-shared key is configured and
+/// encrypting them if an encryption key is configured
+#[derive(Clone, Default)]
+pub struct Codec {
+    auth: Option<Hmac<Sha256>>,
+    encryption: Option<Encryption>,
+    /// Frame sizes to pad to, in increasing order
+    padding: Vec<usize>,
+}
+
+impl fmt::Debug for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Codec")
+            .field("auth", &self.auth.is_some())
+            .field("encryption", &self.encryption.is_some())
+            .field("padding", &self.padding)
+            .finish()
+    }
+}
+
+impl Codec {
+    /// Creates a codec authenticating every frame with the given pre-shared key, if any, and able
+    /// to encrypt frames with a key derived from the given secret, if any
+    pub fn new(psk: Option<&str>, encryption_key: Option<&str>) -> Self {
+        Self {
+            auth: psk.map(|psk| <Hmac<Sha256> as Mac>::new_from_slice(psk.as_bytes()).expect("HMAC accepts keys of any size")),
+            encryption: encryption_key.map(Encryption::new),
+            padding: Vec::new(),
+        }
+    }
+
+    /// Pads frames with a padded header up to the smallest of the given sizes that fits them;
+    /// frames larger than every size are left as they are
+    pub fn with_padding(mut self, mut sizes: Vec<usize>) -> Self {
+        sizes.sort_unstable();
+        sizes.dedup();
+        self.padding = sizes;
+        self
+    }
+
+    /// Whether frames can be padded
+    pub fn can_pad(&self) -> bool {
+        !self.padding.is_empty()
+    }
+
+    /// Whether frames are authenticated, and unauthenticated traffic must be dropped
+    pub fn requires_auth(&self) -> bool {
+        self.auth.is_some()
+    }
+
+    /// Whether frames can be encrypted and decrypted
+    pub fn can_encrypt(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// Number of bytes a frame with the given header adds around the payload
+    pub fn overhead(&self, header: &Header) -> usize {
+        let header = Header {
+            encrypted: header.encrypted && self.can_encrypt(),
+            ..*header
+        };
+        header.overhead() + if self.auth.is_some() { AUTH_TAG_LEN } else { 0 }
+    }
+
+    /// Writes the header, the payload and the optional trailers into `out`, replacing its contents
+    pub fn encode(&self, header: &Header, payload: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        out.reserve(self.overhead(header) + payload.len());
+
+        let encryption = self.encryption.as_ref().filter(|_| header.encrypted);
+        let padding = header.padded.then(|| {
+            let unpadded = self.overhead(header) + payload.len() + PADDING_LEN_LEN;
+            let size = self.padding.iter().find(|size| **size >= unpadded)?;
+            u16::try_from(size - unpadded).ok()
+        }).flatten();
+        let mut flags = 0;
+        if header.session_id.is_some() {
+            flags |= FLAG_SESSION_ID;
+        }
+        if header.checksum {
+            flags |= FLAG_CHECKSUM;
+        }
+        if self.auth.is_some() {
+            flags |= FLAG_AUTH;
+        }
+        if encryption.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        if padding.is_some() {
+            flags |= FLAG_PADDED;
+        }
+        if header.fec.is_some() {
+            flags |= FLAG_FEC;
+        }
+        if header.sequence.is_some() {
+            flags |= FLAG_SEQUENCE;
+        }
+        if header.timestamp.is_some() {
+            flags |= FLAG_TIMESTAMP;
+        }
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&[VERSION, header.kind as u8, flags]);
+        if let Some(session_id) = header.session_id {
+            out.extend_from_slice(&session_id.to_be_bytes());
+        }
+        if let Some(fec) = header.fec {
+            out.extend_from_slice(&fec.group.to_be_bytes());
+            out.extend_from_slice(&[fec.index, fec.size, fec.parity]);
+        }
+        if let Some(sequence) = header.sequence {
+            out.extend_from_slice(&sequence.to_be_bytes());
+        }
+        if let Some(timestamp) = header.timestamp {
+            out.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        let associated_len = out.len();
+        let nonce = encryption.map(|encryption| encryption.next_nonce());
+        if let Some(nonce) = &nonce {
+            out.extend_from_slice(nonce);
+        }
+        out.extend_from_slice(payload);
+        if let Some(padding) = padding {
+            out.resize(out.len() + padding as usize, 0);
+            out.extend_from_slice(&padding.to_be_bytes());
+        }
+        if let (Some(encryption), Some(nonce)) = (encryption, nonce) {
+            let (associated, ciphertext) = out.split_at_mut(associated_len);
+            let tag = encryption
+                .cipher
+                .encrypt_in_place_detached(&nonce, associated, &mut ciphertext[NONCE_LEN..])
+                .expect("payloads are far below the XChaCha20-Poly1305 size limit");
+            out.extend_from_slice(&tag);
+        }
+        if header.checksum {
+            let checksum = crc32c::crc32c(out);
+            out.extend_from_slice(&checksum.to_be_bytes());
+        }
+        if let Some(auth) = &self.auth {
+            let mut mac = auth.clone();
+            mac.update(out);
+            out.extend_from_slice(&mac.finalize().into_bytes()[..AUTH_TAG_LEN]);
+        }
+    }
+
+    /// Splits a frame into its header and payload, verifying its trailers and decrypting the
+    /// payload in place
+    ///
+    /// Without a pre-shared key, authentication tags are stripped without being verified.
+    pub fn decode<'a>(&self, datagram: &'a mut [u8]) -> Result<(Header, &'a [u8]), FrameError> {
+        let Some((magic, &[version, kind, flags, ..])) = datagram.split_first_chunk::<{ MAGIC.len() }>() else {
+            return Err(FrameError::Truncated);
+        };
+        if *magic != MAGIC {
+            return Err(FrameError::InvalidMagic);
+        }
+        if version != VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+
+        let mut header = Header {
+            kind: Kind::try_from(kind)?,
+            encrypted: flags & FLAG_ENCRYPTED != 0,
+            ..Header::default()
+        };
+
+        // Trailers are peeled off from the end, outermost first
+        let mut frame: &[u8] = datagram;
+        if flags & FLAG_AUTH != 0 {
+            let (covered, tag) = split_trailer(frame, AUTH_TAG_LEN)?;
+            if let Some(auth) = &self.auth {
+                let mut mac = auth.clone();
+                mac.update(covered);
+                mac.verify_truncated_left(tag).map_err(|_| FrameError::Unauthenticated)?;
+            }
+            frame = covered;
+        } else if self.auth.is_some() {
+            return Err(FrameError::Unauthenticated);
+        }
+        if flags & FLAG_CHECKSUM != 0 {
+            let (covered, checksum) = split_trailer(frame, CHECKSUM_LEN)?;
+            if crc32c::crc32c(covered).to_be_bytes() != checksum {
+                return Err(FrameError::ChecksumMismatch);
+            }
+            header.checksum = true;
+            frame = covered;
+        }
+
+        let mut offset = HEADER_LEN;
+        if flags & FLAG_SESSION_ID != 0 {
+            let Some((session_id, _)) = frame[offset..].split_first_chunk::<8>() else {
+                return Err(FrameError::Truncated);
+            };
+            header.session_id = Some(SessionId::from_be_bytes(*session_id));
+            offset += 8;
+        }
+        if flags & FLAG_FEC != 0 {
+            let Some(([group @ .., index, size, parity], _)) = frame[offset..].split_first_chunk::<FEC_TAG_LEN>() else {
+                return Err(FrameError::Truncated);
+            };
+            header.fec = Some(FecTag {
+                group: u32::from_be_bytes(*group),
+                index: *index,
+                size: *size,
+                parity: *parity,
+            });
+            offset += FEC_TAG_LEN;
+        }
+        if flags & FLAG_SEQUENCE != 0 {
+            let Some((sequence, _)) = frame[offset..].split_first_chunk::<SEQUENCE_LEN>() else {
+                return Err(FrameError::Truncated);
+            };
+            header.sequence = Some(u32::from_be_bytes(*sequence));
+            offset += SEQUENCE_LEN;
+        }
+        if flags & FLAG_TIMESTAMP != 0 {
+            let Some((timestamp, _)) = frame[offset..].split_first_chunk::<TIMESTAMP_LEN>() else {
+                return Err(FrameError::Truncated);
+            };
+            header.timestamp = Some(u32::from_be_bytes(*timestamp));
+            offset += TIMESTAMP_LEN;
+        }
+
+        let end = frame.len();
+        let (frame, _) = datagram.split_at_mut(end);
+        let payload: &[u8] = if header.encrypted {
+            let Some(encryption) = &self.encryption else {
+                return Err(FrameError::DecryptionFailed);
+            };
+            if end < offset + NONCE_LEN + AEAD_TAG_LEN {
+                return Err(FrameError::Truncated);
+            }
+            let (associated, rest) = frame.split_at_mut(offset);
+            let (nonce, rest) = rest.split_at_mut(NONCE_LEN);
+            let (payload, tag) = rest.split_at_mut(rest.len() - AEAD_TAG_LEN);
+            encryption
+                .cipher
+                .decrypt_in_place_detached(XNonce::from_slice(nonce), associated, payload, Tag::from_slice(tag))
+                .map_err(|_| FrameError::DecryptionFailed)?;
+            payload
+        } else {
+            &frame[offset..]
+        };
+
+        if flags & FLAG_PADDED == 0 {
+            return Ok((header, payload));
+        }
+        header.padded = true;
+        let Some((payload, padding)) = payload.split_last_chunk::<PADDING_LEN_LEN>() else {
+            return Err(FrameError::Truncated);
+        };
+        let Some(unpadded_len) = payload.len().checked_sub(u16::from_be_bytes(*padding) as usize) else {
+            return Err(FrameError::Truncated);
+        };
+        Ok((header, &payload[..unpadded_len]))
+    }
+}
+
+/// Splits a trailer of `len` bytes off the end of the frame, leaving the fixed header intact
+fn split_trailer(frame: &[u8], len: usize) -> Result<(&[u8], &[u8]), FrameError> {
+    if frame.len() < HEADER_LEN + len {
+        return Err(FrameError::Truncated);
+    }
+    Ok(frame.split_at(frame.len() - len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: &[u8] = b"a WireGuard datagram";
+
+    fn full_header() -> Header {
+        Header {
+            kind: Kind::Data,
+            session_id: Some(0x0123_4567_89ab_cdef),
+            checksum: true,
+            encrypted: true,
+            padded: true,
+            fec: Some(FecTag { group: 7, index: 1, size: 3, parity: 2 }),
+            sequence: Some(42),
+            timestamp: Some(1_000_000),
+        }
+    }
+
+    fn round_trip(codec: &Codec, header: &Header) -> (Header, Vec<u8>) {
+        let mut frame = Vec::new();
+        codec.encode(header, PAYLOAD, &mut frame);
+        assert!(is_frame(&frame));
+        let (header, payload) = codec.decode(&mut frame).unwrap();
+        (header, payload.to_vec())
+    }
+
+    #[test]
+    fn round_trips_a_bare_frame() {
+        let header = Header::default();
+        let (decoded, payload) = round_trip(&Codec::default(), &header);
+        assert_eq!(decoded, header);
+        assert_eq!(payload, PAYLOAD);
+    }
+
+    #[test]
+    fn round_trips_every_option() {
+        let codec = Codec::new(Some("psk"), Some("encryption key")).with_padding(vec![128, 256]);
+        let (decoded, payload) = round_trip(&codec, &full_header());
+        assert_eq!(decoded, full_header());
+        assert_eq!(payload, PAYLOAD);
+    }
+
+    #[test]
+    fn round_trips_control_frames() {
+        let header = Header { kind: Kind::Control, session_id: Some(1), ..Header::default() };
+        let (decoded, payload) = round_trip(&Codec::new(Some("psk"), None), &header);
+        assert_eq!(decoded, header);
+        assert_eq!(payload, PAYLOAD);
+    }
+
+    #[test]
+    fn pads_to_the_smallest_size_fitting() {
+        let codec = Codec::default().with_padding(vec![256, 64, 128]);
+        let mut frame = Vec::new();
+        codec.encode(&Header { padded: true, ..Header::default() }, PAYLOAD, &mut frame);
+        assert_eq!(frame.len(), 64);
+
+        codec.encode(&Header { padded: true, ..Header::default() }, &[0; 100], &mut frame);
+        assert_eq!(frame.len(), 128);
+
+        codec.encode(&Header { padded: true, ..Header::default() }, &[0; 300], &mut frame);
+        let (header, payload) = codec.decode(&mut frame).unwrap();
+        assert!(!header.padded);
+        assert_eq!(payload, [0; 300]);
+    }
+
+    #[test]
+    fn ignores_encryption_and_padding_the_codec_cant_do() {
+        let codec = Codec::default();
+        let mut frame = Vec::new();
+        codec.encode(&full_header(), PAYLOAD, &mut frame);
+        assert_eq!(frame.len(), codec.overhead(&full_header()) + PAYLOAD.len());
+        let (header, payload) = codec.decode(&mut frame).unwrap();
+        assert!(!header.encrypted && !header.padded);
+        assert_eq!(payload, PAYLOAD);
+    }
+
+    #[test]
+    fn overhead_matches_the_encoded_size() {
+        let codec = Codec::new(Some("psk"), Some("encryption key"));
+        let header = Header { padded: false, ..full_header() };
+        let mut frame = Vec::new();
+        codec.encode(&header, PAYLOAD, &mut frame);
+        assert_eq!(frame.len(), codec.overhead(&header) + PAYLOAD.len());
+    }
+
+    #[test]
+    fn hides_the_payload_under_a_fresh_nonce() {
+        let codec = Codec::new(None, Some("encryption key"));
+        let header = Header { encrypted: true, ..Header::default() };
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        codec.encode(&header, PAYLOAD, &mut first);
+        codec.encode(&header, PAYLOAD, &mut second);
+        assert!(!first.windows(PAYLOAD.len()).any(|window| window == PAYLOAD));
+        assert_ne!(first[HEADER_LEN..HEADER_LEN + NONCE_LEN], second[HEADER_LEN..HEADER_LEN + NONCE_LEN]);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn rejects_every_tampered_byte_of_an_authenticated_frame() {
+        let codec = Codec::new(Some("psk"), None);
+        let mut frame = Vec::new();
+        codec.encode(&Header { session_id: Some(1), sequence: Some(2), ..Header::default() }, PAYLOAD, &mut frame);
+        for i in HEADER_LEN..frame.len() {
+            let mut tampered = frame.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(codec.decode(&mut tampered).unwrap_err(), FrameError::Unauthenticated, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn rejects_every_tampered_byte_of_an_encrypted_frame() {
+        let codec = Codec::new(None, Some("encryption key"));
+        let mut frame = Vec::new();
+        codec.encode(&Header { encrypted: true, session_id: Some(1), ..Header::default() }, PAYLOAD, &mut frame);
+        for i in HEADER_LEN..frame.len() {
+            let mut tampered = frame.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(codec.decode(&mut tampered).unwrap_err(), FrameError::DecryptionFailed, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn rejects_frames_under_other_keys() {
+        let mut frame = Vec::new();
+        Codec::new(Some("psk"), None).encode(&Header::default(), PAYLOAD, &mut frame);
+        assert_eq!(Codec::new(Some("other psk"), None).decode(&mut frame.clone()).unwrap_err(), FrameError::Unauthenticated);
+        // Without a pre-shared key, the tag is stripped unverified
+        assert_eq!(Codec::default().decode(&mut frame).unwrap().1, PAYLOAD);
+
+        let header = Header { encrypted: true, ..Header::default() };
+        Codec::new(None, Some("encryption key")).encode(&header, PAYLOAD, &mut frame);
+        assert_eq!(Codec::new(None, Some("other key")).decode(&mut frame.clone()).unwrap_err(), FrameError::DecryptionFailed);
+        assert_eq!(Codec::default().decode(&mut frame).unwrap_err(), FrameError::DecryptionFailed);
+    }
+
+    #[test]
+    fn requires_authentication_with_a_pre_shared_key() {
+        let mut frame = Vec::new();
+        Codec::default().encode(&Header::default(), PAYLOAD, &mut frame);
+        assert_eq!(Codec::new(Some("psk"), None).decode(&mut frame).unwrap_err(), FrameError::Unauthenticated);
+    }
+
+    #[test]
+    fn rejects_corrupted_checksums() {
+        let codec = Codec::default();
+        let mut frame = Vec::new();
+        codec.encode(&Header { checksum: true, ..Header::default() }, PAYLOAD, &mut frame);
+        frame[HEADER_LEN] ^= 0x01;
+        assert_eq!(codec.decode(&mut frame).unwrap_err(), FrameError::ChecksumMismatch);
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let codec = Codec::default();
+        let mut frame = Vec::new();
+        codec.encode(&Header { session_id: Some(1), ..Header::default() }, &[], &mut frame);
+
+        assert_eq!(codec.decode(&mut frame[..HEADER_LEN - 1].to_vec()).unwrap_err(), FrameError::Truncated);
+        assert_eq!(codec.decode(&mut frame[..HEADER_LEN + 4].to_vec()).unwrap_err(), FrameError::Truncated);
+
+        let mut invalid = frame.clone();
+        invalid[1] = b'x';
+        assert_eq!(codec.decode(&mut invalid).unwrap_err(), FrameError::InvalidMagic);
+
+        let mut invalid = frame.clone();
+        invalid[MAGIC.len()] = VERSION + 1;
+        assert_eq!(codec.decode(&mut invalid).unwrap_err(), FrameError::UnsupportedVersion(VERSION + 1));
+
+        let mut invalid = frame.clone();
+        invalid[MAGIC.len() + 1] = 9;
+        assert_eq!(codec.decode(&mut invalid).unwrap_err(), FrameError::UnknownKind(9));
+
+        // A raw WireGuard handshake initiation isn't a frame
+        assert!(!is_frame(&[1, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use tracing::debug;
+
+/// Lock on a listen address, held for the lifetime of the process
+///
+/// The lock file lives in `$XDG_RUNTIME_DIR` (or the temporary directory) and holds the pid and
+/// version of the owning instance, so a second instance started on the same address can tell
+/// which one is in the way instead of failing half-bound.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Locks `listen_addr` for the `name` binary, failing if another instance already holds it
+    pub fn acquire(name: &str, listen_addr: &str, version: &str) -> Result<Self> {
+        let addr: String = listen_addr
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+            .join(format!("rengarde-{}-{}.lock", name, addr));
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut owner = String::new();
+                file.read_to_string(&mut owner)?;
+                let mut owner = owner.split_whitespace();
+                bail!(
+                    "Another {} instance is already listening on {} (pid {}, version {}); lock file: {}",
+                    name,
+                    listen_addr,
+                    owner.next().unwrap_or("unknown"),
+                    owner.next().unwrap_or("unknown"),
+                    path.display(),
+                );
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+
+        file.set_len(0)?;
+        writeln!(file, "{} {}", std::process::id(), version)?;
+        debug!("Acquired instance lock {}", path.display());
+
+        Ok(Self { _file: file })
+    }
+}
@@ -1,69 +1,210 @@
+use std::time::Duration;
+
 use anyhow::{Context, Result};
 use opentelemetry::{global, KeyValue};
-use opentelemetry_otlp::WithExportConfig;
+use opentelemetry::logs::LoggerProvider as _;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::TonicExporterBuilder;
 use opentelemetry_sdk::{
+    logs::LoggerProvider,
     metrics::{MeterProviderBuilder, PeriodicReader, SdkMeterProvider},
     Resource,
     runtime,
-    trace::{BatchConfig, RandomIdGenerator, Sampler, Tracer},
+    trace::{RandomIdGenerator, Sampler, Tracer, TracerProvider},
 };
 use opentelemetry_sdk::metrics::reader::{DefaultAggregationSelector, DefaultTemporalitySelector};
 use opentelemetry_semantic_conventions::resource::{DEPLOYMENT_ENVIRONMENT, SERVICE_NAME, SERVICE_VERSION};
 use opentelemetry_semantic_conventions::SCHEMA_URL;
+use serde::Serialize;
 use tracing_core::{Level, LevelFilter};
 use tracing_opentelemetry::{MetricsLayer, OpenTelemetryLayer};
-use tracing_subscriber::{Layer, layer::SubscriberExt, util::SubscriberInitExt};
+use tonic::metadata::{MetadataKey, MetadataMap};
+use tonic::transport::Channel;
+use tracing::warn;
+use tracing_subscriber::{Layer, filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
+
+pub mod cli;
+pub mod config;
+pub mod control;
+pub mod datagram;
+pub mod dedup;
+pub mod drops;
+pub mod fec;
+pub mod frame;
+pub mod instance;
+pub mod json_log;
+pub mod otel_log;
+pub mod otlp;
+pub mod packet_trace;
+pub mod path;
+pub mod profile;
+pub mod prometheus;
+pub mod statsd;
+pub mod summary;
+
+#[cfg(all(feature = "tokio-console", not(tokio_unstable)))]
+compile_error!("The tokio-console feature needs tokio's unstable features: build with RUSTFLAGS=\"--cfg tokio_unstable\"");
+
+/// Default size of the datagram buffers: the 1500-byte payload of an untagged Ethernet frame, whose MTU is 1518 bytes
+/// with the 18 bytes of frame overhead
+pub const DEFAULT_BUFFER_SIZE: usize = 1500;
+
+/// Backend the metrics are exported to
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricsExporter {
+    /// Pushed to the OTLP endpoint, if set
+    Otlp,
+    /// Served in the Prometheus text format on the address
+    Prometheus(String),
+    /// Sent to the StatsD server at the address, with tags if it speaks DogStatsD
+    Statsd { addr: String, dogstatsd: bool },
+}
+
+/// Transport of the OTLP exports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OtlpProtocol {
+    Grpc,
+    HttpProtobuf,
+}
+
+/// Format of the logs written to the standard output
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, keeping the fields of the events
+    Json,
+}
 
 #[derive(Debug)]
 pub struct TracingConfig {
+    /// Name the traces and metrics are reported under
+    pub service_name: String,
+    /// Whether traces and metrics are exported at all, unless `OTEL_SDK_DISABLED` is `true`
+    pub enabled: bool,
     pub endpoint: Option<String>,
+    pub protocol: OtlpProtocol,
+    /// Headers sent with every export, e.g. for authentication
+    pub headers: Vec<(String, String)>,
+    /// Time after which an export is given up
+    pub timeout: Duration,
+    /// Share of the traces sampled, from 0 to 1, unless the parent span decided already
+    pub sampling_ratio: f64,
+    /// Deployment environment the traces and metrics are tagged with
+    pub environment: String,
+    /// Other resource attributes, e.g. `host.name`
+    pub resource_attributes: Vec<(String, String)>,
+    pub metrics_exporter: MetricsExporter,
+    /// Whether the logs are exported over OTLP too, unless `OTEL_LOGS_EXPORTER` is `none`
+    pub export_logs: bool,
     pub log_level: Level,
+    pub log_format: LogFormat,
     pub default_directive: LevelFilter,
+    /// Whether `RUST_LOG` may override the default directive
+    pub from_env: bool,
 }
 
 impl Default for TracingConfig {
     fn default() -> Self {
+        let mut resource_attributes = std::env::var("OTEL_RESOURCE_ATTRIBUTES")
+            .map(|attributes| otlp::parse_key_values(&attributes))
+            .unwrap_or_default();
+        let environment = resource_attributes
+            .iter()
+            .position(|(key, _)| key == DEPLOYMENT_ENVIRONMENT)
+            .map_or_else(|| "develop".to_owned(), |index| resource_attributes.remove(index).1);
         Self {
+            service_name: std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "rengarde".to_owned()),
+            enabled: std::env::var("OTEL_SDK_DISABLED").map_or(true, |disabled| !disabled.eq_ignore_ascii_case("true")),
             endpoint: std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok(),
+            protocol: match std::env::var("OTEL_EXPORTER_OTLP_PROTOCOL").as_deref() {
+                Ok("http/protobuf") => OtlpProtocol::HttpProtobuf,
+                _ => OtlpProtocol::Grpc,
+            },
+            headers: std::env::var("OTEL_EXPORTER_OTLP_HEADERS").map(|headers| otlp::parse_key_values(&headers)).unwrap_or_default(),
+            // In milliseconds, as the specification says
+            timeout: std::env::var("OTEL_EXPORTER_OTLP_TIMEOUT")
+                .ok()
+                .and_then(|timeout| timeout.parse().ok())
+                .map_or(Duration::from_secs(10), Duration::from_millis),
+            sampling_ratio: std::env::var("OTEL_TRACES_SAMPLER_ARG")
+                .ok()
+                .and_then(|ratio| ratio.parse().ok())
+                .unwrap_or(1.0),
+            environment,
+            resource_attributes,
+            metrics_exporter: match std::env::var("OTEL_METRICS_EXPORTER").as_deref() {
+                Ok("prometheus") => MetricsExporter::Prometheus(prometheus::listen_addr()),
+                Ok("statsd") => MetricsExporter::Statsd { addr: statsd::target_addr(), dogstatsd: false },
+                Ok("dogstatsd") => MetricsExporter::Statsd { addr: statsd::target_addr(), dogstatsd: true },
+                _ => MetricsExporter::Otlp,
+            },
+            export_logs: std::env::var("OTEL_LOGS_EXPORTER").map_or(true, |exporter| exporter != "none"),
             log_level: Level::DEBUG,
+            log_format: LogFormat::Text,
             default_directive: LevelFilter::INFO,
+            from_env: true,
         }
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn print_header(
-    rengarde_official_build: bool,
-    cargo_pkg_name: &str,
-    cargo_pkg_version: &str,
-    vergen_git_describe: &str,
-    vergen_git_dirty: &str,
-    vergen_build_timestamp: &str,
-    vergen_cargo_target_triple: &str,
-    rust_runtime: &str,
-) {
-    let version_string = if rengarde_official_build {
-        cargo_pkg_version.to_string()
-    } else {
-        format!(
-            "{}{} built at {} for {} - UNOFFICIAL BUILD",
-            vergen_git_describe,
-            if vergen_git_dirty == "true" { "* (dirty)" } else { "" },
-            vergen_build_timestamp.split_at(19).0,
-            vergen_cargo_target_triple
-        )
-    };
+/// Build metadata of a binary, printed in its header and by its `version` command
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    /// Whether the binary was built with `RENGARDE_OFFICIAL_BUILD=true`
+    pub official_build: bool,
+    pub git_describe: &'static str,
+    pub git_dirty: bool,
+    pub build_timestamp: &'static str,
+    pub target_triple: &'static str,
+    /// Runtime feature the binary was built with: `rayon` or `tokio`
+    pub runtime: &'static str,
+}
 
-    println!(
-        "rengarde-{} ({}) ver. {}",
-        cargo_pkg_name,
-        rust_runtime,
-        version_string,
-    );
+impl BuildInfo {
+    /// Returns the header line, e.g. `rengarde-server (tokio) ver. 0.3.0`
+    pub fn header(&self) -> String {
+        let version_string = if self.official_build {
+            self.version.to_string()
+        } else {
+            format!(
+                "{}{} built at {} for {} - UNOFFICIAL BUILD",
+                self.git_describe,
+                if self.git_dirty { "* (dirty)" } else { "" },
+                self.build_timestamp.split_at(19).0,
+                self.target_triple
+            )
+        };
+
+        format!("rengarde-{} ({}) ver. {}", self.name, self.runtime, version_string)
+    }
+}
+
+/// Prints the header, or logs it when the logs are JSON so the output stays one object per line
+pub fn print_header(info: &BuildInfo, log_format: LogFormat) {
+    match log_format {
+        LogFormat::Text => println!("{}", info.header()),
+        LogFormat::Json => tracing::info!(version = info.version, "{}", info.header()),
+    }
+}
+
+/// Prints the build metadata for the `version` command, as JSON for fleet inventories if `json` is set
+pub fn print_version(info: &BuildInfo, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(info)?);
+    } else {
+        print_header(info, LogFormat::Text);
+    }
+    Ok(())
 }
 
 pub struct Guard {
     meter_provider: Option<SdkMeterProvider>,
+    logger_provider: Option<LoggerProvider>,
 }
 
 impl Drop for Guard {
@@ -71,45 +212,115 @@ impl Drop for Guard {
         if let Err(err) = self.meter_provider.as_ref().map_or(Ok(()), |m| m.shutdown()) {
             eprintln!("{err:?}");
         }
+        if let Err(err) = self.logger_provider.as_ref().map_or(Ok(()), |l| l.shutdown()) {
+            eprintln!("{err:?}");
+        }
         global::shutdown_tracer_provider();
     }
 }
 
-pub fn init() -> Result<Guard> {
-    let config = TracingConfig::default();
-    let meter_provider = init_tracing_subscriber(&config);
-    Ok(Guard { meter_provider })
+/// Starts configuring the logging and the telemetry, from the `OTEL_*` environment variables and `RUST_LOG`, e.g.
+/// `shared::telemetry().service_name("rengarde-server").log_level(args.log_level).init()`
+pub fn telemetry() -> Telemetry {
+    Telemetry { config: TracingConfig::default() }
+}
+
+/// Builder of the logging and telemetry setup, see [`telemetry`]
+#[derive(Debug)]
+pub struct Telemetry {
+    config: TracingConfig,
+}
+
+impl Telemetry {
+    /// Reports the traces and metrics under `service_name`, unless `OTEL_SERVICE_NAME` is set
+    pub fn service_name(mut self, service_name: impl Into<String>) -> Self {
+        if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+            self.config.service_name = service_name.into();
+        }
+        self
+    }
+
+    /// Exports the traces and metrics to the OTLP `endpoint` instead of `OTEL_EXPORTER_OTLP_ENDPOINT`'s
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.config.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Logs at `log_level` if set (e.g. from `--log-level`), or as `RUST_LOG` says
+    pub fn log_level(mut self, log_level: Option<LevelFilter>) -> Self {
+        if let Some(log_level) = log_level {
+            self.config.log_level = Level::DEBUG.max(log_level.into_level().unwrap_or(Level::ERROR));
+            self.config.default_directive = log_level;
+            self.config.from_env = false;
+        }
+        self
+    }
+
+    pub fn log_format(mut self, log_format: LogFormat) -> Self {
+        self.config.log_format = log_format;
+        self
+    }
+
+    /// Installs the logging and the exporters, which keep running until the returned guard is dropped
+    pub fn init(self) -> Result<Guard> {
+        let (meter_provider, logger_provider) = init_tracing_subscriber(&self.config);
+        Ok(Guard { meter_provider, logger_provider })
+    }
 }
 
-fn resource() -> Resource {
+fn resource(config: &TracingConfig) -> Resource {
     Resource::from_schema_url(
         [
-            KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
+            KeyValue::new(SERVICE_NAME, config.service_name.clone()),
             KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
-            KeyValue::new(DEPLOYMENT_ENVIRONMENT, "develop"),
-        ],
+            KeyValue::new(DEPLOYMENT_ENVIRONMENT, config.environment.clone()),
+        ]
+        .into_iter()
+        .chain(config.resource_attributes.iter().map(|(key, value)| KeyValue::new(key.clone(), value.clone()))),
         SCHEMA_URL,
     )
 }
 
-fn init_meter_provider(endpoint: &str) -> Result<SdkMeterProvider> {
-    let exporter = opentelemetry_otlp::new_exporter()
-        .tonic()
-        .with_endpoint(endpoint);
+/// Builds the gRPC exporter, on a channel of its own so the timeout is read in milliseconds like for HTTP
+fn tonic_exporter(config: &TracingConfig, endpoint: &str) -> Result<TonicExporterBuilder> {
+    let mut metadata = MetadataMap::new();
+    for (name, value) in &config.headers {
+        let key = MetadataKey::from_bytes(name.as_bytes()).with_context(|| format!("Invalid OTLP header '{}'", name))?;
+        metadata.insert(key, value.parse().with_context(|| format!("Invalid value of the OTLP header '{}'", name))?);
+    }
+    let channel = Channel::from_shared(endpoint.to_owned())
+        .with_context(|| format!("Invalid OTLP endpoint '{}'", endpoint))?
+        .timeout(config.timeout)
+        .connect_lazy();
+    Ok(opentelemetry_otlp::new_exporter().tonic().with_channel(channel).with_metadata(metadata))
+}
 
-    let exporter = exporter
-        .build_metrics_exporter(
-            Box::new(DefaultAggregationSelector::new()),
-            Box::new(DefaultTemporalitySelector::new()),
-        )
-        .context("Failed to build metrics exporter")?;
+/// Interval between the metrics exports
+pub(crate) const METRICS_INTERVAL: Duration = Duration::from_secs(5);
 
-    let reader = PeriodicReader::builder(exporter, runtime::Tokio)
-        .with_interval(std::time::Duration::from_secs(5))
-        .build();
+fn init_meter_provider(config: &TracingConfig, endpoint: &str) -> Result<SdkMeterProvider> {
+    let reader = match config.protocol {
+        OtlpProtocol::Grpc => {
+            let exporter = tonic_exporter(config, endpoint)?
+                .build_metrics_exporter(
+                    Box::new(DefaultAggregationSelector::new()),
+                    Box::new(DefaultTemporalitySelector::new()),
+                )
+                .context("Failed to build metrics exporter")?;
+            PeriodicReader::builder(exporter, runtime::Tokio)
+                .with_interval(METRICS_INTERVAL)
+                .build()
+        }
+        OtlpProtocol::HttpProtobuf => {
+            let exporter = otlp::HttpMetricsExporter::new(endpoint, &config.headers, config.timeout)?;
+            PeriodicReader::builder(exporter, runtime::Tokio)
+                .with_interval(METRICS_INTERVAL)
+                .build()
+        }
+    };
 
     let meter_provider = MeterProviderBuilder::default()
-        .with_resource(resource())
+        .with_resource(resource(config))
         .with_reader(reader)
         .build();
 
@@ -117,50 +328,150 @@ fn init_meter_provider(endpoint: &str) -> Result<SdkMeterProvider> {
     Ok(meter_provider)
 }
 
-fn init_tracer_provider(endpoint: &str) -> Result<Tracer> {
-    let exporter = opentelemetry_otlp::new_exporter()
-        .tonic()
-        .with_endpoint(endpoint);
-
-    let tracer = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_trace_config(
+fn init_tracer_provider(config: &TracingConfig, endpoint: &str) -> Result<Tracer> {
+    let provider = TracerProvider::builder();
+    let provider = match config.protocol {
+        OtlpProtocol::Grpc => {
+            let exporter = tonic_exporter(config, endpoint)?
+                .build_span_exporter()
+                .context("Failed to build span exporter")?;
+            provider.with_batch_exporter(exporter, runtime::Tokio)
+        }
+        OtlpProtocol::HttpProtobuf => {
+            let exporter = otlp::HttpSpanExporter::new(endpoint, &config.headers, config.timeout)?;
+            provider.with_batch_exporter(exporter, runtime::Tokio)
+        }
+    };
+    let provider = provider
+        .with_config(
             opentelemetry_sdk::trace::Config::default()
-                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(1.0))))
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sampling_ratio))))
                 .with_id_generator(RandomIdGenerator::default())
-                .with_resource(resource()),
+                .with_resource(resource(config)),
         )
-        .with_batch_config(BatchConfig::default())
-        .with_exporter(exporter)
-        .install_batch(runtime::Tokio)
-        .context("Failed to install tracer provider")?;
+        .build();
 
+    let tracer = provider.tracer_builder(env!("CARGO_PKG_NAME")).with_version(env!("CARGO_PKG_VERSION")).build();
+    global::set_tracer_provider(provider);
     Ok(tracer)
 }
 
-fn init_tracing_subscriber(config: &TracingConfig) -> Option<SdkMeterProvider> {
-    let tracer_provider = config.endpoint.as_ref().map(|endpoint| {
-        init_tracer_provider(endpoint).unwrap()
-    });
-    let meter_provider = config.endpoint.as_ref().map(|endpoint| {
-        init_meter_provider(endpoint).unwrap()
-    });
+fn init_logger_provider(config: &TracingConfig, endpoint: &str) -> Result<LoggerProvider> {
+    let provider = LoggerProvider::builder();
+    let provider = match config.protocol {
+        OtlpProtocol::Grpc => {
+            let exporter = tonic_exporter(config, endpoint)?
+                .build_log_exporter()
+                .context("Failed to build log exporter")?;
+            provider.with_batch_exporter(exporter, runtime::Tokio)
+        }
+        OtlpProtocol::HttpProtobuf => {
+            let exporter = otlp::HttpLogExporter::new(endpoint, &config.headers, config.timeout)?;
+            provider.with_batch_exporter(exporter, runtime::Tokio)
+        }
+    };
+    Ok(provider
+        .with_config(opentelemetry_sdk::logs::Config::default().with_resource(resource(config)))
+        .build())
+}
+
+/// Filter of the events written to the standard output, and exported as logs
+fn log_filter(config: &TracingConfig) -> tracing_subscriber::EnvFilter {
+    let filter = tracing_subscriber::EnvFilter::builder().with_default_directive(config.default_directive.into());
+    if config.from_env { filter.from_env_lossy() } else { filter.parse_lossy("") }
+}
+
+/// Installs the logging and the exporters. An exporter failing to set up (e.g. an invalid endpoint) is logged and left
+/// out rather than stopping the binary; unreachable backends are retried by the exporters themselves.
+fn init_tracing_subscriber(config: &TracingConfig) -> (Option<SdkMeterProvider>, Option<LoggerProvider>) {
+    let tracer_provider = match &config.endpoint {
+        Some(endpoint) if config.enabled => {
+            Some(init_tracer_provider(config, endpoint).context("Failed to set up the trace exporter"))
+        }
+        _ => None,
+    };
+    let logger_provider = match &config.endpoint {
+        Some(endpoint) if config.enabled && config.export_logs => {
+            Some(init_logger_provider(config, endpoint).context("Failed to set up the log exporter"))
+        }
+        _ => None,
+    };
+    let meter_provider = if !config.enabled {
+        None
+    } else {
+        match &config.metrics_exporter {
+            MetricsExporter::Prometheus(addr) => Some(Ok(prometheus::init_meter_provider(addr, resource(config)))),
+            MetricsExporter::Statsd { addr, dogstatsd } => {
+                Some(Ok(statsd::init_meter_provider(addr, *dogstatsd, resource(config))))
+            }
+            MetricsExporter::Otlp => config.endpoint.as_ref().map(|endpoint| {
+                init_meter_provider(config, endpoint)
+            }),
+        }
+        .map(|meter_provider| meter_provider.context("Failed to set up the metrics exporter"))
+    };
+    let (tracer_provider, tracer_error) = split(tracer_provider);
+    let (meter_provider, meter_error) = split(meter_provider);
+    let (logger_provider, logger_error) = split(logger_provider);
+
+    // The console reads the runtime's own events, which are more verbose than the level of the logs
+    #[cfg(feature = "tokio-console")]
+    let (level_filter, console) = (
+        Targets::new().with_default(config.log_level).with_targets(RUNTIME_TARGETS.map(|target| (target, Level::TRACE))),
+        Some(console_subscriber::ConsoleLayer::builder().with_default_env().spawn()),
+    );
+    #[cfg(not(feature = "tokio-console"))]
+    let (level_filter, console) = (LevelFilter::from_level(config.log_level), None::<tracing_subscriber::layer::Identity>);
 
     tracing_subscriber::registry()
-        .with(LevelFilter::from_level(config.log_level))
-        .with(tracing_subscriber::fmt::layer()
-            .with_level(true)
-            .with_target(false)
-            .with_thread_ids(true)
-            .with_filter(
-                tracing_subscriber::EnvFilter::builder()
-                    .with_default_directive(config.default_directive.into())
-                    .from_env_lossy(),
-            )
-        )
+        .with(level_filter)
+        .with(console)
+        .with({
+            let filter = log_filter(config);
+            match config.log_format {
+                LogFormat::Text => tracing_subscriber::fmt::layer()
+                    .with_level(true)
+                    .with_target(false)
+                    .with_thread_ids(true)
+                    .with_filter(filter)
+                    .boxed(),
+                LogFormat::Json => tracing_subscriber::fmt::layer()
+                    .fmt_fields(json_log::JsonFields)
+                    .event_format(json_log::JsonFormat)
+                    .with_filter(filter)
+                    .boxed(),
+            }
+        })
         .with(meter_provider.clone().map(MetricsLayer::new))
-        .with(tracer_provider.map(OpenTelemetryLayer::new))
+        .with(tracer_provider.map(|tracer| {
+            // Leave out the spans of the runtime's tasks, which only tokio-console needs
+            let filter = Targets::new().with_default(Level::TRACE).with_targets(RUNTIME_TARGETS.map(|target| (target, LevelFilter::OFF)));
+            OpenTelemetryLayer::new(tracer).with_filter(filter)
+        }))
+        .with(logger_provider.as_ref().map(|logger_provider| {
+            let logger = logger_provider.logger_builder(config.service_name.clone()).with_version(env!("CARGO_PKG_VERSION")).build();
+            // Leave out the exporters' own dependencies, which would log about every export
+            let filter = ["h2", "hyper", "tonic", "tower"]
+                .into_iter()
+                .fold(log_filter(config), |filter, target| filter.add_directive(format!("{}=off", target).parse().unwrap()));
+            otel_log::OtelLogLayer::new(logger).with_filter(filter)
+        }))
         .init();
 
-    meter_provider
+    for err in tracer_error.into_iter().chain(meter_error).chain(logger_error) {
+        warn!("{:#}; continuing without it", err);
+    }
+    (meter_provider, logger_provider)
+}
+
+/// Targets of the events tokio emits about its tasks and resources when built with `--cfg tokio_unstable`
+const RUNTIME_TARGETS: [&str; 2] = ["tokio", "runtime"];
+
+/// Splits the outcome of an optional setup into its result and its error
+fn split<T>(setup: Option<Result<T>>) -> (Option<T>, Option<anyhow::Error>) {
+    match setup {
+        Some(Ok(value)) => (Some(value), None),
+        Some(Err(err)) => (None, Some(err)),
+        None => (None, None),
+    }
 }
@@ -0,0 +1,126 @@
+//! Export of the logs over OTLP, next to the traces and metrics, so a single collector receives all three: every
+//! record carries the trace and span IDs of the span it was logged in.
+//!
+//! The events are exported as the standard output shows them (`RUST_LOG` or `--log-level`), with the message as the
+//! body and the other fields as attributes; the fields recording metrics are left out.
+
+use std::time::SystemTime;
+
+use opentelemetry::logs::{AnyValue, LogRecord as _, Logger as _, Severity};
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceId, TraceState};
+use opentelemetry::Key;
+use opentelemetry_sdk::logs::{Logger, TraceContext};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Prefixes of the fields `MetricsLayer` turns into metrics
+const METRIC_PREFIXES: [&str; 4] = ["monotonic_counter.", "counter.", "histogram.", "gauge."];
+
+/// Emits the events as OpenTelemetry log records
+pub struct OtelLogLayer {
+    logger: Logger,
+}
+
+impl OtelLogLayer {
+    pub fn new(logger: Logger) -> Self {
+        Self { logger }
+    }
+}
+
+impl<S> Layer<S> for OtelLogLayer
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        let mut record = self.logger.create_log_record();
+        record.set_timestamp(SystemTime::now());
+        record.set_severity_number(severity(level));
+        record.set_severity_text(level.as_str().into());
+
+        let mut visitor = RecordVisitor { body: None, attributes: Vec::new() };
+        event.record(&mut visitor);
+        if let Some(body) = visitor.body {
+            record.set_body(body);
+        }
+        if !visitor.attributes.is_empty() {
+            record.add_attributes(visitor.attributes);
+        }
+
+        // Correlate with the span the event happened in, as the OpenTelemetry layer exports it
+        if let Some(span) = ctx.event_span(event) {
+            if let Some(otel_data) = span.extensions().get::<OtelData>() {
+                let parent = otel_data.parent_cx.span().span_context().clone();
+                let trace_id = otel_data.builder.trace_id.unwrap_or_else(|| parent.trace_id());
+                let span_id = otel_data.builder.span_id.unwrap_or(SpanId::INVALID);
+                if trace_id != TraceId::INVALID && span_id != SpanId::INVALID {
+                    let span_context = SpanContext::new(trace_id, span_id, parent.trace_flags(), false, TraceState::default());
+                    record.trace_context = Some(TraceContext::from(&span_context));
+                }
+            }
+        }
+
+        self.logger.emit(record);
+    }
+}
+
+fn severity(level: Level) -> Severity {
+    match level {
+        Level::TRACE => Severity::Trace,
+        Level::DEBUG => Severity::Debug,
+        Level::INFO => Severity::Info,
+        Level::WARN => Severity::Warn,
+        Level::ERROR => Severity::Error,
+    }
+}
+
+/// Collects the message of an event as the body of its record, and its other fields as attributes
+struct RecordVisitor {
+    body: Option<AnyValue>,
+    attributes: Vec<(Key, AnyValue)>,
+}
+
+impl RecordVisitor {
+    fn insert(&mut self, field: &Field, value: AnyValue) {
+        let name = field.name();
+        if name == "message" {
+            self.body = Some(value);
+        } else if !METRIC_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+            self.attributes.push((Key::new(name.trim_start_matches("r#")), value));
+        }
+    }
+}
+
+impl Visit for RecordVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, i64::try_from(value).map_or_else(|_| value.to_string().into(), AnyValue::from));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.to_owned().into());
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.insert(field, value.to_string().into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
@@ -0,0 +1,224 @@
+//! OTLP over HTTP with protobuf payloads, for collectors and vendors only accepting HTTP.
+//!
+//! Selected with `OTEL_EXPORTER_OTLP_PROTOCOL=http/protobuf`, the spans, metrics and logs are posted to `/v1/traces`,
+//! `/v1/metrics` and `/v1/logs` under `OTEL_EXPORTER_OTLP_ENDPOINT`, with the headers of `OTEL_EXPORTER_OTLP_HEADERS` (e.g. the
+//! `Authorization` header of a hosted backend). Only `http://` endpoints are supported, as the binaries are built without
+//! TLS: reach `https://` backends through a local collector.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures_util::future::BoxFuture;
+use opentelemetry::logs::{LogError, LogResult};
+use opentelemetry::metrics::MetricsError;
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema;
+use opentelemetry_sdk::export::logs::{LogData, LogExporter};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::metrics::data::{ResourceMetrics, Temporality};
+use opentelemetry_sdk::metrics::exporter::PushMetricsExporter;
+use opentelemetry_sdk::metrics::reader::{
+    AggregationSelector, DefaultAggregationSelector, DefaultTemporalitySelector, TemporalitySelector,
+};
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind};
+use opentelemetry_sdk::Resource;
+use prost::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Largest response read, which is plenty for the status line and an error message
+const MAX_RESPONSE: usize = 16384;
+
+/// Parses the `key1=value1,key2=value2` format of `OTEL_EXPORTER_OTLP_HEADERS` and `OTEL_RESOURCE_ATTRIBUTES`, whose
+/// values are percent-encoded
+pub fn parse_key_values(value: &str) -> Vec<(String, String)> {
+    value
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_owned(), percent_decode(value.trim())))
+        .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes.get(index + 1..index + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.filter(|_| bytes[index] == b'%').and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Client posting protobuf payloads to an OTLP/HTTP endpoint
+#[derive(Debug, Clone)]
+struct Client {
+    /// `host:port` the requests are sent to
+    authority: String,
+    /// Path prefix of the endpoint, without trailing slash
+    base_path: String,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+}
+
+impl Client {
+    fn new(endpoint: &str, headers: &[(String, String)], timeout: Duration) -> Result<Self> {
+        let Some(rest) = endpoint.strip_prefix("http://") else {
+            if endpoint.starts_with("https://") {
+                bail!("OTLP endpoint '{}' needs TLS, which this build doesn't support; export through a local collector", endpoint);
+            }
+            bail!("OTLP endpoint '{}' must start with 'http://'", endpoint);
+        };
+        let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let authority = if host.rsplit_once(':').is_some_and(|(_, port)| !port.contains(']')) {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Self { authority, base_path: path.trim_end_matches('/').to_owned(), headers: headers.to_vec(), timeout })
+    }
+
+    async fn post(&self, path: &str, body: Vec<u8>) -> Result<()> {
+        tokio::time::timeout(self.timeout, self.send(path, body))
+            .await
+            .with_context(|| format!("OTLP export timed out after {:?}", self.timeout))?
+    }
+
+    async fn send(&self, path: &str, body: Vec<u8>) -> Result<()> {
+        let mut stream = TcpStream::connect(&self.authority)
+            .await
+            .with_context(|| format!("Failed to connect to the OTLP endpoint '{}'", self.authority))?;
+        let mut request = format!(
+            "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-protobuf\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.base_path, path, self.authority, body.len()
+        );
+        for (key, value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(&body).await?;
+
+        let mut response = Vec::new();
+        (&mut stream).take(MAX_RESPONSE as u64).read_to_end(&mut response).await?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => {
+                let message = response.split_once("\r\n\r\n").map(|(_, body)| body.trim()).unwrap_or_default();
+                bail!("OTLP endpoint answered '{}' {}", status, message)
+            }
+        }
+    }
+}
+
+/// Exports the spans to `/v1/traces`
+#[derive(Debug)]
+pub struct HttpSpanExporter(Client);
+
+impl HttpSpanExporter {
+    pub fn new(endpoint: &str, headers: &[(String, String)], timeout: Duration) -> Result<Self> {
+        Ok(Self(Client::new(endpoint, headers, timeout)?))
+    }
+}
+
+impl SpanExporter for HttpSpanExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let client = self.0.clone();
+        let request = ExportTraceServiceRequest { resource_spans: batch.into_iter().map(Into::into).collect() };
+        Box::pin(async move { client.post("/v1/traces", request.encode_to_vec()).await.map_err(|err| format!("{:#}", err).into()) })
+    }
+}
+
+/// Exports the metrics to `/v1/metrics`, with the default temporality and aggregations
+#[derive(Debug)]
+pub struct HttpMetricsExporter {
+    client: Client,
+    temporality: DefaultTemporalitySelector,
+    aggregation: DefaultAggregationSelector,
+}
+
+impl HttpMetricsExporter {
+    pub fn new(endpoint: &str, headers: &[(String, String)], timeout: Duration) -> Result<Self> {
+        Ok(Self {
+            client: Client::new(endpoint, headers, timeout)?,
+            temporality: DefaultTemporalitySelector::new(),
+            aggregation: DefaultAggregationSelector::new(),
+        })
+    }
+}
+
+impl TemporalitySelector for HttpMetricsExporter {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.temporality.temporality(kind)
+    }
+}
+
+impl AggregationSelector for HttpMetricsExporter {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.aggregation.aggregation(kind)
+    }
+}
+
+#[async_trait]
+impl PushMetricsExporter for HttpMetricsExporter {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+        let request = ExportMetricsServiceRequest::from(&*metrics);
+        self.client
+            .post("/v1/metrics", request.encode_to_vec())
+            .await
+            .map_err(|err| MetricsError::Other(format!("{:#}", err)))
+    }
+
+    async fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+        Ok(())
+    }
+}
+
+/// Exports the logs to `/v1/logs`
+#[derive(Debug)]
+pub struct HttpLogExporter {
+    client: Client,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl HttpLogExporter {
+    pub fn new(endpoint: &str, headers: &[(String, String)], timeout: Duration) -> Result<Self> {
+        Ok(Self { client: Client::new(endpoint, headers, timeout)?, resource: ResourceAttributesWithSchema::default() })
+    }
+}
+
+#[async_trait]
+impl LogExporter for HttpLogExporter {
+    async fn export(&mut self, batch: Vec<LogData>) -> LogResult<()> {
+        let request = ExportLogsServiceRequest {
+            resource_logs: batch.into_iter().map(|log| (log, &self.resource).into()).collect(),
+        };
+        self.client
+            .post("/v1/logs", request.encode_to_vec())
+            .await
+            .map_err(|err| LogError::Other(format!("{:#}", err).into()))
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
@@ -0,0 +1,43 @@
+//! Sampling of the per-packet spans and log events (`packetTraceSampling`), which cost CPU on gigabit links: with a
+//! sampling of N, each of them is only emitted for one in N packets reaching it, and 0 disables them. The lifecycle
+//! logs and the metrics (recorded by DEBUG events) are unaffected.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use tracing::info;
+
+static SAMPLING: AtomicU32 = AtomicU32::new(1);
+
+/// Emits an event at `level` for one packet in `packetTraceSampling` reaching this call site, e.g.
+/// `packet_event!(Level::TRACE, "Sent {} bytes", sent_bytes)`
+#[macro_export]
+macro_rules! packet_event {
+    ($level:expr, $($arg:tt)+) => {
+        if ::tracing::level_enabled!($level) && $crate::packet_trace::sample({
+            static PACKETS: ::std::sync::atomic::AtomicU32 = ::std::sync::atomic::AtomicU32::new(0);
+            &PACKETS
+        }) {
+            ::tracing::event!($level, $($arg)+);
+        }
+    };
+}
+
+/// Traces one packet in `sampling`, or none if 0
+pub fn set_sampling(sampling: u32) {
+    if SAMPLING.swap(sampling, Ordering::Relaxed) != sampling {
+        match sampling {
+            0 => info!("Per-packet tracing disabled"),
+            1 => info!("Tracing every packet"),
+            sampling => info!("Tracing one packet in {}", sampling),
+        }
+    }
+}
+
+/// Whether to trace the current packet, given the number of `packets` that reached the same call site
+pub fn sample(packets: &AtomicU32) -> bool {
+    match SAMPLING.load(Ordering::Relaxed) {
+        0 => false,
+        1 => true,
+        sampling => packets.fetch_add(1, Ordering::Relaxed).is_multiple_of(sampling),
+    }
+}
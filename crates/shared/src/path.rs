@@ -0,0 +1,158 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::control::PathReport;
+
+/// Queueing delay above which a path is reported as building up a queue
+pub const QUEUEING_DELAY_WARNING: Duration = Duration::from_millis(100);
+
+/// How long the minimum one-way delay of a window serves as the base of the next one, so that the
+/// base follows the drift between the sender and receiver clocks
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Returns the current time to put in a frame, in microseconds on this process's monotonic clock
+///
+/// Timestamps wrap around every 71 minutes and only make sense relative to each other.
+pub fn timestamp() -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+/// Traffic received on one path, with the loss estimated from gaps in the sequence numbers
+#[derive(Debug, Default)]
+pub struct PathStats {
+    packets: u32,
+    bytes: u64,
+    highest_sequence: Option<u32>,
+    /// Sequenced frames expected since the last report, given the highest sequence number seen
+    expected: u32,
+    /// Sequenced frames received since the last report
+    received: u32,
+}
+
+impl PathStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a received frame, with its sequence number if it has one
+    ///
+    /// Returns the number of sequence numbers it skipped, i.e. frames lost unless they arrive later.
+    pub fn record(&mut self, sequence: Option<u32>, bytes: usize) -> u32 {
+        self.packets = self.packets.saturating_add(1);
+        self.bytes = self.bytes.saturating_add(bytes as u64);
+
+        let Some(sequence) = sequence else {
+            return 0;
+        };
+        self.received = self.received.saturating_add(1);
+        let ahead = match self.highest_sequence {
+            None => 1,
+            Some(highest) => sequence.wrapping_sub(highest) as i32,
+        };
+        if ahead <= 0 {
+            // Reordered; its slot was already expected
+            return 0;
+        }
+        self.expected = self.expected.saturating_add(ahead as u32);
+        self.highest_sequence = Some(sequence);
+        ahead as u32 - 1
+    }
+
+    /// Returns whether the frames received on this path are numbered, so its loss is known
+    pub fn is_sequenced(&self) -> bool {
+        self.highest_sequence.is_some()
+    }
+
+    /// Returns what was received since the previous report, and starts a new one
+    pub fn take_report(&mut self) -> PathReport {
+        let lost = self.expected.saturating_sub(self.received);
+        let report = PathReport {
+            packets: self.packets,
+            bytes: self.bytes,
+            loss_permille: if self.expected == 0 { 0 } else { (lost as u64 * 1000 / self.expected as u64) as u16 },
+        };
+        *self = Self {
+            highest_sequence: self.highest_sequence,
+            ..Self::default()
+        };
+        report
+    }
+}
+
+/// Follows the queueing delay of a path from the sender timestamps of the frames received on it
+///
+/// The clocks of both ends aren't synchronized, so the one-way delay of a frame is only known up to a
+/// constant offset; the queueing delay is how far it rises above the smallest one-way delay recently
+/// seen, which is taken as the path's propagation delay.
+#[derive(Debug)]
+pub struct DelayTrend {
+    /// Smallest one-way delay of the current and the previous window
+    base: [Option<u32>; 2],
+    window_started_at: Instant,
+    /// Smoothed queueing delay in microseconds
+    smoothed: Option<u32>,
+    building_up: bool,
+}
+
+impl Default for DelayTrend {
+    fn default() -> Self {
+        Self {
+            base: [None; 2],
+            window_started_at: Instant::now(),
+            smoothed: None,
+            building_up: false,
+        }
+    }
+}
+
+impl DelayTrend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the sender timestamp of a received frame
+    ///
+    /// Returns `Some(true)` when the queueing delay rises above [`QUEUEING_DELAY_WARNING`], and
+    /// `Some(false)` when it falls back below half of it.
+    pub fn record(&mut self, timestamp: u32) -> Option<bool> {
+        let delay = self::timestamp().wrapping_sub(timestamp);
+        if self.window_started_at.elapsed() >= BASE_DELAY_WINDOW {
+            self.base = [None, self.base[0]];
+            self.window_started_at = Instant::now();
+        }
+        let current = &mut self.base[0];
+        if current.is_none_or(|base| (delay.wrapping_sub(base) as i32) < 0) {
+            *current = Some(delay);
+        }
+
+        let base = self.base
+            .iter()
+            .flatten()
+            .copied()
+            .min_by_key(|base| base.wrapping_sub(delay) as i32)
+            .unwrap_or(delay);
+        let queueing = (delay.wrapping_sub(base) as i32).max(0) as u32;
+        let smoothed = match self.smoothed {
+            Some(smoothed) => ((smoothed as u64 * 7 + queueing as u64) / 8) as u32,
+            None => queueing,
+        };
+        self.smoothed = Some(smoothed);
+
+        let warning = QUEUEING_DELAY_WARNING.as_micros() as u32;
+        if !self.building_up && smoothed > warning {
+            self.building_up = true;
+            return Some(true);
+        }
+        if self.building_up && smoothed < warning / 2 {
+            self.building_up = false;
+            return Some(false);
+        }
+        None
+    }
+
+    /// Returns the smoothed queueing delay, if any timestamped frame was received
+    pub fn queueing_delay(&self) -> Option<Duration> {
+        self.smoothed.map(|smoothed| Duration::from_micros(smoothed as u64))
+    }
+}
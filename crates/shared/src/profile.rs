@@ -0,0 +1,64 @@
+use std::fmt;
+
+use dashmap::DashMap;
+
+/// Sizing of the in-memory structures, selected with the `lowMemory` setting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MemoryProfile {
+    #[default]
+    Standard,
+    /// For embedded targets (e.g. OpenWrt devices with 64–128 MB of RAM)
+    Low,
+}
+
+impl MemoryProfile {
+    pub fn new(low_memory: bool) -> Self {
+        if low_memory { Self::Low } else { Self::Standard }
+    }
+
+    /// Maximum number of entries in the client (server) and sending routine (client) maps
+    pub fn max_entries(&self) -> Option<usize> {
+        match self {
+            Self::Standard => None,
+            Self::Low => Some(64),
+        }
+    }
+
+    /// Capacity of bounded channels between tasks
+    pub fn channel_capacity(&self) -> usize {
+        match self {
+            Self::Standard => 1024,
+            Self::Low => 64,
+        }
+    }
+
+    /// Number of samples kept by history ring buffers; zero disables them
+    pub fn history_len(&self) -> usize {
+        match self {
+            Self::Standard => 64,
+            Self::Low => 0,
+        }
+    }
+
+    /// Creates a map sharded according to the profile
+    pub fn new_map<K: Eq + std::hash::Hash, V>(&self) -> DashMap<K, V> {
+        match self {
+            Self::Standard => DashMap::new(),
+            Self::Low => DashMap::with_shard_amount(4),
+        }
+    }
+}
+
+impl fmt::Display for MemoryProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Standard => write!(f, "standard"),
+            Self::Low => write!(
+                f,
+                "low (max {} entries, channel capacity {}, history disabled)",
+                self.max_entries().unwrap_or_default(),
+                self.channel_capacity(),
+            ),
+        }
+    }
+}
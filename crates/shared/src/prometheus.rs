@@ -0,0 +1,209 @@
+//! Prometheus exporter, for setups scraping Prometheus without an OpenTelemetry collector.
+//!
+//! Selected with `OTEL_METRICS_EXPORTER=prometheus`, it serves the metrics in the Prometheus text format at
+//! `/metrics` on `OTEL_EXPORTER_PROMETHEUS_HOST:OTEL_EXPORTER_PROMETHEUS_PORT` (`localhost:9464` by default), instead
+//! of pushing them to the OTLP endpoint. The metrics keep the names they have over OTLP.
+
+use std::fmt::{Display, Write};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use opentelemetry::global;
+use opentelemetry_sdk::metrics::data::{self, ResourceMetrics, Temporality};
+use opentelemetry_sdk::metrics::reader::{AggregationSelector, MetricReader, TemporalitySelector};
+use opentelemetry_sdk::metrics::{InstrumentKind, ManualReader, MeterProviderBuilder, Pipeline, SdkMeterProvider};
+use opentelemetry_sdk::{AttributeSet, Resource};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Interval between the attempts to bind the address of the exporter
+const BIND_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Largest request read, which is plenty for a scrape
+const MAX_REQUEST: usize = 8192;
+
+/// Address the exporter listens on, from the standard environment variables
+pub fn listen_addr() -> String {
+    let host = std::env::var("OTEL_EXPORTER_PROMETHEUS_HOST").unwrap_or_else(|_| "localhost".to_owned());
+    let port = std::env::var("OTEL_EXPORTER_PROMETHEUS_PORT").unwrap_or_else(|_| "9464".to_owned());
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Reader collecting the metrics on every scrape, shared between the meter provider and the HTTP server
+#[derive(Debug, Clone)]
+struct Reader(Arc<ManualReader>);
+
+impl TemporalitySelector for Reader {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+impl AggregationSelector for Reader {
+    fn aggregation(&self, kind: InstrumentKind) -> opentelemetry_sdk::metrics::Aggregation {
+        self.0.aggregation(kind)
+    }
+}
+
+impl MetricReader for Reader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+        self.0.shutdown()
+    }
+}
+
+/// Starts serving the metrics on `addr`, returning the meter provider recording them. If the address can't be bound
+/// (e.g. the port is still taken by a previous instance), it is retried in the background.
+pub fn init_meter_provider(addr: &str, resource: Resource) -> SdkMeterProvider {
+    let reader = Reader(Arc::new(ManualReader::builder().build()));
+    let meter_provider = MeterProviderBuilder::default()
+        .with_resource(resource)
+        .with_reader(reader.clone())
+        .build();
+    tokio::spawn(serve(addr.to_owned(), reader));
+
+    global::set_meter_provider(meter_provider.clone());
+    meter_provider
+}
+
+async fn serve(addr: String, reader: Reader) {
+    let listener = loop {
+        match TcpListener::bind(&addr).await {
+            Ok(listener) => break listener,
+            Err(err) => {
+                warn!("Failed to bind the Prometheus exporter to '{}'; retrying in {:?}: {}", addr, BIND_RETRY_INTERVAL, err);
+                tokio::time::sleep(BIND_RETRY_INTERVAL).await;
+            }
+        }
+    };
+    info!("Serving Prometheus metrics on 'http://{}/metrics'", addr);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let reader = reader.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = respond(stream, &reader).await {
+                        debug!("Failed to answer a Prometheus scrape: {:?}", err);
+                    }
+                });
+            }
+            Err(err) => debug!("Failed to accept a Prometheus scrape: {:?}", err),
+        }
+    }
+}
+
+/// Answers an HTTP request with the metrics if it asks for `/metrics`, or with a 404
+async fn respond(mut stream: TcpStream, reader: &Reader) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.lines().next().and_then(|line| line.split(' ').nth(1)).unwrap_or_default();
+
+    let (status, body) = if path == "/metrics" || path.starts_with("/metrics?") {
+        let mut metrics = ResourceMetrics { resource: Resource::empty(), scope_metrics: Vec::new() };
+        reader.collect(&mut metrics)?;
+        ("200 OK", render(&metrics))
+    } else {
+        ("404 Not Found", "Not found; metrics are at /metrics\n".to_owned())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Renders the metrics in the Prometheus text format
+fn render(metrics: &ResourceMetrics) -> String {
+    let mut text = String::new();
+    for metric in metrics.scope_metrics.iter().flat_map(|scope| &scope.metrics) {
+        let name = metric.name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != ':', "_");
+        if !metric.description.is_empty() {
+            let _ = writeln!(text, "# HELP {} {}", name, metric.description.replace('\\', "\\\\").replace('\n', "\\n"));
+        }
+        let data = metric.data.as_any();
+        if let Some(sum) = data.downcast_ref::<data::Sum<u64>>() {
+            write_sum(&mut text, &name, sum.is_monotonic, &sum.data_points);
+        } else if let Some(sum) = data.downcast_ref::<data::Sum<i64>>() {
+            write_sum(&mut text, &name, sum.is_monotonic, &sum.data_points);
+        } else if let Some(sum) = data.downcast_ref::<data::Sum<f64>>() {
+            write_sum(&mut text, &name, sum.is_monotonic, &sum.data_points);
+        } else if let Some(gauge) = data.downcast_ref::<data::Gauge<u64>>() {
+            write_sum(&mut text, &name, false, &gauge.data_points);
+        } else if let Some(gauge) = data.downcast_ref::<data::Gauge<i64>>() {
+            write_sum(&mut text, &name, false, &gauge.data_points);
+        } else if let Some(gauge) = data.downcast_ref::<data::Gauge<f64>>() {
+            write_sum(&mut text, &name, false, &gauge.data_points);
+        } else if let Some(histogram) = data.downcast_ref::<data::Histogram<u64>>() {
+            write_histogram(&mut text, &name, &histogram.data_points);
+        } else if let Some(histogram) = data.downcast_ref::<data::Histogram<f64>>() {
+            write_histogram(&mut text, &name, &histogram.data_points);
+        }
+    }
+    text
+}
+
+/// Writes a sum, as a counter if monotonic and as a gauge otherwise
+fn write_sum<T: Display>(text: &mut String, name: &str, monotonic: bool, points: &[data::DataPoint<T>]) {
+    let _ = writeln!(text, "# TYPE {} {}", name, if monotonic { "counter" } else { "gauge" });
+    for point in points {
+        let _ = writeln!(text, "{}{} {}", name, labels(&point.attributes, None), point.value);
+    }
+}
+
+fn write_histogram<T: Display>(text: &mut String, name: &str, points: &[data::HistogramDataPoint<T>]) {
+    let _ = writeln!(text, "# TYPE {} histogram", name);
+    for point in points {
+        let mut cumulative = 0;
+        for (index, count) in point.bucket_counts.iter().enumerate() {
+            cumulative += count;
+            let bound = point.bounds.get(index).map_or_else(|| "+Inf".to_owned(), f64::to_string);
+            let _ = writeln!(text, "{}_bucket{} {}", name, labels(&point.attributes, Some(&bound)), cumulative);
+        }
+        let _ = writeln!(text, "{}_sum{} {}", name, labels(&point.attributes, None), point.sum);
+        let _ = writeln!(text, "{}_count{} {}", name, labels(&point.attributes, None), point.count);
+    }
+}
+
+/// Returns the label set of a data point, with the `le` label of a histogram bucket
+fn labels(attributes: &AttributeSet, le: Option<&str>) -> String {
+    let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    let labels = attributes
+        .iter()
+        .map(|(key, value)| {
+            let key = key.as_str().replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_");
+            format!("{}=\"{}\"", key, escape(&value.as_str()))
+        })
+        .chain(le.map(|le| format!("le=\"{}\"", le)))
+        .collect::<Vec<_>>();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
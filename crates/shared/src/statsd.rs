@@ -0,0 +1,209 @@
+//! StatsD exporter, for setups where neither an OpenTelemetry collector nor Prometheus scraping is convenient (e.g.
+//! OpenWrt with collectd, or a Datadog agent).
+//!
+//! Selected with `OTEL_METRICS_EXPORTER=statsd` or `dogstatsd`, it sends the metrics over UDP to
+//! `STATSD_HOST:STATSD_PORT` (`localhost:8125` by default) every few seconds: counters as the increase since the last
+//! send (`|c`), up-down counters as gauges (`|g`), and histograms as the increase of their `.count` and `.sum`.
+//! DogStatsD carries the attributes as tags; plain StatsD has none, so their values are appended to the metric names,
+//! e.g. `rengarde_path_sent_bytes_total.iface_name.eth0`.
+
+use std::fmt::Display;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use opentelemetry::global;
+use opentelemetry::metrics::MetricsError;
+use opentelemetry_sdk::metrics::data::{self, ResourceMetrics, Temporality};
+use opentelemetry_sdk::metrics::exporter::PushMetricsExporter;
+use opentelemetry_sdk::metrics::reader::{AggregationSelector, DefaultAggregationSelector, TemporalitySelector};
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, MeterProviderBuilder, PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::{runtime, AttributeSet, Resource};
+use tracing::info;
+use tokio::net::UdpSocket;
+use tokio::sync::OnceCell;
+
+/// Largest datagram sent, which fits the MTU of most paths
+const MAX_DATAGRAM: usize = 1432;
+
+/// Address the metrics are sent to, from the environment variables StatsD clients commonly read
+pub fn target_addr() -> String {
+    let host = std::env::var("STATSD_HOST").unwrap_or_else(|_| "localhost".to_owned());
+    let port = std::env::var("STATSD_PORT").unwrap_or_else(|_| "8125".to_owned());
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Starts sending the metrics to `addr`, returning the meter provider recording them
+pub fn init_meter_provider(addr: &str, dogstatsd: bool, resource: Resource) -> SdkMeterProvider {
+    let exporter = StatsdExporter::new(addr, dogstatsd);
+
+    let reader = PeriodicReader::builder(exporter, runtime::Tokio)
+        .with_interval(crate::METRICS_INTERVAL)
+        .build();
+    let meter_provider = MeterProviderBuilder::default()
+        .with_resource(resource)
+        .with_reader(reader)
+        .build();
+
+    global::set_meter_provider(meter_provider.clone());
+    meter_provider
+}
+
+/// Sends the metrics to a StatsD server, with the attributes as tags if it speaks DogStatsD
+#[derive(Debug)]
+pub struct StatsdExporter {
+    addr: String,
+    /// Socket connected to the server, once its address resolved, so an unresolvable one is retried on every send
+    socket: OnceCell<UdpSocket>,
+    dogstatsd: bool,
+    aggregation: DefaultAggregationSelector,
+}
+
+impl StatsdExporter {
+    pub fn new(addr: &str, dogstatsd: bool) -> Self {
+        Self { addr: addr.to_owned(), socket: OnceCell::new(), dogstatsd, aggregation: DefaultAggregationSelector::new() }
+    }
+
+    async fn connect(&self) -> Result<UdpSocket> {
+        let target = tokio::net::lookup_host(&self.addr)
+            .await
+            .with_context(|| format!("Failed to resolve the StatsD address '{}'", self.addr))?
+            .next()
+            .with_context(|| format!("No address found for the StatsD address '{}'", self.addr))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+        socket.connect(target).await?;
+        info!("Sending {} metrics to '{}'", if self.dogstatsd { "DogStatsD" } else { "StatsD" }, target);
+        Ok(socket)
+    }
+
+    /// Renders the metrics in the StatsD line format
+    fn render(&self, metrics: &ResourceMetrics) -> Vec<String> {
+        let mut lines = Vec::new();
+        for metric in metrics.scope_metrics.iter().flat_map(|scope| &scope.metrics) {
+            let name = metric.name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.', "_");
+            let data = metric.data.as_any();
+            if let Some(sum) = data.downcast_ref::<data::Sum<u64>>() {
+                self.write_sum(&mut lines, &name, sum.is_monotonic, &sum.data_points);
+            } else if let Some(sum) = data.downcast_ref::<data::Sum<i64>>() {
+                self.write_sum(&mut lines, &name, sum.is_monotonic, &sum.data_points);
+            } else if let Some(sum) = data.downcast_ref::<data::Sum<f64>>() {
+                self.write_sum(&mut lines, &name, sum.is_monotonic, &sum.data_points);
+            } else if let Some(gauge) = data.downcast_ref::<data::Gauge<u64>>() {
+                self.write_sum(&mut lines, &name, false, &gauge.data_points);
+            } else if let Some(gauge) = data.downcast_ref::<data::Gauge<i64>>() {
+                self.write_sum(&mut lines, &name, false, &gauge.data_points);
+            } else if let Some(gauge) = data.downcast_ref::<data::Gauge<f64>>() {
+                self.write_sum(&mut lines, &name, false, &gauge.data_points);
+            } else if let Some(histogram) = data.downcast_ref::<data::Histogram<u64>>() {
+                self.write_histogram(&mut lines, &name, &histogram.data_points);
+            } else if let Some(histogram) = data.downcast_ref::<data::Histogram<f64>>() {
+                self.write_histogram(&mut lines, &name, &histogram.data_points);
+            }
+        }
+        lines
+    }
+
+    /// Writes a sum, as a counter increase if monotonic and as a gauge otherwise
+    fn write_sum<T: Display>(&self, lines: &mut Vec<String>, name: &str, monotonic: bool, points: &[data::DataPoint<T>]) {
+        for point in points {
+            let value = point.value.to_string();
+            if monotonic {
+                if value != "0" {
+                    lines.push(self.line(name, &point.attributes, &value, "c"));
+                }
+            } else {
+                // A signed gauge value is relative, so a negative one is sent from zero
+                if value.starts_with('-') {
+                    lines.push(self.line(name, &point.attributes, "0", "g"));
+                }
+                lines.push(self.line(name, &point.attributes, &value, "g"));
+            }
+        }
+    }
+
+    fn write_histogram<T: Display>(&self, lines: &mut Vec<String>, name: &str, points: &[data::HistogramDataPoint<T>]) {
+        for point in points.iter().filter(|point| point.count > 0) {
+            lines.push(self.line(&format!("{}.count", name), &point.attributes, &point.count.to_string(), "c"));
+            lines.push(self.line(&format!("{}.sum", name), &point.attributes, &point.sum.to_string(), "c"));
+        }
+    }
+
+    fn line(&self, name: &str, attributes: &AttributeSet, value: &str, kind: &str) -> String {
+        let sanitize = |value: &str| value.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-' && c != '.', "_");
+        let attributes = attributes.iter().filter(|(_, value)| !value.as_str().is_empty());
+        if self.dogstatsd {
+            let tags = attributes
+                .map(|(key, value)| format!("{}:{}", sanitize(key.as_str()), sanitize(&value.as_str())))
+                .collect::<Vec<_>>();
+            if tags.is_empty() {
+                format!("{}:{}|{}", name, value, kind)
+            } else {
+                format!("{}:{}|{}|#{}", name, value, kind, tags.join(","))
+            }
+        } else {
+            let name = attributes.fold(name.to_owned(), |name, (key, value)| {
+                format!("{}.{}.{}", name, sanitize(key.as_str()), sanitize(&value.as_str()).replace('.', "_"))
+            });
+            format!("{}:{}|{}", name, value, kind)
+        }
+    }
+
+    async fn send(&self, datagram: &str) -> opentelemetry::metrics::Result<()> {
+        let socket = self.socket.get_or_try_init(|| self.connect()).await.map_err(|err| MetricsError::Other(format!("{:#}", err)))?;
+        socket
+            .send(datagram.as_bytes())
+            .await
+            .map(|_| ())
+            .map_err(|err| MetricsError::Other(format!("Failed to send metrics to StatsD: {}", err)))
+    }
+}
+
+impl TemporalitySelector for StatsdExporter {
+    /// StatsD sums the counter increases itself, but gauges are absolute
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        match kind {
+            InstrumentKind::UpDownCounter | InstrumentKind::ObservableUpDownCounter | InstrumentKind::ObservableGauge => {
+                Temporality::Cumulative
+            }
+            _ => Temporality::Delta,
+        }
+    }
+}
+
+impl AggregationSelector for StatsdExporter {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.aggregation.aggregation(kind)
+    }
+}
+
+#[async_trait]
+impl PushMetricsExporter for StatsdExporter {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+        let mut datagram = String::new();
+        for line in self.render(metrics) {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                self.send(&datagram).await?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.send(&datagram).await?;
+        }
+        Ok(())
+    }
+
+    async fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+//! Traffic summaries logged periodically by both binaries (`summaryInterval`), so operators running without any
+//! metrics backend still see the traffic of each path in their logs.
+
+use std::time::Duration;
+
+/// Formats `bytes` and `packets` transferred over `elapsed` as rates, e.g. `12.3 kB/s 45 pkt/s`
+pub fn rates(bytes: usize, packets: usize, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    let bytes_per_second = bytes as f64 / secs;
+    let bytes = match bytes_per_second {
+        rate if rate >= 1e6 => format!("{:.1} MB/s", rate / 1e6),
+        rate if rate >= 1e3 => format!("{:.1} kB/s", rate / 1e3),
+        rate => format!("{:.0} B/s", rate),
+    };
+    let packets_per_second = packets as f64 / secs;
+    if packets_per_second < 10.0 {
+        format!("{} {:.1} pkt/s", bytes, packets_per_second)
+    } else {
+        format!("{} {:.0} pkt/s", bytes, packets_per_second)
+    }
+}
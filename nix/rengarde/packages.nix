@@ -40,4 +40,9 @@ in {
     pname = "rengarde-server";
     cargoExtraArgs = "-p server";
   });
+  rengarde = crane.buildPackage (basePkg // {
+    meta.mainProgram = "rengarde";
+    pname = "rengarde";
+    cargoExtraArgs = "-p rengarde";
+  });
 }